@@ -0,0 +1,118 @@
+use std::str::FromStr;
+use actix_web::dev::BodySize;
+use serde_json::json;
+
+
+const REDACTED: &'static str = "<redacted>";
+// query params whose values never go in the log
+const SECRET_PARAMS: [&'static str; 1] = ["password"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(AccessLogFormat::Text),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!("Unknown access log format '{}'", s)),
+        }
+    }
+}
+
+// which responses get a line: info is every request, warn is 4xx and 5xx, error only 5xx
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum AccessLogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+}
+
+impl FromStr for AccessLogLevel {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(AccessLogLevel::Off),
+            "error" => Ok(AccessLogLevel::Error),
+            "warn" => Ok(AccessLogLevel::Warn),
+            "info" => Ok(AccessLogLevel::Info),
+            _ => Err(format!("Unknown access log level '{}'", s)),
+        }
+    }
+}
+
+impl AccessLogLevel {
+    pub fn logs (&self, status: u16) -> bool {
+        let needed = if status >= 500 {
+            AccessLogLevel::Error
+        } else if status >= 400 {
+            AccessLogLevel::Warn
+        } else {
+            AccessLogLevel::Info
+        };
+        *self >= needed
+    }
+}
+
+// a download token in a log is as good as the link itself, "/download/abc/done" becomes "/download/<redacted>/done"
+pub fn redact_path (path: &str) -> String {
+    match path.strip_prefix("/download/") {
+        None => path.to_string(),
+        Some(rest) => match rest.find('/') {
+            None => format!("/download/{}", REDACTED),
+            Some(i) => format!("/download/{}{}", REDACTED, &rest[i..]),
+        },
+    }
+}
+
+pub fn redact_query (query: &str) -> String {
+    query.split('&').map(|pair| {
+        let name = pair.splitn(2, '=').next().unwrap_or("");
+        if SECRET_PARAMS.contains(&name) {
+            format!("{}={}", name, REDACTED)
+        } else {
+            pair.to_string()
+        }
+    }).collect::<Vec<String>>().join("&")
+}
+
+// streamed bodies have no size until they are sent, those log as unknown
+fn body_bytes (size: BodySize) -> Option<u64> {
+    match size {
+        BodySize::None | BodySize::Empty => Some(0),
+        BodySize::Sized(n) => Some(n as u64),
+        BodySize::Sized64(n) => Some(n),
+        BodySize::Stream => None,
+    }
+}
+
+pub fn log (format: AccessLogFormat, method: &str, path: &str, query: &str, status: u16, size: BodySize, elapsed_ms: u128) {
+    let path = redact_path(path);
+    let path = if query.is_empty() { path } else { format!("{}?{}", path, redact_query(query)) };
+    let bytes = body_bytes(size);
+    match format {
+        AccessLogFormat::Text => println!(
+            "access {} {} {} {} {}ms",
+            method,
+            path,
+            status,
+            bytes.map(|bytes| bytes.to_string()).unwrap_or_else(|| String::from("-")),
+            elapsed_ms,
+        ),
+        AccessLogFormat::Json => println!("{}", json!({
+            "log": "access",
+            "method": method,
+            "path": path,
+            "status": status,
+            "bytes": bytes,
+            "elapsed_ms": elapsed_ms as u64,
+        })),
+    }
+}
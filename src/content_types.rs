@@ -0,0 +1,173 @@
+
+// for files stored before uploads kept their content type, and for sniffing one with SNIFF_CONTENT_TYPE
+//  anything not listed here is opaque bytes
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("zip", "application/zip"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+];
+
+// these can run script in the browser, and inline they would run on the download origin
+const NEVER_INLINE: &[&str] = &[
+    "text/html",
+    "image/svg+xml",
+    "application/xhtml+xml",
+    "application/xml",
+    "text/xml",
+];
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+// lowercased and without the dot, None for a name with none
+//  trailing dots and spaces are dropped first, windows does the same when saving, so "x.exe." is still an exe
+pub fn extension (filename: &str) -> Option<String> {
+    let filename = filename.trim_end_matches(|c| c == '.' || c == ' ');
+    match filename.rsplit('.').next() {
+        Some(extension) if extension.len() < filename.len() => Some(extension.to_lowercase()),
+        _ => None,
+    }
+}
+
+// ALLOWED_EXTENSIONS and BLOCKED_EXTENSIONS, both already lowercase and without dots
+//  blocked wins, and with an allowed list a name without an extension can't be on it
+pub fn check_extension (filename: &str, allowed: &[String], blocked: &[String]) -> Result<(), String> {
+    let extension = extension(filename);
+    if let Some(extension) = &extension {
+        if blocked.contains(extension) {
+            return Err(format!("Extension .{} is blocked!", extension));
+        }
+    }
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    match extension {
+        None => Err(format!("Filename needs an extension, one of .{}!", allowed.join(", ."))),
+        Some(extension) if !allowed.contains(&extension) => Err(format!("Extension .{} is not allowed, use one of .{}!", extension, allowed.join(", ."))),
+        Some(_) => Ok(()),
+    }
+}
+
+pub fn content_type_for (filename: &str) -> &'static str {
+    let extension = match extension(filename) {
+        Some(extension) => extension,
+        None => return OCTET_STREAM,
+    };
+    EXTENSIONS.iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, content_type)| *content_type)
+        .unwrap_or(OCTET_STREAM)
+}
+
+// what an upload is stored as: the type it came with, else the extension's when sniffing, else the default
+pub fn resolve (given: Option<String>, filename: &str, default: &str, sniff: bool) -> String {
+    match given.map(|given| given.trim().to_string()).filter(|given| !given.is_empty()) {
+        Some(given) => given,
+        None if sniff && content_type_for(filename) != OCTET_STREAM => content_type_for(filename).to_string(),
+        None => default.to_string(),
+    }
+}
+
+// inline only for types in the policy, exact or a "type/*" prefix, and never for the script capable ones above
+//  stored types come from clients, so parameters and case must not get one past the checks
+pub fn is_inline (content_type: &str, inline_types: &[String]) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let content_type = content_type.as_str();
+    if NEVER_INLINE.contains(&content_type) {
+        return false;
+    }
+    inline_types.iter().any(|inline| {
+        if inline.ends_with("/*") {
+            content_type.starts_with(&inline[..inline.len() - 1])
+        } else {
+            inline == content_type
+        }
+    })
+}
+
+pub fn disposition_for (content_type: &str, inline_types: &[String]) -> &'static str {
+    if is_inline(content_type, inline_types) { "inline" } else { "attachment" }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_prefers_given_type () {
+        assert_eq!(resolve(Some("image/png".to_string()), "x.pdf", OCTET_STREAM, true), "image/png");
+        assert_eq!(resolve(Some(" text/plain ".to_string()), "x.pdf", OCTET_STREAM, false), "text/plain");
+    }
+
+    #[test]
+    fn resolve_sniffs_blank_or_missing () {
+        assert_eq!(resolve(None, "x.pdf", OCTET_STREAM, true), "application/pdf");
+        assert_eq!(resolve(Some("  ".to_string()), "x.PDF", OCTET_STREAM, true), "application/pdf");
+    }
+
+    #[test]
+    fn resolve_falls_back_to_default () {
+        assert_eq!(resolve(None, "x.pdf", "text/plain", false), "text/plain");
+        // sniffing only counts when the extension is known
+        assert_eq!(resolve(None, "x.unknown", "text/plain", true), "text/plain");
+        assert_eq!(resolve(None, "noextension", OCTET_STREAM, true), OCTET_STREAM);
+    }
+
+    fn list (extensions: &[&str]) -> Vec<String> {
+        extensions.iter().map(|extension| extension.to_string()).collect()
+    }
+
+    #[test]
+    fn extension_lowercased_without_dot () {
+        assert_eq!(extension("Report.PDF"), Some("pdf".to_string()));
+        assert_eq!(extension("archive.tar.gz"), Some("gz".to_string()));
+        assert_eq!(extension("x.exe. . "), Some("exe".to_string()));
+        assert_eq!(extension("README"), None);
+        assert_eq!(extension(""), None);
+    }
+
+    #[test]
+    fn check_extension_without_lists () {
+        assert_eq!(check_extension("x.exe", &[], &[]), Ok(()));
+        assert_eq!(check_extension("noextension", &[], &[]), Ok(()));
+    }
+
+    #[test]
+    fn check_extension_blocked () {
+        let blocked = list(&["exe", "bat"]);
+        assert!(check_extension("x.exe", &[], &blocked).is_err());
+        assert!(check_extension("x.EXE", &[], &blocked).is_err());
+        assert!(check_extension("x.exe.", &[], &blocked).is_err());
+        assert_eq!(check_extension("x.pdf", &[], &blocked), Ok(()));
+        assert_eq!(check_extension("noextension", &[], &blocked), Ok(()));
+    }
+
+    #[test]
+    fn check_extension_allowed () {
+        let allowed = list(&["pdf", "png"]);
+        assert_eq!(check_extension("x.pdf", &allowed, &[]), Ok(()));
+        assert_eq!(check_extension("x.PNG", &allowed, &[]), Ok(()));
+        assert!(check_extension("x.exe", &allowed, &[]).is_err());
+        assert!(check_extension("noextension", &allowed, &[]).is_err());
+    }
+
+    #[test]
+    fn check_extension_blocked_wins () {
+        let both = list(&["pdf"]);
+        assert!(check_extension("x.pdf", &both, &both).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use std::fs;
+
+
+// {status}, {title} and {message} are filled in, already html escaped
+const DEFAULT_TEMPLATE: &'static str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body { font-family: system-ui, sans-serif; background: #f4f4f5; color: #27272a; display: flex; min-height: 100vh; margin: 0; align-items: center; justify-content: center; }
+main { background: #fff; border-radius: 8px; box-shadow: 0 1px 4px rgba(0, 0, 0, 0.1); max-width: 28rem; padding: 2rem; }
+h1 { font-size: 1.25rem; margin-top: 0; }
+p { line-height: 1.5; }
+small { color: #71717a; }
+</style>
+</head>
+<body>
+<main>
+<h1>{title}</h1>
+<p>{message}</p>
+<small>{status}</small>
+</main>
+</body>
+</html>
+"#;
+
+// a file given by DOWNLOAD_ERROR_TEMPLATE, or the built in page when there is none or it can't be read
+pub fn load_template (path: &str) -> String {
+    if path.is_empty() {
+        return DEFAULT_TEMPLATE.to_string();
+    }
+    match fs::read_to_string(path) {
+        Ok(template) => template,
+        Err(why) => {
+            println!("Read download error template {} failed, using the default: {}", path, why);
+            DEFAULT_TEMPLATE.to_string()
+        }
+    }
+}
+
+// what a person following a link needs to know, the same few words whatever exactly went wrong
+//  None for the rest, those keep the body they had
+pub fn describe (status: u16) -> Option<(&'static str, &'static str)> {
+    match status {
+        400 | 404 => Some(("Link not found", "This download link does not exist. Check that it was copied in full, or ask the sender for a new one.")),
+        410 => Some(("Link no longer available", "This download link has expired or has already been used. Each link only works a limited number of times, ask the sender for a new one.")),
+        401 => Some(("Password needed", "This download needs a current one-time password from the sender.")),
+        403 | 451 => Some(("Not available here", "This download is not available from where you are.")),
+        409 => Some(("Download in progress", "This link is being downloaded right now. Try again in a moment.")),
+        429 | 503 => Some(("Too busy", "Too many downloads right now. Try again shortly.")),
+        _ => None,
+    }
+}
+
+pub fn render (template: &str, status: u16, title: &str, message: &str) -> String {
+    template
+        .replace("{status}", &status.to_string())
+        .replace("{title}", &escape_html(title))
+        .replace("{message}", &escape_html(message))
+}
+
+fn escape_html (value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::stream::{self, LocalBoxStream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, RecvError};
+
+
+// how many downloads a slow listener can fall behind by before it starts missing some
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone, Serialize)]
+pub struct LinkEvent {
+    pub token: String,
+    pub downloads: i64,
+    pub max_downloads: i64,
+    // set on the last download the link allows, nothing more will come after it
+    pub downloaded_at: Option<i64>,
+    pub at: i64,
+}
+
+// downloads as they happen
+//  only this process sees them, a download served by another instance is never announced here
+#[derive(Clone)]
+pub struct LinkEvents {
+    sender: broadcast::Sender<LinkEvent>,
+}
+
+impl LinkEvents {
+    pub fn new () -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            sender: sender,
+        }
+    }
+
+    // nobody listening is the usual case, not an error
+    pub fn publish (&self, event: LinkEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    // server-sent events for one token, with a comment line every heartbeat so proxies keep the connection open
+    //  ends after the link's last download, and is dropped (unsubscribing) when the client goes away
+    // https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+    pub fn watch (&self, token: String, heartbeat: Duration) -> LocalBoxStream<'static, Result<Bytes, actix_web::Error>> {
+        let receiver = self.sender.subscribe();
+        // its own clock, a timeout per recv would start over on every download of any other link and might never fire
+        let ticks = tokio::time::interval_at(tokio::time::Instant::now() + heartbeat, heartbeat);
+        stream::unfold(Some((receiver, ticks)), move |state| {
+            let token = token.clone();
+            async move {
+                let (mut receiver, mut ticks) = state?;
+                loop {
+                    // whichever loses is dropped here, a download not yet received stays in the channel
+                    let received = match future::select(Box::pin(ticks.tick()), Box::pin(receiver.recv())).await {
+                        Either::Left(_) => None,
+                        Either::Right((received, _)) => Some(received),
+                    };
+                    // every listener wakes for every download, and skips the ones for other tokens
+                    match received {
+                        None => return Some((Bytes::from_static(b": heartbeat\n\n"), Some((receiver, ticks)))),
+                        Some(Err(RecvError::Closed)) => return None,
+                        Some(Err(RecvError::Lagged(missed))) => println!("Link events listener missed {} downloads", missed),
+                        Some(Ok(event)) if event.token != token => continue,
+                        Some(Ok(event)) => {
+                            let data = serde_json::to_string(&event).unwrap_or_default();
+                            let chunk = Bytes::from(format!("event: download\ndata: {}\n\n", data));
+                            let next = if event.downloaded_at.is_some() { None } else { Some((receiver, ticks)) };
+                            return Some((chunk, next));
+                        }
+                    }
+                }
+            }
+        }).map(Ok).boxed_local()
+    }
+}
@@ -0,0 +1,26 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use maxminddb::{geoip2, Reader};
+
+
+// https://docs.rs/maxminddb/0.15.0/maxminddb/
+#[derive(Clone)]
+pub struct GeoIp {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open (path: &str) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|why| format!("Failed opening geoip db {}: {}", path, why))?;
+        Ok(Self { reader: Arc::new(reader) })
+    }
+
+    // ISO 3166-1 alpha-2, None when the address is not in the db
+    pub fn country_code (&self, ip: IpAddr) -> Option<String> {
+        match self.reader.lookup::<geoip2::Country>(ip) {
+            Err(_) => None,
+            Ok(country) => country.country.and_then(|c| c.iso_code).map(|code| code.to_string()),
+        }
+    }
+}
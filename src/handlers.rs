@@ -1,20 +1,59 @@
 
-use rand::Rng;
-use bytes::{Bytes};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::Serialize;
 // https://actix.rs/
 // very fast framework: https://www.techempower.com/benchmarks/#section=data-r19
-use actix_web::{web, HttpRequest, HttpResponse, http::header};
+use actix_web::{web, HttpRequest, HttpResponse, dev::SizedStream, http::{header, Method, StatusCode, Uri}};
 use actix_multipart::{Field, Multipart};
-use futures::{StreamExt, TryStreamExt}; // adds... something for multipart processsing
+use futures::{future, stream, Stream, StreamExt, TryStreamExt}; // adds... something for multipart processsing
+use futures::stream::LocalBoxStream;
 
-use crate::models::{CreateLink, OnetimeDownloaderService, OnetimeFile, OnetimeLink};
+use crate::tokens::{generate_token, validate_token, TokenFormat};
+use crate::totp;
+use crate::content_types;
+use crate::error_pages;
+use crate::signed;
+use crate::slots::DownloadPermit;
+use crate::rate_limit::RateLimitState;
+use crate::events::{LinkEvent, LinkEvents};
+use crate::uploads::{paced, UploadError, UploadPace, UploadProgress};
+use crate::tus::{self, PartialUpload, UploadArea};
+use crate::storage::hashed;
+use crate::models::{Base64Download, BulkFilesSummary, BulkLinkResult, ConfirmForm, ConsumedLink, CreateLink, CreatedLink, DeleteFileQuery, DownloadAttempt, DownloadMetrics, DownloadQuery, ExpiredUnusedLinks, FileInfo, FilesExist, ImportError, ImportQuery, ImportSummary, LinkInfo, LinkRecord, LinkStatus, ListQuery, ListSort, MetricsQuery, SignLink, MyError, OnetimeDownloaderService, OnetimeError, OnetimeStorage, OnetimeFile, OnetimeLink, QuotaExceeded, StorageOp, TokenStatus, VersionInfo, parse_cidr};
 
 
 const API_KEY_HEADER: &'static str = "X-Api-Key";
+const TOTP_SECRET_HEADER: &'static str = "X-Totp-Secret";
+const TOTP_CODE_HEADER: &'static str = "X-Totp-Code";
+const ADMIN_OTP_HEADER: &'static str = "X-Admin-OTP";
+const RATE_LIMIT_LIMIT_HEADER: &'static str = "X-RateLimit-Limit";
+const RATE_LIMIT_REMAINING_HEADER: &'static str = "X-RateLimit-Remaining";
+const RATE_LIMIT_RESET_HEADER: &'static str = "X-RateLimit-Reset";
+const FILE_EXPIRES_AT_HEADER: &'static str = "X-File-Expires-At";
+const CONTENT_SHA256_HEADER: &'static str = "X-Content-SHA256";
+const BASE64_MEDIA_TYPE: &'static str = "application/vnd.onetime-downloader.base64+json";
+const SKIPPED_ROWS_HEADER: &'static str = "X-Skipped-Rows";
+const X_FORWARDED_FOR_HEADER: &'static str = "X-Forwarded-For";
+const FLUSH_CHUNK_LEN: usize = 65536;
+// what quotas are counted against, there is only the one key of each kind
+const QUOTA_OWNER_FILES: &'static str = "files";
+const QUOTA_OWNER_LINKS: &'static str = "links";
+const EXPORT_PAGE_SIZE: usize = 100;
+const IMPORT_BATCH_SIZE: usize = 100;
+const IMPORT_MAX_LINE_LEN: usize = 65536;
+const MAX_CONSUME_RETRY_DELAY_MS: u64 = 5000;
 
 fn check_api_key (req: &HttpRequest, api_key: &str) -> Result<bool, HttpResponse> {
     let valid_api_key = match req.headers().get(API_KEY_HEADER) {
-        Some(v) => v == api_key,
+        // an unset key (e.g. no ADMIN_API_KEY) must not be matched by an empty header
+        Some(v) => !api_key.is_empty() && v == api_key,
         _ => false
     };
     if valid_api_key {
@@ -24,27 +63,289 @@ fn check_api_key (req: &HttpRequest, api_key: &str) -> Result<bool, HttpResponse
     }
 }
 
-fn check_rate_limit (req: &HttpRequest) -> Result<bool, HttpResponse> {
-    let valid_ip = match req.connection_info().remote() {
-        Some(ip) => ip != "0.0.0.0",
-        _ => false
+// with ADMIN_TOTP_SECRET set, the endpoints that delete or change things want a current code too,
+//  so a leaked api key alone can't wipe everything, reads and downloads never ask for one
+fn check_admin_otp (req: &HttpRequest, service: &OnetimeDownloaderService) -> Result<bool, HttpResponse> {
+    if service.config.admin_totp_secret.is_empty() {
+        return Ok(true);
+    }
+    let now = service.time_provider.unix_ts_ms();
+    let valid_code = match req.headers().get(ADMIN_OTP_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(code) => totp::check_code(service.config.admin_totp_secret.as_str(), code, now),
+        None => false,
+    };
+    if valid_code {
+        Ok(true)
+    } else {
+        Err(HttpResponse::Unauthorized().body("Invalid or missing admin one-time password!"))
+    }
+}
+
+fn check_rate_limit (req: &HttpRequest, service: &OnetimeDownloaderService) -> Result<bool, HttpResponse> {
+    // by address only, otherwise every new source port would get a fresh window
+    let client = match request_ip(req, &service.config.trusted_proxies) {
+        Some(ip) if !ip.is_unspecified() => ip.to_string(),
+        _ => return Err(HttpResponse::TooManyRequests().finish()),
+    };
+
+    let now = service.time_provider.unix_ts_ms();
+    match service.rate_limiter.check(client.as_str(), now) {
+        Some(state) if !state.allowed => Err(rate_limited(&state, now)),
+        _ => Ok(true),
+    }
+}
+
+// https://tools.ietf.org/id/draft-polli-ratelimit-headers-00.html, reset as unix seconds like github
+fn rate_limited (state: &RateLimitState, now: i64) -> HttpResponse {
+    let retry_after_secs = (state.reset_at - now + 999) / 1000;
+    HttpResponse::TooManyRequests()
+        .set_header(RATE_LIMIT_LIMIT_HEADER, state.limit.to_string())
+        .set_header(RATE_LIMIT_REMAINING_HEADER, state.remaining.to_string())
+        .set_header(RATE_LIMIT_RESET_HEADER, ((state.reset_at + 999) / 1000).to_string())
+        .set_header(header::RETRY_AFTER, retry_after_secs.to_string())
+        .finish()
+}
+
+// lengths are in bytes, since that is what the storage limits are measured in
+// files or links already counted against their key's quota, a single file's bytes are only known once stored
+struct QuotaClaim {
+    owner: &'static str,
+    window_start: i64,
+    count: i64,
+    bytes: i64,
+}
+
+// counts one more for the owner, or refuses with 429 over the count or 507 over the bytes
+//  None when the owner has no quota, so nothing is counted at all
+async fn claim_quota (service: &OnetimeDownloaderService, owner: &'static str, max_count: i64, max_bytes: i64) -> Result<Option<QuotaClaim>, HttpResponse> {
+    claim_quota_batch(service, owner, max_count, max_bytes, 1, 0).await
+}
+
+// a bulk request claims its whole batch at once, so it can't go over the quota any more than single ones can
+async fn claim_quota_batch (service: &OnetimeDownloaderService, owner: &'static str, max_count: i64, max_bytes: i64, count: i64, bytes: i64) -> Result<Option<QuotaClaim>, HttpResponse> {
+    if max_count <= 0 && max_bytes <= 0 {
+        return Ok(None);
+    }
+    let now = service.time_provider.unix_ts_ms();
+    let window_ms = i64::max(1, service.config.quota_window_ms);
+    let claim = QuotaClaim { owner: owner, window_start: now - now % window_ms, count: count, bytes: bytes };
+
+    // counted first and taken back when over, so concurrent requests can't all squeeze past the check
+    let (used_count, used_bytes) = service.storage.add_usage(owner.to_string(), claim.window_start, count, bytes).await
+        .map_err(|why| HttpResponse::InternalServerError().body(format!("Quota check failed! {}", why)))?;
+    let over_count = max_count > 0 && used_count > max_count;
+    let over_bytes = max_bytes > 0 && used_bytes > max_bytes;
+    if !over_count && !over_bytes {
+        return Ok(Some(claim));
+    }
+
+    let window_resets_at = claim.window_start + window_ms;
+    settle_quota(service, Some(claim), None).await;
+    if over_count {
+        Err(HttpResponse::TooManyRequests().json(QuotaExceeded {
+            error: "Quota exceeded".to_string(),
+            owner: owner.to_string(),
+            used: used_count - count,
+            max: max_count,
+            window_resets_at: window_resets_at,
+        }))
+    } else {
+        Err(HttpResponse::InsufficientStorage().json(QuotaExceeded {
+            error: "Storage quota exceeded".to_string(),
+            owner: owner.to_string(),
+            used: used_bytes - bytes,
+            max: max_bytes,
+            window_resets_at: window_resets_at,
+        }))
+    }
+}
+
+// Some(bytes) once stored adds them, None takes the claim back since nothing was stored
+async fn settle_quota (service: &OnetimeDownloaderService, claim: Option<QuotaClaim>, stored: Option<usize>) {
+    match stored {
+        Some(bytes) => settle_quota_batch(service, claim, 1, bytes).await,
+        None => settle_quota_batch(service, claim, 0, 0).await,
+    }
+}
+
+// corrects the claim to what was actually stored, skipped or failed items are taken back
+//  a failure here only miscounts, so it is logged rather than failing a request that already succeeded
+async fn settle_quota_batch (service: &OnetimeDownloaderService, claim: Option<QuotaClaim>, stored_count: usize, stored_bytes: usize) {
+    let claim = match claim {
+        Some(claim) => claim,
+        None => return,
+    };
+    let count = stored_count as i64 - claim.count;
+    let bytes = stored_bytes as i64 - claim.bytes;
+    if count == 0 && bytes == 0 {
+        return;
+    }
+    if let Err(why) = service.storage.add_usage(claim.owner.to_string(), claim.window_start, count, bytes).await {
+        println!("Settle quota failed for {}: {}", claim.owner, why);
+    }
+}
+
+fn too_long (name: &str, value: &str, max: usize) -> Result<bool, String> {
+    if value.len() > max {
+        Err(format!("{} too long! {} > {}", name, value.len(), max))
+    } else {
+        Ok(true)
+    }
+}
+
+fn check_max_len (name: &str, value: &str, max: usize) -> Result<bool, HttpResponse> {
+    too_long(name, value, max).map_err(|why| HttpResponse::BadRequest().body(why))
+}
+
+// by the name alone, whatever content type the upload says it is
+fn check_extension (service: &OnetimeDownloaderService, filename: &str) -> Result<(), HttpResponse> {
+    content_types::check_extension(filename, &service.config.allowed_extensions, &service.config.blocked_extensions)
+        .map_err(|why| HttpResponse::UnsupportedMediaType().body(why))
+}
+
+// a file part with no bytes at all is the usual way to get here, eg a form submitted without choosing a file
+fn check_min_len_file (service: &OnetimeDownloaderService, len: usize) -> Result<(), HttpResponse> {
+    if len < service.config.min_len_file {
+        return Err(HttpResponse::BadRequest().body(too_small_file(len, service.config.min_len_file)));
+    }
+    Ok(())
+}
+
+fn too_small_file (len: usize, min: usize) -> String {
+    if len == 0 {
+        String::from("Empty file contents!")
+    } else {
+        format!("File too small! {} < {}", len, min)
+    }
+}
+
+// ?sort=created_at|expires_at|filename&order=asc|desc, newest first when not given
+fn parse_list_sort (req: &HttpRequest) -> Result<ListSort, HttpResponse> {
+    let query = match web::Query::<ListQuery>::from_query(req.query_string()) {
+        Ok(query) => query.into_inner(),
+        Err(why) => return Err(HttpResponse::BadRequest().body(format!("Invalid query! {}", why))),
+    };
+    let mut sort = ListSort::default();
+    if let Some(field) = query.sort {
+        sort.field = field.parse().map_err(|why| HttpResponse::BadRequest().body(why))?;
+    }
+    if let Some(order) = query.order {
+        sort.order = order.parse().map_err(|why| HttpResponse::BadRequest().body(why))?;
+    }
+    Ok(sort)
+}
+
+// forwarded entries are usually a bare address, but some proxies add the port
+fn client_ip (remote: &str) -> Option<IpAddr> {
+    match remote.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => remote.parse::<SocketAddr>().ok().map(|addr| addr.ip()),
+    }
+}
+
+// the peer is whoever actually connected, X-Forwarded-For is only believed when that is one of TRUSTED_PROXIES,
+//  otherwise any client could send one naming an address that gets it past allowed_cidrs, countries or the rate limit
+fn request_ip (req: &HttpRequest, trusted_proxies: &Vec<IpNet>) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let forwarded_for: Vec<&str> = req.headers().get_all(X_FORWARDED_FOR_HEADER)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    Some(forwarded_client(peer, forwarded_for, trusted_proxies))
+}
+
+// each proxy appends who it got the request from, so reading from the right the first address that is not
+//  one of ours is the client, everything left of it is whatever the client chose to send
+fn forwarded_client (peer: IpAddr, forwarded_for: Vec<&str>, trusted_proxies: &Vec<IpNet>) -> IpAddr {
+    let trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut client = peer;
+    for entry in forwarded_for.iter().rev() {
+        if !trusted(&client) {
+            break;
+        }
+        match client_ip(entry.trim()) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+    client
+}
+
+fn check_allowed_cidrs (allowed_cidrs: &Vec<String>, client: IpAddr) -> bool {
+    if allowed_cidrs.is_empty() {
+        return true;
+    }
+
+    allowed_cidrs.iter()
+        .filter_map(|cidr| parse_cidr(cidr).ok())
+        .any(|net| net.contains(&client))
+}
+
+fn parse_countries (countries: &Option<Vec<String>>) -> Result<Vec<String>, String> {
+    let mut codes = Vec::new();
+    for country in countries.iter().flatten() {
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!("Invalid country code '{}'", country));
+        }
+        codes.push(country.to_ascii_uppercase());
+    }
+    Ok(codes)
+}
+
+// an address the db does not know only gets through when there is no allow list
+fn check_countries (service: &OnetimeDownloaderService, link: &OnetimeLink, client: IpAddr) -> bool {
+    let geoip = match &service.geoip {
+        None => return true,
+        Some(geoip) => geoip,
+    };
+    if link.allowed_countries.is_empty() && link.blocked_countries.is_empty() {
+        return true;
+    }
+
+    match geoip.country_code(client) {
+        None => link.allowed_countries.is_empty(),
+        Some(code) => {
+            !link.blocked_countries.contains(&code)
+                && (link.allowed_countries.is_empty() || link.allowed_countries.contains(&code))
+        }
+    }
+}
+
+// only absolute http(s) urls, so a link cannot be turned into a javascript: or relative redirect
+fn check_redirect_url (redirect_url: &str) -> Result<bool, String> {
+    let valid = match redirect_url.parse::<Uri>() {
+        Err(_) => false,
+        Ok(uri) => match (uri.scheme_str(), uri.host()) {
+            (Some("https"), Some(_)) | (Some("http"), Some(_)) => true,
+            _ => false,
+        },
     };
-    if valid_ip {
+    if valid {
         Ok(true)
     } else {
-        Err(HttpResponse::TooManyRequests().finish())
+        Err(format!("Invalid redirect url '{}'", redirect_url))
+    }
+}
+
+// with LENIENT_LISTS, unreadable rows are left out and counted in a header rather than failing the whole list
+fn list_response<T: Serialize> (items: Vec<T>, skipped: usize) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if skipped > 0 {
+        response.header(SKIPPED_ROWS_HEADER, skipped.to_string());
     }
+    response.json(items)
 }
 
 pub async fn list_files (
     req: HttpRequest,
     service: web::Data<OnetimeDownloaderService>,
-) -> Result<web::Json<Vec<OnetimeFile>>, HttpResponse> {
+) -> Result<HttpResponse, HttpResponse> {
     println!("list files");
     check_api_key(&req, service.config.api_key_files.as_str())?;
 
-    match service.storage.list_files().await {
-        Ok(files) => Ok(web::Json(files)),
+    let sort = parse_list_sort(&req)?;
+    match service.storage.list_files(sort).await {
+        Ok((files, skipped)) => Ok(list_response(files, skipped)),
         Err(why) => Err(HttpResponse::InternalServerError().body(format!("List files failed! {}", why))),
     }
 }
@@ -52,22 +353,310 @@ pub async fn list_files (
 pub async fn list_links (
     req: HttpRequest,
     service: web::Data<OnetimeDownloaderService>,
-) -> Result<web::Json<Vec<OnetimeLink>>, HttpResponse> {
+) -> Result<HttpResponse, HttpResponse> {
     println!("list links");
     check_api_key(&req, service.config.api_key_links.as_str())?;
 
-    match service.storage.list_links().await {
-        Ok(links) => Ok(web::Json(links)),
+    let sort = parse_list_sort(&req)?;
+    match service.storage.list_links(sort).await {
+        Ok((links, skipped)) => Ok(list_response(links, skipped)),
         Err(why) => Err(HttpResponse::InternalServerError().body(format!("List links failed! {}", why))),
     }
 }
 
-async fn collect_chunks (mut field: Field, max: usize) -> Result<Vec<u8>, HttpResponse> {
+// newline delimited json, one link per line, streamed a page at a time so the table is never all in memory
+pub async fn export_links (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    println!("export links");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+
+    let storage = service.storage.clone();
+    // None once there are no more pages, otherwise the cursor to continue from
+    let pages = stream::unfold(Some(None), move |cursor: Option<Option<String>>| {
+        let storage = storage.clone();
+        async move {
+            let after = match cursor {
+                None => return None,
+                Some(after) => after,
+            };
+            match storage.list_links_page(after, EXPORT_PAGE_SIZE).await {
+                // the status already went out, so all that is left is to cut the response short
+                Err(why) => Some((Err(actix_web::error::ErrorInternalServerError(format!("Export links failed! {}", why))), None)),
+                Ok((links, next)) => {
+                    let mut lines = BytesMut::new();
+                    for link in links {
+                        match serde_json::to_vec(&LinkRecord::from(link)) {
+                            Err(why) => return Some((Err(actix_web::error::ErrorInternalServerError(why)), None)),
+                            Ok(line) => lines.extend_from_slice(&line),
+                        }
+                        lines.put_u8(b'\n');
+                    }
+                    Some((Ok(lines.freeze()), next.map(Some)))
+                }
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(pages.boxed_local()))
+}
+
+// the same newline delimited json export_links writes, so links can be moved between backends
+pub async fn import_links (
+    req: HttpRequest,
+    mut payload: web::Payload,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<ImportSummary>, HttpResponse> {
+    println!("import links");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+    check_admin_otp(&req, &service)?;
+
+    let overwrite = web::Query::<ImportQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().overwrite)
+        .unwrap_or(false);
+
+    let mut summary = ImportSummary { imported: 0, errors: Vec::new() };
+    let mut seen = HashSet::new();
+    let mut batch = Vec::new();
+    let mut buffer = BytesMut::new();
+    let mut line_number = 0;
+    let mut done = false;
+    // a line at a time as the body comes in, so only one batch is ever held
+    while !done {
+        match payload.next().await {
+            Some(chunk) => {
+                let chunk = chunk.map_err(|why| HttpResponse::BadRequest().body(format!("Import links failed! {}", why)))?;
+                buffer.extend_from_slice(&chunk);
+            }
+            // whatever is left is the last line, without a trailing newline
+            None => {
+                buffer.extend_from_slice(b"\n");
+                done = true;
+            }
+        }
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line = buffer.split_to(end + 1);
+            line_number += 1;
+            match parse_import_line(&line[..end], &mut seen) {
+                Ok(None) => (),
+                Ok(Some(link)) => batch.push((line_number, link)),
+                Err(why) => summary.errors.push(ImportError { line: line_number, error: why }),
+            }
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                import_batch(&service, std::mem::take(&mut batch), overwrite, &mut summary).await?;
+            }
+        }
+        if buffer.len() > IMPORT_MAX_LINE_LEN {
+            return Err(HttpResponse::BadRequest().body(format!("Line {} too long! > {}", line_number + 1, IMPORT_MAX_LINE_LEN)));
+        }
+    }
+    if !batch.is_empty() {
+        import_batch(&service, batch, overwrite, &mut summary).await?;
+    }
+
+    Ok(web::Json(summary))
+}
+
+// None for blank lines, tokens repeated within one import are rejected since batches can't hold duplicates
+fn parse_import_line (line: &[u8], seen: &mut HashSet<String>) -> Result<Option<OnetimeLink>, String> {
+    if line.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(None);
+    }
+    let record: LinkRecord = serde_json::from_slice(line).map_err(|why| format!("Invalid link! {}", why))?;
+    let link = OnetimeLink::from(record);
+    if link.token.is_empty() || link.filename.is_empty() {
+        return Err("Token and filename are required".to_string());
+    }
+    if !seen.insert(link.token.clone()) {
+        return Err(format!("Token '{}' repeated in import", link.token));
+    }
+    Ok(Some(link))
+}
+
+async fn import_batch (
+    service: &OnetimeDownloaderService,
+    batch: Vec<(usize, OnetimeLink)>,
+    overwrite: bool,
+    summary: &mut ImportSummary,
+) -> Result<(), HttpResponse> {
+    let lines: Vec<(usize, String)> = batch.iter().map(|(line, link)| (*line, link.token.clone())).collect();
+    let links = batch.into_iter().map(|(_, link)| link).collect();
+    // imported links count against the links quota like created ones, a batch at a time
+    let claim = claim_quota_batch(service, QUOTA_OWNER_LINKS, service.config.quota_max_links, 0, lines.len() as i64, 0).await?;
+    let result = service.storage.import_links(links, overwrite).await;
+    settle_quota_batch(service, claim, result.as_ref().map(|tokens| tokens.len()).unwrap_or(0), 0).await;
+    let written: HashSet<String> = match result {
+        Ok(tokens) => tokens.into_iter().collect(),
+        Err(why) => return Err(HttpResponse::InternalServerError().body(
+            format!("Import links failed after {} imported! {}", summary.imported, why)
+        )),
+    };
+    for (line, token) in lines {
+        if written.contains(&token) {
+            summary.imported += 1;
+        } else {
+            summary.errors.push(ImportError { line: line, error: format!("Token '{}' already exists", token) });
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_links_for_file (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<Vec<OnetimeLink>>, HttpResponse> {
+    println!("list links for file");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+
+    let filename = req.match_info().get("filename").unwrap().to_string();
+    match service.storage.list_links_for_file(filename).await {
+        Ok(links) => Ok(web::Json(links)),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("List links for file failed! {}", why))),
+    }
+}
+
+// for polling, eg "has my recipient picked it up yet?", without touching the link
+pub async fn link_status (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<TokenStatus>, HttpResponse> {
+    println!("link status");
+    check_api_key(&req, service.config.api_key_links.as_str())?;
+
+    let token = path_token(&req)?;
+    match service.storage.link_status(token.clone()).await {
+        Ok(status) => Ok(web::Json(TokenStatus { token: token, status: status })),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("Link status failed! {}", why))),
+    }
+}
+
+// server-sent events for the link's creator, one per download, held open until the last download the link allows
+pub async fn link_events (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    println!("link events");
+    check_api_key(&req, service.config.api_key_links.as_str())?;
+
+    let token = path_token(&req)?;
+    // nothing will ever come for a link that can't be downloaded any more
+    match service.storage.link_status(token.clone()).await {
+        Ok(LinkStatus::Pending) => (),
+        Ok(LinkStatus::Missing) => return Err(HttpResponse::NotFound().body("Link events failed! Link not found")),
+        Ok(status) => return Err(HttpResponse::Conflict().json(TokenStatus { token: token, status: status })),
+        Err(why) => return Err(HttpResponse::InternalServerError().body(format!("Link events failed! {}", why))),
+    }
+
+    let heartbeat = Duration::from_millis(service.config.link_events_heartbeat_ms);
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .set_header(header::CACHE_CONTROL, "no-store, private")
+        // https://nginx.org/en/docs/http/ngx_http_proxy_module.html#proxy_buffering -- nginx would otherwise hold events back
+        .set_header("X-Accel-Buffering", "no")
+        .streaming(service.link_events.watch(token, heartbeat)))
+}
+
+// for admins: who downloaded it and when stays readable for as long as the row exists, LINK_INFO_RETENTION_MS does not apply
+//  the download path still refuses it, this never consumes or changes anything
+pub async fn get_link_record (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<LinkInfo>, HttpResponse> {
+    println!("get link record");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+
+    let token = path_token(&req)?;
+    let link = match service.storage.get_link_admin(token).await {
+        Ok(link) => link,
+        Err(why) => return Err(HttpResponse::NotFound().body(format!("Get link record failed! {}", why))),
+    };
+
+    let now = service.time_provider.unix_ts_ms();
+    Ok(web::Json(LinkInfo {
+        state: link.state(now, service.config.expiry_skew_ms),
+        link: link,
+    }))
+}
+
+// for the link's creator: any state, until it has been consumed or deleted for longer than the retention
+pub async fn get_link (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<LinkInfo>, HttpResponse> {
+    println!("get link");
+    check_api_key(&req, service.config.api_key_links.as_str())?;
+
+    let token = path_token(&req)?;
+    let link = match service.storage.get_any_link(token).await {
+        Ok(link) => link,
+        Err(why) => return Err(HttpResponse::NotFound().body(format!("Get link failed! {}", why))),
+    };
+
+    let now = service.time_provider.unix_ts_ms();
+    let ended_at = link.deleted_at.or(link.downloaded_at);
+    if let Some(ended_at) = ended_at {
+        if ended_at + service.config.link_info_retention_ms < now {
+            return Err(HttpResponse::NotFound().body("Get link failed! Link not found"));
+        }
+    }
+
+    Ok(web::Json(LinkInfo {
+        state: link.state(now, service.config.expiry_skew_ms),
+        link: link,
+    }))
+}
+
+// the name and, for file parts, the filename -- a part missing its name is a malformed request, not a panic
+fn field_names (field: &Field) -> Result<(String, Option<String>), HttpResponse> {
+    let content_disposition = field.content_disposition()
+        .ok_or_else(|| HttpResponse::BadRequest().body("Multipart field has no content disposition!"))?;
+    let name = content_disposition.get_name()
+        .ok_or_else(|| HttpResponse::BadRequest().body("Multipart field has no name!"))?;
+    Ok((name.to_owned(), content_disposition.get_filename().map(|filename| filename.to_owned())))
+}
+
+// the part's own Content-Type, actix-multipart reports a missing one as octet-stream so content_type() can't tell
+fn field_content_type (field: &Field) -> Option<String> {
+    field.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+fn resolve_content_type (service: &OnetimeDownloaderService, filename: &str, given: Option<String>) -> String {
+    content_types::resolve(given, filename, service.config.default_content_type.as_str(), service.config.sniff_content_type)
+}
+
+fn upload_pace (service: &OnetimeDownloaderService) -> UploadPace {
+    UploadPace {
+        idle_timeout_ms: service.config.upload_idle_timeout_ms,
+        min_bytes_per_s: service.config.upload_min_bytes_per_s,
+        grace_ms: service.config.upload_min_rate_grace_ms,
+    }
+}
+
+// 408 for a client sending too slowly, 400 for one whose body broke off
+fn upload_error (why: UploadError) -> HttpResponse {
+    match why {
+        UploadError::TooSlow(why) => HttpResponse::RequestTimeout().body(why),
+        UploadError::Read(why) => HttpResponse::BadRequest().body(why),
+    }
+}
+
+// only file contents count as upload progress, not the small value fields
+async fn collect_chunks (field: Field, max: usize, pace: UploadPace, mut progress: Option<&mut UploadProgress>) -> Result<Vec<u8>, HttpResponse> {
     let mut size = 0;
     let mut val = Vec::new();
+    let mut field = paced(field, pace);
     while let Some(chunk) = field.next().await {
-        let data = chunk.unwrap();
+        let data = chunk.map_err(upload_error)?;
         size += data.len();
+        if let Some(progress) = progress.as_mut() {
+            progress.add(data.len());
+        }
         if size > max {
             return Err(HttpResponse::BadRequest().body(format!("field value too big! {}", size)))
         }
@@ -76,6 +665,82 @@ async fn collect_chunks (mut field: Field, max: usize) -> Result<Vec<u8>, HttpRe
     Ok(val)
 }
 
+// unix ms, from the X-File-Expires-At header or an expires_at field, no expiry when neither is given
+fn parse_file_expires_at (value: &str) -> Result<i64, HttpResponse> {
+    value.trim().parse::<i64>().map_err(|why| HttpResponse::BadRequest().body(format!("Invalid file expires_at! {}", why)))
+}
+
+// counts bytes as they flow so the max length still applies without buffering here
+//  the body is either a multipart field, up to max_len_file, or a whole raw request body, up to max_len_raw_file
+async fn stream_file<S, E> (
+    body: S,
+    raw: bool,
+    filename: String,
+    now: i64,
+    expires_at: Option<i64>,
+    content_type: String,
+    mut progress: UploadProgress,
+    claim: Option<QuotaClaim>,
+    service: &OnetimeDownloaderService,
+) -> Result<HttpResponse, HttpResponse>
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: std::fmt::Display + 'static,
+{
+    let max = if raw { service.config.max_len_raw_file } else { service.config.max_len_file };
+    let min = service.config.min_len_file;
+    let too_big = Rc::new(Cell::new(false));
+    let too_big_flag = too_big.clone();
+    let too_small = Rc::new(Cell::new(false));
+    let too_small_flag = too_small.clone();
+    let too_slow = Rc::new(Cell::new(false));
+    let too_slow_flag = too_slow.clone();
+    let size = Rc::new(Cell::new(0));
+    let size_count = size.clone();
+    let size_final = size.clone();
+
+    let stream = paced(body, upload_pace(service)).map(move |chunk| {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(UploadError::Read(why)) => return Err(why),
+            Err(UploadError::TooSlow(why)) => {
+                too_slow_flag.set(true);
+                return Err(why);
+            }
+        };
+        size_count.set(size_count.get() + data.len());
+        progress.add(data.len());
+        if size_count.get() > max {
+            too_big_flag.set(true);
+            return Err(format!("field value too big! {}", size_count.get()));
+        }
+        Ok(data)
+    });
+    // only known once the body has ended, failing the stream there keeps the file from being stored
+    let check_min = stream::once(async move {
+        if size_final.get() < min {
+            too_small_flag.set(true);
+            return Some(Err(too_small_file(size_final.get(), min)));
+        }
+        None
+    }).filter_map(future::ready);
+    let stream = stream.chain(check_min).boxed_local();
+
+    let result = service.storage.add_file_stream(filename, now, expires_at, Some(content_type), stream).await;
+    settle_quota(service, claim, result.as_ref().ok().map(|_| size.get())).await;
+    if result.is_ok() {
+        service.histograms.file_size_bytes.observe(size.get() as u64);
+    }
+    match result {
+        Ok(_) => Ok(HttpResponse::Ok().body("added file")),
+        // a whole body too big is 413, a multipart field too big stays the 400 it always was
+        Err(why) if too_big.get() && raw => Err(HttpResponse::PayloadTooLarge().body(why)),
+        Err(why) if too_big.get() || too_small.get() => Err(HttpResponse::BadRequest().body(why)),
+        Err(why) if too_slow.get() => Err(HttpResponse::RequestTimeout().body(why)),
+        Err(why) => Ok(HttpResponse::InternalServerError().body(format!("Add file failed! {}", why))),
+    }
+}
+
 pub async fn add_file (
     req: HttpRequest,
     mut payload: Multipart,
@@ -83,31 +748,63 @@ pub async fn add_file (
 ) -> Result<HttpResponse, HttpResponse> {
     println!("add file");
     check_api_key(&req, service.config.api_key_files.as_str())?;
-    check_rate_limit(&req)?;
+    check_rate_limit(&req, &service)?;
 
     let mut file_filename: Option<String> = None;
     let mut field_filename: Option<String> = None;
     let mut contents: Option<Bytes> = None;
+    let mut part_content_type: Option<String> = None;
+    let mut expires_at = match req.headers().get(FILE_EXPIRES_AT_HEADER).and_then(|v| v.to_str().ok()) {
+        None => None,
+        Some(value) => Some(parse_file_expires_at(value)?),
+    };
+    // for the 400 when something is missing, so the client can see what it actually sent
+    let mut seen: Vec<String> = Vec::new();
 
     while let Ok(Some(field)) = payload.try_next().await {
-        let content_disposition = field.content_disposition().unwrap();
-        let field_name = content_disposition.get_name().unwrap().to_owned();
+        let (field_name, filename) = field_names(&field)?;
 
-        match content_disposition.get_filename() {
+        match filename {
             Some(filename) => {
                 println!("'{}' filename '{}'", field_name, filename);
-                if field_name == "file" {
-                    let val = collect_chunks(field, service.config.max_len_file).await?;
-                    //println!("file:\n{:?}", val);
-                    contents = Some(Bytes::from(val));
-                    file_filename = Some(filename.to_string());
+                seen.push(format!("{} (file)", field_name));
+                // the file part can have any name, usually "file", but there can only be one of them
+                if contents.is_some() {
+                    return Err(HttpResponse::BadRequest().body(format!("More than one file part, use /api/files/bulk for several! Fields: {}", seen.join(", "))));
                 }
+                let mut progress = service.uploads.start(filename.clone());
+                // the filename field came first, so nothing later can change where the contents go
+                //  immutable files are buffered instead, since create_file has no streaming variant
+                if !service.config.immutable_files {
+                    if let Some(filename) = field_filename {
+                        check_max_len("Filename", filename.as_str(), service.config.max_len_filename)?;
+                        check_extension(&service, filename.as_str())?;
+                        let now = service.time_provider.unix_ts_ms();
+                        let content_type = resolve_content_type(&service, filename.as_str(), field_content_type(&field));
+                        let claim = claim_quota(&service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes).await?;
+                        return stream_file(field, false, filename, now, expires_at, content_type, progress, claim, &service).await;
+                    }
+                }
+
+                part_content_type = field_content_type(&field);
+                let val = collect_chunks(field, service.config.max_len_file, upload_pace(&service), Some(&mut progress)).await?;
+                check_min_len_file(&service, val.len())?;
+                //println!("file:\n{:?}", val);
+                contents = Some(Bytes::from(val));
+                file_filename = Some(filename.to_string());
             }
             None => {
                 println!("'{}' not a file!", field_name);
-                if field_name == "filename" {
-                    let val = collect_chunks(field, service.config.max_len_value).await?;
-                    field_filename = Some(String::from_utf8(val).unwrap());
+                seen.push(field_name.clone());
+                if field_name == "filename" || field_name == "name" {
+                    let val = collect_chunks(field, service.config.max_len_value, upload_pace(&service), None).await?;
+                    let filename = String::from_utf8(val).map_err(|_| HttpResponse::BadRequest().body("filename not valid UTF-8"))?;
+                    field_filename = Some(filename);
+                } else if field_name == "expires_at" {
+                    let val = collect_chunks(field, service.config.max_len_value, upload_pace(&service), None).await?;
+                    expires_at = Some(parse_file_expires_at(String::from_utf8_lossy(&val).as_ref())?);
+                } else {
+                    println!("'{}' ignored", field_name);
                 }
             }
         }
@@ -116,148 +813,2326 @@ pub async fn add_file (
     if (field_filename.is_some() || file_filename.is_some()) && contents.is_some() {
         let now = service.time_provider.unix_ts_ms();
         let filename = field_filename.unwrap_or_else(|| file_filename.unwrap());
+        check_max_len("Filename", filename.as_str(), service.config.max_len_filename)?;
+        check_extension(&service, filename.as_str())?;
 
         let file = OnetimeFile {
+            content_type: Some(resolve_content_type(&service, filename.as_str(), part_content_type)),
             filename: filename,
             contents: contents.unwrap(),
             created_at: now,
             updated_at: now,
+            expires_at: expires_at,
         };
+        let len = file.contents.len();
+        let claim = claim_quota(&service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes).await?;
+
+        if service.config.immutable_files {
+            let result = service.storage.create_file(file).await;
+            settle_quota(&service, claim, match result { Ok(true) => Some(len), _ => None }).await;
+            if let Ok(true) = result {
+                service.histograms.file_size_bytes.observe(len as u64);
+            }
+            return match result {
+                Ok(true) => Ok(HttpResponse::Ok().body("added file")),
+                Ok(false) => Ok(HttpResponse::Conflict().body("File already exists and files are immutable!")),
+                Err(why) => Ok(HttpResponse::InternalServerError().body(format!("Add file failed! {}", why))),
+            };
+        }
 
-        match service.storage.add_file(file).await {
+        let result = service.storage.add_file(file).await;
+        settle_quota(&service, claim, result.as_ref().ok().map(|_| len)).await;
+        if result.is_ok() {
+            service.histograms.file_size_bytes.observe(len as u64);
+        }
+        match result {
             Ok(_) => Ok(HttpResponse::Ok().body("added file")),
             Err(why) => Ok(HttpResponse::InternalServerError().body(format!("Add file failed! {}", why))),
         }
     } else {
-        Ok(HttpResponse::BadRequest().body("No filename or file contents provided!"))
+        let fields = if seen.is_empty() { String::from("none") } else { seen.join(", ") };
+        if contents.is_none() {
+            Ok(HttpResponse::BadRequest().body(format!("No file part provided, one part needs a filename in its Content-Disposition! Fields: {}", fields)))
+        } else {
+            Ok(HttpResponse::BadRequest().body(format!("No filename provided, as a filename or name field or on the file part! Fields: {}", fields)))
+        }
     }
 }
 
-pub async fn add_link (
+// every part with a filename is a file, all stored in one batch, ?overwrite=true replaces existing files
+pub async fn add_files (
     req: HttpRequest,
-    payload: web::Json<CreateLink>,
+    mut payload: Multipart,
     service: web::Data<OnetimeDownloaderService>,
-) -> Result<HttpResponse, HttpResponse> {
-    println!("add link");
-    check_api_key(&req, service.config.api_key_links.as_str())?;
-    check_rate_limit(&req)?;
-
-    // TODO validate filename is stored file
-    if true {
-        let now = service.time_provider.unix_ts_ms();
-        // https://rust-lang-nursery.github.io/rust-cookbook/algorithms/randomness.html
-        let n: u64 = rand::thread_rng().gen();
+) -> Result<web::Json<BulkFilesSummary>, HttpResponse> {
+    println!("add files");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_rate_limit(&req, &service)?;
 
-        let token = format!("{:016x}{:016x}", now, n);
+    let overwrite = web::Query::<ImportQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().overwrite)
+        .unwrap_or(false);
+    if overwrite && service.config.immutable_files {
+        return Err(HttpResponse::Conflict().body("Files are immutable, they cannot be overwritten!"));
+    }
+    let expires_at = match req.headers().get(FILE_EXPIRES_AT_HEADER).and_then(|v| v.to_str().ok()) {
+        None => None,
+        Some(value) => Some(parse_file_expires_at(value)?),
+    };
 
-        let expires_at = match payload.expires_at {
-            None => now + service.config.default_expiration_ms,
-            Some(v) => v,
+    let now = service.time_provider.unix_ts_ms();
+    let mut filenames = HashSet::new();
+    let mut files = Vec::new();
+    let mut progress = service.uploads.start("bulk".to_string());
+    while let Ok(Some(field)) = payload.try_next().await {
+        let filename = match field.content_disposition().and_then(|cd| cd.get_filename().map(|f| f.to_string())) {
+            Some(filename) => filename,
+            None => continue,
         };
-        println!("token {} expires_at {}", token, expires_at);
+        check_max_len("Filename", filename.as_str(), service.config.max_len_filename)?;
+        check_extension(&service, filename.as_str())?;
+        if !filenames.insert(filename.clone()) {
+            return Err(HttpResponse::BadRequest().body(format!("Duplicate filename! {}", filename)));
+        }
+        if files.len() >= service.config.max_bulk_files {
+            return Err(HttpResponse::BadRequest().body(format!("Too many files! > {}", service.config.max_bulk_files)));
+        }
 
-        let link = OnetimeLink {
-            filename: payload.filename.clone(),
-            token: token.clone(),
-            note: payload.note.clone(),
+        let content_type = resolve_content_type(&service, filename.as_str(), field_content_type(&field));
+        let val = collect_chunks(field, service.config.max_len_file, upload_pace(&service), Some(&mut progress)).await?;
+        if val.len() < service.config.min_len_file {
+            return Err(HttpResponse::BadRequest().body(format!("{} {}", too_small_file(val.len(), service.config.min_len_file), filename)));
+        }
+        files.push(OnetimeFile {
+            content_type: Some(content_type),
+            filename: filename,
+            contents: Bytes::from(val),
             created_at: now,
+            updated_at: now,
             expires_at: expires_at,
-            downloaded_at: None,
-            ip_address: None,
-        };
+        });
+    }
+    if files.is_empty() {
+        return Err(HttpResponse::BadRequest().body("No files provided!"));
+    }
+    // only the ones actually added count, skipped ones were already there
+    let lens: Vec<(String, usize)> = files.iter().map(|file| (file.filename.clone(), file.contents.len())).collect();
+    let total: usize = lens.iter().map(|(_, len)| len).sum();
+    let claim = claim_quota_batch(&service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes, lens.len() as i64, total as i64).await?;
 
-        match service.storage.add_link(link).await {
-            Ok(_) => Ok(
-                HttpResponse::Ok()
-                    .content_type("text/plain")
-                    .body(token)
-            ),
-            Err(why) => Err(HttpResponse::InternalServerError().body(format!("Add link failed! {}", why))),
+    match service.storage.add_files(files, overwrite).await {
+        Ok(added) => {
+            let added_set: HashSet<&String> = added.iter().collect();
+            let mut added_bytes = 0;
+            for (filename, len) in &lens {
+                if added_set.contains(filename) {
+                    service.histograms.file_size_bytes.observe(*len as u64);
+                    added_bytes += len;
+                }
+            }
+            settle_quota_batch(&service, claim, added.len(), added_bytes).await;
+            let mut skipped: Vec<String> = filenames.iter().filter(|filename| !added_set.contains(filename)).cloned().collect();
+            skipped.sort();
+            Ok(web::Json(BulkFilesSummary { added: added, skipped: skipped }))
+        }
+        Err(why) => {
+            settle_quota_batch(&service, claim, 0, 0).await;
+            Err(HttpResponse::InternalServerError().body(format!("Add files failed! {}", why)))
         }
-    } else {
-        Err(HttpResponse::BadRequest().body("Invalid filename for link!"))
     }
 }
 
-pub async fn download_link (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> HttpResponse {
-    println!("download link");
-    if let Err(badreq) = check_rate_limit(&req) {
-        return badreq
+// PUT is both: a multipart body updates an existing file, anything else is the raw contents to store
+pub async fn put_file (
+    req: HttpRequest,
+    payload: web::Payload,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    let is_multipart = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("multipart/"))
+        .unwrap_or(false);
+
+    if is_multipart {
+        let multipart = Multipart::new(req.headers(), payload);
+        update_file(req, multipart, service).await
+    } else {
+        upload_file(req, payload, service).await
     }
+}
 
-    let token = req.match_info().get("token").unwrap().to_string();
-    let ip_address = req.connection_info().remote().unwrap().to_string();
-    println!("downloading... {} by {}", token, ip_address);
+// for scripts, eg curl --data-binary @file, adds or replaces like add_file
+async fn upload_file (
+    req: HttpRequest,
+    payload: web::Payload,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    println!("upload file");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_rate_limit(&req, &service)?;
 
-    let not_found_file = format!("Could not find file for link {}", token);
-    let link = match service.storage.get_link(token).await {
-        Ok(link) => link,
-        Err(why) => return HttpResponse::NotFound().body(
+    let filename = req.match_info().get("filename").unwrap().to_string();
+    check_max_len("Filename", filename.as_str(), service.config.max_len_filename)?;
+    check_extension(&service, filename.as_str())?;
+    let expires_at = match req.headers().get(FILE_EXPIRES_AT_HEADER).and_then(|v| v.to_str().ok()) {
+        None => None,
+        Some(value) => Some(parse_file_expires_at(value)?),
+    };
+    // curl --data-binary sends form-urlencoded unless told otherwise, which is never what the file is
+    let given = header_value(&req, header::CONTENT_TYPE).filter(|ct| !ct.starts_with("application/x-www-form-urlencoded"));
+    let content_type = resolve_content_type(&service, filename.as_str(), given);
+    // a declared length over the max is refused before any of the body is read
+    let max = service.config.max_len_raw_file;
+    let declared = header_value(&req, header::CONTENT_LENGTH).and_then(|len| len.parse::<usize>().ok());
+    if let Some(declared) = declared.filter(|declared| *declared > max) {
+        return Err(HttpResponse::PayloadTooLarge().body(format!("Body too big! {} > {}", declared, max)));
+    }
+    let now = service.time_provider.unix_ts_ms();
+    let mut progress = service.uploads.start(filename.clone());
+
+    if !service.config.immutable_files {
+        let claim = claim_quota(&service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes).await?;
+        return stream_file(payload, true, filename, now, expires_at, content_type, progress, claim, &service).await;
+    }
+
+    // create_file has no streaming variant, so immutable files are buffered
+    let mut contents = BytesMut::new();
+    let mut payload = paced(payload, upload_pace(&service));
+    while let Some(chunk) = payload.next().await {
+        let data = chunk.map_err(upload_error)?;
+        progress.add(data.len());
+        // chunked bodies have no length up front, so this still counts as they come
+        if contents.len() + data.len() > max {
+            return Err(HttpResponse::PayloadTooLarge().body(format!("Body too big! {} > {}", contents.len() + data.len(), max)));
+        }
+        contents.extend_from_slice(&data);
+    }
+    check_min_len_file(&service, contents.len())?;
+
+    let file = OnetimeFile {
+        filename: filename,
+        contents: contents.freeze(),
+        created_at: now,
+        updated_at: now,
+        expires_at: expires_at,
+        content_type: Some(content_type),
+    };
+    let len = file.contents.len();
+    let claim = claim_quota(&service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes).await?;
+    let result = service.storage.create_file(file).await;
+    settle_quota(&service, claim, match result { Ok(true) => Some(len), _ => None }).await;
+    if let Ok(true) = result {
+        service.histograms.file_size_bytes.observe(len as u64);
+    }
+    match result {
+        Ok(true) => Ok(HttpResponse::Ok().body("added file")),
+        Ok(false) => Ok(HttpResponse::Conflict().body("File already exists and files are immutable!")),
+        Err(why) => Ok(HttpResponse::InternalServerError().body(format!("Add file failed! {}", why))),
+    }
+}
+
+// the upload area when TUS_DIR is set, 404 otherwise like any unknown path
+fn tus_area (service: &OnetimeDownloaderService) -> Result<UploadArea, HttpResponse> {
+    if service.config.tus_dir.is_empty() {
+        return Err(HttpResponse::NotFound().body("Resumable uploads are not enabled!"));
+    }
+    Ok(UploadArea::new(service.config.tus_dir.as_str()))
+}
+
+// clients say which protocol version they speak, only 1.0.0 is supported
+fn check_tus_resumable (req: &HttpRequest) -> Result<bool, HttpResponse> {
+    match header_value(req, header::HeaderName::from_static("tus-resumable")) {
+        Some(version) if version != tus::TUS_VERSION => Err(HttpResponse::PreconditionFailed()
+            .set_header("Tus-Version", tus::TUS_VERSION)
+            .body(format!("Unsupported Tus-Resumable {}!", version))),
+        _ => Ok(true),
+    }
+}
+
+fn tus_u64_header (req: &HttpRequest, name: &'static str) -> Result<u64, HttpResponse> {
+    header_value(req, header::HeaderName::from_static(name))
+        .ok_or_else(|| HttpResponse::BadRequest().body(format!("Missing {}!", name)))?
+        .trim().parse::<u64>()
+        .map_err(|why| HttpResponse::BadRequest().body(format!("Invalid {}! {}", name, why)))
+}
+
+// https://tus.io/protocols/resumable-upload.html#options
+pub async fn tus_options (service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    tus_area(&service)?;
+    Ok(HttpResponse::NoContent()
+        .set_header("Tus-Resumable", tus::TUS_VERSION)
+        .set_header("Tus-Version", tus::TUS_VERSION)
+        .set_header("Tus-Extension", tus::TUS_EXTENSIONS)
+        .set_header("Tus-Max-Size", service.config.max_len_file.to_string())
+        .set_header(header::ALLOW, "POST, OPTIONS")
+        .finish())
+}
+
+// https://tus.io/protocols/resumable-upload.html#creation
+//  the filename comes in Upload-Metadata, as does an optional expires_at (or the usual header)
+pub async fn tus_create (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("tus create");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_rate_limit(&req, &service)?;
+    check_tus_resumable(&req)?;
+    let area = tus_area(&service)?;
+
+    let length = tus_u64_header(&req, "upload-length")?;
+    if length > service.config.max_len_file as u64 {
+        return Err(HttpResponse::PayloadTooLarge().body(format!("Upload-Length over the max of {}!", service.config.max_len_file)));
+    }
+    check_min_len_file(&service, length as usize)?;
+    let metadata = match header_value(&req, header::HeaderName::from_static("upload-metadata")) {
+        None => HashMap::new(),
+        Some(header) => tus::parse_metadata(header.as_str()).map_err(|why| HttpResponse::BadRequest().body(why))?,
+    };
+    let filename = match metadata.get("filename") {
+        Some(filename) if !filename.is_empty() => filename.clone(),
+        _ => return Err(HttpResponse::BadRequest().body("Missing filename in Upload-Metadata!")),
+    };
+    check_max_len("Filename", filename.as_str(), service.config.max_len_filename)?;
+    check_extension(&service, filename.as_str())?;
+    let expires_at = match metadata.get("expires_at").cloned().or_else(|| header_value(&req, header::HeaderName::from_static("x-file-expires-at"))) {
+        None => None,
+        Some(value) => Some(parse_file_expires_at(value.as_str())?),
+    };
+
+    let now = service.time_provider.unix_ts_ms();
+    area.prune(now - service.config.tus_expiry_ms).await;
+    // "filetype" is what tus-js-client and uppy send
+    let upload = PartialUpload {
+        filename: filename,
+        length: length,
+        expires_at: expires_at,
+        content_type: metadata.get("filetype").cloned(),
+        created_at: now,
+    };
+    let id = area.create(&upload).await
+        .map_err(|why| HttpResponse::InternalServerError().body(why))?;
+
+    // nothing to send for an empty file (with MIN_LEN_FILE=0), so it is complete already
+    if length == 0 {
+        finish_tus_upload(&service, &area, id.as_str(), upload).await?;
+    }
+
+    Ok(HttpResponse::Created()
+        .set_header(header::LOCATION, format!("{}/api/uploads/{}", service.config.path_prefix, id))
+        .set_header("Tus-Resumable", tus::TUS_VERSION)
+        .finish())
+}
+
+// https://tus.io/protocols/resumable-upload.html#head -- where the client should resume from
+pub async fn tus_head (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_tus_resumable(&req)?;
+    let area = tus_area(&service)?;
+
+    let id = req.match_info().get("id").unwrap().to_string();
+    match area.get(id.as_str()).await {
+        Ok(Some((upload, offset))) => Ok(HttpResponse::Ok()
+            .set_header("Tus-Resumable", tus::TUS_VERSION)
+            .set_header("Upload-Offset", offset.to_string())
+            .set_header("Upload-Length", upload.length.to_string())
+            .set_header(header::CACHE_CONTROL, "no-store")
+            .finish()),
+        Ok(None) => Err(HttpResponse::NotFound().finish()),
+        Err(why) => Err(HttpResponse::InternalServerError().body(why)),
+    }
+}
+
+// https://tus.io/protocols/resumable-upload.html#patch
+//  what arrives is kept even when the request breaks off, so the client can resume from there
+pub async fn tus_patch (req: HttpRequest, payload: web::Payload, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("tus patch");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_rate_limit(&req, &service)?;
+    check_tus_resumable(&req)?;
+    let area = tus_area(&service)?;
+
+    if header_value(&req, header::CONTENT_TYPE).as_deref() != Some("application/offset+octet-stream") {
+        return Err(HttpResponse::UnsupportedMediaType().body("Content-Type must be application/offset+octet-stream!"));
+    }
+    let id = req.match_info().get("id").unwrap().to_string();
+    // held from reading the offset until the last write, so two PATCHes of one upload can't both append at it
+    let _patching = match service.tus_patches.claim(id.as_str()) {
+        Some(guard) => guard,
+        None => return Err(HttpResponse::Conflict().body("Upload is already being patched!")),
+    };
+    let (upload, mut offset) = match area.get(id.as_str()).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(HttpResponse::NotFound().finish()),
+        Err(why) => return Err(HttpResponse::InternalServerError().body(why)),
+    };
+    let claimed = tus_u64_header(&req, "upload-offset")?;
+    if claimed != offset {
+        return Err(HttpResponse::Conflict().body(format!("Upload-Offset {} does not match {}!", claimed, offset)));
+    }
+
+    let mut progress = service.uploads.start(upload.filename.clone());
+    let mut payload = paced(payload, upload_pace(&service));
+    while let Some(chunk) = payload.next().await {
+        let data = chunk.map_err(upload_error)?;
+        if offset + data.len() as u64 > upload.length {
+            return Err(HttpResponse::PayloadTooLarge().body(format!("Upload longer than its Upload-Length of {}!", upload.length)));
+        }
+        let len = data.len();
+        offset = area.append(id.as_str(), data).await
+            .map_err(|why| HttpResponse::InternalServerError().body(why))?;
+        progress.add(len);
+    }
+
+    if offset == upload.length {
+        finish_tus_upload(&service, &area, id.as_str(), upload).await?;
+    }
+    Ok(HttpResponse::NoContent()
+        .set_header("Tus-Resumable", tus::TUS_VERSION)
+        .set_header("Upload-Offset", offset.to_string())
+        .finish())
+}
+
+// https://tus.io/protocols/resumable-upload.html#termination
+pub async fn tus_delete (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("tus delete");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_tus_resumable(&req)?;
+    let area = tus_area(&service)?;
+
+    let id = req.match_info().get("id").unwrap().to_string();
+    match area.get(id.as_str()).await {
+        Ok(Some(_)) => (),
+        Ok(None) => return Err(HttpResponse::NotFound().finish()),
+        Err(why) => return Err(HttpResponse::InternalServerError().body(why)),
+    }
+    area.remove(id.as_str()).await.map_err(|why| HttpResponse::InternalServerError().body(why))?;
+    Ok(HttpResponse::NoContent().set_header("Tus-Resumable", tus::TUS_VERSION).finish())
+}
+
+// stores the whole upload as a file like add_file would, the partial upload goes once stored
+//  a failure keeps it, so the last PATCH can be retried (with no bytes) to try storing again
+async fn finish_tus_upload (service: &OnetimeDownloaderService, area: &UploadArea, id: &str, upload: PartialUpload) -> Result<bool, HttpResponse> {
+    let contents = area.contents(id).await.map_err(|why| HttpResponse::InternalServerError().body(why))?;
+    let now = service.time_provider.unix_ts_ms();
+    let file = OnetimeFile {
+        content_type: Some(resolve_content_type(service, upload.filename.as_str(), upload.content_type)),
+        filename: upload.filename,
+        contents: Bytes::from(contents),
+        created_at: now,
+        updated_at: now,
+        expires_at: upload.expires_at,
+    };
+    let len = file.contents.len();
+    let claim = claim_quota(service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes).await?;
+    let result = if service.config.immutable_files {
+        service.storage.create_file(file).await
+    } else {
+        service.storage.add_file(file).await
+    };
+    settle_quota(service, claim, match result { Ok(true) => Some(len), _ => None }).await;
+    match result {
+        Ok(true) => service.histograms.file_size_bytes.observe(len as u64),
+        // it can never be stored, so there is no point keeping it
+        Ok(false) => {
+            let _ = area.remove(id).await;
+            return Err(HttpResponse::Conflict().body("File already exists and files are immutable!"));
+        }
+        Err(why) => return Err(HttpResponse::InternalServerError().body(format!("Add file failed! {}", why))),
+    }
+    if let Err(why) = area.remove(id).await {
+        println!("Remove finished upload {} failed: {}", id, why);
+    }
+    Ok(true)
+}
+
+pub async fn update_file (
+    req: HttpRequest,
+    mut payload: Multipart,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    println!("update file");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_rate_limit(&req, &service)?;
+
+    if service.config.immutable_files {
+        return Ok(HttpResponse::Conflict().body("Files are immutable!"));
+    }
+
+    let filename = req.match_info().get("filename").unwrap().to_string();
+    // refused by name before any of the body is read, like every other upload
+    check_extension(&service, filename.as_str())?;
+    let mut contents: Option<(Bytes, Option<String>)> = None;
+    let mut progress = service.uploads.start(filename.clone());
+
+    while let Ok(Some(field)) = payload.try_next().await {
+        let (field_name, part_filename) = field_names(&field)?;
+
+        if field_name == "file" && part_filename.is_some() {
+            let given = field_content_type(&field);
+            let val = collect_chunks(field, service.config.max_len_file, upload_pace(&service), Some(&mut progress)).await?;
+            check_min_len_file(&service, val.len())?;
+            contents = Some((Bytes::from(val), given));
+        }
+    }
+
+    let (contents, given) = match contents {
+        None => return Ok(HttpResponse::BadRequest().body("No file contents provided!")),
+        Some(contents) => contents,
+    };
+    let claim = claim_quota(&service, QUOTA_OWNER_FILES, service.config.quota_max_files, service.config.quota_max_bytes).await?;
+
+    let content_type = resolve_content_type(&service, filename.as_str(), given);
+    let now = service.time_provider.unix_ts_ms();
+    let len = contents.len();
+    let result = service.storage.update_file(filename, contents, Some(content_type), now).await;
+    settle_quota(&service, claim, match result { Ok(true) => Some(len), _ => None }).await;
+    match result {
+        Ok(true) => {
+            service.histograms.file_size_bytes.observe(len as u64);
+            Ok(HttpResponse::Ok().body("updated file"))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().body("No such file to update!")),
+        Err(why) => Ok(HttpResponse::InternalServerError().body(format!("Update file failed! {}", why))),
+    }
+}
+
+// placeholders not listed here are left in the note as-is
+fn expand_note_template (template: &str, filename: &str, created_at: i64, expires_at: i64) -> String {
+    template
+        .replace("{filename}", filename)
+        .replace("{created_at}", &created_at.to_string())
+        .replace("{expires_at}", &expires_at.to_string())
+}
+
+// the offered type the client ranks highest, by q value then order, the first offered when nothing matches
+//  https://developer.mozilla.org/en-US/docs/Web/HTTP/Content_negotiation
+fn negotiate (accept: &str, offered: &[&'static str]) -> &'static str {
+    let mut best: Option<(&'static str, f32)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media_type = parts.next().unwrap_or("").trim().to_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+            .next()
+            .unwrap_or(1.0);
+        let matched = offered.iter().find(|offer| {
+            media_type == **offer || media_type == "*/*" || (media_type.ends_with("/*") && offer.starts_with(&media_type[..media_type.len() - 1]))
+        });
+        if let Some(offer) = matched {
+            if q > 0.0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((*offer, q));
+            }
+        }
+    }
+    best.map(|(offer, _)| offer).unwrap_or(offered[0])
+}
+
+// absolute, from the host and scheme the request came in on, under PATH_PREFIX when behind a proxy
+fn download_url (req: &HttpRequest, service: &OnetimeDownloaderService, token: &str) -> String {
+    let connection_info = req.connection_info();
+    format!("{}://{}{}/download/{}", connection_info.scheme(), connection_info.host(), service.config.path_prefix, token)
+}
+
+// json is the documented format, urlencoded is for plain html forms, which can't send the list fields
+fn parse_create_link (req: &HttpRequest, body: &[u8]) -> Result<CreateLink, HttpResponse> {
+    let is_form = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/x-www-form-urlencoded"))
+        .unwrap_or(false);
+
+    if is_form {
+        // the same encoding as a query string, so the query parser reads it
+        let body = std::str::from_utf8(body).map_err(|why| HttpResponse::BadRequest().body(format!("Invalid form! {}", why)))?;
+        web::Query::<CreateLink>::from_query(body)
+            .map(|query| query.into_inner())
+            .map_err(|why| HttpResponse::BadRequest().body(format!("Invalid form! {}", why)))
+    } else {
+        serde_json::from_slice(body).map_err(|why| HttpResponse::BadRequest().body(format!("Invalid json! {}", why)))
+    }
+}
+
+pub async fn add_link (
+    req: HttpRequest,
+    body: web::Bytes,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    println!("add link");
+    check_api_key(&req, service.config.api_key_links.as_str())?;
+    check_rate_limit(&req, &service)?;
+
+    let payload = parse_create_link(&req, &body)?;
+
+    // TODO validate filename is stored file
+    let now = service.time_provider.unix_ts_ms();
+    let link = new_link(&service, &payload, now).map_err(|why| HttpResponse::BadRequest().body(why))?;
+    let token = link.token.clone();
+    let totp_secret = link.totp_secret.clone();
+
+    let claim = claim_quota(&service, QUOTA_OWNER_LINKS, service.config.quota_max_links, 0).await?;
+    let result = service.storage.add_link(link).await;
+    settle_quota(&service, claim, result.as_ref().ok().map(|_| 0)).await;
+    match result {
+        Ok(_) => {
+            let mut response = HttpResponse::Ok();
+            // the only time the secret is handed out, it must be shared with the downloader out-of-band
+            if let Some(totp_secret) = &totp_secret {
+                response.set_header(TOTP_SECRET_HEADER, totp_secret.as_str());
+            }
+            let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+            match negotiate(accept, &["text/plain", "text/uri-list", "application/json"]) {
+                "text/uri-list" => Ok(response.content_type("text/uri-list").body(format!("{}\r\n", download_url(&req, &service, token.as_str())))),
+                "application/json" => Ok(response.json(CreatedLink {
+                    url: download_url(&req, &service, token.as_str()),
+                    token: token,
+                    totp_secret: totp_secret,
+                })),
+                _ => Ok(response.content_type("text/plain").body(token)),
+            }
+        },
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("Add link failed! {}", why))),
+    }
+}
+
+pub async fn files_exist (
+    req: HttpRequest,
+    payload: web::Json<FilesExist>,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<HashMap<String, bool>>, HttpResponse> {
+    println!("files exist");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+
+    let filenames = payload.into_inner().filenames;
+    if filenames.len() > service.config.max_bulk_files {
+        return Err(HttpResponse::BadRequest().body(format!("Too many filenames! {} > {}", filenames.len(), service.config.max_bulk_files)));
+    }
+
+    match service.storage.files_exist(filenames).await {
+        Ok(exist) => Ok(web::Json(exist)),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("Files exist failed! {}", why))),
+    }
+}
+
+pub async fn add_links (
+    req: HttpRequest,
+    payload: web::Json<Vec<CreateLink>>,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<Vec<BulkLinkResult>>, HttpResponse> {
+    println!("add links");
+    check_api_key(&req, service.config.api_key_links.as_str())?;
+    check_rate_limit(&req, &service)?;
+
+    if payload.len() > service.config.max_bulk_links {
+        return Err(HttpResponse::BadRequest().body(format!("Too many links! {} > {}", payload.len(), service.config.max_bulk_links)));
+    }
+
+    let now = service.time_provider.unix_ts_ms();
+    let filenames = payload.iter().map(|create| create.filename.clone()).collect();
+    let files_found = match service.storage.files_exist(filenames).await {
+        Ok(files_found) => files_found,
+        Err(why) => return Err(HttpResponse::InternalServerError().body(format!("Add links failed! {}", why))),
+    };
+    let mut results = Vec::new();
+    let mut links = Vec::new();
+    // each item is checked on its own, a bad one is reported without failing the rest
+    for create in payload.iter() {
+        let link = match new_link(&service, create, now) {
+            Err(why) => {
+                results.push(BulkLinkResult { token: None, totp_secret: None, error: Some(why) });
+                continue;
+            }
+            Ok(link) => link,
+        };
+        if link.expires_at <= now {
+            results.push(BulkLinkResult { token: None, totp_secret: None, error: Some("Already expired".to_string()) });
+            continue;
+        }
+        if !files_found.get(&link.filename).copied().unwrap_or(false) {
+            results.push(BulkLinkResult { token: None, totp_secret: None, error: Some(format!("No file named '{}'", link.filename)) });
+            continue;
+        }
+        results.push(BulkLinkResult { token: Some(link.token.clone()), totp_secret: link.totp_secret.clone(), error: None });
+        links.push(link);
+    }
+
+    if !links.is_empty() {
+        let count = links.len();
+        let claim = claim_quota_batch(&service, QUOTA_OWNER_LINKS, service.config.quota_max_links, 0, count as i64, 0).await?;
+        let result = service.storage.add_links(links).await;
+        settle_quota_batch(&service, claim, if result.is_ok() { count } else { 0 }, 0).await;
+        if let Err(why) = result {
+            return Err(HttpResponse::InternalServerError().body(format!("Add links failed! {}", why)));
+        }
+    }
+    Ok(web::Json(results))
+}
+
+// the same url the holder of the secret could make themselves, for callers that would rather not
+pub async fn sign_link (
+    req: HttpRequest,
+    payload: web::Json<SignLink>,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<CreatedLink>, HttpResponse> {
+    println!("sign link");
+    check_api_key(&req, service.config.api_key_links.as_str())?;
+    check_rate_limit(&req, &service)?;
+
+    if service.config.link_signing_secret.is_empty() {
+        return Err(HttpResponse::NotFound().body("Signed links are disabled"));
+    }
+    check_max_len("Filename", payload.filename.as_str(), service.config.max_len_filename)?;
+
+    let now = service.time_provider.unix_ts_ms();
+    let expires_at = payload.expires_at.unwrap_or(now + service.config.default_expiration_ms);
+    if expires_at <= now {
+        return Err(HttpResponse::BadRequest().body("Already expired"));
+    }
+
+    let token = signed::sign(service.config.link_signing_secret.as_str(), payload.filename.as_str(), expires_at);
+    Ok(web::Json(CreatedLink {
+        url: download_url(&req, &service, token.as_str()),
+        token: token,
+        totp_secret: None,
+    }))
+}
+
+// everything about a new link that can be rejected, shared by single and bulk creation
+fn new_link (service: &OnetimeDownloaderService, payload: &CreateLink, now: i64) -> Result<OnetimeLink, String> {
+    too_long("Filename", payload.filename.as_str(), service.config.max_len_filename)?;
+
+    let token = generate_token(service.config.token_format);
+
+    let expires_at = match payload.expires_at {
+        None => now + service.config.default_expiration_ms,
+        Some(v) => v,
+    };
+    println!("token {} expires_at {}", token, expires_at);
+
+    let note = match &payload.note {
+        Some(note) => Some(note.clone()),
+        None if service.config.link_note_template.is_empty() => None,
+        None => Some(expand_note_template(
+            service.config.link_note_template.as_str(),
+            payload.filename.as_str(),
+            now,
+            expires_at,
+        )),
+    };
+    if let Some(note) = &note {
+        too_long("Note", note.as_str(), service.config.max_len_note)?;
+    }
+
+    let mut allowed_cidrs = Vec::new();
+    for cidr in payload.allowed_cidrs.iter().flatten() {
+        allowed_cidrs.push(parse_cidr(cidr)?.to_string());
+    }
+
+    if let Some(redirect_url) = &payload.redirect_url {
+        check_redirect_url(redirect_url.as_str())?;
+    }
+
+    let allowed_countries = parse_countries(&payload.allowed_countries)?;
+    let blocked_countries = parse_countries(&payload.blocked_countries)?;
+
+    let totp_secret = match payload.require_totp {
+        Some(true) => Some(totp::generate_secret()),
+        _ => None,
+    };
+
+    let max_downloads = payload.max_downloads.unwrap_or(service.config.default_max_downloads);
+    if max_downloads < 1 || max_downloads > service.config.max_max_downloads {
+        return Err(format!("max_downloads must be between 1 and {}!", service.config.max_max_downloads));
+    }
+
+    Ok(OnetimeLink {
+        filename: payload.filename.clone(),
+        token: token,
+        note: note,
+        created_at: now,
+        expires_at: expires_at,
+        downloaded_at: None,
+        ip_address: None,
+        attempts: 0,
+        last_attempt_at: None,
+        totp_secret: totp_secret,
+        allowed_cidrs: allowed_cidrs,
+        allowed_countries: allowed_countries,
+        blocked_countries: blocked_countries,
+        deleted_at: None,
+        redirect_url: payload.redirect_url.clone(),
+        downloads: 0,
+        max_downloads: max_downloads,
+        revoked_at: None,
+    })
+}
+
+// the {token} in the path, a 400 for anything that could never be a token
+// the api also takes the sha256:<hex> form lists show with HASH_TOKENS, downloads never do
+fn path_token (req: &HttpRequest) -> Result<String, HttpResponse> {
+    let token = req.match_info().get("token").unwrap_or("");
+    let unprefixed = if token.starts_with(hashed::HASHED_PREFIX) { &token[hashed::HASHED_PREFIX.len()..] } else { token };
+    validate_token(unprefixed).map_err(|why| HttpResponse::BadRequest().body(why))?;
+    Ok(token.to_string())
+}
+
+// /download/{namespace}/{token} reads the link from that namespace's table, plain /download/{token} from the usual one
+fn namespace_storage (req: &HttpRequest, service: &OnetimeDownloaderService) -> Result<Box<dyn OnetimeStorage>, HttpResponse> {
+    match req.match_info().get("namespace") {
+        None => Ok(service.storage.clone()),
+        Some(namespace) => service.namespaces.get(namespace)
+            .cloned()
+            .ok_or_else(|| HttpResponse::NotFound().body("Unknown namespace")),
+    }
+}
+
+// hex and uuid tokens are generated lowercase, so with CASE_INSENSITIVE_TOKENS any case of them finds the link
+//  only all hex (and dash) tokens are folded, signed tokens are left exactly as sent
+fn download_token (req: &HttpRequest, service: &OnetimeDownloaderService) -> Result<String, HttpResponse> {
+    let token = req.match_info().get("token").unwrap_or("").to_string();
+    validate_token(token.as_str()).map_err(|why| HttpResponse::BadRequest().body(why))?;
+    let foldable = service.config.case_insensitive_tokens && service.config.token_format != TokenFormat::Base62;
+    if foldable && token.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        Ok(token.to_ascii_lowercase())
+    } else {
+        Ok(token)
+    }
+}
+
+// absent, empty and non-ascii headers are all just None
+fn header_value (req: &HttpRequest, name: header::HeaderName) -> Option<String> {
+    req.headers().get(name)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+}
+
+// header wins over the query string, since query strings tend to end up in logs
+fn check_totp_code (req: &HttpRequest, totp_secret: &str, now: i64) -> bool {
+    let header_code = req.headers().get(TOTP_CODE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let code = match header_code {
+        Some(code) => Some(code),
+        None => web::Query::<DownloadQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().code),
+    };
+
+    match code {
+        None => false,
+        Some(code) => totp::check_code(totp_secret, code.as_str(), now),
+    }
+}
+
+// one attempt id for every try of this download, so a retry of a consume that went through but timed out
+//  gets that consume back rather than counting a second download
+async fn consume (service: &OnetimeDownloaderService, storage: &Box<dyn OnetimeStorage>, token: String, ip_address: String, now: i64) -> Result<OnetimeLink, OnetimeError> {
+    let attempt_id = generate_token(TokenFormat::Hex);
+    let mut retries = 0;
+    loop {
+        match storage.consume_link(token.clone(), ip_address.clone(), now, attempt_id.clone()).await {
+            Err(OnetimeError::Failed(why)) if retries < service.config.consume_retries => {
+                println!("Consume link {} failed, retrying: {}", token, why);
+                tokio::time::delay_for(Duration::from_millis(consume_retry_delay_ms(service.config.consume_retry_delay_ms, retries))).await;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// doubles with each retry, but a high CONSUME_RETRIES can't overflow it or wait for minutes
+//  never less than the configured delay itself, even when that is over the cap
+fn consume_retry_delay_ms (delay_ms: u64, retries: usize) -> u64 {
+    let factor = if retries >= 64 { u64::MAX } else { 1 << retries };
+    delay_ms.saturating_mul(factor).min(MAX_CONSUME_RETRY_DELAY_MS.max(delay_ms))
+}
+
+// actix only polls for more once the previous chunk is written out, and stops polling if the client goes away,
+//  so the consume at the end only happens when the whole body was delivered
+fn consume_after_body (
+    service: web::Data<OnetimeDownloaderService>,
+    storage: Box<dyn OnetimeStorage>,
+    token: String,
+    ip_address: String,
+    now: i64,
+    contents: Bytes,
+) -> LocalBoxStream<'static, Result<Bytes, actix_web::Error>> {
+    let chunks: Vec<Result<Bytes, actix_web::Error>> = (0..contents.len())
+        .step_by(FLUSH_CHUNK_LEN)
+        .map(|start| Ok(contents.slice(start..usize::min(start + FLUSH_CHUNK_LEN, contents.len()))))
+        .collect();
+
+    let consume = stream::once(async move {
+        match consume(&service, &storage, token.clone(), ip_address, now).await {
+            Ok(link) => after_download(&service, storage.clone(), link, now),
+            Err(why) => println!("Consume link {} after download failed: {:?}", token, why),
+        }
+        None::<Result<Bytes, actix_web::Error>>
+    }).filter_map(future::ready);
+
+    stream::iter(chunks).chain(consume).boxed_local()
+}
+
+// whichever download path consumed it, the link here has the counts after this download
+//  storage is the one it was consumed from, the namespace's when it has one
+fn after_download (service: &OnetimeDownloaderService, storage: Box<dyn OnetimeStorage>, link: OnetimeLink, now: i64) {
+    // a signed link has no stored creation time, its created_at is just this download
+    if !signed::is_signed(link.token.as_str()) {
+        service.histograms.consume_after_ms.observe(i64::max(0, now - link.created_at) as u64);
+    }
+    // only once the link is used up, a multi-download link keeps its row until its last download
+    if service.config.delete_after_download && link.downloaded_at.is_some() {
+        actix_rt::spawn(burn_after_reading(
+            storage,
+            link.token.clone(),
+            link.filename.clone(),
+            service.config.delete_after_download_delay_ms,
+            service.config.delete_file_after_download,
+        ));
+    }
+    publish_download(&service.link_events, link, now);
+}
+
+// the contents are already in memory for the response, so deleting the rows can't cut a download short
+//  best effort, a failure is logged and the link stays consumed as it would have without this
+async fn burn_after_reading (storage: Box<dyn OnetimeStorage>, token: String, filename: String, delay_ms: u64, delete_file: bool) {
+    if delay_ms > 0 {
+        tokio::time::delay_for(Duration::from_millis(delay_ms)).await;
+    }
+    match storage.delete_link(token.clone()).await {
+        Ok(_) => println!("burned link {} after download", token),
+        Err(why) => {
+            println!("Burn link {} after download failed: {}", token, why);
+            return;
+        }
+    }
+    if !delete_file {
+        return;
+    }
+    // the file goes too only when no other link could still download it
+    match storage.list_links_for_file(filename.clone()).await {
+        Err(why) => println!("Burn file {} after download failed: {}", filename, why),
+        Ok(links) if has_other_unused_link(&links, token.as_str()) => {
+            println!("kept file {} after download, other links still use it", filename);
+        }
+        Ok(_) => match storage.delete_file(filename.clone()).await {
+            Ok(_) => println!("burned file {} after download", filename),
+            Err(why) => println!("Burn file {} after download failed: {}", filename, why),
+        },
+    }
+}
+
+fn has_other_unused_link (links: &[OnetimeLink], token: &str) -> bool {
+    links.iter().any(|link| link.token != token && link.downloaded_at.is_none())
+}
+
+// for anyone watching the link's events
+fn publish_download (events: &LinkEvents, link: OnetimeLink, now: i64) {
+    events.publish(LinkEvent {
+        token: link.token,
+        downloads: link.downloads,
+        max_downloads: link.max_downloads,
+        downloaded_at: link.downloaded_at,
+        at: now,
+    });
+}
+
+// keeps the download slot taken until the body has gone out, or the client went away and it got dropped
+fn hold_permit (
+    body: LocalBoxStream<'static, Result<Bytes, actix_web::Error>>,
+    permit: DownloadPermit,
+) -> LocalBoxStream<'static, Result<Bytes, actix_web::Error>> {
+    let release = stream::once(async move {
+        drop(permit);
+        None::<Result<Bytes, actix_web::Error>>
+    }).filter_map(future::ready);
+
+    body.chain(release).boxed_local()
+}
+
+// content type and disposition, the real type is only sent for inline files, everything else stays opaque bytes
+//  the stored type when there is one, files from before it was stored go by their extension
+fn download_headers (service: &OnetimeDownloaderService, filename: &str, stored: Option<&str>) -> (String, String) {
+    let content_type = stored.unwrap_or_else(|| content_types::content_type_for(filename));
+    let disposition = content_types::disposition_for(content_type, &service.config.inline_content_types);
+    let filename = if filename.is_empty() { service.config.default_download_name.as_str() } else { filename };
+    let content_type = if disposition == "inline" { content_type } else { content_types::OCTET_STREAM };
+    (content_type.to_string(), format!("{}; filename=\"{}\"", disposition, filename))
+}
+
+fn already_downloaded (service: &OnetimeDownloaderService, link: OnetimeLink) -> HttpResponse {
+    HttpResponse::Gone().json(ConsumedLink {
+        error: "Already downloaded".to_string(),
+        downloaded_at: link.downloaded_at.unwrap_or_default(),
+        ip_address: if service.config.show_consumption_details { link.ip_address } else { None },
+    })
+}
+
+pub async fn download_link (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> HttpResponse {
+    println!("download link");
+    let accept = header_value(&req, header::ACCEPT).unwrap_or_default();
+    let response = timed_download(req, service.clone(), false).await;
+    browser_error(&service, accept.as_str(), response)
+}
+
+// a nonce is only handed out on the confirm page, so this can't be reached by following the link alone
+pub async fn download_confirm (
+    req: HttpRequest,
+    form: web::Form<ConfirmForm>,
+    service: web::Data<OnetimeDownloaderService>,
+) -> HttpResponse {
+    println!("download confirm");
+    let accept = header_value(&req, header::ACCEPT).unwrap_or_default();
+    let token = match download_token(&req, &service) {
+        Ok(token) => token,
+        Err(badreq) => return browser_error(&service, accept.as_str(), badreq),
+    };
+    let now = service.time_provider.unix_ts_ms();
+    if !service.nonces.take(form.nonce.as_str(), token.as_str(), now) {
+        return HttpResponse::Forbidden().body("Invalid or expired confirmation, reload the download page");
+    }
+    let response = timed_download(req, service.clone(), true).await;
+    browser_error(&service, accept.as_str(), response)
+}
+
+// someone following the link in a browser gets a page saying what happened, api clients keep the json or text body
+//  browsers rank text/html first, curl's */* and api clients asking for json don't
+fn browser_error (service: &OnetimeDownloaderService, accept: &str, response: HttpResponse) -> HttpResponse {
+    if !service.config.html_download_errors || negotiate(accept, &["application/json", "text/html"]) != "text/html" {
+        return response;
+    }
+    let status = response.status();
+    let (title, message) = match error_pages::describe(status.as_u16()) {
+        Some(described) => described,
+        None => return response,
+    };
+    let mut page = HttpResponse::build(status);
+    if let Some(retry_after) = response.headers().get(header::RETRY_AFTER) {
+        page.set_header(header::RETRY_AFTER, retry_after.clone());
+    }
+    page
+        .content_type("text/html; charset=utf-8")
+        .set_header(header::CACHE_CONTROL, "no-store")
+        .body(error_pages::render(service.download_error_template.as_str(), status.as_u16(), title, message))
+}
+
+// only downloads that went out count, a quick 404 or 410 (or the confirm page) would drag the latencies down
+async fn timed_download (req: HttpRequest, service: web::Data<OnetimeDownloaderService>, confirmed: bool) -> HttpResponse {
+    let started = Instant::now();
+    let histograms = service.histograms.clone();
+    let shows_confirm = service.config.require_confirm && !confirmed;
+    let response = download(req, service, confirmed).await;
+    if response.status().is_success() && !shows_confirm {
+        histograms.download_ms.observe(started.elapsed().as_millis() as u64);
+    }
+    response
+}
+
+// only what a browser needs to post back, escaped since the token and query come from the url
+fn confirm_page (prefix: &str, token: &str, query: &str, nonce: &str) -> HttpResponse {
+    let action = if query.is_empty() {
+        format!("{}/download/{}/confirm", prefix, token)
+    } else {
+        format!("{}/download/{}/confirm?{}", prefix, token, query)
+    };
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .set_header(header::CACHE_CONTROL, "no-store")
+        .body(format!(
+            "<!DOCTYPE html>\n<html><body><form method=\"post\" action=\"{}\"><input type=\"hidden\" name=\"nonce\" value=\"{}\"><button type=\"submit\">Download</button></form></body></html>\n",
+            escape_html(action.as_str()), escape_html(nonce),
+        ))
+}
+
+fn escape_html (value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+async fn download (req: HttpRequest, service: web::Data<OnetimeDownloaderService>, confirmed: bool) -> HttpResponse {
+    if let Err(badreq) = check_rate_limit(&req, &service) {
+        return badreq
+    }
+    let storage = match namespace_storage(&req, &service) {
+        Ok(storage) => storage,
+        Err(badreq) => return badreq,
+    };
+
+    // before any storage work, so a spike waits here instead of using up db connections
+    let timeout_ms = service.config.download_slot_timeout_ms;
+    let permit = match service.download_slots.acquire(timeout_ms).await {
+        Some(permit) => permit,
+        None => return HttpResponse::ServiceUnavailable()
+            .set_header(header::RETRY_AFTER, u64::max(1, (timeout_ms + 999) / 1000).to_string())
+            .body("Too many downloads in progress, try again shortly"),
+    };
+
+    let token = match download_token(&req, &service) {
+        Ok(token) => token,
+        Err(badreq) => return badreq,
+    };
+    let client = match request_ip(&req, &service.config.trusted_proxies) {
+        Some(client) => client,
+        None => return HttpResponse::BadRequest().body("Could not determine client address!"),
+    };
+    let ip_address = client.to_string();
+    println!("downloading... {} by {}", token, ip_address);
+
+    // every hit counts as an attempt, even ones that fail below, and failing to record it should not block the download
+    let attempted_at = service.time_provider.unix_ts_ms();
+    if let Err(why) = storage.record_attempt(token.clone(), attempted_at).await {
+        println!("Record attempt failed for {}: {}", token, why);
+    }
+
+    if service.config.log_referer {
+        let attempt = DownloadAttempt {
+            token: token.clone(),
+            ip_address: ip_address.clone(),
+            attempted_at: attempted_at,
+            referer: header_value(&req, header::REFERER),
+            origin: header_value(&req, header::ORIGIN),
+        };
+        match serde_json::to_string(&attempt) {
+            Ok(json) => println!("audit {}", json),
+            Err(why) => println!("Audit log failed for {}: {}", token, why),
+        }
+    }
+
+    // held until the response is made, by then the link is consumed, or with consume_after_flush at least read
+    //  a burst on one token then costs the db one request instead of all of them
+    let _in_flight = match service.in_flight.claim(token.as_str()) {
+        Some(guard) => guard,
+        // the one holding it may yet fail its checks, or leave downloads to spare, so this is only a try again
+        None => return HttpResponse::Conflict()
+            .set_header(header::RETRY_AFTER, "1")
+            .body("A download of this link is in progress, try again shortly"),
+    };
+
+    if signed::is_signed(token.as_str()) && !service.config.link_signing_secret.is_empty() {
+        return download_signed(&service, token, ip_address, permit).await;
+    }
+
+    let not_found_file = format!("Could not find file for link {}", token);
+    // the checks below need the link before it is consumed, so a rejected request does not burn it
+    let link = match storage.get_link(token).await {
+        Ok(link) => link,
+        Err(why) => return HttpResponse::NotFound().body(
             format!("{}: {}",  not_found_file, why)
         )
     };
 
-    if link.downloaded_at.is_some() {
-        return HttpResponse::Gone().body("Already downloaded");
+    if link.downloaded_at.is_some() {
+        return already_downloaded(&service, link);
+    }
+
+    let now = service.time_provider.unix_ts_ms();
+    if link.is_expired(now, service.config.expiry_skew_ms) {
+        return HttpResponse::Gone().body("Expired");
+    }
+
+    if !check_allowed_cidrs(&link.allowed_cidrs, client) {
+        return HttpResponse::Forbidden().body("Not allowed from this address!");
+    }
+
+    if !check_countries(&service, &link, client) {
+        // actix 2 has no shortcut for 451
+        return HttpResponse::build(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS).body("Not available in this region!");
+    }
+
+    // checked before consuming, so a wrong code does not burn the link
+    if let Some(totp_secret) = &link.totp_secret {
+        if !check_totp_code(&req, totp_secret.as_str(), now) {
+            return HttpResponse::Unauthorized().body("Invalid or missing one-time password!");
+        }
+    }
+
+    // after the checks, so the page is only shown for a link that would actually download
+    if service.config.require_confirm && !confirmed {
+        let nonce = service.nonces.issue(link.token.as_str(), now);
+        // the form posts back to the same namespace
+        let action_token = match req.match_info().get("namespace") {
+            Some(namespace) => format!("{}/{}", namespace, link.token),
+            None => link.token.clone(),
+        };
+        return confirm_page(service.config.path_prefix.as_str(), action_token.as_str(), req.query_string(), nonce.as_str());
+    }
+
+    // a confirmed download with somewhere to go after is a 303 there once the link is consumed,
+    //  one response can't be both the file and a redirect, so the redirect is what the browser gets
+    if confirmed {
+        if let Some(redirect_url) = post_download_redirect(&service, &link) {
+            return match consume(&service, &storage, link.token.clone(), ip_address, now).await {
+                Ok(link) => {
+                    after_download(&service, storage.clone(), link, now);
+                    HttpResponse::SeeOther()
+                        .set_header(header::LOCATION, redirect_url)
+                        .finish()
+                }
+                Err(OnetimeError::AlreadyConsumed(link)) => already_downloaded(&service, link),
+                Err(OnetimeError::NotFound(why)) => HttpResponse::NotFound().body(format!("{}: {}", not_found_file, why)),
+                Err(OnetimeError::Failed(why)) => HttpResponse::InternalServerError().body(format!("Consume link failed! {}", why)),
+            };
+        }
+    }
+
+    if wants_base64(&req) {
+        return download_base64(&service, storage, link, ip_address, now).await;
+    }
+
+    let filename = link.filename.clone();
+
+    // fetched before consuming, so a link to a file deleted since is not used up for nothing
+    let file = match storage.get_file(filename.clone()).await {
+        Ok(file) => file,
+        Err(why) => return missing_file(&service, filename.as_str(), why),
+    };
+    let (content_type, content_disposition) = download_headers(&service, filename.as_str(), file.content_type.as_deref());
+    let contents = file.contents;
+
+    // an empty body may never be polled, so even with consume_after_flush there is nothing to consume after
+    let consume_later = service.config.consume_after_flush && !contents.is_empty();
+    if !consume_later {
+        match consume(&service, &storage, link.token.clone(), ip_address.clone(), now).await {
+            Ok(link) => after_download(&service, storage.clone(), link, now),
+            // someone else got there between get_link and here
+            Err(OnetimeError::AlreadyConsumed(link)) => return already_downloaded(&service, link),
+            Err(OnetimeError::NotFound(why)) => return HttpResponse::NotFound().body(format!("{}: {}", not_found_file, why)),
+            Err(OnetimeError::Failed(why)) => return HttpResponse::InternalServerError().body(format!("Consume link failed! {}", why)),
+        }
+    }
+
+    // https://github.com/actix/examples/blob/master/basics/src/main.rs
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        // https://actix.rs/actix-web/actix_web/dev/struct.HttpResponseBuilder.html#method.set_header
+        .set_header(header::CONTENT_DISPOSITION, content_disposition)
+        // so a browser can't decide an attachment is html after all
+        .set_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .set_header(CONTENT_SHA256_HEADER, sha256_hex(&contents));
+
+    if consume_later {
+        response.streaming(hold_permit(consume_after_body(service.clone(), storage, link.token, ip_address, now, contents), permit))
+    } else {
+        // sized, so the content length header is still sent, 0 for an empty file
+        let len = contents.len() as u64;
+        response.body(SizedStream::new(len, hold_permit(stream::once(future::ok(contents)).boxed_local(), permit)))
+    }
+}
+
+// the link's own redirect wins over POST_DOWNLOAD_REDIRECT, both were checked for http(s) when set
+fn post_download_redirect (service: &OnetimeDownloaderService, link: &OnetimeLink) -> Option<String> {
+    match &link.redirect_url {
+        Some(redirect_url) => Some(redirect_url.clone()),
+        None if service.config.post_download_redirect.is_empty() => None,
+        None => Some(service.config.post_download_redirect.clone()),
+    }
+}
+
+// the link is left as it was, MISSING_FILE_GONE says whether the file is reported gone for good or just not found
+fn missing_file (service: &OnetimeDownloaderService, filename: &str, why: MyError) -> HttpResponse {
+    let body = format!("Could not find contents for filename {}: {}", filename, why);
+    if service.config.missing_file_gone {
+        HttpResponse::Gone().body(body)
+    } else {
+        HttpResponse::NotFound().body(body)
+    }
+}
+
+fn wants_base64 (req: &HttpRequest) -> bool {
+    let accept = header_value(req, header::ACCEPT).unwrap_or_default();
+    base64_requested(req.query_string(), accept.as_str())
+}
+
+// ?format=base64, or Accept naming BASE64_MEDIA_TYPE itself
+//  not plain application/json, which api clients send for everything and would get a json body instead of the file
+fn base64_requested (query: &str, accept: &str) -> bool {
+    let format = web::Query::<DownloadQuery>::from_query(query)
+        .ok()
+        .and_then(|query| query.into_inner().format);
+    if let Some(format) = format {
+        return format == "base64";
+    }
+    accept.split(',')
+        .map(|offer| offer.split(';').next().unwrap_or("").trim())
+        .any(|offer| offer.eq_ignore_ascii_case(BASE64_MEDIA_TYPE))
+}
+
+// the file is read before consuming, so one too big for json leaves the link usable as a normal download
+async fn download_base64 (service: &OnetimeDownloaderService, storage: Box<dyn OnetimeStorage>, link: OnetimeLink, ip_address: String, now: i64) -> HttpResponse {
+    let file = match storage.get_file(link.filename.clone()).await {
+        Ok(file) => file,
+        Err(why) => return missing_file(service, link.filename.as_str(), why),
+    };
+    if file.contents.len() > service.config.max_len_base64_download {
+        return HttpResponse::NotAcceptable().body(format!(
+            "File too large for base64! {} > {}", file.contents.len(), service.config.max_len_base64_download,
+        ));
+    }
+
+    match consume(service, &storage, link.token.clone(), ip_address, now).await {
+        Ok(link) => after_download(service, storage, link, now),
+        Err(OnetimeError::AlreadyConsumed(link)) => return already_downloaded(service, link),
+        Err(OnetimeError::NotFound(why)) => return HttpResponse::NotFound().body(format!("Could not find file for link {}: {}", link.token, why)),
+        Err(OnetimeError::Failed(why)) => return HttpResponse::InternalServerError().body(format!("Consume link failed! {}", why)),
+    }
+
+    HttpResponse::Ok().json(Base64Download {
+        filename: if file.filename.is_empty() { service.config.default_download_name.clone() } else { file.filename },
+        content_type: file.content_type.clone().unwrap_or_else(|| content_types::OCTET_STREAM.to_string()),
+        data_base64: base64::encode(&file.contents),
+    })
+}
+
+// unauthenticated, for confirming what is deployed
+pub async fn version (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> web::Json<VersionInfo> {
+    web::Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        // https://doc.rust-lang.org/std/macro.option_env.html -- set at build time, see the Dockerfile
+        git_sha: option_env!("GIT_SHA").filter(|sha| !sha.is_empty()),
+        backend: service.storage.name(),
+        files_auth: !service.config.api_key_files.is_empty(),
+        links_auth: !service.config.api_key_links.is_empty(),
+        admin_auth: !service.config.api_key_admin.is_empty(),
+        tls: req.connection_info().scheme() == "https",
+    })
+}
+
+// for load balancers and orchestrators, only ready while the storage answers
+pub async fn readyz (service: web::Data<OnetimeDownloaderService>) -> HttpResponse {
+    if let Some(why) = service.storage.unconfigured() {
+        return service_unconfigured(why);
+    }
+    match service.storage.ping().await {
+        Ok(_) => HttpResponse::Ok().body("ready"),
+        Err(why) => HttpResponse::ServiceUnavailable().body(format!("Storage not reachable! {}", why)),
+    }
+}
+
+// a configuration problem, not a passing failure, so no retrying will help until the config is fixed
+pub fn service_unconfigured (why: MyError) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body(format!("Service not configured! {}", why))
+}
+
+pub async fn metrics (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<HttpResponse, HttpResponse> {
+    println!("metrics");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+
+    let metrics = DownloadMetrics {
+        downloads_in_flight: service.download_slots.in_flight(),
+        max_concurrent_downloads: service.download_slots.max(),
+        storage_pool: service.storage.pool_status(),
+        upload_bytes_total: service.uploads.bytes_total(),
+        uploads_in_flight: service.uploads.in_flight(),
+        download_ms: service.histograms.download_ms.snapshot(),
+        file_size_bytes: service.histograms.file_size_bytes.snapshot(),
+        consume_after_ms: service.histograms.consume_after_ms.snapshot(),
+        storage_ops: service.storage_timings.snapshot(),
+    };
+
+    let format = web::Query::<MetricsQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().format);
+    match format.as_deref() {
+        None | Some("json") => Ok(HttpResponse::Ok().json(metrics)),
+        Some("prometheus") => Ok(HttpResponse::Ok()
+            // https://prometheus.io/docs/instrumenting/exposition_formats/#basic-info
+            .content_type("text/plain; version=0.0.4")
+            .body(prometheus_metrics(&metrics))),
+        Some(format) => Err(HttpResponse::BadRequest().body(format!("Unknown metrics format '{}', json or prometheus", format))),
+    }
+}
+
+// for scraping, the pool status stays json only
+fn prometheus_metrics (metrics: &DownloadMetrics) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("# TYPE onetime_downloads_in_flight gauge\nonetime_downloads_in_flight {}\n", metrics.downloads_in_flight));
+    out.push_str(&format!("# TYPE onetime_uploads_in_flight gauge\nonetime_uploads_in_flight {}\n", metrics.uploads_in_flight));
+    out.push_str(&format!("# TYPE onetime_upload_bytes_total counter\nonetime_upload_bytes_total {}\n", metrics.upload_bytes_total));
+    metrics.download_ms.write_prometheus(&mut out, "onetime_download_ms", "Time to make a successful download response, in ms");
+    metrics.file_size_bytes.write_prometheus(&mut out, "onetime_file_size_bytes", "Size of each file stored, in bytes");
+    metrics.consume_after_ms.write_prometheus(&mut out, "onetime_consume_after_ms", "Time from a link being created to each download of it, in ms");
+    out.push_str("# TYPE onetime_storage_calls_total counter\n");
+    for (method, stats) in &metrics.storage_ops {
+        out.push_str(&format!("onetime_storage_calls_total{{method=\"{}\"}} {}\n", method, stats.calls));
+    }
+    out.push_str("# TYPE onetime_storage_slow_total counter\n");
+    for (method, stats) in &metrics.storage_ops {
+        out.push_str(&format!("onetime_storage_slow_total{{method=\"{}\"}} {}\n", method, stats.slow));
+    }
+    out.push_str("# TYPE onetime_storage_ms_total counter\n");
+    for (method, stats) in &metrics.storage_ops {
+        out.push_str(&format!("onetime_storage_ms_total{{method=\"{}\"}} {}\n", method, stats.total_ms));
     }
+    out
+}
+
+// everything needed is in the token itself, so there is no stored link to look up or revoke
+async fn download_signed (
+    service: &OnetimeDownloaderService,
+    token: String,
+    ip_address: String,
+    permit: DownloadPermit,
+) -> HttpResponse {
+    let (filename, expires_at) = match signed::verify(service.config.link_signing_secret.as_str(), token.as_str()) {
+        Some(signed) => signed,
+        None => return HttpResponse::NotFound().body("Invalid signed link"),
+    };
 
     let now = service.time_provider.unix_ts_ms();
-    if link.expires_at < now {
+    if now > expires_at + service.config.expiry_skew_ms {
         return HttpResponse::Gone().body("Expired");
     }
 
-    let filename = link.filename.clone();
-    match service.storage.mark_downloaded(link, ip_address, now).await {
-        Err(why) => return HttpResponse::InternalServerError().body(format!("Mark downloaded failed! {}", why)),
-        Ok(already_downloaded) => if already_downloaded {
-            return HttpResponse::Gone().body("Already downloaded race");
-        },
+    // before the signed link is recorded and consumed, same as for stored links
+    let file = match service.storage.get_file(filename.clone()).await {
+        Ok(file) => file,
+        Err(why) => return missing_file(service, filename.as_str(), why),
+    };
+
+    // the first download stores a row for the token, then it is consumed like any other link
+    if service.config.signed_links_consume {
+        let link = OnetimeLink {
+            token: token.clone(),
+            filename: filename.clone(),
+            note: None,
+            created_at: now,
+            expires_at: expires_at,
+            downloaded_at: None,
+            ip_address: None,
+            attempts: 0,
+            last_attempt_at: None,
+            totp_secret: None,
+            allowed_cidrs: Vec::new(),
+            allowed_countries: Vec::new(),
+            blocked_countries: Vec::new(),
+            deleted_at: None,
+            redirect_url: None,
+            downloads: 0,
+            max_downloads: 1,
+            revoked_at: None,
+        };
+        if let Err(why) = service.storage.import_links(vec![link], false).await {
+            return HttpResponse::InternalServerError().body(format!("Record signed link failed! {}", why));
+        }
+        match consume(service, &service.storage, token, ip_address, now).await {
+            Ok(link) => after_download(service, service.storage.clone(), link, now),
+            Err(OnetimeError::AlreadyConsumed(link)) => return already_downloaded(service, link),
+            Err(OnetimeError::NotFound(why)) => return HttpResponse::NotFound().body(format!("Could not find signed link: {}", why)),
+            Err(OnetimeError::Failed(why)) => return HttpResponse::InternalServerError().body(format!("Consume link failed! {}", why)),
+        }
     }
 
-    let not_found_contents = format!("Could not find contents for filename {}", filename);
-    let content_disposition = format!("inline; filename=\"{}\"", filename);
+    sized_download(service, file, permit)
+}
 
-    let contents = match service.storage.get_file(filename).await {
-        Ok(file) => file.contents,
-        Err(why) => return HttpResponse::NotFound().body(
-            format!("{}: {}", not_found_contents, why)
-        )
-    };
+// the whole file is in memory for every download anyway, so it is hashed there rather than stored
+// https://docs.rs/sha2/0.9.2/sha2/
+fn sha256_hex (contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
 
-    // https://github.com/actix/examples/blob/master/basics/src/main.rs
+// the whole file in one sized body, the permit goes back once it is sent
+fn sized_download (service: &OnetimeDownloaderService, file: OnetimeFile, permit: DownloadPermit) -> HttpResponse {
+    let (content_type, content_disposition) = download_headers(service, file.filename.as_str(), file.content_type.as_deref());
+    let contents = file.contents;
+    let len = contents.len() as u64;
     HttpResponse::Ok()
-        .content_type("application/octet-stream")
-        // https://actix.rs/actix-web/actix_web/dev/struct.HttpResponseBuilder.html#method.set_header
+        .content_type(content_type)
         .set_header(header::CONTENT_DISPOSITION, content_disposition)
-        .body(contents)
+        .set_header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .set_header(CONTENT_SHA256_HEADER, sha256_hex(&contents))
+        .body(SizedStream::new(len, hold_permit(stream::once(future::ok(contents)).boxed_local(), permit)))
+}
+
+// for admins to get a file back out without making a link, nothing about any link is read or changed
+// everything about the file but its contents, the etag changes whenever they do
+pub async fn get_file_info (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<web::Json<FileInfo>, HttpResponse> {
+    println!("get file info");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+
+    let filename = req.match_info().get("filename").unwrap().to_string();
+    match service.storage.get_file_info(filename.clone()).await {
+        Ok(info) => Ok(web::Json(info)),
+        Err(why) => Err(HttpResponse::NotFound().body(format!("Could not find file {}: {}", filename, why))),
+    }
+}
+
+pub async fn get_file_contents (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("get file contents");
+    check_api_key(&req, service.config.api_key_files.as_str())?;
+    check_rate_limit(&req, &service)?;
+
+    // still a download as far as the slots go, it holds the file in memory the same
+    let timeout_ms = service.config.download_slot_timeout_ms;
+    let permit = match service.download_slots.acquire(timeout_ms).await {
+        Some(permit) => permit,
+        None => return Err(HttpResponse::ServiceUnavailable()
+            .set_header(header::RETRY_AFTER, u64::max(1, (timeout_ms + 999) / 1000).to_string())
+            .body("Too many downloads in progress, try again shortly")),
+    };
+
+    let filename = req.match_info().get("filename").unwrap().to_string();
+    match service.storage.get_file(filename.clone()).await {
+        Ok(file) => Ok(sized_download(&service, file, permit)),
+        Err(why) => Err(HttpResponse::NotFound().body(format!("Could not find contents for filename {}: {}", filename, why))),
+    }
 }
 
 pub async fn delete_file (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> HttpResponse {
     println!("delete file");
-    if let Err(badreq) = check_rate_limit(&req) {
+    if let Err(badreq) = check_api_key(&req, service.config.api_key_admin.as_str()) {
+        return badreq
+    }
+    if let Err(badreq) = check_rate_limit(&req, &service) {
+        return badreq
+    }
+    if let Err(badreq) = check_admin_otp(&req, &service) {
         return badreq
     }
 
     let filename = req.match_info().get("filename").unwrap().to_string();
-    match service.storage.delete_file(filename).await {
-        Ok(_) => HttpResponse::Ok().body("File deleted"),
+    let with_links = web::Query::<DeleteFileQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().links)
+        .unwrap_or(false);
+    if let Some(if_match) = header_value(&req, header::IF_MATCH) {
+        if with_links {
+            return HttpResponse::BadRequest().body("If-Match can't be used with links=true");
+        }
+        return delete_file_if_match(&service, filename, if_match.as_str()).await;
+    }
+    if with_links {
+        return delete_file_with_links(&service, filename).await;
+    }
+    match service.storage.delete_file(filename.clone()).await {
+        Ok(existed) => deleted(&service, existed, format!("No file {} to delete", filename)),
         Err(why) => HttpResponse::InternalServerError().body(format!("Delete file failed! {}", why)),
     }
 }
 
+// 204 whether or not there was anything to delete, unless DELETE_MISSING_IS_404 says a missing one is a 404
+fn deleted (service: &OnetimeDownloaderService, existed: bool, missing: String) -> HttpResponse {
+    if !existed && service.config.delete_missing_is_404 {
+        HttpResponse::NotFound().body(missing)
+    } else {
+        HttpResponse::NoContent().finish()
+    }
+}
+
+// the file and every link to it, or none of them, so no link is left to a file that is gone
+//  with SOFT_DELETE the links are tombstoned rather than removed, so they can still be listed and restored
+async fn delete_file_with_links (service: &OnetimeDownloaderService, filename: String) -> HttpResponse {
+    let existed = match service.storage.files_exist(vec![filename.clone()]).await {
+        Ok(exist) => exist.get(&filename).copied().unwrap_or(false),
+        Err(why) => return HttpResponse::InternalServerError().body(format!("Delete file failed! {}", why)),
+    };
+    let links = match service.storage.list_links_for_file(filename.clone()).await {
+        Ok(links) => links,
+        Err(why) => return HttpResponse::InternalServerError().body(format!("Delete file failed! {}", why)),
+    };
+
+    let deleted_at = if service.config.soft_delete { Some(service.time_provider.unix_ts_ms()) } else { None };
+    match service.storage.transact(cascade_ops(filename.clone(), links, deleted_at)).await {
+        Ok(_) => deleted(service, existed, format!("No file {} to delete", filename)),
+        Err(why) => HttpResponse::InternalServerError().body(format!("Delete file failed! {}", why)),
+    }
+}
+
+fn cascade_ops (filename: String, links: Vec<OnetimeLink>, deleted_at: Option<i64>) -> Vec<StorageOp> {
+    let mut ops = vec![StorageOp::DeleteFile(filename)];
+    ops.extend(links.into_iter().map(|link| match deleted_at {
+        Some(deleted_at) => StorageOp::SoftDeleteLink(link.token, deleted_at),
+        None => StorageOp::DeleteLink(link.token),
+    }));
+    ops
+}
+
+// only deletes the file the client last saw, a 412 when it has changed since or is gone
+//  "*" is any version, as long as there is one https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/If-Match
+async fn delete_file_if_match (service: &OnetimeDownloaderService, filename: String, if_match: &str) -> HttpResponse {
+    let mismatch = || HttpResponse::PreconditionFailed().body(format!("File {} does not match {}", filename, if_match));
+    if if_match.trim() == "*" {
+        return match service.storage.delete_file(filename.clone()).await {
+            Ok(true) => HttpResponse::NoContent().finish(),
+            Ok(false) => mismatch(),
+            Err(why) => HttpResponse::InternalServerError().body(format!("Delete file failed! {}", why)),
+        };
+    }
+
+    // a list is any of them, each tried in turn until one is the version stored
+    for updated_at in if_match.split(',').filter_map(FileInfo::etag_updated_at) {
+        match service.storage.delete_file_if_unchanged(filename.clone(), updated_at).await {
+            Ok(true) => return HttpResponse::NoContent().finish(),
+            Ok(false) => continue,
+            Err(why) => return HttpResponse::InternalServerError().body(format!("Delete file failed! {}", why)),
+        }
+    }
+    mismatch()
+}
+
 pub async fn delete_link (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> HttpResponse {
     println!("delete link");
-    if let Err(badreq) = check_rate_limit(&req) {
+    if let Err(badreq) = check_api_key(&req, service.config.api_key_admin.as_str()) {
+        return badreq
+    }
+    if let Err(badreq) = check_rate_limit(&req, &service) {
+        return badreq
+    }
+    if let Err(badreq) = check_admin_otp(&req, &service) {
         return badreq
     }
 
-    let token = req.match_info().get("token").unwrap().to_string();
-    match service.storage.delete_link(token).await {
-        Ok(_) => HttpResponse::Ok().body("Link deleted"),
+    let token = match path_token(&req) {
+        Ok(token) => token,
+        Err(badreq) => return badreq,
+    };
+    let result = if service.config.soft_delete {
+        let now = service.time_provider.unix_ts_ms();
+        service.storage.soft_delete_link(token, now).await
+    } else {
+        service.storage.delete_link(token).await
+    };
+
+    // soft deleting one that is already tombstoned counts as missing too
+    match result {
+        Ok(existed) => deleted(&service, existed, "No link to delete".to_string()),
         Err(why) => HttpResponse::InternalServerError().body(format!("Delete link failed! {}", why)),
     }
 }
 
-pub fn not_found () -> HttpResponse {
-    HttpResponse::NotFound().body("404 DNE")
+// for a link that may have leaked: it can never be downloaded again, but stays around with its history
+//  unlike a delete, get_link and the status show it as revoked rather than gone
+pub async fn revoke_link (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<web::Json<TokenStatus>, HttpResponse> {
+    println!("revoke link");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+    check_admin_otp(&req, &service)?;
+
+    let token = path_token(&req)?;
+    let now = service.time_provider.unix_ts_ms();
+    let revoked = service.storage.revoke_link(token.clone(), now).await
+        .map_err(|why| HttpResponse::InternalServerError().body(format!("Revoke link failed! {}", why)))?;
+    let status = service.storage.link_status(token.clone()).await
+        .map_err(|why| HttpResponse::InternalServerError().body(format!("Link status failed! {}", why)))?;
+    match status {
+        _ if revoked => Ok(web::Json(TokenStatus { token: token, status: status })),
+        LinkStatus::Missing => Err(HttpResponse::NotFound().body("Link not found")),
+        // already used up or revoked, either way there is nothing left to take away
+        _ => Err(HttpResponse::Conflict().json(TokenStatus { token: token, status: status })),
+    }
+}
+
+pub async fn list_deleted_links (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<Vec<OnetimeLink>>, HttpResponse> {
+    println!("list deleted links");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+
+    // older tombstones may still be around until purged, but can no longer be restored
+    let deleted_after = service.time_provider.unix_ts_ms() - service.config.tombstone_retention_ms;
+    match service.storage.list_deleted_links().await {
+        Ok(links) => Ok(web::Json(
+            links.into_iter().filter(|link| link.deleted_at.unwrap_or_default() >= deleted_after).collect()
+        )),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("List deleted links failed! {}", why))),
+    }
+}
+
+// for cleanup dashboards, links that were handed out but never used before they ran out
+pub async fn list_expired_unused_links (
+    req: HttpRequest,
+    service: web::Data<OnetimeDownloaderService>,
+) -> Result<web::Json<ExpiredUnusedLinks>, HttpResponse> {
+    println!("list expired unused links");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+
+    match service.storage.list_expired_unused_links(service.time_provider.unix_ts_ms()).await {
+        Ok(links) => {
+            let mut files = BTreeMap::new();
+            for link in &links {
+                *files.entry(link.filename.clone()).or_insert(0) += 1;
+            }
+            Ok(web::Json(ExpiredUnusedLinks {
+                count: links.len(),
+                files: files,
+                links: links,
+            }))
+        }
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("List expired unused links failed! {}", why))),
+    }
+}
+
+pub async fn restore_link (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("restore link");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+    check_admin_otp(&req, &service)?;
+
+    let token = path_token(&req)?;
+    let deleted_after = service.time_provider.unix_ts_ms() - service.config.tombstone_retention_ms;
+    match service.storage.restore_link(token, deleted_after).await {
+        Ok(true) => Ok(HttpResponse::Ok().body("Link restored")),
+        Ok(false) => Ok(HttpResponse::NotFound().body("No deleted link to restore within retention!")),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("Restore link failed! {}", why))),
+    }
+}
+
+pub async fn purge_deleted_links (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("purge deleted links");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+    check_admin_otp(&req, &service)?;
+
+    let deleted_before = service.time_provider.unix_ts_ms() - service.config.tombstone_retention_ms;
+    match service.storage.purge_deleted_links(deleted_before).await {
+        Ok(purged) => Ok(HttpResponse::Ok().body(format!("Purged {} links", purged))),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("Purge deleted links failed! {}", why))),
+    }
+}
+
+pub async fn purge_expired_files (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> Result<HttpResponse, HttpResponse> {
+    println!("purge expired files");
+    check_api_key(&req, service.config.api_key_admin.as_str())?;
+    check_admin_otp(&req, &service)?;
+
+    match service.storage.purge_expired_files(service.time_provider.unix_ts_ms()).await {
+        Ok(purged) => Ok(HttpResponse::Ok().body(format!("Purged {} files", purged))),
+        Err(why) => Err(HttpResponse::InternalServerError().body(format!("Purge expired files failed! {}", why))),
+    }
+}
+
+pub fn allowed_methods (allow: &'static str) -> HttpResponse {
+    HttpResponse::NoContent()
+        .set_header(header::ALLOW, allow)
+        .finish()
+}
+
+pub fn method_not_allowed (allow: &'static str) -> HttpResponse {
+    HttpResponse::MethodNotAllowed()
+        .set_header(header::ALLOW, allow)
+        .finish()
+}
+
+pub fn not_found (req: HttpRequest, service: web::Data<OnetimeDownloaderService>) -> HttpResponse {
+    // preflight requests should get an empty answer (that cors headers can be added to), not a 404 page
+    if req.method() == Method::OPTIONS {
+        return HttpResponse::NoContent().finish();
+    }
+
+    HttpResponse::NotFound()
+        .content_type(service.config.not_found_content_type.as_str())
+        .body(service.config.not_found_body.clone())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::dev::{Body, ResponseBody};
+    use actix_web::test::TestRequest;
+    use crate::events::LinkEvents;
+    use crate::histograms::UsageHistograms;
+    use crate::inflight::InFlightTokens;
+    use crate::nonces::NonceStore;
+    use crate::rate_limit::RateLimiter;
+    use crate::slots::DownloadSlots;
+    use crate::storage::invalid;
+    use crate::storage::timed::StorageTimings;
+    use crate::uploads::UploadMetrics;
+
+    // configured as from the environment, but never with a storage behind it, so anything reaching one fails
+    fn service () -> OnetimeDownloaderService {
+        let mut service = crate::build_service(DownloadSlots::new(0), RateLimiter::new(0, 0), NonceStore::new(0), UploadMetrics::new(), InFlightTokens::new(false), InFlightTokens::new(true), LinkEvents::new(), UsageHistograms::none(), StorageTimings::new());
+        service.storage = Box::new(invalid::Storage { error: "No storage in tests".to_string() });
+        service
+    }
+
+    // from a client address, the rate limit refuses requests without one
+    fn request (uri: &str) -> TestRequest {
+        TestRequest::with_uri(uri).peer_addr("192.0.2.1:50000".parse().unwrap())
+    }
+
+    fn no_parts (req: &HttpRequest) -> Multipart {
+        Multipart::new(req.headers(), stream::empty::<Result<Bytes, actix_web::error::PayloadError>>())
+    }
+
+    const BOUNDARY: &'static str = "TESTBOUNDARY";
+
+    // an upload as files api clients send it, the body's parts separated by BOUNDARY
+    fn upload_request (uri: &str) -> HttpRequest {
+        request(uri)
+            .header(API_KEY_HEADER, "files-key")
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+            .to_http_request()
+    }
+
+    fn parts (req: &HttpRequest, body: Vec<u8>) -> Multipart {
+        Multipart::new(req.headers(), stream::once(future::ok::<_, actix_web::error::PayloadError>(Bytes::from(body))))
+    }
+
+    fn part (disposition: &str, value: &[u8]) -> Vec<u8> {
+        let mut part = format!("--{}\r\nContent-Disposition: form-data; {}\r\n\r\n", BOUNDARY, disposition).into_bytes();
+        part.extend_from_slice(value);
+        part.extend_from_slice(b"\r\n");
+        part
+    }
+
+    fn body (parts: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut body = parts.concat();
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    fn files_service () -> OnetimeDownloaderService {
+        let mut service = service();
+        service.config.api_key_files = "files-key".to_string();
+        service
+    }
+
+    fn body_text (response: &HttpResponse) -> String {
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => String::new(),
+        }
+    }
+
+    fn proxies (cidrs: &[&str]) -> Vec<IpNet> {
+        cidrs.iter().map(|cidr| parse_cidr(cidr).unwrap()).collect()
+    }
+
+    fn ip (address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_ignored_from_untrusted_peer () {
+        let client = forwarded_client(ip("203.0.113.9"), vec!["10.0.0.1"], &proxies(&["10.0.0.0/8"]));
+        assert_eq!(client, ip("203.0.113.9"));
+        let client = forwarded_client(ip("203.0.113.9"), vec!["198.51.100.1"], &Vec::new());
+        assert_eq!(client, ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn forwarded_for_from_trusted_peer () {
+        let client = forwarded_client(ip("10.0.0.2"), vec!["198.51.100.1"], &proxies(&["10.0.0.0/8"]));
+        assert_eq!(client, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn forwarded_for_skips_spoofed_entries () {
+        // the client sent its own header, the proxy appended the real address
+        let client = forwarded_client(ip("10.0.0.2"), vec!["192.0.2.1", " 198.51.100.1"], &proxies(&["10.0.0.2"]));
+        assert_eq!(client, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn forwarded_for_through_proxy_chain () {
+        let client = forwarded_client(ip("10.0.0.2"), vec!["198.51.100.1", "10.0.0.3"], &proxies(&["10.0.0.0/8"]));
+        assert_eq!(client, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn forwarded_for_with_port_or_garbage () {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        assert_eq!(forwarded_client(ip("10.0.0.2"), vec!["198.51.100.1:4321"], &trusted), ip("198.51.100.1"));
+        assert_eq!(forwarded_client(ip("10.0.0.2"), vec!["unknown"], &trusted), ip("10.0.0.2"));
+        assert_eq!(forwarded_client(ip("10.0.0.2"), Vec::new(), &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn rate_limited_headers () {
+        let state = RateLimitState { allowed: false, limit: 10, remaining: 0, reset_at: 1600000030500 };
+        let response = rate_limited(&state, 1600000000000);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let headers = response.headers();
+        assert_eq!(headers.get(RATE_LIMIT_LIMIT_HEADER).unwrap(), "10");
+        assert_eq!(headers.get(RATE_LIMIT_REMAINING_HEADER).unwrap(), "0");
+        // both rounded up, a client waiting exactly that long is never early
+        assert_eq!(headers.get(RATE_LIMIT_RESET_HEADER).unwrap(), "1600000031");
+        assert_eq!(headers.get(header::RETRY_AFTER).unwrap(), "31");
+    }
+
+    #[test]
+    fn allowed_cidrs_match_client () {
+        let allowed = vec!["198.51.100.0/24".to_string(), "2001:db8::1".to_string()];
+        assert!(check_allowed_cidrs(&allowed, ip("198.51.100.7")));
+        assert!(check_allowed_cidrs(&allowed, ip("2001:db8::1")));
+        assert!(!check_allowed_cidrs(&allowed, ip("192.0.2.1")));
+        assert!(check_allowed_cidrs(&Vec::new(), ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn consume_retry_delay_doubles_up_to_cap () {
+        assert_eq!(consume_retry_delay_ms(100, 0), 100);
+        assert_eq!(consume_retry_delay_ms(100, 3), 800);
+        assert_eq!(consume_retry_delay_ms(100, 10), MAX_CONSUME_RETRY_DELAY_MS);
+        assert_eq!(consume_retry_delay_ms(100, 200), MAX_CONSUME_RETRY_DELAY_MS);
+        assert_eq!(consume_retry_delay_ms(u64::MAX, 2), u64::MAX);
+        assert_eq!(consume_retry_delay_ms(0, 5), 0);
+    }
+
+    #[test]
+    fn base64_only_when_asked_by_name () {
+        assert!(base64_requested("format=base64", ""));
+        assert!(!base64_requested("format=raw", BASE64_MEDIA_TYPE));
+        assert!(base64_requested("", "application/vnd.onetime-downloader.base64+json"));
+        assert!(base64_requested("", "text/html, Application/Vnd.Onetime-Downloader.Base64+JSON;q=0.9"));
+        assert!(!base64_requested("", "application/json"));
+        assert!(!base64_requested("", "application/json, text/plain, */*"));
+        assert!(!base64_requested("", ""));
+    }
+
+    #[test]
+    fn note_template_expanded () {
+        let note = expand_note_template("{filename} made {created_at}, gone {expires_at}", "report.pdf", 1600000000000, 1600086400000);
+        assert_eq!(note, "report.pdf made 1600000000000, gone 1600086400000");
+    }
+
+    #[test]
+    fn note_template_leaves_unknown_placeholders () {
+        assert_eq!(expand_note_template("{filename} for {who}", "a.txt", 0, 0), "a.txt for {who}");
+        assert_eq!(expand_note_template("no placeholders", "a.txt", 0, 0), "no placeholders");
+    }
+
+    const OFFERED: &[&str] = &["text/plain", "text/uri-list", "application/json"];
+
+    #[test]
+    fn negotiate_by_q_value () {
+        assert_eq!(negotiate("application/json", OFFERED), "application/json");
+        assert_eq!(negotiate("text/uri-list;q=0.5, application/json;q=0.9", OFFERED), "application/json");
+        assert_eq!(negotiate("application/json;q=0.1, text/uri-list", OFFERED), "text/uri-list");
+        // q=0 is never acceptable
+        assert_eq!(negotiate("application/json;q=0, text/uri-list;q=0.2", OFFERED), "text/uri-list");
+    }
+
+    #[test]
+    fn negotiate_ties_go_to_first_listed () {
+        assert_eq!(negotiate("text/uri-list, application/json", OFFERED), "text/uri-list");
+    }
+
+    #[test]
+    fn negotiate_wildcards () {
+        assert_eq!(negotiate("*/*", OFFERED), "text/plain");
+        assert_eq!(negotiate("application/*", OFFERED), "application/json");
+        assert_eq!(negotiate("text/*;q=0.3, application/json;q=0.8", OFFERED), "application/json");
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_first_offered () {
+        assert_eq!(negotiate("", OFFERED), "text/plain");
+        assert_eq!(negotiate("image/png", OFFERED), "text/plain");
+        assert_eq!(negotiate("application/json;q=0", OFFERED), "text/plain");
+    }
+
+    #[test]
+    fn too_long_over_max_only () {
+        assert_eq!(too_long("Note", "abc", 3), Ok(true));
+        assert_eq!(too_long("Note", "", 0), Ok(true));
+        assert_eq!(too_long("Note", "abcd", 3), Err("Note too long! 4 > 3".to_string()));
+        // counts bytes, not chars
+        assert!(too_long("Filename", "é", 1).is_err());
+    }
+
+    #[test]
+    fn check_max_len_is_bad_request () {
+        assert!(check_max_len("Filename", "abc", 3).is_ok());
+        let response = check_max_len("Filename", "abcd", 3).unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn not_found_sends_configured_body () {
+        let mut service = service();
+        service.config.not_found_body = r#"{"error": "not found"}"#.to_string();
+        service.config.not_found_content_type = "application/json".to_string();
+        let response = not_found(TestRequest::with_uri("/nowhere").to_http_request(), web::Data::new(service));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(body_text(&response), r#"{"error": "not found"}"#);
+    }
+
+    #[test]
+    fn not_found_preflight_is_empty () {
+        let response = not_found(TestRequest::with_uri("/nowhere").method(Method::OPTIONS).to_http_request(), web::Data::new(service()));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(body_text(&response), "");
+    }
+
+    #[actix_rt::test]
+    async fn immutable_files_refuse_updates () {
+        let mut service = service();
+        service.config.immutable_files = true;
+        service.config.api_key_files = "files-key".to_string();
+        let req = request("/api/files/a.txt").param("filename", "a.txt").header(API_KEY_HEADER, "files-key").to_http_request();
+        let response = update_file(req.clone(), no_parts(&req), web::Data::new(service)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn immutable_files_refuse_bulk_overwrite () {
+        let mut service = service();
+        service.config.immutable_files = true;
+        service.config.api_key_files = "files-key".to_string();
+        let req = request("/api/files/bulk?overwrite=true").header(API_KEY_HEADER, "files-key").to_http_request();
+        let response = match add_files(req.clone(), no_parts(&req), web::Data::new(service)).await {
+            Err(response) => response,
+            Ok(_) => panic!("overwrite allowed"),
+        };
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn filename_field_must_be_utf8 () {
+        let req = upload_request("/api/files");
+        let payload = parts(&req, body(vec![
+            part(r#"name="filename""#, b"\xff\xfe.txt"),
+            part(r#"name="file"; filename="a.txt""#, b"contents"),
+        ]));
+        let response = add_file(req, payload, web::Data::new(files_service())).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(&response), "filename not valid UTF-8");
+    }
+
+    #[test]
+    fn unnamed_download_gets_default_name () {
+        let mut service = service();
+        service.config.default_download_name = "download".to_string();
+        let (_, disposition) = download_headers(&service, "", None);
+        assert_eq!(disposition, "attachment; filename=\"download\"");
+        let (_, disposition) = download_headers(&service, "report.bin", None);
+        assert_eq!(disposition, "attachment; filename=\"report.bin\"");
+    }
+
+    #[test]
+    fn create_link_from_json () {
+        let req = request("/api/links").header(header::CONTENT_TYPE, "application/json").to_http_request();
+        let create = parse_create_link(&req, br#"{"filename": "a.txt", "allowed_cidrs": ["10.0.0.0/8"]}"#).unwrap();
+        assert_eq!(create.filename, "a.txt");
+        assert_eq!(create.allowed_cidrs, Some(vec!["10.0.0.0/8".to_string()]));
+    }
+
+    #[test]
+    fn create_link_from_form () {
+        let req = request("/api/links").header(header::CONTENT_TYPE, "application/x-www-form-urlencoded; charset=utf-8").to_http_request();
+        let create = parse_create_link(&req, b"filename=a%20b.txt&note=hi&expires_at=1600000000000").unwrap();
+        assert_eq!(create.filename, "a b.txt");
+        assert_eq!(create.note, Some("hi".to_string()));
+        assert_eq!(create.expires_at, Some(1600000000000));
+    }
+
+    #[test]
+    fn create_link_without_type_is_json () {
+        let req = request("/api/links").to_http_request();
+        assert!(parse_create_link(&req, br#"{"filename": "a.txt"}"#).is_ok());
+        let response = parse_create_link(&req, b"filename=a.txt").err().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn create (json: &str) -> CreateLink {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn max_downloads_defaulted_and_bounded () {
+        let mut service = service();
+        service.config.default_max_downloads = 3;
+        service.config.max_max_downloads = 10;
+        let link = new_link(&service, &create(r#"{"filename": "a.txt"}"#), 0).unwrap();
+        assert_eq!(link.max_downloads, 3);
+        let link = new_link(&service, &create(r#"{"filename": "a.txt", "max_downloads": 10}"#), 0).unwrap();
+        assert_eq!(link.max_downloads, 10);
+        assert!(new_link(&service, &create(r#"{"filename": "a.txt", "max_downloads": 11}"#), 0).is_err());
+        assert!(new_link(&service, &create(r#"{"filename": "a.txt", "max_downloads": 0}"#), 0).is_err());
+    }
+
+    #[test]
+    fn missing_file_not_found_or_gone () {
+        let mut service = service();
+        service.config.missing_file_gone = false;
+        assert_eq!(missing_file(&service, "a.txt", "No file".to_string()).status(), StatusCode::NOT_FOUND);
+        service.config.missing_file_gone = true;
+        let response = missing_file(&service, "a.txt", "No file".to_string());
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert_eq!(body_text(&response), "Could not find contents for filename a.txt: No file");
+    }
+
+    #[test]
+    fn sha256_hex_is_lowercase_hex () {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn upload_content_type_given_or_default () {
+        let mut service = service();
+        service.config.default_content_type = "application/octet-stream".to_string();
+        service.config.sniff_content_type = false;
+        assert_eq!(resolve_content_type(&service, "a.pdf", Some("image/png".to_string())), "image/png");
+        assert_eq!(resolve_content_type(&service, "a.pdf", None), "application/octet-stream");
+        service.config.sniff_content_type = true;
+        assert_eq!(resolve_content_type(&service, "a.pdf", None), "application/pdf");
+    }
+
+    #[test]
+    fn download_headers_use_stored_type () {
+        let mut service = service();
+        service.config.inline_content_types = vec!["image/*".to_string(), "application/pdf".to_string()];
+        assert_eq!(download_headers(&service, "a.bin", Some("image/png")), ("image/png".to_string(), "inline; filename=\"a.bin\"".to_string()));
+        // never inline, whatever the name says
+        assert_eq!(download_headers(&service, "a.png", Some("text/html")), (content_types::OCTET_STREAM.to_string(), "attachment; filename=\"a.png\"".to_string()));
+        // stored before types were, so by extension
+        assert_eq!(download_headers(&service, "a.pdf", None), ("application/pdf".to_string(), "inline; filename=\"a.pdf\"".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn add_file_lists_fields_without_file_part () {
+        let req = upload_request("/api/files");
+        let payload = parts(&req, body(vec![
+            part(r#"name="filename""#, b"a.txt"),
+            part(r#"name="note""#, b"hi"),
+        ]));
+        let response = add_file(req, payload, web::Data::new(files_service())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(&response), "No file part provided, one part needs a filename in its Content-Disposition! Fields: filename, note");
+
+        let req = upload_request("/api/files");
+        let response = add_file(req.clone(), parts(&req, body(Vec::new())), web::Data::new(files_service())).await.unwrap();
+        assert_eq!(body_text(&response), "No file part provided, one part needs a filename in its Content-Disposition! Fields: none");
+    }
+
+    #[actix_rt::test]
+    async fn add_file_takes_one_file_part_under_any_name () {
+        let req = upload_request("/api/files");
+        let payload = parts(&req, body(vec![
+            part(r#"name="upload"; filename="a.txt""#, b"one"),
+            part(r#"name="file"; filename="b.txt""#, b"two"),
+        ]));
+        let response = add_file(req, payload, web::Data::new(files_service())).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(&response), "More than one file part, use /api/files/bulk for several! Fields: upload (file), file (file)");
+    }
+
+    #[test]
+    fn too_small_file_messages () {
+        assert_eq!(too_small_file(0, 1), "Empty file contents!");
+        assert_eq!(too_small_file(0, 10), "Empty file contents!");
+        assert_eq!(too_small_file(3, 10), "File too small! 3 < 10");
+    }
+
+    #[test]
+    fn min_len_file_is_inclusive () {
+        let mut service = service();
+        service.config.min_len_file = 4;
+        assert!(check_min_len_file(&service, 4).is_ok());
+        assert!(check_min_len_file(&service, 5).is_ok());
+        let response = check_min_len_file(&service, 3).unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(&response), "File too small! 3 < 4");
+        service.config.min_len_file = 0;
+        assert!(check_min_len_file(&service, 0).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn empty_file_part_refused () {
+        let mut service = files_service();
+        service.config.min_len_file = 1;
+        let req = upload_request("/api/files");
+        let payload = parts(&req, body(vec![
+            part(r#"name="file"; filename="a.txt""#, b""),
+        ]));
+        let response = add_file(req, payload, web::Data::new(service)).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(&response), "Empty file contents!");
+    }
+
+    fn file_link (token: &str, downloaded_at: Option<i64>) -> OnetimeLink {
+        let mut link: OnetimeLink = serde_json::from_str(r#"{"token":"","filename":"a.txt","created_at":0,"expires_at":0}"#).unwrap();
+        link.token = token.to_string();
+        link.downloaded_at = downloaded_at;
+        link
+    }
+
+    #[test]
+    fn burned_file_kept_for_other_unused_links () {
+        assert!(!has_other_unused_link(&[], "abc"));
+        assert!(!has_other_unused_link(&[file_link("abc", Some(1))], "abc"));
+        assert!(!has_other_unused_link(&[file_link("abc", None), file_link("def", Some(1))], "abc"));
+        assert!(has_other_unused_link(&[file_link("abc", Some(1)), file_link("def", None)], "abc"));
+    }
+
+    #[actix_rt::test]
+    async fn burn_stops_when_link_delete_fails () {
+        let timings = StorageTimings::new();
+        let storage = Box::new(crate::storage::timed::Storage {
+            inner: Box::new(invalid::Storage { error: "No storage in tests".to_string() }),
+            timings: timings.clone(),
+            slow_ms: 0,
+        });
+        burn_after_reading(storage, "abc".to_string(), "a.txt".to_string(), 0, true).await;
+        let ops = timings.snapshot();
+        assert_eq!(ops["delete_link"].calls, 1);
+        // the link is still there, so its file can't be decided on
+        assert!(!ops.contains_key("list_links_for_file"));
+        assert!(!ops.contains_key("delete_file"));
+    }
+
+    #[test]
+    fn download_url_under_path_prefix () {
+        let mut service = service();
+        let req = request("/api/links").header(header::HOST, "files.example.com").to_http_request();
+        assert_eq!(download_url(&req, &service, "abc"), "http://files.example.com/download/abc");
+        service.config.path_prefix = "/onetime".to_string();
+        assert_eq!(download_url(&req, &service, "abc"), "http://files.example.com/onetime/download/abc");
+    }
+
+    async fn raw_upload (service: OnetimeDownloaderService, req: TestRequest) -> Result<HttpResponse, HttpResponse> {
+        let (req, payload) = req
+            .param("filename", "a.txt")
+            .header(API_KEY_HEADER, "files-key")
+            .to_http_parts();
+        upload_file(req, web::Payload(payload), web::Data::new(service)).await
+    }
+
+    #[actix_rt::test]
+    async fn raw_upload_declared_too_big () {
+        let mut service = files_service();
+        service.config.max_len_raw_file = 10;
+        let req = request("/api/files/a.txt").method(Method::PUT).header(header::CONTENT_LENGTH, "11");
+        let response = raw_upload(service, req).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body_text(&response), "Body too big! 11 > 10");
+    }
+
+    #[actix_rt::test]
+    async fn raw_upload_streamed_too_big () {
+        // buffered for immutable files, so nothing reaches the storage before the body is counted
+        let mut service = files_service();
+        service.config.immutable_files = true;
+        service.config.max_len_file = 100;
+        service.config.max_len_raw_file = 10;
+        let req = request("/api/files/a.txt").method(Method::PUT).set_payload(Bytes::from_static(b"0123456789a"));
+        let response = raw_upload(service, req).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body_text(&response), "Body too big! 11 > 10");
+    }
+
+    #[actix_rt::test]
+    async fn invalid_storage_is_unconfigured () {
+        let response = readyz(web::Data::new(service())).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_text(&response), "Service not configured! No storage in tests");
+    }
+
+    #[test]
+    fn unconfigured_is_service_unavailable () {
+        let response = service_unconfigured("No ONETIME_PROVIDER".to_string());
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body_text(&response), "Service not configured! No ONETIME_PROVIDER");
+    }
+
+    #[test]
+    fn delete_always_204_by_default () {
+        let mut service = service();
+        service.config.delete_missing_is_404 = false;
+        assert_eq!(deleted(&service, true, "No link to delete".to_string()).status(), StatusCode::NO_CONTENT);
+        assert_eq!(deleted(&service, false, "No link to delete".to_string()).status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn delete_missing_404_when_asked () {
+        let mut service = service();
+        service.config.delete_missing_is_404 = true;
+        assert_eq!(deleted(&service, true, "No link to delete".to_string()).status(), StatusCode::NO_CONTENT);
+        let response = deleted(&service, false, "No link to delete".to_string());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_text(&response), "No link to delete");
+    }
+
+    #[test]
+    fn unset_api_key_never_matches () {
+        let req = request("/api/links/abc/record").header(API_KEY_HEADER, "").to_http_request();
+        let response = check_api_key(&req, "").unwrap_err();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(check_api_key(&request("/api/links/abc/record").to_http_request(), "").is_err());
+        let req = request("/api/links/abc/record").header(API_KEY_HEADER, "admin-key").to_http_request();
+        assert!(check_api_key(&req, "admin-key").is_ok());
+    }
+
+    // the usage totals after a quota claim, as dynamodb answers an ADD
+    #[cfg(feature = "dynamodb")]
+    fn usage (count: i64, bytes: i64) -> rusoto_mock::MockRequestDispatcher {
+        rusoto_mock::MockRequestDispatcher::with_status(200)
+            .with_body(format!(r#"{{"Attributes": {{"Count": {{"N": "{}"}}, "Bytes": {{"N": "{}"}}}}}}"#, count, bytes).as_str())
+    }
+
+    #[cfg(feature = "dynamodb")]
+    async fn bulk_over_quota (service: OnetimeDownloaderService) -> HttpResponse {
+        let req = upload_request("/api/files/bulk");
+        let payload = parts(&req, body(vec![
+            part(r#"name="a"; filename="a.txt""#, b"aa"),
+            part(r#"name="b"; filename="b.txt""#, b"bb"),
+            part(r#"name="c"; filename="c.txt""#, b"cc"),
+        ]));
+        match add_files(req, payload, web::Data::new(service)).await {
+            Err(response) => response,
+            Ok(_) => panic!("over quota allowed"),
+        }
+    }
+
+    #[cfg(feature = "dynamodb")]
+    #[actix_rt::test]
+    async fn bulk_files_count_against_quota () {
+        let mut service = files_service();
+        service.config.quota_max_files = 3;
+        // one already used, the whole batch of three is claimed at once, then taken back
+        service.storage = Box::new(crate::storage::dynamodb::Storage::mocked(vec![usage(4, 6), usage(1, 0)]));
+        let response = bulk_over_quota(service).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let exceeded: serde_json::Value = serde_json::from_str(body_text(&response).as_str()).unwrap();
+        assert_eq!(exceeded["used"], 1);
+        assert_eq!(exceeded["max"], 3);
+    }
+
+    #[cfg(feature = "dynamodb")]
+    #[actix_rt::test]
+    async fn bulk_files_bytes_against_quota () {
+        let mut service = files_service();
+        service.config.quota_max_bytes = 5;
+        service.storage = Box::new(crate::storage::dynamodb::Storage::mocked(vec![usage(3, 6), usage(0, 0)]));
+        let response = bulk_over_quota(service).await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        let exceeded: serde_json::Value = serde_json::from_str(body_text(&response).as_str()).unwrap();
+        assert_eq!(exceeded["used"], 0);
+        assert_eq!(exceeded["max"], 5);
+    }
+
+    #[actix_rt::test]
+    async fn download_in_progress_is_retry_not_gone () {
+        let timings = StorageTimings::new();
+        let mut service = service();
+        service.in_flight = InFlightTokens::new(true);
+        service.storage = Box::new(crate::storage::timed::Storage {
+            inner: Box::new(invalid::Storage { error: "No storage in tests".to_string() }),
+            timings: timings.clone(),
+            slow_ms: 0,
+        });
+        let _held = service.in_flight.claim("abc").unwrap();
+        let req = request("/download/abc").param("token", "abc").to_http_request();
+        let response = download(req, web::Data::new(service), false).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        // still counted as an attempt, but nothing more reached the storage
+        let ops = timings.snapshot();
+        assert_eq!(ops["record_attempt"].calls, 1);
+        assert!(!ops.contains_key("get_link"));
+    }
+
+    #[test]
+    fn cascade_tombstones_links_with_soft_delete () {
+        let links = || vec![file_link("abc", None), file_link("def", Some(1))];
+        let ops: Vec<String> = cascade_ops("a.txt".to_string(), links(), None).iter().map(StorageOp::name).collect();
+        assert_eq!(ops, vec!["delete file a.txt", "delete link abc", "delete link def"]);
+        let ops: Vec<String> = cascade_ops("a.txt".to_string(), links(), Some(1600000000000)).iter().map(StorageOp::name).collect();
+        assert_eq!(ops, vec!["delete file a.txt", "soft delete link abc", "soft delete link def"]);
+    }
 }
@@ -0,0 +1,126 @@
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+
+
+// upper bounds, in ms or bytes, values over the last one only show up in the count and sum
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buckets(pub Vec<u64>);
+
+// "10,100,1000", any order, repeats dropped
+impl FromStr for Buckets {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        let mut bounds = Vec::new();
+        for item in s.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+            bounds.push(item.parse::<u64>().map_err(|why| format!("Bucket '{}' is not a number! {}", item, why))?);
+        }
+        if bounds.is_empty() {
+            return Err("No buckets!".to_string());
+        }
+        bounds.sort();
+        bounds.dedup();
+        Ok(Buckets(bounds))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Bucket {
+    pub le: u64,
+    // cumulative, like prometheus: everything up to and including le
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<Bucket>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+// shared by every worker like the upload metrics, so it covers the whole server since it started
+//  each observation touches one bucket, the cumulative counts are only added up when read
+#[derive(Clone)]
+pub struct Histogram {
+    bounds: Arc<Vec<u64>>,
+    counts: Arc<Vec<AtomicU64>>,
+    count: Arc<AtomicU64>,
+    sum: Arc<AtomicU64>,
+}
+
+impl Histogram {
+    pub fn new (buckets: &Buckets) -> Self {
+        Self {
+            bounds: Arc::new(buckets.0.clone()),
+            counts: Arc::new(buckets.0.iter().map(|_| AtomicU64::new(0)).collect()),
+            count: Arc::new(AtomicU64::new(0)),
+            sum: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn observe (&self, value: u64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    // relaxed reads, so one taken during an observation can be off by that one, fine for metrics
+    pub fn snapshot (&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self.bounds.iter().zip(self.counts.iter()).map(|(bound, count)| {
+            cumulative += count.load(Ordering::Relaxed);
+            Bucket { le: *bound, count: cumulative }
+        }).collect();
+        HistogramSnapshot {
+            buckets: buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    // https://prometheus.io/docs/instrumenting/exposition_formats/#histograms-and-summaries
+    pub fn write_prometheus (&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for bucket in &self.buckets {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bucket.le, bucket.count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+// the three usage histograms, created once in main with the configured buckets
+#[derive(Clone)]
+pub struct UsageHistograms {
+    // from the download request coming in to its response being ready, the body streams out after that
+    pub download_ms: Histogram,
+    // each file as stored, by whichever upload path
+    pub file_size_bytes: Histogram,
+    // from the link being created to each of its downloads
+    pub consume_after_ms: Histogram,
+}
+
+impl UsageHistograms {
+    pub fn new (download_ms: &Buckets, file_size_bytes: &Buckets, consume_after_ms: &Buckets) -> Self {
+        Self {
+            download_ms: Histogram::new(download_ms),
+            file_size_bytes: Histogram::new(file_size_bytes),
+            consume_after_ms: Histogram::new(consume_after_ms),
+        }
+    }
+
+    // for the one-off commands, which never serve a request to observe
+    pub fn none () -> Self {
+        let buckets = Buckets(Vec::new());
+        Self::new(&buckets, &buckets, &buckets)
+    }
+}
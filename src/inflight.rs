@@ -0,0 +1,74 @@
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::locks;
+
+
+// tokens with a download being handled right now (or resumable upload ids being patched)
+//  only this process is covered, other instances still race through the db as before
+#[derive(Clone)]
+pub struct InFlightTokens {
+    tokens: Option<Arc<Mutex<HashSet<String>>>>,
+}
+
+// lets the token go again when dropped, however the download ended
+pub struct InFlightGuard {
+    tokens: Option<Arc<Mutex<HashSet<String>>>>,
+    token: String,
+}
+
+impl InFlightTokens {
+    // disabled hands out guards that do nothing, so callers need no special case
+    pub fn new (enabled: bool) -> Self {
+        Self {
+            tokens: if enabled { Some(Arc::new(Mutex::new(HashSet::new()))) } else { None },
+        }
+    }
+
+    // None when another request already has this token
+    pub fn claim (&self, token: &str) -> Option<InFlightGuard> {
+        if let Some(tokens) = &self.tokens {
+            let mut tokens = locks::lock(&tokens);
+            if !tokens.insert(token.to_string()) {
+                return None;
+            }
+        }
+        Some(InFlightGuard {
+            tokens: self.tokens.clone(),
+            token: token.to_string(),
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop (&mut self) {
+        if let Some(tokens) = &self.tokens {
+            locks::lock(&tokens).remove(&self.token);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claimed_until_dropped () {
+        let in_flight = InFlightTokens::new(true);
+        let guard = in_flight.claim("abc");
+        assert!(guard.is_some());
+        assert!(in_flight.claim("abc").is_none());
+        assert!(in_flight.claim("def").is_some());
+        drop(guard);
+        assert!(in_flight.claim("abc").is_some());
+    }
+
+    #[test]
+    fn disabled_always_claims () {
+        let in_flight = InFlightTokens::new(false);
+        let _guard = in_flight.claim("abc");
+        assert!(in_flight.claim("abc").is_some());
+    }
+}
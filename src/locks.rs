@@ -0,0 +1,7 @@
+use std::sync::{Mutex, MutexGuard};
+
+
+// a poisoned lock only means another request panicked while holding it, what it guards is still usable
+pub fn lock<T> (mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -1,42 +1,282 @@
 
 // https://stackoverflow.com/questions/56714619/including-a-file-from-another-that-is-not-main-rs-nor-lib-rs
 mod time_provider;
+mod tokens;
+mod content_types;
+mod totp;
+mod geoip;
+mod locks;
+mod slots;
+mod rate_limit;
+mod nonces;
+mod uploads;
+mod tus;
+mod inflight;
+mod events;
+mod histograms;
+mod signed;
 mod models;
 mod storage;
 mod handlers;
+mod telemetry;
+mod access_log;
+mod error_pages;
+mod migrate;
+mod seed;
 
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use dotenv::dotenv;
-use actix_web::{web, App, HttpServer};
+use actix_web::{web, App, HttpServer, Resource, dev::{BodySize, MessageBody, ServiceRequest}, http::{header, HeaderValue, Method, Uri}};
+use actix_service::Service;
+use futures::future::{self, Either};
+use tracing::{info_span, Instrument};
 
 use crate::time_provider::{SystemTimeProvider, TimeProvider};
-use crate::models::{OnetimeDownloaderConfig, OnetimeDownloaderService, OnetimeStorage};
-use crate::storage::{dynamodb, invalid, postgres};
-use crate::handlers::{list_files, list_links, add_file, add_link, download_link, not_found, delete_file, delete_link};
+use crate::geoip::GeoIp;
+use crate::slots::DownloadSlots;
+use crate::rate_limit::RateLimiter;
+use crate::nonces::NonceStore;
+use crate::uploads::UploadMetrics;
+use crate::inflight::InFlightTokens;
+use crate::events::LinkEvents;
+use crate::histograms::UsageHistograms;
+use crate::access_log::AccessLogLevel;
+use crate::models::{MyError, OnetimeDownloaderConfig, OnetimeDownloaderService, OnetimeStorage};
+use crate::storage::{hashed, invalid, timed, traced};
+use crate::storage::timed::StorageTimings;
+#[cfg(feature = "dynamodb")]
+use crate::storage::dynamodb;
+#[cfg(feature = "postgres")]
+use crate::storage::postgres;
+#[cfg(feature = "faults")]
+use crate::storage::faulty;
+use crate::handlers::{list_files, list_links, list_links_for_file, get_link, get_link_record, link_status, link_events, add_file, add_files, put_file, add_link, add_links, sign_link, files_exist, export_links, import_links, download_link, download_confirm, not_found, allowed_methods, method_not_allowed, delete_file, delete_link, list_deleted_links, revoke_link, restore_link, purge_deleted_links, purge_expired_files, list_expired_unused_links, get_file_info, get_file_contents, metrics, version, readyz, service_unconfigured, tus_options, tus_create, tus_head, tus_patch, tus_delete};
 
 
-fn build_service () -> OnetimeDownloaderService {
-    // https://stackoverflow.com/questions/28219519/are-polymorphic-variables-allowed
-    let time_provider: Box<dyn TimeProvider> = Box::new(SystemTimeProvider {});
-
-    let config = OnetimeDownloaderConfig::from_env();
-    println!("config {:?}", config);
+#[allow(dead_code)]
+fn not_built_in (provider: &str) -> String {
+    format!("Storage provider '{}' was not built in! Build with --features {}", provider, provider)
+}
 
+// with no backend features at all, every provider is invalid and the config and time provider go unused
+#[cfg_attr(not(any(feature = "dynamodb", feature = "postgres")), allow(unused_variables))]
+fn build_storage (provider: &str, config: &OnetimeDownloaderConfig, time_provider: Box<dyn TimeProvider>) -> Box<dyn OnetimeStorage> {
     // https://stackoverflow.com/questions/25383488/how-to-match-a-string-against-string-literals-in-rust
-    let storage: Box<dyn OnetimeStorage> = match config.provider.as_str() {
-        "dynamodb" => Box::new(dynamodb::Storage::from_env(time_provider.clone())),
-        "postgres" => match postgres::Storage::from_env(time_provider.clone()) {
+    let storage: Box<dyn OnetimeStorage> = match provider {
+        #[cfg(feature = "dynamodb")]
+        "dynamodb" => Box::new(dynamodb::Storage::from_env(time_provider, config.lenient_lists)),
+        #[cfg(feature = "postgres")]
+        "postgres" => match postgres::Storage::from_env(time_provider, config.lenient_lists) {
             Err(why) => Box::new(invalid::Storage { error: format!("Invalid postgres storage provider! {}", why) }),
             Ok(storage) => Box::new(storage),
         },
-        _ => Box::new(invalid::Storage { error: format!("Invalid or no storage provider given! '{}'", config.provider) })
+        // known, just not compiled in
+        #[cfg(not(feature = "dynamodb"))]
+        "dynamodb" => Box::new(invalid::Storage { error: not_built_in(provider) }),
+        #[cfg(not(feature = "postgres"))]
+        "postgres" => Box::new(invalid::Storage { error: not_built_in(provider) }),
+        _ => Box::new(invalid::Storage { error: format!("Invalid or no storage provider given! '{}'", provider) })
     };
 
     println!("created storage: {}", storage.name());
+    storage
+}
+
+// the same layers around every storage, the namespaces' too
+fn wrap_storage (storage: Box<dyn OnetimeStorage>, config: &OnetimeDownloaderConfig, storage_timings: &StorageTimings) -> Box<dyn OnetimeStorage> {
+    // inside the tracing, so injected delays show up in the storage spans
+    #[cfg(feature = "faults")]
+    let storage = faulty::Storage::from_env(storage);
+
+    let storage: Box<dyn OnetimeStorage> = if config.hash_tokens {
+        Box::new(hashed::Storage { inner: storage })
+    } else {
+        storage
+    };
+
+    // always on, the counts are cheap and the slow ones are what to look at first when something went wrong
+    let storage: Box<dyn OnetimeStorage> = Box::new(timed::Storage {
+        inner: storage,
+        timings: storage_timings.clone(),
+        slow_ms: config.slow_storage_ms,
+    });
+
+    // spans are no-ops without a subscriber, but skip the extra indirection entirely
+    if config.otel_endpoint.is_empty() {
+        storage
+    } else {
+        Box::new(traced::Storage { inner: storage })
+    }
+}
+
+fn build_service (download_slots: DownloadSlots, rate_limiter: RateLimiter, nonces: NonceStore, uploads: UploadMetrics, in_flight: InFlightTokens, tus_patches: InFlightTokens, link_events: LinkEvents, histograms: UsageHistograms, storage_timings: StorageTimings) -> OnetimeDownloaderService {
+    // https://stackoverflow.com/questions/28219519/are-polymorphic-variables-allowed
+    let time_provider: Box<dyn TimeProvider> = Box::new(SystemTimeProvider {});
+
+    let config = OnetimeDownloaderConfig::from_env();
+    println!("config {:?}", config);
+
+    let storage = build_storage(config.provider.as_str(), &config, time_provider.clone());
+
+    // each namespace shares the backend and its connections, only the links table differs
+    let mut namespaces = HashMap::new();
+    for (namespace, table) in config.link_namespaces.iter() {
+        match storage.with_links_table(table.as_str()) {
+            Ok(namespaced) => {
+                namespaces.insert(namespace.clone(), wrap_storage(namespaced, &config, &storage_timings));
+            }
+            Err(why) => println!("!!!!! NAMESPACE {} IS NOT USABLE: {} !!!!!", namespace, why),
+        }
+    }
+
+    let storage = wrap_storage(storage, &config, &storage_timings);
+
+    // no db means no geo restrictions, rather than blocking everything
+    let geoip = if config.geoip_db.is_empty() {
+        None
+    } else {
+        match GeoIp::open(config.geoip_db.as_str()) {
+            Err(why) => {
+                println!("{}", why);
+                None
+            }
+            Ok(geoip) => Some(geoip),
+        }
+    };
+
+    let download_error_template = error_pages::load_template(config.download_error_template.as_str());
 
     OnetimeDownloaderService {
         time_provider: time_provider,
         config: config,
         storage: storage,
+        namespaces: namespaces,
+        geoip: geoip,
+        download_error_template: download_error_template,
+        download_slots: download_slots,
+        rate_limiter: rate_limiter,
+        nonces: nonces,
+        uploads: uploads,
+        in_flight: in_flight,
+        tus_patches: tus_patches,
+        link_events: link_events,
+        histograms: histograms,
+        storage_timings: storage_timings,
+    }
+}
+
+// answers OPTIONS and wrong methods for the path, add the real method routes to the result
+fn resource (path: &str, allow: &'static str) -> Resource {
+    web::resource(path)
+        .route(web::method(Method::OPTIONS).to(move || allowed_methods(allow)))
+        // https://docs.rs/actix-web/2.0.0/actix_web/struct.Resource.html#method.default_service
+        .default_service(web::route().to(move || method_not_allowed(allow)))
+}
+
+// the only paths that answer without ever touching the storage
+fn uses_storage (req: &ServiceRequest) -> bool {
+    let path = req.path();
+    path != "/version" && path != "/api/metrics"
+}
+
+// these read their whole request body before responding, so a big one can take a while legitimately
+fn is_upload (req: &ServiceRequest) -> bool {
+    let path = req.path();
+    (req.method() == Method::POST || req.method() == Method::PUT) &&
+        (path.starts_with("/api/files") || path == "/api/links/import") ||
+        req.method() == Method::PATCH && path.starts_with("/api/uploads/")
+}
+
+// "/download/abc/" becomes "/download/abc" before routing, the query is kept
+//  actix's NormalizePath only merges repeated slashes in 2.0, so this does what it does for trailing ones
+// https://docs.rs/actix-web/2.0.0/src/actix_web/middleware/normalize.rs.html
+fn trim_trailing_slash (req: &mut ServiceRequest) {
+    let path = req.path();
+    if path.len() <= 1 || !path.ends_with('/') {
+        return;
+    }
+    let trimmed = path.trim_end_matches('/');
+    let trimmed = if trimmed.is_empty() { "/" } else { trimmed }.to_string();
+    set_path(req, trimmed.as_str());
+}
+
+// "/onetime/download/abc" becomes "/download/abc" for PATH_PREFIX=/onetime, for proxies that pass the prefix along
+//  paths without it are left alone, so the service still answers on both
+fn strip_path_prefix (req: &mut ServiceRequest, prefix: &str) {
+    let stripped = match req.path().strip_prefix(prefix) {
+        Some("") => "/".to_string(),
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => return,
+    };
+    set_path(req, stripped.as_str());
+}
+
+// the query is kept, and match_info updated too since routing reads the path from there
+fn set_path (req: &mut ServiceRequest, path: &str) {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match path_and_query.parse() {
+        Ok(path_and_query) => Some(path_and_query),
+        Err(_) => return,
+    };
+    if let Ok(uri) = Uri::from_parts(parts) {
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+    }
+}
+
+// downloads and anything with file contents or live state must never be cached, admin lists briefly and only by the client
+//  None leaves the response as the handler made it
+fn cache_control (req: &ServiceRequest, list_max_age_s: u64) -> Option<String> {
+    let path = req.path();
+    if path.starts_with("/download/") || path.ends_with("/contents") || path.ends_with("/status") || path == "/api/metrics" {
+        return Some("no-store, private".to_string());
+    }
+    if req.method() == Method::GET && path.starts_with("/api/") {
+        return Some(if list_max_age_s == 0 { "no-cache, private".to_string() } else { format!("private, max-age={}", list_max_age_s) });
+    }
+    None
+}
+
+// the server would otherwise start fine and then 500 on every request
+//  returns why the storage can never work, if it can't, for every request to answer with instead
+async fn check_storage () -> Option<MyError> {
+    let service = build_service(DownloadSlots::new(0), RateLimiter::new(0, 0), NonceStore::new(0), UploadMetrics::new(), InFlightTokens::new(false), InFlightTokens::new(true), LinkEvents::new(), UsageHistograms::none(), StorageTimings::new());
+    if let Some(why) = service.storage.unconfigured() {
+        println!("!!!!! STORAGE IS NOT CONFIGURED: {} !!!!!", why);
+        if service.config.strict_startup {
+            std::process::exit(1);
+        }
+        return Some(why);
+    }
+    match service.storage.ping().await {
+        Ok(_) => println!("storage {} is reachable", service.storage.name()),
+        Err(why) => {
+            println!("!!!!! STORAGE {} IS NOT USABLE: {} !!!!!", service.storage.name(), why);
+            if service.config.strict_startup {
+                std::process::exit(1);
+            }
+        }
+    }
+    None
+}
+
+// only ever adds, so restarting a demo keeps whatever was changed in it
+async fn seed_storage () {
+    let service = build_service(DownloadSlots::new(0), RateLimiter::new(0, 0), NonceStore::new(0), UploadMetrics::new(), InFlightTokens::new(false), InFlightTokens::new(true), LinkEvents::new(), UsageHistograms::none(), StorageTimings::new());
+    let now = service.time_provider.unix_ts_ms();
+    match seed::run(service.storage.as_ref(), service.config.seed_file.as_str(), now, service.config.default_expiration_ms).await {
+        Ok(summary) => println!("seeding done: {:?}", summary),
+        Err(why) => {
+            println!("!!!!! SEEDING FAILED: {} !!!!!", why);
+            if service.config.strict_startup {
+                std::process::exit(1);
+            }
+        }
     }
 }
 
@@ -44,20 +284,294 @@ fn build_service () -> OnetimeDownloaderService {
 async fn main () -> std::io::Result<()> {
     dotenv().ok();
 
-    HttpServer::new(|| {
+    let _telemetry = telemetry::init(OnetimeDownloaderConfig::from_env().otel_endpoint.as_str());
+
+    let (config, report) = OnetimeDownloaderConfig::from_env_report();
+    println!("effective config:");
+    for line in report.lines.iter() {
+        println!("  {}", line);
+    }
+    for error in report.errors.iter() {
+        println!("!!!!! CONFIG: {} !!!!!", error);
+    }
+    if !report.errors.is_empty() && config.strict_startup {
+        std::process::exit(1);
+    }
+
+    if !config.migrate_from.is_empty() && !config.migrate_to.is_empty() {
+        let time_provider: Box<dyn TimeProvider> = Box::new(SystemTimeProvider {});
+        let from = build_storage(config.migrate_from.as_str(), &config, time_provider.clone());
+        let to = build_storage(config.migrate_to.as_str(), &config, time_provider);
+        // raw tokens are hashed on the way in, tokens already hashed are copied as they are
+        let to: Box<dyn OnetimeStorage> = if config.hash_tokens {
+            Box::new(hashed::Storage { inner: to })
+        } else {
+            to
+        };
+        let summary = migrate::run(from.as_ref(), to.as_ref(), config.migrate_overwrite).await;
+        println!("migration done: {:?}", summary);
+        std::process::exit(if summary.failed() { 1 } else { 0 });
+    }
+
+    let unconfigured = check_storage().await;
+    if config.seed_on_start && !config.seed_file.is_empty() {
+        seed_storage().await;
+    }
+
+    // created once out here, the closure below runs per worker
+    let download_slots = DownloadSlots::new(config.max_concurrent_downloads);
+    let rate_limiter = RateLimiter::new(config.rate_limit, config.rate_limit_window_ms);
+    let nonces = NonceStore::new(config.confirm_nonce_ttl_ms);
+    let uploads = UploadMetrics::new();
+    let in_flight = InFlightTokens::new(config.dedupe_downloads);
+    let tus_patches = InFlightTokens::new(true);
+    let download_events = LinkEvents::new();
+    let storage_timings = StorageTimings::new();
+    let histograms = UsageHistograms::new(&config.histogram_download_ms, &config.histogram_file_size_bytes, &config.histogram_consume_after_ms);
+    let request_timeout_ms = config.request_timeout_ms;
+    let upload_timeout_ms = config.upload_timeout_ms;
+    let list_cache_max_age_s = config.list_cache_max_age_s;
+    let trim_slash = config.trim_trailing_slash;
+    let path_prefix = if config.strip_path_prefix { config.path_prefix.clone() } else { String::new() };
+    let access_log_level = config.access_log_level;
+    let access_log_format = config.access_log_format;
+    let lists_disabled = config.disable_list_endpoints;
+    let namespaced = !config.link_namespaces.is_empty();
+
+    HttpServer::new(move || {
+        // each worker's closures below take their own
+        let path_prefix = path_prefix.clone();
+        let unconfigured = unconfigured.clone();
         App::new()
-            .data(build_service())
+            .data(build_service(download_slots.clone(), rate_limiter.clone(), nonces.clone(), uploads.clone(), in_flight.clone(), tus_patches.clone(), download_events.clone(), histograms.clone(), storage_timings.clone()))
+            // a storage that can never work is a 503 up front, rather than every handler trying it and 500ing
+            .wrap_fn(move |req, srv| {
+                match unconfigured.clone().filter(|_| uses_storage(&req)) {
+                    Some(why) => Either::Left(future::ok(req.into_response(service_unconfigured(why)))),
+                    None => Either::Right(srv.call(req)),
+                }
+            })
+            // the deadline covers the handler making its response, a download body streams out after that and is not cut off
+            .wrap_fn(move |req, srv| {
+                let timeout_ms = if is_upload(&req) { upload_timeout_ms } else { request_timeout_ms };
+                let response = srv.call(req);
+                async move {
+                    if timeout_ms == 0 {
+                        return response.await;
+                    }
+                    // https://docs.rs/tokio/0.2.22/tokio/time/fn.timeout.html -- dropping the handler cancels its storage calls too
+                    match tokio::time::timeout(Duration::from_millis(timeout_ms), response).await {
+                        Ok(response) => response,
+                        // as an error, the request itself went into the handler and can't be had back to respond with
+                        Err(_) => Err(actix_web::error::ErrorGatewayTimeout("Request timed out")),
+                    }
+                }
+            })
+            // a handler that set its own cache control, like the confirm page, keeps it
+            .wrap_fn(move |req, srv| {
+                let cache_control = cache_control(&req, list_cache_max_age_s);
+                let response = srv.call(req);
+                async move {
+                    let mut response = match response.await {
+                        Ok(response) => response,
+                        Err(why) => return Err(why),
+                    };
+                    if let Some(cache_control) = cache_control {
+                        let headers = response.headers_mut();
+                        if !headers.contains_key(header::CACHE_CONTROL) {
+                            if let Ok(value) = HeaderValue::from_str(cache_control.as_str()) {
+                                headers.insert(header::CACHE_CONTROL, value);
+                            }
+                            // for http/1.0 caches, which ignore cache control
+                            if cache_control.starts_with("no-store") {
+                                headers.insert(header::PRAGMA, HeaderValue::from_static("no-cache"));
+                            }
+                        }
+                    }
+                    Ok(response)
+                }
+            })
+            // https://docs.rs/actix-web/2.0.0/actix_web/struct.App.html#method.wrap_fn
+            .wrap_fn(|req, srv| {
+                let span = info_span!("request", method = %req.method(), path = %req.path());
+                srv.call(req).instrument(span)
+            })
+            // around everything else, so the status and time are what the client got, timeouts included
+            .wrap_fn(move |req, srv| {
+                let started = Instant::now();
+                let method = req.method().to_string();
+                let path = req.path().to_string();
+                let query = req.query_string().to_string();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    if access_log_level != AccessLogLevel::Off {
+                        let (status, size) = match &response {
+                            Ok(response) => (response.status().as_u16(), response.response().body().size()),
+                            Err(why) => (why.as_response_error().status_code().as_u16(), BodySize::None),
+                        };
+                        if access_log_level.logs(status) {
+                            access_log::log(access_log_format, method.as_str(), path.as_str(), query.as_str(), status, size, started.elapsed().as_millis());
+                        }
+                    }
+                    response
+                }
+            })
+            // last so it runs first, everything after it sees only the trimmed path
+            .wrap_fn(move |mut req, srv| {
+                if !path_prefix.is_empty() {
+                    strip_path_prefix(&mut req, path_prefix.as_str());
+                }
+                if trim_slash {
+                    trim_trailing_slash(&mut req);
+                }
+                srv.call(req)
+            })
             // https://actix.rs/docs/application/
             .service(
                 web::scope("/api")
-                    .route("files", web::get().to(list_files))
-                    .route("links", web::get().to(list_links))
-                    .route("files", web::post().to(add_file))
-                    .route("links", web::post().to(add_link))
-                    .route("files/{filename}", web::delete().to(delete_file))
-                    .route("links/{token}", web::delete().to(delete_link))
+                    // with the lists disabled a GET is the same 404 as any unknown path, so it can't tell them apart
+                    .service(
+                        if lists_disabled {
+                            resource("files", "POST, OPTIONS").route(web::get().to(not_found))
+                        } else {
+                            resource("files", "GET, POST, OPTIONS").route(web::get().to(list_files))
+                        }
+                            .route(web::post().to(add_file))
+                    )
+                    // before files/{filename}, which would otherwise match it first
+                    .service(
+                        resource("files/exists", "POST, OPTIONS")
+                            .route(web::post().to(files_exist))
+                    )
+                    .service(
+                        resource("files/bulk", "POST, OPTIONS")
+                            .route(web::post().to(add_files))
+                    )
+                    // not resource(), tus clients expect its OPTIONS to describe the protocol
+                    .service(
+                        web::resource("uploads")
+                            .route(web::method(Method::OPTIONS).to(tus_options))
+                            .route(web::post().to(tus_create))
+                            .default_service(web::route().to(|| method_not_allowed("POST, OPTIONS")))
+                    )
+                    .service(
+                        resource("uploads/{id}", "HEAD, PATCH, DELETE, OPTIONS")
+                            .route(web::head().to(tus_head))
+                            .route(web::patch().to(tus_patch))
+                            .route(web::delete().to(tus_delete))
+                    )
+                    .service(
+                        if lists_disabled {
+                            resource("links", "POST, OPTIONS").route(web::get().to(not_found))
+                        } else {
+                            resource("links", "GET, POST, OPTIONS").route(web::get().to(list_links))
+                        }
+                            .route(web::post().to(add_link))
+                    )
+                    // before links/{token}, which would otherwise match it first
+                    .service(
+                        resource("links/bulk", "POST, OPTIONS")
+                            .route(web::post().to(add_links))
+                    )
+                    .service(
+                        resource("links/export", "GET, OPTIONS")
+                            .route(web::get().to(export_links))
+                    )
+                    .service(
+                        resource("links/import", "POST, OPTIONS")
+                            .route(web::post().to(import_links))
+                    )
+                    .service(
+                        resource("files/{filename}", "GET, PUT, DELETE, OPTIONS")
+                            .route(web::get().to(get_file_info))
+                            .route(web::put().to(put_file))
+                            .route(web::delete().to(delete_file))
+                    )
+                    .service(
+                        resource("files/{filename}/contents", "GET, OPTIONS")
+                            .route(web::get().to(get_file_contents))
+                    )
+                    .service(
+                        resource("files/{filename}/links", "GET, OPTIONS")
+                            .route(web::get().to(list_links_for_file))
+                    )
+                    .service(
+                        resource("signed-links", "POST, OPTIONS")
+                            .route(web::post().to(sign_link))
+                    )
+                    .service(
+                        resource("deleted-links", "GET, DELETE, OPTIONS")
+                            .route(web::get().to(list_deleted_links))
+                            .route(web::delete().to(purge_deleted_links))
+                    )
+                    .service(
+                        resource("deleted-links/{token}", "POST, OPTIONS")
+                            .route(web::post().to(restore_link))
+                    )
+                    .service(
+                        resource("expired-files", "DELETE, OPTIONS")
+                            .route(web::delete().to(purge_expired_files))
+                    )
+                    .service(
+                        resource("expired-links", "GET, OPTIONS")
+                            .route(web::get().to(list_expired_unused_links))
+                    )
+                    .service(
+                        resource("metrics", "GET, OPTIONS")
+                            .route(web::get().to(metrics))
+                    )
+                    .service(
+                        resource("links/{token}/status", "GET, OPTIONS")
+                            .route(web::get().to(link_status))
+                    )
+                    .service(
+                        resource("links/{token}/revoke", "POST, OPTIONS")
+                            .route(web::post().to(revoke_link))
+                    )
+                    .service(
+                        resource("links/{token}/events", "GET, OPTIONS")
+                            .route(web::get().to(link_events))
+                    )
+                    .service(
+                        resource("links/{token}/record", "GET, OPTIONS")
+                            .route(web::get().to(get_link_record))
+                    )
+                    .service(
+                        resource("links/{token}", "GET, DELETE, OPTIONS")
+                            .route(web::get().to(get_link))
+                            .route(web::delete().to(delete_link))
+                    )
+            )
+            .service(
+                resource("version", "GET, OPTIONS")
+                    .route(web::get().to(version))
+            )
+            .service(
+                resource("readyz", "GET, OPTIONS")
+                    .route(web::get().to(readyz))
+            )
+            .service(
+                resource("download/{token}", "GET, OPTIONS")
+                    .route(web::get().to(download_link))
             )
-            .route("download/{token}", web::get().to(download_link))
+            .service(
+                resource("download/{token}/confirm", "POST, OPTIONS")
+                    .route(web::post().to(download_confirm))
+            )
+            // only with LINK_NAMESPACES, after the above so /download/{token}/confirm is never taken for a namespace
+            // https://docs.rs/actix-web/2.0.0/actix_web/struct.App.html#method.configure
+            .configure(|cfg| if namespaced {
+                cfg
+                    .service(
+                        resource("download/{namespace}/{token}", "GET, OPTIONS")
+                            .route(web::get().to(download_link))
+                    )
+                    .service(
+                        resource("download/{namespace}/{token}/confirm", "POST, OPTIONS")
+                            .route(web::post().to(download_confirm))
+                    );
+            })
             // https://github.com/actix/actix-website/blob/master/content/docs/url-dispatch.md
             .default_service(
                 // https://docs.rs/actix-web/2.0.0/actix_web/struct.App.html#method.service
@@ -70,3 +584,76 @@ async fn main () -> std::io::Result<()> {
     .run()
     .await
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request (method: Method, uri: &str) -> ServiceRequest {
+        TestRequest::with_uri(uri).method(method).to_srv_request()
+    }
+
+    #[test]
+    fn uploads_get_the_longer_timeout () {
+        assert!(is_upload(&request(Method::POST, "/api/files")));
+        assert!(is_upload(&request(Method::POST, "/api/files/bulk")));
+        assert!(is_upload(&request(Method::PUT, "/api/files/a.txt")));
+        assert!(is_upload(&request(Method::POST, "/api/links/import")));
+        assert!(is_upload(&request(Method::PATCH, "/api/uploads/abc")));
+    }
+
+    #[test]
+    fn everything_else_gets_the_request_timeout () {
+        assert!(!is_upload(&request(Method::GET, "/api/files")));
+        assert!(!is_upload(&request(Method::DELETE, "/api/files/a.txt")));
+        assert!(!is_upload(&request(Method::POST, "/api/links")));
+        assert!(!is_upload(&request(Method::POST, "/api/uploads")));
+        assert!(!is_upload(&request(Method::GET, "/download/abc")));
+    }
+
+    #[test]
+    fn one_time_contents_never_cached () {
+        for (method, uri) in &[(Method::GET, "/download/abc"), (Method::POST, "/download/abc/confirm"), (Method::GET, "/api/files/a.txt/contents"), (Method::GET, "/api/links/abc/status"), (Method::GET, "/api/metrics")] {
+            assert_eq!(cache_control(&request(method.clone(), uri), 5), Some("no-store, private".to_string()), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn admin_lists_cached_briefly () {
+        assert_eq!(cache_control(&request(Method::GET, "/api/files"), 5), Some("private, max-age=5".to_string()));
+        assert_eq!(cache_control(&request(Method::GET, "/api/links"), 0), Some("no-cache, private".to_string()));
+        assert_eq!(cache_control(&request(Method::POST, "/api/links"), 5), None);
+        assert_eq!(cache_control(&request(Method::GET, "/version"), 5), None);
+    }
+
+    fn stripped (uri: &str, prefix: &str) -> (String, String) {
+        let mut req = request(Method::GET, uri);
+        strip_path_prefix(&mut req, prefix);
+        (req.path().to_string(), req.query_string().to_string())
+    }
+
+    #[test]
+    fn path_prefix_stripped_before_routing () {
+        assert_eq!(stripped("/onetime/download/abc", "/onetime"), ("/download/abc".to_string(), String::new()));
+        assert_eq!(stripped("/onetime/api/files?sort=filename", "/onetime"), ("/api/files".to_string(), "sort=filename".to_string()));
+        assert_eq!(stripped("/onetime", "/onetime"), ("/".to_string(), String::new()));
+    }
+
+    #[test]
+    fn paths_without_prefix_left_alone () {
+        assert_eq!(stripped("/download/abc", "/onetime"), ("/download/abc".to_string(), String::new()));
+        // only whole segments, "/onetimer" is not under "/onetime"
+        assert_eq!(stripped("/onetimer/download/abc", "/onetime"), ("/onetimer/download/abc".to_string(), String::new()));
+    }
+
+    #[test]
+    fn only_version_and_metrics_skip_storage () {
+        assert!(!uses_storage(&request(Method::GET, "/version")));
+        assert!(!uses_storage(&request(Method::GET, "/api/metrics")));
+        assert!(uses_storage(&request(Method::GET, "/readyz")));
+        assert!(uses_storage(&request(Method::GET, "/download/abc")));
+        assert!(uses_storage(&request(Method::POST, "/api/files")));
+    }
+}
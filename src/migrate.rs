@@ -0,0 +1,90 @@
+
+use crate::models::OnetimeStorage;
+
+
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files_copied: usize,
+    pub files_skipped: usize,
+    pub files_failed: usize,
+    pub links_copied: usize,
+    pub links_skipped: usize,
+    pub links_failed: usize,
+    // a page that could not be read ends that table's copy, there is no cursor to go on from
+    pub pages_failed: usize,
+}
+
+impl Summary {
+    pub fn failed (&self) -> bool {
+        self.files_failed > 0 || self.links_failed > 0 || self.pages_failed > 0
+    }
+}
+
+// one-shot copy of everything, items that fail are logged and counted and the rest still go
+//  without overwrite, whatever the destination already has is left alone and counted as skipped
+pub async fn run (from: &dyn OnetimeStorage, to: &dyn OnetimeStorage, overwrite: bool) -> Summary {
+    println!("migrating from {} to {}", from.name(), to.name());
+    let mut summary = Summary::default();
+
+    let mut after = None;
+    loop {
+        let (files, next) = match from.list_files_page(after.clone(), PAGE_SIZE).await {
+            Ok(page) => page,
+            Err(why) => {
+                println!("List files after {:?} failed, no more files will be copied: {}", after, why);
+                summary.pages_failed += 1;
+                break;
+            }
+        };
+        let count = files.len();
+        // a whole page at once, postgres copies these in rather than inserting each
+        match to.add_files(files, overwrite).await {
+            Ok(filenames) => {
+                summary.files_copied += filenames.len();
+                summary.files_skipped += count - filenames.len();
+            }
+            Err(why) => {
+                println!("Copy {} files after {:?} failed: {}", count, after, why);
+                summary.files_failed += count;
+            }
+        }
+        println!("files: {} copied, {} skipped, {} failed", summary.files_copied, summary.files_skipped, summary.files_failed);
+        match next {
+            None => break,
+            Some(next) => after = Some(next),
+        }
+    }
+
+    let mut after = None;
+    loop {
+        let (links, next) = match from.list_links_page(after.clone(), PAGE_SIZE).await {
+            Ok(page) => page,
+            Err(why) => {
+                println!("List links after {:?} failed, no more links will be copied: {}", after, why);
+                summary.pages_failed += 1;
+                break;
+            }
+        };
+        let count = links.len();
+        // import keeps tokens, timestamps and consumed state exactly as they were
+        match to.import_links(links, overwrite).await {
+            Ok(tokens) => {
+                summary.links_copied += tokens.len();
+                summary.links_skipped += count - tokens.len();
+            }
+            Err(why) => {
+                println!("Copy {} links after {:?} failed: {}", count, after, why);
+                summary.links_failed += count;
+            }
+        }
+        println!("links: {} copied, {} skipped, {} failed", summary.links_copied, summary.links_skipped, summary.links_failed);
+        match next {
+            None => break,
+            Some(next) => after = Some(next),
+        }
+    }
+
+    summary
+}
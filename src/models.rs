@@ -1,30 +1,385 @@
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use bytes::{Bytes};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use bytes::{Bytes, BytesMut};
+use ipnet::IpNet;
+use futures::stream::{LocalBoxStream, StreamExt};
 use serde::{Serialize, Deserialize};
 use serde::ser::{Serializer, SerializeStruct};
 use async_trait::async_trait;
 use dyn_clonable::clonable;
 
 use crate::time_provider::TimeProvider;
+use crate::tokens::{validate_token, TokenFormat};
+use crate::totp;
+use crate::access_log::{AccessLogFormat, AccessLogLevel};
+use crate::geoip::GeoIp;
+use crate::slots::DownloadSlots;
+use crate::rate_limit::RateLimiter;
+use crate::nonces::NonceStore;
+use crate::uploads::UploadMetrics;
+use crate::inflight::InFlightTokens;
+use crate::events::LinkEvents;
+use crate::histograms::{Buckets, HistogramSnapshot, UsageHistograms};
+use crate::storage::timed::{StorageOpStats, StorageTimings};
 
 
 const EMPTY_STRING: String = String::new();
 const DEFAULT_MAX_LEN_FILE: usize = 100000;
+// an empty file only makes for a useless download
+const DEFAULT_MIN_LEN_FILE: usize = 1;
 const DEFAULT_MAX_LEN_VALUE: usize = 80;
+// dynamodb hash keys max out at 2048 bytes, stay well under that
+const DEFAULT_MAX_LEN_FILENAME: usize = 255;
+const DEFAULT_MAX_LEN_NOTE: usize = 1000;
 const DEFAULT_EXPIRATION_MS: i64 = 300000;
+const DEFAULT_EXPIRY_SKEW_MS: i64 = 0;
+const DEFAULT_CONSUME_RETRY_DELAY_MS: u64 = 100;
+// a week
+const DEFAULT_TOMBSTONE_RETENTION_MS: i64 = 604800000;
+const DEFAULT_LINK_INFO_RETENTION_MS: i64 = 604800000;
+const DEFAULT_DOWNLOAD_SLOT_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_MAX_BULK_LINKS: usize = 100;
+const DEFAULT_MAX_BULK_FILES: usize = 1000;
+const DEFAULT_RATE_LIMIT_WINDOW_MS: i64 = 60000;
+const DEFAULT_CONFIRM_NONCE_TTL_MS: i64 = 300000;
+const DEFAULT_LIST_CACHE_MAX_AGE_S: u64 = 5;
+const DEFAULT_UPLOAD_IDLE_TIMEOUT_MS: u64 = 30000;
+const DEFAULT_UPLOAD_MIN_RATE_GRACE_MS: u64 = 10000;
+const DEFAULT_TUS_EXPIRY_MS: i64 = 86400000;
+// a day
+const DEFAULT_QUOTA_WINDOW_MS: i64 = 86400000;
+const DEFAULT_TOKEN_FORMAT: TokenFormat = TokenFormat::Hex;
+const DEFAULT_LINK_EVENTS_HEARTBEAT_MS: u64 = 15000;
+// 5ms to a minute, a download is mostly the storage read
+const DEFAULT_HISTOGRAM_DOWNLOAD_MS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 5000, 60000];
+// 1KB to 100MB, well past the default FILE_MAX_LEN so raising it does not need new buckets
+const DEFAULT_HISTOGRAM_FILE_SIZE_BYTES: [u64; 6] = [1000, 10000, 100000, 1000000, 10000000, 100000000];
+// a minute, 5 minutes (the default expiration), an hour, a day, a week
+const DEFAULT_HISTOGRAM_CONSUME_AFTER_MS: [u64; 5] = [60000, 300000, 3600000, 86400000, 604800000];
+const DEFAULT_SLOW_STORAGE_MS: u64 = 1000;
+const DEFAULT_ACCESS_LOG_LEVEL: AccessLogLevel = AccessLogLevel::Info;
+const DEFAULT_ACCESS_LOG_FORMAT: AccessLogFormat = AccessLogFormat::Text;
+const DEFAULT_MAX_DOWNLOADS: i64 = 1;
+const DEFAULT_MAX_MAX_DOWNLOADS: i64 = 10;
+const DEFAULT_MAX_LEN_BASE64_DOWNLOAD: usize = 1000000;
+const DEFAULT_DOWNLOAD_NAME: &'static str = "download";
+const DEFAULT_INLINE_CONTENT_TYPES: &'static str = "image/*,application/pdf";
+const DEFAULT_CONTENT_TYPE: &'static str = "application/octet-stream";
+const DEFAULT_NOT_FOUND_BODY: &'static str = r#"{"error":"Not found"}"#;
+const DEFAULT_NOT_FOUND_CONTENT_TYPE: &'static str = "application/json";
 
 
 pub type MyError = String;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+pub enum OnetimeError {
+    NotFound(MyError),
+    AlreadyConsumed(OnetimeLink),
+    Failed(MyError),
+}
+
+#[derive(Clone)]
 pub struct OnetimeDownloaderConfig {
     pub provider: String,
     pub api_key_files: String,
     pub api_key_links: String,
+    pub api_key_admin: String,
+    // base32, a second factor for the admin writes, see README "Admin one-time passwords"
+    pub admin_totp_secret: String,
     pub max_len_file: usize,
+    // raw PUT bodies, 0 for the same as max_len_file
+    pub max_len_raw_file: usize,
+    pub min_len_file: usize,
     pub max_len_value: usize,
+    pub max_len_filename: usize,
+    pub max_len_note: usize,
     pub default_expiration_ms: i64,
+    pub expiry_skew_ms: i64,
+    pub link_note_template: String,
+    // failed downloads get an html page when the client asks for html first, see README "Download error pages"
+    pub html_download_errors: bool,
+    pub download_error_template: String,
+    pub token_format: TokenFormat,
+    // both off by default, so a url that 404s today does not start resolving after an upgrade
+    pub trim_trailing_slash: bool,
+    // where a reverse proxy mounts the service, eg "/onetime", "" when it is at the root
+    pub path_prefix: String,
+    // also route requests that still have the prefix on them, for proxies that don't strip it
+    pub strip_path_prefix: bool,
+    // namespace and its links table, downloads at /download/{namespace}/{token} read links from that table
+    pub link_namespaces: Vec<(String, String)>,
+    // only sha-256 hashes of link tokens in storage, existing links need migrating first
+    pub hash_tokens: bool,
+    // GET /api/files and GET /api/links are not routed at all
+    pub disable_list_endpoints: bool,
+    // a comment line this often keeps idle event streams from being cut by proxies
+    pub link_events_heartbeat_ms: u64,
+    pub case_insensitive_tokens: bool,
+    pub show_consumption_details: bool,
+    // unreadable rows are left out of whole lists rather than failing them, passed to the storage providers
+    pub lenient_lists: bool,
+    pub strict_startup: bool,
+    pub otel_endpoint: String,
+    pub not_found_body: String,
+    // 0 is no deadline, uploads have their own since they read the whole body in the handler
+    pub request_timeout_ms: u64,
+    pub upload_timeout_ms: u64,
+    // a client sending slower than this is cut off with a 408, 0 disables each
+    pub upload_idle_timeout_ms: u64,
+    pub upload_min_bytes_per_s: u64,
+    pub upload_min_rate_grace_ms: u64,
+    // where resumable uploads wait until complete, empty disables them
+    pub tus_dir: String,
+    pub tus_expiry_ms: i64,
+    // how long a client may reuse an admin list response, 0 makes it revalidate every time
+    pub list_cache_max_age_s: u64,
+    // for links created without max_downloads, which can never be above max_max_downloads
+    pub default_max_downloads: i64,
+    pub max_max_downloads: i64,
+    // base64 grows files by a third and is built in memory, so only files up to this are sent that way
+    pub max_len_base64_download: usize,
+    // sent as the download filename when the stored one is empty
+    pub default_download_name: String,
+    // checked against the name of every upload, lowercase and without dots, empty allows any
+    pub allowed_extensions: Vec<String>,
+    pub blocked_extensions: Vec<String>,
+    // served inline, exact types or "image/*" style prefixes, everything else downloads as an attachment
+    pub inline_content_types: Vec<String>,
+    // stored for uploads that don't say, after the extension guess when sniffing
+    pub default_content_type: String,
+    pub sniff_content_type: bool,
+    pub not_found_content_type: String,
+    pub geoip_db: String,
+    pub immutable_files: bool,
+    pub soft_delete: bool,
+    // a delete of a file or link that isn't there is a 404, rather than the same 204 as deleting it
+    pub delete_missing_is_404: bool,
+    // removes a used up link's row entirely, after the delay, and with delete_file_after_download its file too
+    pub delete_after_download: bool,
+    pub delete_after_download_delay_ms: u64,
+    pub delete_file_after_download: bool,
+    pub tombstone_retention_ms: i64,
+    // how long after being consumed or deleted a link's metadata can still be looked up
+    pub link_info_retention_ms: i64,
+    // consume only after the whole body went out, so a dropped connection can retry
+    //  the trade-off: concurrent downloads of one link can now all receive the file before any consumes it
+    pub consume_after_flush: bool,
+    // extra tries for a consume that failed outright (a timeout, a dropped connection), never for one that was refused
+    //  safe because every try of one download carries the same attempt id, see consume_link
+    pub consume_retries: usize,
+    // doubled after each retry
+    pub consume_retry_delay_ms: u64,
+    // 410 instead of 404 for a link whose file was deleted, the link is not consumed either way
+    pub missing_file_gone: bool,
+    pub post_download_redirect: String,
+    // 0 is unlimited
+    pub max_concurrent_downloads: usize,
+    pub download_slot_timeout_ms: u64,
+    // concurrent downloads of one token in this process get a 409 instead of each going to the db
+    pub dedupe_downloads: bool,
+    pub max_bulk_links: usize,
+    pub max_bulk_files: usize,
+    // both set runs a one-shot copy between the two providers instead of the server
+    pub migrate_from: String,
+    pub migrate_to: String,
+    pub migrate_overwrite: bool,
+    // demo data, only loaded when asked for so a stray file can't put it into production
+    pub seed_file: String,
+    pub seed_on_start: bool,
+    // referer and origin can identify who shared or followed a link, so they are only logged when asked for
+    pub log_referer: bool,
+    pub access_log_level: AccessLogLevel,
+    pub access_log_format: AccessLogFormat,
+    // storage calls taking longer are logged, 0 logs none
+    pub slow_storage_ms: u64,
+    // bucket upper bounds for the histograms in GET /api/metrics
+    pub histogram_download_ms: Buckets,
+    pub histogram_file_size_bytes: Buckets,
+    pub histogram_consume_after_ms: Buckets,
+    // requests per client per window, 0 is unlimited
+    pub rate_limit: u64,
+    pub rate_limit_window_ms: i64,
+    // proxies whose X-Forwarded-For is believed, anyone else is taken to be the client itself
+    pub trusted_proxies: Vec<IpNet>,
+    // empty disables signed links, anyone with the secret can mint a valid download url
+    pub link_signing_secret: String,
+    // signed links have no row to revoke, this at least makes each one single use
+    pub signed_links_consume: bool,
+    // per key (files or links) per fixed window, 0 is unlimited
+    pub quota_window_ms: i64,
+    pub quota_max_files: i64,
+    pub quota_max_bytes: i64,
+    pub quota_max_links: i64,
+    // a plain GET only gets a page with a confirm button, so link previews and prefetches can't consume the link
+    pub require_confirm: bool,
+    pub confirm_nonce_ttl_ms: i64,
+}
+
+// derived Debug would print the api keys and signing secret into the logs
+impl fmt::Debug for OnetimeDownloaderConfig {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnetimeDownloaderConfig")
+            .field("provider", &self.provider)
+            .field("api_key_files", &redact(&self.api_key_files))
+            .field("api_key_links", &redact(&self.api_key_links))
+            .field("api_key_admin", &redact(&self.api_key_admin))
+            .field("admin_totp_secret", &redact(&self.admin_totp_secret))
+            .field("max_len_file", &self.max_len_file)
+            .field("max_len_raw_file", &self.max_len_raw_file)
+            .field("min_len_file", &self.min_len_file)
+            .field("max_len_value", &self.max_len_value)
+            .field("max_len_filename", &self.max_len_filename)
+            .field("max_len_note", &self.max_len_note)
+            .field("default_expiration_ms", &self.default_expiration_ms)
+            .field("expiry_skew_ms", &self.expiry_skew_ms)
+            .field("link_note_template", &self.link_note_template)
+            .field("html_download_errors", &self.html_download_errors)
+            .field("download_error_template", &self.download_error_template)
+            .field("token_format", &self.token_format)
+            .field("trim_trailing_slash", &self.trim_trailing_slash)
+            .field("path_prefix", &self.path_prefix)
+            .field("strip_path_prefix", &self.strip_path_prefix)
+            .field("link_namespaces", &self.link_namespaces)
+            .field("hash_tokens", &self.hash_tokens)
+            .field("disable_list_endpoints", &self.disable_list_endpoints)
+            .field("link_events_heartbeat_ms", &self.link_events_heartbeat_ms)
+            .field("case_insensitive_tokens", &self.case_insensitive_tokens)
+            .field("show_consumption_details", &self.show_consumption_details)
+            .field("lenient_lists", &self.lenient_lists)
+            .field("strict_startup", &self.strict_startup)
+            .field("otel_endpoint", &self.otel_endpoint)
+            .field("not_found_body", &self.not_found_body)
+            .field("request_timeout_ms", &self.request_timeout_ms)
+            .field("upload_timeout_ms", &self.upload_timeout_ms)
+            .field("upload_idle_timeout_ms", &self.upload_idle_timeout_ms)
+            .field("upload_min_bytes_per_s", &self.upload_min_bytes_per_s)
+            .field("upload_min_rate_grace_ms", &self.upload_min_rate_grace_ms)
+            .field("tus_dir", &self.tus_dir)
+            .field("tus_expiry_ms", &self.tus_expiry_ms)
+            .field("list_cache_max_age_s", &self.list_cache_max_age_s)
+            .field("default_max_downloads", &self.default_max_downloads)
+            .field("max_max_downloads", &self.max_max_downloads)
+            .field("max_len_base64_download", &self.max_len_base64_download)
+            .field("default_download_name", &self.default_download_name)
+            .field("allowed_extensions", &self.allowed_extensions)
+            .field("blocked_extensions", &self.blocked_extensions)
+            .field("inline_content_types", &self.inline_content_types)
+            .field("default_content_type", &self.default_content_type)
+            .field("sniff_content_type", &self.sniff_content_type)
+            .field("not_found_content_type", &self.not_found_content_type)
+            .field("geoip_db", &self.geoip_db)
+            .field("immutable_files", &self.immutable_files)
+            .field("soft_delete", &self.soft_delete)
+            .field("delete_missing_is_404", &self.delete_missing_is_404)
+            .field("delete_after_download", &self.delete_after_download)
+            .field("delete_after_download_delay_ms", &self.delete_after_download_delay_ms)
+            .field("delete_file_after_download", &self.delete_file_after_download)
+            .field("tombstone_retention_ms", &self.tombstone_retention_ms)
+            .field("link_info_retention_ms", &self.link_info_retention_ms)
+            .field("consume_after_flush", &self.consume_after_flush)
+            .field("consume_retries", &self.consume_retries)
+            .field("consume_retry_delay_ms", &self.consume_retry_delay_ms)
+            .field("missing_file_gone", &self.missing_file_gone)
+            .field("post_download_redirect", &self.post_download_redirect)
+            .field("max_concurrent_downloads", &self.max_concurrent_downloads)
+            .field("dedupe_downloads", &self.dedupe_downloads)
+            .field("download_slot_timeout_ms", &self.download_slot_timeout_ms)
+            .field("max_bulk_links", &self.max_bulk_links)
+            .field("max_bulk_files", &self.max_bulk_files)
+            .field("migrate_from", &self.migrate_from)
+            .field("migrate_to", &self.migrate_to)
+            .field("migrate_overwrite", &self.migrate_overwrite)
+            .field("seed_file", &self.seed_file)
+            .field("seed_on_start", &self.seed_on_start)
+            .field("log_referer", &self.log_referer)
+            .field("access_log_level", &self.access_log_level)
+            .field("access_log_format", &self.access_log_format)
+            .field("slow_storage_ms", &self.slow_storage_ms)
+            .field("histogram_download_ms", &self.histogram_download_ms)
+            .field("histogram_file_size_bytes", &self.histogram_file_size_bytes)
+            .field("histogram_consume_after_ms", &self.histogram_consume_after_ms)
+            .field("rate_limit", &self.rate_limit)
+            .field("rate_limit_window_ms", &self.rate_limit_window_ms)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("link_signing_secret", &redact(&self.link_signing_secret))
+            .field("signed_links_consume", &self.signed_links_consume)
+            .field("quota_window_ms", &self.quota_window_ms)
+            .field("quota_max_files", &self.quota_max_files)
+            .field("quota_max_bytes", &self.quota_max_bytes)
+            .field("quota_max_links", &self.quota_max_links)
+            .field("require_confirm", &self.require_confirm)
+            .field("confirm_nonce_ttl_ms", &self.confirm_nonce_ttl_ms)
+            .finish()
+    }
+}
+
+// "onetime/", "/onetime" and "/onetime/" are all "/onetime", so urls can be built with format!("{}/download", prefix)
+fn path_prefix (value: &str) -> String {
+    let trimmed = value.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+// "a:links_a,b:links_b", a namespace is a path segment so it is held to what a token may be
+fn parse_namespaces (value: &str, errors: &mut Vec<String>) -> Vec<(String, String)> {
+    let mut namespaces: Vec<(String, String)> = Vec::new();
+    for item in split_list(value) {
+        let mut parts = item.splitn(2, ':');
+        let namespace = parts.next().unwrap_or("").trim().to_string();
+        let table = parts.next().unwrap_or("").trim().to_string();
+        if table.is_empty() || validate_token(namespace.as_str()).is_err() {
+            errors.push(format!("LINK_NAMESPACES entry '{}' is not namespace:table", item));
+        } else if namespaces.iter().any(|(seen, _)| *seen == namespace) {
+            errors.push(format!("LINK_NAMESPACES has namespace '{}' more than once", namespace));
+        } else {
+            namespaces.push((namespace, table));
+        }
+    }
+    namespaces
+}
+
+// a bare address is accepted as a single host network
+pub fn parse_cidr (cidr: &str) -> Result<IpNet, String> {
+    match cidr.parse::<IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => cidr.parse::<IpAddr>()
+            .map(IpNet::from)
+            .map_err(|why| format!("Invalid cidr '{}': {}", cidr, why)),
+    }
+}
+
+// a typo here would quietly stop believing the proxy, and every client would look like it
+fn parse_trusted_proxies (value: &str, errors: &mut Vec<String>) -> Vec<IpNet> {
+    let mut proxies = Vec::new();
+    for item in split_list(value) {
+        match parse_cidr(item.as_str()) {
+            Ok(net) => proxies.push(net),
+            Err(why) => errors.push(format!("TRUSTED_PROXIES {}", why)),
+        }
+    }
+    proxies
+}
+
+// comma separated, blanks dropped, so an empty var is an empty list
+fn split_list (value: &str) -> Vec<String> {
+    value.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()).map(|item| item.to_string()).collect()
+}
+
+// "pdf, .PNG" is the same as "pdf,png"
+fn split_extensions (value: &str) -> Vec<String> {
+    split_list(value).iter().map(|item| item.trim_start_matches('.').to_lowercase()).filter(|item| !item.is_empty()).collect()
+}
+
+// enough to tell whether it is set, and which of two keys it is by length, nothing more
+fn redact (secret: &str) -> String {
+    if secret.is_empty() { String::new() } else { format!("<redacted, {} chars>", secret.len()) }
 }
 
 impl OnetimeDownloaderConfig {
@@ -32,9 +387,13 @@ impl OnetimeDownloaderConfig {
         env::var(name).unwrap_or(default)
     }
 
-    fn env_var_parse<T : std::str::FromStr> (name: &str, default: T) -> T {
+    // for the storage providers' own vars, which are not in the startup report
+    pub fn env_var_parse<T : std::str::FromStr> (name: &str, default: T) -> T {
         match env::var(name) {
-            Ok(s) => s.parse::<T>().unwrap_or(default),
+            Ok(s) => s.parse::<T>().unwrap_or_else(|_| {
+                println!("{}={:?} is not valid, using the default", name, s);
+                default
+            }),
             _ => default
         }
     }
@@ -42,23 +401,201 @@ impl OnetimeDownloaderConfig {
     // maybe TODO? https://github.com/actix/examples/blob/ec6e14aacc10bf4d44309ddb73fe01f9c27faf6f/async_pg/src/main.rs#L10
     // seems very ubiquitous: https://crates.io/crates/config
     pub fn from_env () -> OnetimeDownloaderConfig {
-        OnetimeDownloaderConfig {
-            provider: Self::env_var_string("ONETIME_PROVIDER", EMPTY_STRING),
-            api_key_files: Self::env_var_string("FILES_API_KEY", EMPTY_STRING),
-            api_key_links: Self::env_var_string("LINKS_API_KEY", EMPTY_STRING),
-            max_len_file: Self::env_var_parse("FILE_MAX_LEN", DEFAULT_MAX_LEN_FILE),
-            max_len_value: Self::env_var_parse("VALUE_MAX_LEN", DEFAULT_MAX_LEN_VALUE),
-            default_expiration_ms: Self::env_var_parse("LINK_EXPIRATION", DEFAULT_EXPIRATION_MS),
+        Self::from_env_report().0
+    }
+
+    // the report lists every var with its effective value, and any that were invalid or missing
+    pub fn from_env_report () -> (OnetimeDownloaderConfig, EnvReport) {
+        let mut env = EnvReport { lines: Vec::new(), errors: Vec::new() };
+        let mut config = OnetimeDownloaderConfig {
+            provider: env.string("ONETIME_PROVIDER", EMPTY_STRING),
+            api_key_files: env.secret("FILES_API_KEY"),
+            api_key_links: env.secret("LINKS_API_KEY"),
+            api_key_admin: env.secret("ADMIN_API_KEY"),
+            admin_totp_secret: env.secret("ADMIN_TOTP_SECRET"),
+            max_len_file: env.parse("FILE_MAX_LEN", DEFAULT_MAX_LEN_FILE),
+            max_len_raw_file: env.parse("MAX_LEN_RAW_FILE", 0),
+            min_len_file: env.parse("MIN_LEN_FILE", DEFAULT_MIN_LEN_FILE),
+            max_len_value: env.parse("VALUE_MAX_LEN", DEFAULT_MAX_LEN_VALUE),
+            max_len_filename: env.parse("MAX_FILENAME_LEN", DEFAULT_MAX_LEN_FILENAME),
+            max_len_note: env.parse("MAX_NOTE_LEN", DEFAULT_MAX_LEN_NOTE),
+            default_expiration_ms: env.parse("LINK_EXPIRATION", DEFAULT_EXPIRATION_MS),
+            expiry_skew_ms: env.parse("EXPIRY_SKEW_MS", DEFAULT_EXPIRY_SKEW_MS),
+            link_note_template: env.string("LINK_NOTE_TEMPLATE", EMPTY_STRING),
+            html_download_errors: env.parse("HTML_DOWNLOAD_ERRORS", true),
+            download_error_template: env.string("DOWNLOAD_ERROR_TEMPLATE", EMPTY_STRING),
+            token_format: env.parse("TOKEN_FORMAT", DEFAULT_TOKEN_FORMAT),
+            trim_trailing_slash: env.parse("TRIM_TRAILING_SLASH", false),
+            path_prefix: path_prefix(env.string("PATH_PREFIX", EMPTY_STRING).as_str()),
+            strip_path_prefix: env.parse("STRIP_PATH_PREFIX", false),
+            link_namespaces: parse_namespaces(env.string("LINK_NAMESPACES", EMPTY_STRING).as_str(), &mut env.errors),
+            hash_tokens: env.parse("HASH_TOKENS", false),
+            disable_list_endpoints: env.parse("DISABLE_LIST_ENDPOINTS", false),
+            link_events_heartbeat_ms: env.parse("LINK_EVENTS_HEARTBEAT_MS", DEFAULT_LINK_EVENTS_HEARTBEAT_MS),
+            case_insensitive_tokens: env.parse("CASE_INSENSITIVE_TOKENS", false),
+            show_consumption_details: env.parse("SHOW_CONSUMPTION_DETAILS", false),
+            lenient_lists: env.parse("LENIENT_LISTS", false),
+            strict_startup: env.parse("STRICT_STARTUP", false),
+            otel_endpoint: env.string("OTEL_EXPORTER_OTLP_ENDPOINT", EMPTY_STRING),
+            not_found_body: env.string("NOT_FOUND_BODY", String::from(DEFAULT_NOT_FOUND_BODY)),
+            request_timeout_ms: env.parse("REQUEST_TIMEOUT_MS", 0),
+            upload_timeout_ms: env.parse("UPLOAD_TIMEOUT_MS", 0),
+            upload_idle_timeout_ms: env.parse("UPLOAD_IDLE_TIMEOUT_MS", DEFAULT_UPLOAD_IDLE_TIMEOUT_MS),
+            upload_min_bytes_per_s: env.parse("UPLOAD_MIN_BYTES_PER_S", 0),
+            upload_min_rate_grace_ms: env.parse("UPLOAD_MIN_RATE_GRACE_MS", DEFAULT_UPLOAD_MIN_RATE_GRACE_MS),
+            tus_dir: env.string("TUS_DIR", EMPTY_STRING),
+            tus_expiry_ms: env.parse("TUS_EXPIRY_MS", DEFAULT_TUS_EXPIRY_MS),
+            list_cache_max_age_s: env.parse("LIST_CACHE_MAX_AGE_S", DEFAULT_LIST_CACHE_MAX_AGE_S),
+            default_max_downloads: env.parse("DEFAULT_MAX_DOWNLOADS", DEFAULT_MAX_DOWNLOADS),
+            max_max_downloads: env.parse("MAX_MAX_DOWNLOADS", DEFAULT_MAX_MAX_DOWNLOADS),
+            max_len_base64_download: env.parse("MAX_BASE64_DOWNLOAD_LEN", DEFAULT_MAX_LEN_BASE64_DOWNLOAD),
+            default_download_name: env.string("DEFAULT_DOWNLOAD_NAME", String::from(DEFAULT_DOWNLOAD_NAME)),
+            allowed_extensions: split_extensions(env.string("ALLOWED_EXTENSIONS", EMPTY_STRING).as_str()),
+            blocked_extensions: split_extensions(env.string("BLOCKED_EXTENSIONS", EMPTY_STRING).as_str()),
+            inline_content_types: split_list(env.string("INLINE_CONTENT_TYPES", String::from(DEFAULT_INLINE_CONTENT_TYPES)).as_str()),
+            default_content_type: env.string("DEFAULT_CONTENT_TYPE", String::from(DEFAULT_CONTENT_TYPE)),
+            sniff_content_type: env.parse("SNIFF_CONTENT_TYPE", false),
+            not_found_content_type: env.string("NOT_FOUND_CONTENT_TYPE", String::from(DEFAULT_NOT_FOUND_CONTENT_TYPE)),
+            geoip_db: env.string("GEOIP_DB", EMPTY_STRING),
+            immutable_files: env.parse("IMMUTABLE_FILES", false),
+            soft_delete: env.parse("SOFT_DELETE", false),
+            delete_missing_is_404: env.parse("DELETE_MISSING_IS_404", false),
+            delete_after_download: env.parse("DELETE_AFTER_DOWNLOAD", false),
+            delete_after_download_delay_ms: env.parse("DELETE_AFTER_DOWNLOAD_DELAY_MS", 0),
+            delete_file_after_download: env.parse("DELETE_FILE_AFTER_DOWNLOAD", false),
+            tombstone_retention_ms: env.parse("TOMBSTONE_RETENTION_MS", DEFAULT_TOMBSTONE_RETENTION_MS),
+            link_info_retention_ms: env.parse("LINK_INFO_RETENTION_MS", DEFAULT_LINK_INFO_RETENTION_MS),
+            consume_after_flush: env.parse("CONSUME_AFTER_FLUSH", false),
+            consume_retries: env.parse("CONSUME_RETRIES", 0),
+            consume_retry_delay_ms: env.parse("CONSUME_RETRY_DELAY_MS", DEFAULT_CONSUME_RETRY_DELAY_MS),
+            missing_file_gone: env.parse("MISSING_FILE_GONE", false),
+            post_download_redirect: env.string("POST_DOWNLOAD_REDIRECT", EMPTY_STRING),
+            max_concurrent_downloads: env.parse("MAX_CONCURRENT_DOWNLOADS", 0),
+            dedupe_downloads: env.parse("DEDUPE_DOWNLOADS", false),
+            download_slot_timeout_ms: env.parse("DOWNLOAD_SLOT_TIMEOUT_MS", DEFAULT_DOWNLOAD_SLOT_TIMEOUT_MS),
+            max_bulk_links: env.parse("MAX_BULK_LINKS", DEFAULT_MAX_BULK_LINKS),
+            max_bulk_files: env.parse("MAX_BULK_FILES", DEFAULT_MAX_BULK_FILES),
+            migrate_from: env.string("MIGRATE_FROM", EMPTY_STRING),
+            migrate_to: env.string("MIGRATE_TO", EMPTY_STRING),
+            migrate_overwrite: env.parse("MIGRATE_OVERWRITE", false),
+            seed_file: env.string("SEED_FILE", EMPTY_STRING),
+            seed_on_start: env.parse("SEED_ON_START", false),
+            log_referer: env.parse("LOG_REFERER", false),
+            access_log_level: env.parse("ACCESS_LOG_LEVEL", DEFAULT_ACCESS_LOG_LEVEL),
+            access_log_format: env.parse("ACCESS_LOG_FORMAT", DEFAULT_ACCESS_LOG_FORMAT),
+            slow_storage_ms: env.parse("SLOW_STORAGE_MS", DEFAULT_SLOW_STORAGE_MS),
+            histogram_download_ms: env.parse("HISTOGRAM_DOWNLOAD_MS", Buckets(DEFAULT_HISTOGRAM_DOWNLOAD_MS.to_vec())),
+            histogram_file_size_bytes: env.parse("HISTOGRAM_FILE_SIZE_BYTES", Buckets(DEFAULT_HISTOGRAM_FILE_SIZE_BYTES.to_vec())),
+            histogram_consume_after_ms: env.parse("HISTOGRAM_CONSUME_AFTER_MS", Buckets(DEFAULT_HISTOGRAM_CONSUME_AFTER_MS.to_vec())),
+            rate_limit: env.parse("RATE_LIMIT", 0),
+            rate_limit_window_ms: env.parse("RATE_LIMIT_WINDOW_MS", DEFAULT_RATE_LIMIT_WINDOW_MS),
+            trusted_proxies: parse_trusted_proxies(env.string("TRUSTED_PROXIES", EMPTY_STRING).as_str(), &mut env.errors),
+            link_signing_secret: env.secret("LINK_SIGNING_SECRET"),
+            signed_links_consume: env.parse("SIGNED_LINKS_CONSUME", false),
+            quota_window_ms: env.parse("QUOTA_WINDOW_MS", DEFAULT_QUOTA_WINDOW_MS),
+            quota_max_files: env.parse("QUOTA_MAX_FILES", 0),
+            quota_max_bytes: env.parse("QUOTA_MAX_BYTES", 0),
+            quota_max_links: env.parse("QUOTA_MAX_LINKS", 0),
+            require_confirm: env.parse("REQUIRE_CONFIRM", false),
+            confirm_nonce_ttl_ms: env.parse("CONFIRM_NONCE_TTL_MS", DEFAULT_CONFIRM_NONCE_TTL_MS),
+        };
+
+        // migrating is the only thing that can run without a provider
+        if config.provider.is_empty() && (config.migrate_from.is_empty() || config.migrate_to.is_empty()) {
+            env.errors.push("ONETIME_PROVIDER is not set".to_string());
+        }
+        // every admin write would then 401, with nothing to say why
+        if !config.admin_totp_secret.is_empty() && !totp::valid_secret(config.admin_totp_secret.as_str()) {
+            env.errors.push("ADMIN_TOTP_SECRET is not valid base32".to_string());
+        }
+        // a relative or javascript: one would be an open redirect for every confirmed download
+        let redirect = config.post_download_redirect.as_str();
+        if !redirect.is_empty() && !(redirect.starts_with("https://") || redirect.starts_with("http://")) {
+            env.errors.push("POST_DOWNLOAD_REDIRECT is not an http(s) url".to_string());
+        }
+        // base62 tokens differ only by case, folding them would merge distinct links
+        if config.case_insensitive_tokens && config.token_format == TokenFormat::Base62 {
+            env.errors.push("CASE_INSENSITIVE_TOKENS does nothing with TOKEN_FORMAT=base62".to_string());
+        }
+        if config.max_len_raw_file == 0 {
+            config.max_len_raw_file = config.max_len_file;
+        }
+        (config, env)
+    }
+}
+
+// a typo'd value used to silently become the default, now it at least shows up here
+pub struct EnvReport {
+    pub lines: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl EnvReport {
+    fn string (&mut self, name: &str, default: String) -> String {
+        match env::var(name) {
+            Ok(value) => {
+                self.lines.push(format!("{}={:?}", name, value));
+                value
+            }
+            _ => {
+                self.lines.push(format!("{}={:?} (default)", name, default));
+                default
+            }
+        }
+    }
+
+    // only whether it is set gets reported, never the value
+    fn secret (&mut self, name: &str) -> String {
+        let value = env::var(name).unwrap_or(EMPTY_STRING);
+        self.lines.push(format!("{}={}", name, if value.is_empty() { "(not set)" } else { "(redacted)" }));
+        value
+    }
+
+    fn parse<T : FromStr + fmt::Debug> (&mut self, name: &str, default: T) -> T {
+        match env::var(name) {
+            Ok(s) => match s.parse::<T>() {
+                Ok(value) => {
+                    self.lines.push(format!("{}={:?}", name, value));
+                    value
+                }
+                Err(_) => {
+                    self.errors.push(format!("{}={:?} is not valid, using the default {:?}", name, s, default));
+                    self.lines.push(format!("{}={:?} (default, invalid value given)", name, default));
+                    default
+                }
+            },
+            _ => {
+                self.lines.push(format!("{}={:?} (default)", name, default));
+                default
+            }
         }
     }
 }
 
+// all the *_at fields here and on links are unix ms, as from TimeProvider::unix_ts_ms
 #[derive(Debug, Clone)]
 pub struct OnetimeFile {
     pub filename: String,
     pub contents: Bytes,
     pub created_at: i64,
     pub updated_at: i64,
+    // None keeps the file until it is deleted, links to it or not
+    pub expires_at: Option<i64>,
+    // resolved when uploaded, None for files stored before it was, which go by their extension
+    pub content_type: Option<String>,
+}
+
+impl OnetimeFile {
+    pub fn is_expired (&self, now: i64) -> bool {
+        match self.expires_at {
+            None => false,
+            Some(expires_at) => expires_at < now,
+        }
+    }
+
+    pub fn info (&self) -> FileInfo {
+        FileInfo::new(self.filename.clone(), self.content_type.clone(), self.contents.len(), self.created_at, self.updated_at)
+    }
 }
 
 // https://serde.rs/impl-serialize.html
@@ -67,17 +604,19 @@ impl Serialize for OnetimeFile {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("OnetimeFile", 4)?;
+        let mut state = serializer.serialize_struct("OnetimeFile", 6)?;
         state.serialize_field("filename", &self.filename)?;
         // only size of contents because we don't want to send entire files back... (and no default serializer for bytes)
         state.serialize_field("contents_len", &self.contents.len())?;
         state.serialize_field("created_at", &self.created_at)?;
         state.serialize_field("updated_at", &self.updated_at)?;
+        state.serialize_field("expires_at", &self.expires_at)?;
+        state.serialize_field("content_type", &self.content_type)?;
         state.end()
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OnetimeLink {
     pub token: String,
     pub filename: String,
@@ -86,6 +625,276 @@ pub struct OnetimeLink {
     pub expires_at: i64,
     pub downloaded_at: Option<i64>,
     pub ip_address: Option<String>,
+    #[serde(default)]
+    pub attempts: i64,
+    pub last_attempt_at: Option<i64>,
+    // base32, never sent back out once the link is created
+    #[serde(skip_serializing, default)]
+    pub totp_secret: Option<String>,
+    // empty means any address may download
+    #[serde(default)]
+    pub allowed_cidrs: Vec<String>,
+    // ISO 3166-1 alpha-2 codes, only checked when a GEOIP_DB is configured
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+    pub deleted_at: Option<i64>,
+    pub redirect_url: Option<String>,
+    // downloaded_at is only set by the last allowed download, that is what consumes the link
+    #[serde(default)]
+    pub downloads: i64,
+    #[serde(default = "default_max_downloads")]
+    pub max_downloads: i64,
+    // set by an admin revoking the link, which also sets downloaded_at so nothing can consume it after
+    #[serde(default)]
+    pub revoked_at: Option<i64>,
+}
+
+// links from before max_downloads existed were all single use
+fn default_max_downloads () -> i64 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkState {
+    Active,
+    Consumed,
+    Revoked,
+    Expired,
+    Deleted,
+}
+
+// cheap polling for whether a link was picked up, tombstoned links are missing
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    Pending,
+    Consumed,
+    Revoked,
+    Expired,
+    Missing,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenStatus {
+    pub token: String,
+    pub status: LinkStatus,
+}
+
+// links that expired unused, with how many of them were for each file
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiredUnusedLinks {
+    pub count: usize,
+    pub files: BTreeMap<String, usize>,
+    pub links: Vec<OnetimeLink>,
+}
+
+// a file without its contents, for admin uis
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: usize,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub etag: String,
+}
+
+impl FileInfo {
+    // updated_at goes up with every change to the contents, so it versions the file without hashing it
+    //  https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/ETag
+    pub fn new (filename: String, content_type: Option<String>, size: usize, created_at: i64, updated_at: i64) -> Self {
+        Self {
+            filename: filename,
+            content_type: content_type,
+            size: size,
+            created_at: created_at,
+            updated_at: updated_at,
+            etag: format!("\"{}\"", updated_at),
+        }
+    }
+
+    // the updated_at an etag from above was made from, None for anything else, weak etags included since
+    //  If-Match only ever compares strong ones https://www.rfc-editor.org/rfc/rfc7232#section-3.1
+    pub fn etag_updated_at (etag: &str) -> Option<i64> {
+        let etag = etag.trim();
+        if etag.len() < 2 || !etag.starts_with('"') || !etag.ends_with('"') {
+            return None;
+        }
+        etag[1..etag.len() - 1].parse::<i64>().ok()
+    }
+}
+
+// what the link's creator sees, the anonymous download path never says which state a link is in
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkInfo {
+    #[serde(flatten)]
+    pub link: OnetimeLink,
+    pub state: LinkState,
+}
+
+// a backup has to be restorable, so unlike the link itself this includes the totp secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkRecord {
+    #[serde(flatten)]
+    pub link: OnetimeLink,
+    pub totp_secret: Option<String>,
+}
+
+impl From<OnetimeLink> for LinkRecord {
+    fn from (link: OnetimeLink) -> Self {
+        Self {
+            totp_secret: link.totp_secret.clone(),
+            link: link,
+        }
+    }
+}
+
+impl From<LinkRecord> for OnetimeLink {
+    fn from (record: LinkRecord) -> Self {
+        Self {
+            totp_secret: record.totp_secret,
+            ..record.link
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteFileQuery {
+    // its links too, in one transaction
+    pub links: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkFilesSummary {
+    pub added: Vec<String>,
+    // already existed, only without overwrite
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub errors: Vec<ImportError>,
+}
+
+impl OnetimeLink {
+    // skew_ms is a grace window for clocks that disagree, so a link stays usable that long past expires_at
+    pub fn is_expired (&self, now: i64, skew_ms: i64) -> bool {
+        self.expires_at + skew_ms < now
+    }
+
+    // deleted wins over revoked, then consumed, then expired
+    pub fn state (&self, now: i64, skew_ms: i64) -> LinkState {
+        if self.deleted_at.is_some() {
+            LinkState::Deleted
+        } else if self.revoked_at.is_some() {
+            LinkState::Revoked
+        } else if self.downloaded_at.is_some() {
+            LinkState::Consumed
+        } else if self.is_expired(now, skew_ms) {
+            LinkState::Expired
+        } else {
+            LinkState::Active
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadMetrics {
+    pub downloads_in_flight: usize,
+    pub max_concurrent_downloads: usize,
+    // null for storage without a connection pool
+    pub storage_pool: Option<PoolStatus>,
+    // file contents received so far, counted in batches so it can trail an upload in progress slightly
+    pub upload_bytes_total: u64,
+    pub uploads_in_flight: usize,
+    // since this process started, see README "Histograms"
+    pub download_ms: HistogramSnapshot,
+    pub file_size_bytes: HistogramSnapshot,
+    pub consume_after_ms: HistogramSnapshot,
+    // per storage method, since this process started
+    pub storage_ops: BTreeMap<String, StorageOpStats>,
+}
+
+#[derive(Deserialize)]
+pub struct MetricsQuery {
+    // prometheus for the text exposition format instead of json
+    pub format: Option<String>,
+}
+
+// https://docs.rs/deadpool/0.5/deadpool/struct.Status.html
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    pub max_size: usize,
+    pub size: usize,
+    // negative when requests are waiting for a connection
+    pub available: isize,
+}
+
+// written to the log as one json line per download attempt, missing headers are null
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadAttempt {
+    pub token: String,
+    pub ip_address: String,
+    pub attempted_at: i64,
+    pub referer: Option<String>,
+    pub origin: Option<String>,
+}
+
+// nothing here should help an attacker beyond what trying the endpoints would tell them
+#[derive(Clone, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    // null when built without GIT_SHA set
+    pub git_sha: Option<&'static str>,
+    pub backend: &'static str,
+    // whether each key is required, never the keys themselves
+    pub files_auth: bool,
+    pub links_auth: bool,
+    pub admin_auth: bool,
+    // as the request reached the server, https when a proxy in front says so
+    pub tls: bool,
+}
+
+// add_link's body for Accept: application/json, the plain text default is just the token
+#[derive(Clone, Serialize)]
+pub struct CreatedLink {
+    pub token: String,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaExceeded {
+    pub error: String,
+    pub owner: String,
+    pub used: i64,
+    pub max: i64,
+    pub window_resets_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsumedLink {
+    pub error: String,
+    pub downloaded_at: i64,
+    // who consumed the link is sensitive, so only included when configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -93,6 +902,115 @@ pub struct CreateLink {
     pub filename: String,
     pub note: Option<String>,
     pub expires_at: Option<i64>,
+    pub require_totp: Option<bool>,
+    pub allowed_cidrs: Option<Vec<String>>,
+    pub allowed_countries: Option<Vec<String>>,
+    pub blocked_countries: Option<Vec<String>>,
+    pub redirect_url: Option<String>,
+    pub max_downloads: Option<i64>,
+}
+
+// a signed link has nothing stored, so only what goes into its signature
+#[derive(Deserialize)]
+pub struct SignLink {
+    pub filename: String,
+    pub expires_at: Option<i64>,
+}
+
+// one per requested link, in the same order, with either the token or why it was not created
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkLinkResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    // no per item headers in a bulk response, so the secret comes back in the body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    CreatedAt,
+    ExpiresAt,
+    Filename,
+}
+
+impl FromStr for SortField {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created_at" => Ok(SortField::CreatedAt),
+            "expires_at" => Ok(SortField::ExpiresAt),
+            "filename" => Ok(SortField::Filename),
+            _ => Err(format!("Unknown sort field '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(format!("Unknown sort order '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ListSort {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+impl Default for ListSort {
+    fn default () -> Self {
+        Self {
+            field: SortField::CreatedAt,
+            order: SortOrder::Desc,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FilesExist {
+    pub filenames: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    pub sort: Option<String>,
+    pub order: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmForm {
+    pub nonce: String,
+}
+
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    pub code: Option<String>,
+    // base64 for a json body instead of the raw file
+    pub format: Option<String>,
+}
+
+// for clients that can't take a binary body
+#[derive(Debug, Clone, Serialize)]
+pub struct Base64Download {
+    pub filename: String,
+    pub content_type: String,
+    pub data_base64: String,
 }
 
 // https://github.com/dtolnay/async-trait#non-threadsafe-futures
@@ -100,17 +1018,126 @@ pub struct CreateLink {
 #[clonable]
 pub trait OnetimeStorage : Clone {
     fn name(&self) -> &'static str;
+    fn pool_status (&self) -> Option<PoolStatus> {
+        None
+    }
+    // why no storage call can ever work with this config, as opposed to one that failed this time
+    //  only the invalid provider has one, every request to the storage is then a 503 until the config is fixed
+    fn unconfigured (&self) -> Option<MyError> {
+        None
+    }
+    // the same storage reading and writing links in another table, for LINK_NAMESPACES, sharing its connections
+    fn with_links_table (&self, _table: &str) -> Result<Box<dyn OnetimeStorage>, MyError> {
+        Err(format!("Storage {} has no links tables to choose from", self.name()))
+    }
+    async fn ping (&self) -> Result<bool, MyError>;
     async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError>;
-    async fn list_files (&self) -> Result<Vec<OnetimeFile>, MyError>;
+    // backends that can write incrementally should override this, the default buffers everything for add_file
+    async fn add_file_stream (&self, filename: String, now: i64, expires_at: Option<i64>, content_type: Option<String>, mut stream: LocalBoxStream<'static, Result<Bytes, MyError>>) -> Result<bool, MyError> {
+        let mut contents = BytesMut::new();
+        while let Some(chunk) = stream.next().await {
+            contents.extend_from_slice(&chunk?);
+        }
+
+        self.add_file(OnetimeFile {
+            filename: filename,
+            contents: contents.freeze(),
+            created_at: now,
+            updated_at: now,
+            expires_at: expires_at,
+            content_type: content_type,
+        }).await
+    }
+    // never replaces an existing file, false when the filename is already taken
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError>;
+    // many at once for imports and migrations, returns the filenames actually written
+    //  without overwrite existing files are left alone, the filenames must be unique
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError>;
+    // only replaces an existing file, false when there is no file by that name
+    //  its content type too when given, otherwise the stored one is kept
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError>;
+    // files without an expires_at sort as if they never expire
+    // with how many rows were skipped as unreadable, always 0 unless LENIENT_LISTS
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>;
+    // an expired file is treated as missing, even before it is purged
     async fn get_file (&self, filename: String) -> Result<OnetimeFile, MyError>;
+    // the same without the contents, backends that can size them in place should override this, the default reads them
+    async fn get_file_info (&self, filename: String) -> Result<FileInfo, MyError> {
+        self.get_file(filename).await.map(|file| file.info())
+    }
+    // a page at a time with the cursor for the next page, None after the last
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError>;
+    // without loading any contents, every given filename is in the result
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError>;
     async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError>;
-    async fn list_links (&self) -> Result<Vec<OnetimeLink>, MyError>;
+    // one batched write for all of them, the links are already validated
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError>;
+    // as they are, consumed or not, returns the tokens actually written, without overwrite existing ones are left alone
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError>;
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError>;
+    // every link, deleted ones too, a page at a time with the cursor for the next page, None after the last
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError>;
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError>;
     async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError>;
-    async fn mark_downloaded (&self, link: OnetimeLink, ip_address: String, downloaded_at: i64) -> Result<bool, MyError>;
+    // like get_link, but tombstoned links are returned too
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError>;
+    // the full record for admins, whatever state it is in, never filtered by consumed, expired, revoked or deleted
+    //  kept apart from the reads the download path uses, so a change there can't hide records from admins
+    async fn get_link_admin (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.get_any_link(token).await
+    }
+    // reads only the few fields that decide it, never consumes
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError>;
+    // atomically counts a download and returns the link, only ever succeeds max_downloads times per token
+    //  the last of those also marks it downloaded
+    //  repeating it with the same attempt_id never counts another download, it returns the link as that attempt left it,
+    //  so it is safe to retry after an error that leaves unknown whether the first try went through
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError>;
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError>;
+    // tombstones the link, every other link read then treats it as missing
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError>;
+    // false when there is no live, unconsumed link to revoke
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError>;
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError>;
+    // expired before expired_before without ever being used, and not deleted (revoking sets downloaded_at too)
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError>;
+    // false when not tombstoned, or tombstoned before deleted_after
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError>;
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError>;
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError>;
+    // all of the writes or none of them, for anything that must change more than one row together
+    //  backends that can't do that return an error rather than applying some of them
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError>;
+    // atomically adds to the owner's counters for the window starting at window_start, from zero when new,
+    //  and returns the totals after, negative amounts take back an earlier add
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError>;
     async fn delete_file(&self, filename: String) -> Result<bool, MyError>;
+    // only while the file is still as updated_at left it, which is what its etag is made from
+    //  false when it was changed since, or is gone
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError>;
     async fn delete_link(&self, token: String) -> Result<bool, MyError>;
 }
 
+// one write in a transaction, deletes of missing keys are no-ops
+#[derive(Clone)]
+pub enum StorageOp {
+    DeleteFile(String),
+    DeleteLink(String),
+    // tombstones the link at the time given, as soft_delete_link, one already deleted keeps its time
+    SoftDeleteLink(String, i64),
+}
+
+impl StorageOp {
+    // for errors, without dumping file contents into them
+    pub fn name (&self) -> String {
+        match self {
+            StorageOp::DeleteFile(filename) => format!("delete file {}", filename),
+            StorageOp::DeleteLink(token) => format!("delete link {}", token),
+            StorageOp::SoftDeleteLink(token, _) => format!("soft delete link {}", token),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct OnetimeDownloaderService {
     // box vs generics: dynamic vs static dispatch
@@ -118,4 +1145,112 @@ pub struct OnetimeDownloaderService {
     pub time_provider: Box<dyn TimeProvider>,
     pub config: OnetimeDownloaderConfig,
     pub storage: Box<dyn OnetimeStorage>,
+    // LINK_NAMESPACES, each the same storage with its own links table
+    pub namespaces: HashMap<String, Box<dyn OnetimeStorage>>,
+    pub geoip: Option<GeoIp>,
+    // the page for failed browser downloads, read once at startup
+    pub download_error_template: String,
+    pub download_slots: DownloadSlots,
+    pub rate_limiter: RateLimiter,
+    pub nonces: NonceStore,
+    pub uploads: UploadMetrics,
+    pub in_flight: InFlightTokens,
+    // resumable uploads with a PATCH being written right now
+    pub tus_patches: InFlightTokens,
+    pub link_events: LinkEvents,
+    pub histograms: UsageHistograms,
+    pub storage_timings: StorageTimings,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link (expires_at: i64) -> OnetimeLink {
+        serde_json::from_str(format!(r#"{{"token":"abc","filename":"a.txt","created_at":0,"expires_at":{}}}"#, expires_at).as_str()).unwrap()
+    }
+
+    #[test]
+    fn config_debug_redacts_secrets () {
+        let (mut config, _) = OnetimeDownloaderConfig::from_env_report();
+        config.api_key_files = "files-key-0123456789".to_string();
+        config.api_key_links = "links-key-0123456789".to_string();
+        config.api_key_admin = "admin-key-0123456789".to_string();
+        config.admin_totp_secret = "JBSWY3DPEHPK3PXP".to_string();
+        config.link_signing_secret = "signing-secret-0123456789".to_string();
+
+        let debug = format!("{:?}", config);
+        for secret in &["files-key-0123456789", "links-key-0123456789", "admin-key-0123456789", "JBSWY3DPEHPK3PXP", "signing-secret-0123456789"] {
+            assert!(!debug.contains(secret), "{} in {}", secret, debug);
+        }
+        assert!(debug.contains("api_key_files: \"<redacted, 20 chars>\""));
+    }
+
+    #[test]
+    fn config_debug_shows_unset_secrets_empty () {
+        let (mut config, _) = OnetimeDownloaderConfig::from_env_report();
+        config.api_key_admin = String::new();
+        assert!(format!("{:?}", config).contains("api_key_admin: \"\""));
+    }
+
+    #[test]
+    fn env_report_never_lists_secret_values () {
+        env::set_var("ONETIME_TEST_REPORTED_SECRET", "hunter2");
+        let mut env = EnvReport { lines: Vec::new(), errors: Vec::new() };
+        assert_eq!(env.secret("ONETIME_TEST_REPORTED_SECRET"), "hunter2");
+        assert_eq!(env.secret("ONETIME_TEST_UNSET_SECRET"), "");
+        assert_eq!(env.lines, vec!["ONETIME_TEST_REPORTED_SECRET=(redacted)", "ONETIME_TEST_UNSET_SECRET=(not set)"]);
+    }
+
+    #[test]
+    fn link_expires_after_expires_at () {
+        let link = link(1000);
+        assert!(!link.is_expired(999, 0));
+        assert!(!link.is_expired(1000, 0));
+        assert!(link.is_expired(1001, 0));
+    }
+
+    #[test]
+    fn link_expiry_skew_is_grace () {
+        let link = link(1000);
+        assert!(!link.is_expired(1500, 500));
+        assert!(link.is_expired(1501, 500));
+    }
+
+    #[test]
+    fn link_state_order () {
+        let mut link = link(1000);
+        assert_eq!(link.state(0, 0), LinkState::Active);
+        assert_eq!(link.state(2000, 0), LinkState::Expired);
+        assert_eq!(link.state(2000, 5000), LinkState::Active);
+        link.downloaded_at = Some(500);
+        assert_eq!(link.state(2000, 0), LinkState::Consumed);
+        link.revoked_at = Some(600);
+        assert_eq!(link.state(0, 0), LinkState::Revoked);
+        link.deleted_at = Some(700);
+        assert_eq!(link.state(0, 0), LinkState::Deleted);
+    }
+
+    #[test]
+    fn stored_links_without_max_downloads_are_single_use () {
+        let link = link(1000);
+        assert_eq!(link.max_downloads, 1);
+        assert_eq!(link.downloads, 0);
+    }
+
+    #[test]
+    fn path_prefix_normalized () {
+        assert_eq!(path_prefix(""), "");
+        assert_eq!(path_prefix(" / "), "");
+        assert_eq!(path_prefix("onetime"), "/onetime");
+        assert_eq!(path_prefix("/onetime/"), "/onetime");
+        assert_eq!(path_prefix("//apps/onetime//"), "/apps/onetime");
+    }
+
+    #[test]
+    fn raw_file_max_defaults_to_file_max () {
+        let (config, _) = OnetimeDownloaderConfig::from_env_report();
+        assert_eq!(config.max_len_raw_file, config.max_len_file);
+    }
 }
@@ -0,0 +1,48 @@
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::tokens::{generate_token, TokenFormat};
+use crate::locks;
+
+
+struct Issued {
+    token: String,
+    expires_at: i64,
+}
+
+// one-use nonces for the confirm page
+//  random and only ever known to this process, so one can't be made up, only replayed, which taking it prevents
+#[derive(Clone)]
+pub struct NonceStore {
+    ttl_ms: i64,
+    issued: Arc<Mutex<HashMap<String, Issued>>>,
+}
+
+impl NonceStore {
+    pub fn new (ttl_ms: i64) -> Self {
+        Self {
+            ttl_ms: ttl_ms,
+            issued: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // good for one confirm of this token only, until ttl_ms from now
+    pub fn issue (&self, token: &str, now: i64) -> String {
+        let nonce = generate_token(TokenFormat::Hex);
+        let mut issued = locks::lock(&self.issued);
+        // expired nonces go whenever a new one is issued, so the set only holds the last ttl's worth
+        issued.retain(|_, issued| issued.expires_at >= now);
+        issued.insert(nonce.clone(), Issued { token: token.to_string(), expires_at: now + self.ttl_ms });
+        nonce
+    }
+
+    // removes it either way, so a second submit of the same nonce always fails
+    pub fn take (&self, nonce: &str, token: &str, now: i64) -> bool {
+        let mut issued = locks::lock(&self.issued);
+        match issued.remove(nonce) {
+            None => false,
+            Some(issued) => issued.token == token && issued.expires_at >= now,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::locks;
+
+
+// stale windows are only swept once this many clients are being tracked
+const PRUNE_AT: usize = 10000;
+// and past this many live ones the oldest window is dropped for each new client, so a flood of addresses can't grow it without bound
+const MAX_CLIENTS: usize = 100000;
+
+struct Window {
+    started_at: i64,
+    count: u64,
+}
+
+pub struct RateLimitState {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    // unix ms when the current window ends and the count starts over
+    pub reset_at: i64,
+}
+
+// fixed window per client, for the whole server rather than per worker
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: u64,
+    window_ms: i64,
+    max_clients: usize,
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+impl RateLimiter {
+    // a limit of 0 turns it off
+    pub fn new (limit: u64, window_ms: i64) -> Self {
+        Self {
+            limit: limit,
+            window_ms: window_ms,
+            max_clients: MAX_CLIENTS,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // counts this request against the client, None when there is no limit
+    pub fn check (&self, client: &str, now: i64) -> Option<RateLimitState> {
+        if self.limit == 0 {
+            return None;
+        }
+        let mut windows = locks::lock(&self.windows);
+        if windows.len() >= PRUNE_AT {
+            let window_ms = self.window_ms;
+            windows.retain(|_, window| now - window.started_at < window_ms);
+        }
+        if windows.len() >= self.max_clients && !windows.contains_key(client) {
+            let oldest = windows.iter().min_by_key(|(_, window)| window.started_at).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                windows.remove(&oldest);
+            }
+        }
+
+        let window = windows.entry(client.to_string()).or_insert(Window { started_at: now, count: 0 });
+        if now - window.started_at >= self.window_ms {
+            window.started_at = now;
+            window.count = 0;
+        }
+        let allowed = window.count < self.limit;
+        if allowed {
+            window.count += 1;
+        }
+
+        Some(RateLimitState {
+            allowed: allowed,
+            limit: self.limit,
+            remaining: self.limit - window.count,
+            reset_at: window.started_at + self.window_ms,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_limit_is_none () {
+        let limiter = RateLimiter::new(0, 1000);
+        assert!(limiter.check("a", 0).is_none());
+    }
+
+    #[test]
+    fn counts_down_then_refuses () {
+        let limiter = RateLimiter::new(2, 1000);
+        let first = limiter.check("a", 100).unwrap();
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert_eq!(first.reset_at, 1100);
+        assert!(limiter.check("a", 200).unwrap().allowed);
+        let third = limiter.check("a", 300).unwrap();
+        assert!(!third.allowed);
+        assert_eq!(third.remaining, 0);
+        // other clients have their own window
+        assert!(limiter.check("b", 300).unwrap().allowed);
+    }
+
+    #[test]
+    fn window_starts_over () {
+        let limiter = RateLimiter::new(1, 1000);
+        assert!(limiter.check("a", 0).unwrap().allowed);
+        assert!(!limiter.check("a", 999).unwrap().allowed);
+        let next = limiter.check("a", 1000).unwrap();
+        assert!(next.allowed);
+        assert_eq!(next.reset_at, 2000);
+    }
+
+    #[test]
+    fn clients_capped () {
+        let limiter = RateLimiter { max_clients: 3, ..RateLimiter::new(1, 1000) };
+        for (i, client) in ["a", "b", "c", "d"].iter().enumerate() {
+            limiter.check(client, i as i64);
+        }
+        let windows = limiter.windows.lock().unwrap();
+        assert_eq!(windows.len(), 3);
+        // the oldest made room for the newest
+        assert!(!windows.contains_key("a"));
+        assert!(windows.contains_key("d"));
+    }
+}
@@ -0,0 +1,151 @@
+
+use std::fs;
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::models::{MyError, OnetimeFile, OnetimeLink, OnetimeStorage};
+
+
+// contents as plain text, or contents_base64 for anything binary
+#[derive(Deserialize)]
+pub struct SeedFile {
+    pub filename: String,
+    #[serde(default)]
+    pub contents: String,
+    pub contents_base64: Option<String>,
+    pub expires_at: Option<i64>,
+    pub content_type: Option<String>,
+}
+
+// the token is given so a demo can print or bookmark the same urls every time
+#[derive(Deserialize)]
+pub struct SeedLink {
+    pub token: String,
+    pub filename: String,
+    pub note: Option<String>,
+    pub expires_at: Option<i64>,
+    pub max_downloads: Option<i64>,
+    pub totp_secret: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct Seed {
+    #[serde(default)]
+    pub files: Vec<SeedFile>,
+    #[serde(default)]
+    pub links: Vec<SeedLink>,
+}
+
+// ndjson lines are one or the other, a link is the one with a token
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SeedLine {
+    Link(SeedLink),
+    File(SeedFile),
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files_seeded: usize,
+    pub files_skipped: usize,
+    pub links_seeded: usize,
+    pub links_skipped: usize,
+}
+
+// a json document with files and links arrays, or ndjson with one file or link per line
+pub fn parse (text: &str) -> Result<Seed, MyError> {
+    if let Ok(seed) = serde_json::from_str::<Seed>(text) {
+        return Ok(seed);
+    }
+    let mut seed = Seed::default();
+    for (i, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        match serde_json::from_str::<SeedLine>(line) {
+            Ok(SeedLine::Link(link)) => seed.links.push(link),
+            Ok(SeedLine::File(file)) => seed.files.push(file),
+            Err(why) => return Err(format!("Seed line {} is not a file or link! {}", i + 1, why)),
+        }
+    }
+    Ok(seed)
+}
+
+fn seed_file (file: SeedFile, now: i64) -> Result<OnetimeFile, MyError> {
+    let contents = match file.contents_base64 {
+        Some(ref encoded) => base64::decode(encoded.trim()).map_err(|why| format!("Seed file {} is not base64! {}", file.filename, why))?,
+        None => file.contents.into_bytes(),
+    };
+    Ok(OnetimeFile {
+        filename: file.filename,
+        contents: Bytes::from(contents),
+        created_at: now,
+        updated_at: now,
+        expires_at: file.expires_at,
+        content_type: file.content_type,
+    })
+}
+
+fn seed_link (link: SeedLink, now: i64, default_expiration_ms: i64) -> OnetimeLink {
+    OnetimeLink {
+        token: link.token,
+        filename: link.filename,
+        note: link.note,
+        created_at: now,
+        expires_at: link.expires_at.unwrap_or(now + default_expiration_ms),
+        downloaded_at: None,
+        ip_address: None,
+        attempts: 0,
+        last_attempt_at: None,
+        totp_secret: link.totp_secret,
+        allowed_cidrs: Vec::new(),
+        allowed_countries: Vec::new(),
+        blocked_countries: Vec::new(),
+        deleted_at: None,
+        redirect_url: None,
+        downloads: 0,
+        max_downloads: link.max_downloads.unwrap_or(1),
+        revoked_at: None,
+    }
+}
+
+// only ever adds, anything already stored under the same filename or token is left as it is and logged as skipped
+pub async fn run (storage: &dyn OnetimeStorage, path: &str, now: i64, default_expiration_ms: i64) -> Result<Summary, MyError> {
+    println!("seeding {} from {}", storage.name(), path);
+    let text = fs::read_to_string(path).map_err(|why| format!("Read seed file {} failed! {}", path, why))?;
+    let seed = parse(text.as_str())?;
+    let mut summary = Summary::default();
+
+    let files = seed.files.into_iter().map(|file| seed_file(file, now)).collect::<Result<Vec<_>, _>>()?;
+    let filenames: Vec<String> = files.iter().map(|file| file.filename.clone()).collect();
+    let added = if files.is_empty() {
+        Vec::new()
+    } else {
+        storage.add_files(files, false).await.map_err(|why| format!("Seed files failed! {}", why))?
+    };
+    for filename in filenames {
+        if added.contains(&filename) {
+            println!("seeded file {}", filename);
+            summary.files_seeded += 1;
+        } else {
+            println!("skipped file {}, it already exists", filename);
+            summary.files_skipped += 1;
+        }
+    }
+
+    let links: Vec<OnetimeLink> = seed.links.into_iter().map(|link| seed_link(link, now, default_expiration_ms)).collect();
+    let tokens: Vec<String> = links.iter().map(|link| link.token.clone()).collect();
+    let imported = if links.is_empty() {
+        Vec::new()
+    } else {
+        storage.import_links(links, false).await.map_err(|why| format!("Seed links failed! {}", why))?
+    };
+    for token in tokens {
+        if imported.contains(&token) {
+            println!("seeded link {}", token);
+            summary.links_seeded += 1;
+        } else {
+            println!("skipped link {}, it already exists", token);
+            summary.links_skipped += 1;
+        }
+    }
+
+    Ok(summary)
+}
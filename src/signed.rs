@@ -0,0 +1,87 @@
+
+use base32::Alphabet;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+
+// s.<base32 filename>.<expires_at unix ms>.<base32 hmac-sha256 of "<filename>\n<expires_at>">
+//  random tokens never contain a '.', so the prefix can't collide with a stored link
+pub const PREFIX: &'static str = "s.";
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+fn mac (secret: &str, filename: &str, expires_at: i64) -> Hmac<Sha256> {
+    // https://docs.rs/hmac/0.10.1/hmac/ -- any key length is valid for hmac
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("hmac takes keys of any length");
+    mac.update(format!("{}\n{}", filename, expires_at).as_bytes());
+    mac
+}
+
+pub fn is_signed (token: &str) -> bool {
+    token.starts_with(PREFIX)
+}
+
+pub fn sign (secret: &str, filename: &str, expires_at: i64) -> String {
+    let signature = mac(secret, filename, expires_at).finalize().into_bytes();
+    format!(
+        "{}{}.{}.{}",
+        PREFIX,
+        base32::encode(ALPHABET, filename.as_bytes()),
+        expires_at,
+        base32::encode(ALPHABET, &signature),
+    )
+}
+
+// the filename and expiry, only when the signature matches, expiry is left to the caller
+pub fn verify (secret: &str, token: &str) -> Option<(String, i64)> {
+    let mut parts = token.strip_prefix(PREFIX)?.split('.');
+    let filename = String::from_utf8(base32::decode(ALPHABET, parts.next()?)?).ok()?;
+    let expires_at = parts.next()?.parse::<i64>().ok()?;
+    let signature = base32::decode(ALPHABET, parts.next()?)?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    // constant time comparison
+    match mac(secret, filename.as_str(), expires_at).verify(&signature) {
+        Err(_) => None,
+        Ok(_) => Some((filename, expires_at)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_then_verify () {
+        let token = sign("secret", "some file.txt", 1600000000000);
+        assert!(is_signed(token.as_str()));
+        assert_eq!(verify("secret", token.as_str()), Some(("some file.txt".to_string(), 1600000000000)));
+    }
+
+    #[test]
+    fn expiry_is_returned_not_checked () {
+        // already long expired, still verifies, the caller compares it to now
+        let token = sign("secret", "file.txt", 1);
+        assert_eq!(verify("secret", token.as_str()), Some(("file.txt".to_string(), 1)));
+    }
+
+    #[test]
+    fn tampered_tokens_rejected () {
+        let token = sign("secret", "file.txt", 1600000000000);
+        assert_eq!(verify("other secret", token.as_str()), None);
+
+        let later = token.replace("1600000000000", "1700000000000");
+        assert_eq!(verify("secret", later.as_str()), None);
+
+        let other_file = sign("secret", "other.txt", 1600000000000);
+        let (_, rest) = token.split_at(token.find('.').unwrap() + 1);
+        let (_, other_rest) = other_file.split_at(other_file.find('.').unwrap() + 1);
+        let swapped = format!("{}{}{}", PREFIX, &other_rest[..other_rest.find('.').unwrap()], &rest[rest.find('.').unwrap()..]);
+        assert_eq!(verify("secret", swapped.as_str()), None);
+
+        assert_eq!(verify("secret", format!("{}.extra", token).as_str()), None);
+        assert_eq!(verify("secret", token.trim_start_matches(PREFIX)), None);
+    }
+}
@@ -0,0 +1,65 @@
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+
+// shared by every worker, so the limit is for the whole server and not per thread
+// https://docs.rs/tokio/0.2.22/tokio/sync/struct.Semaphore.html
+#[derive(Clone)]
+pub struct DownloadSlots {
+    max: usize,
+    semaphore: Option<Arc<Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+// hands its slot back when dropped, which for a streamed body is once the response is done or abandoned
+pub struct DownloadPermit {
+    semaphore: Option<Arc<Semaphore>>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl DownloadSlots {
+    // 0 is unlimited, but in flight downloads are still counted
+    pub fn new (max: usize) -> Self {
+        Self {
+            max: max,
+            semaphore: if max == 0 { None } else { Some(Arc::new(Semaphore::new(max))) },
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn max (&self) -> usize {
+        self.max
+    }
+
+    pub fn in_flight (&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // None when no slot freed up within the timeout
+    pub async fn acquire (&self, timeout_ms: u64) -> Option<DownloadPermit> {
+        if let Some(semaphore) = &self.semaphore {
+            match tokio::time::timeout(Duration::from_millis(timeout_ms), semaphore.acquire()).await {
+                Err(_) => return None,
+                // tokio 0.2 permits borrow the semaphore, so forget it and add it back on drop instead
+                Ok(permit) => permit.forget(),
+            }
+        }
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(DownloadPermit {
+            semaphore: self.semaphore.clone(),
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop (&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        if let Some(semaphore) = &self.semaphore {
+            semaphore.add_permits(1);
+        }
+    }
+}
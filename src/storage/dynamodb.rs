@@ -13,39 +13,119 @@ https://www.rusoto.org/regions.html
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::time::Duration;
 use bytes::{Bytes};
 use maplit::hashmap;
 use async_trait::async_trait;
 
-use rusoto_core::{Region};
+use rusoto_core::{Region, RusotoError};
 use rusoto_dynamodb::{
     DynamoDb,
     DynamoDbClient,
     AttributeValue,
+    BatchGetItemInput,
+    BatchWriteItemInput,
     GetItemInput,
+    KeysAndAttributes,
     PutItemInput,
+    PutItemError,
+    PutRequest,
+    Delete,
+    Update,
+    TransactWriteItem,
+    TransactWriteItemsInput,
+    QueryInput,
     ScanInput,
     DeleteItemInput,
+    DeleteItemError,
+    DeleteRequest,
+    DescribeTableInput,
+    UpdateItemInput,
+    UpdateItemError,
+    WriteRequest,
 };
 
 use crate::time_provider::TimeProvider;
-use crate::models::{MyError, OnetimeDownloaderConfig, OnetimeFile, OnetimeLink, OnetimeStorage};
-use super::util::{try_from_vec};
+use crate::models::{LinkStatus, ListSort, MyError, OnetimeDownloaderConfig, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp};
+use super::util::{link_status, list_from_vec, sort_files, sort_links, try_from_vec};
 
 
 const DEFAULT_TABLE_FILES: &'static str = "Onetime.Files";
 const DEFAULT_TABLE_LINKS: &'static str = "Onetime.Links";
+const DEFAULT_TABLE_USAGE: &'static str = "Onetime.Usage";
 
+// every *At attribute, and the window start in the usage key, is a number of unix ms
+//  so none can be the table's TTL attribute, which DynamoDB reads as seconds, that would take time_provider::ms_to_s
 const FIELD_FILENAME: &'static str = "Filename";
 const FIELD_CONTENTS: &'static str = "Contents";
 const FIELD_CREATED_AT: &'static str = "CreatedAt";
 const FIELD_UPDATED_AT: &'static str = "UpdatedAt";
+const FIELD_CONTENT_TYPE: &'static str = "ContentType";
 
 const FIELD_TOKEN: &'static str = "Token";
 const FIELD_NOTE: &'static str = "Note";
 const FIELD_EXPIRES_AT: &'static str = "ExpiresAt";
 const FIELD_DOWNLOADED_AT: &'static str = "DownloadedAt";
 const FIELD_IP_ADDRESS: &'static str = "IpAddress";
+const FIELD_ATTEMPTS: &'static str = "Attempts";
+const FIELD_LAST_ATTEMPT_AT: &'static str = "LastAttemptAt";
+const FIELD_TOTP_SECRET: &'static str = "TotpSecret";
+const FIELD_ALLOWED_CIDRS: &'static str = "AllowedCidrs";
+const FIELD_ALLOWED_COUNTRIES: &'static str = "AllowedCountries";
+const FIELD_BLOCKED_COUNTRIES: &'static str = "BlockedCountries";
+const FIELD_DELETED_AT: &'static str = "DeletedAt";
+const FIELD_REDIRECT_URL: &'static str = "RedirectUrl";
+const FIELD_DOWNLOADS: &'static str = "Downloads";
+const FIELD_MAX_DOWNLOADS: &'static str = "MaxDownloads";
+const FIELD_REVOKED_AT: &'static str = "RevokedAt";
+// only written and matched by consume_link, never read back into a link
+const FIELD_CONSUME_ATTEMPT_ID: &'static str = "ConsumeAttemptId";
+
+// "<owner>#<window start>", one item per owner per window
+const FIELD_USAGE_KEY: &'static str = "UsageKey";
+const FIELD_COUNT: &'static str = "Count";
+const FIELD_BYTES: &'static str = "Bytes";
+
+const FILE_FIELDS: &'static [&'static str] = &[
+    FIELD_FILENAME,
+    FIELD_CONTENTS,
+    FIELD_CREATED_AT,
+    FIELD_UPDATED_AT,
+    FIELD_EXPIRES_AT,
+    FIELD_CONTENT_TYPE,
+];
+
+// token is a reserved word, so expressions have to refer to it by substitute
+const TOKEN_SUBSTITUTE: &'static str = "#Token";
+const KEY_SUBSTITUTE: &'static str = "#Key";
+
+// https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/ServiceQuotas.html#limits-api
+const BATCH_WRITE_MAX_ITEMS: usize = 25;
+const BATCH_RETRIES: usize = 5;
+// https://docs.aws.amazon.com/amazondynamodb/latest/APIReference/API_TransactWriteItems.html
+const TRANSACT_MAX_ITEMS: usize = 25;
+const BATCH_GET_MAX_KEYS: usize = 100;
+
+// everything but the token, which goes by TOKEN_SUBSTITUTE in projections
+const LINK_FIELDS: &'static [&'static str] = &[
+    FIELD_FILENAME,
+    FIELD_NOTE,
+    FIELD_CREATED_AT,
+    FIELD_EXPIRES_AT,
+    FIELD_DOWNLOADED_AT,
+    FIELD_IP_ADDRESS,
+    FIELD_ATTEMPTS,
+    FIELD_LAST_ATTEMPT_AT,
+    FIELD_TOTP_SECRET,
+    FIELD_ALLOWED_CIDRS,
+    FIELD_ALLOWED_COUNTRIES,
+    FIELD_BLOCKED_COUNTRIES,
+    FIELD_DELETED_AT,
+    FIELD_REDIRECT_URL,
+    FIELD_DOWNLOADS,
+    FIELD_MAX_DOWNLOADS,
+    FIELD_REVOKED_AT,
+];
 
 
 #[derive(Clone)]
@@ -53,6 +133,12 @@ pub struct Storage {
     time_provider: Box<dyn TimeProvider>,
     files_table: String,
     links_table: String,
+    usage_table: String,
+    links_filename_index: Option<String>,
+    // strongly consistent reads cost twice the read capacity, but see writes made just before
+    consistent_read: bool,
+    // skip unreadable items in whole lists rather than failing them
+    lenient_lists: bool,
     client: DynamoDbClient,
 }
 
@@ -61,6 +147,7 @@ trait DdbAttributeValueExt {
     fn from_s (val: String) -> AttributeValue;
     fn from_n (val: i64) -> AttributeValue;
     fn from_b (val: Bytes) -> AttributeValue;
+    fn from_ss (val: Vec<String>) -> AttributeValue;
 }
 
 impl DdbAttributeValueExt for AttributeValue {
@@ -84,6 +171,13 @@ impl DdbAttributeValueExt for AttributeValue {
             ..Default::default()
         }
     }
+
+    fn from_ss (val: Vec<String>) -> AttributeValue {
+        AttributeValue {
+            ss: Some(val),
+            ..Default::default()
+        }
+    }
 }
 
 trait RowExt {
@@ -96,6 +190,7 @@ trait RowExt {
     fn get_b (&self, field: &String) -> Result<Bytes, MyError>;
     fn get_n (&self, field: &String) -> Result<i64, MyError>;
     fn get_on (&self, field: &String) -> Result<Option<i64>, MyError>;
+    fn get_ss (&self, field: &String) -> Result<Vec<String>, MyError>;
 }
 
 type Row = HashMap<String, AttributeValue>;
@@ -157,6 +252,84 @@ impl RowExt for Row {
             }
         }
     }
+
+    // string sets cannot be empty in dynamodb, so a missing field is an empty set
+    fn get_ss (&self, field: &String) -> Result<Vec<String>, MyError> {
+        match self.get(field) {
+            None => Ok(Vec::new()),
+            Some(val) => val.ss.clone().ok_or(format!("Empty field {}", field)),
+        }
+    }
+}
+
+fn file_item (file: OnetimeFile) -> HashMap<String, AttributeValue> {
+    let mut item = hashmap! {
+        FIELD_FILENAME.to_string() => AttributeValue::from_s(file.filename),
+        FIELD_CONTENTS.to_string() => AttributeValue::from_b(file.contents),
+        FIELD_CREATED_AT.to_string() => AttributeValue::from_n(file.created_at),
+        FIELD_UPDATED_AT.to_string() => AttributeValue::from_n(file.updated_at),
+    };
+    if let Some(expires_at) = file.expires_at {
+        item.insert(FIELD_EXPIRES_AT.to_string(), AttributeValue::from_n(expires_at));
+    }
+    if let Some(content_type) = file.content_type {
+        item.insert(FIELD_CONTENT_TYPE.to_string(), AttributeValue::from_s(content_type));
+    }
+    item
+}
+
+// everything but the unset optional fields, dynamodb has no nulls or empty sets
+fn link_item (link: OnetimeLink) -> HashMap<String, AttributeValue> {
+    let mut item = hashmap! {
+        FIELD_TOKEN.to_string() => AttributeValue::from_s(link.token),
+        FIELD_FILENAME.to_string() => AttributeValue::from_s(link.filename),
+        FIELD_CREATED_AT.to_string() => AttributeValue::from_n(link.created_at),
+        FIELD_EXPIRES_AT.to_string() => AttributeValue::from_n(link.expires_at),
+        FIELD_ATTEMPTS.to_string() => AttributeValue::from_n(link.attempts),
+        FIELD_DOWNLOADS.to_string() => AttributeValue::from_n(link.downloads),
+        FIELD_MAX_DOWNLOADS.to_string() => AttributeValue::from_n(link.max_downloads),
+    };
+    if let Some(note) = link.note {
+        item.insert(FIELD_NOTE.to_string(), AttributeValue::from_s(note));
+    }
+    if let Some(last_attempt_at) = link.last_attempt_at {
+        item.insert(FIELD_LAST_ATTEMPT_AT.to_string(), AttributeValue::from_n(last_attempt_at));
+    }
+    if let Some(totp_secret) = link.totp_secret {
+        item.insert(FIELD_TOTP_SECRET.to_string(), AttributeValue::from_s(totp_secret));
+    }
+    if !link.allowed_cidrs.is_empty() {
+        item.insert(FIELD_ALLOWED_CIDRS.to_string(), AttributeValue::from_ss(link.allowed_cidrs));
+    }
+    if !link.allowed_countries.is_empty() {
+        item.insert(FIELD_ALLOWED_COUNTRIES.to_string(), AttributeValue::from_ss(link.allowed_countries));
+    }
+    if !link.blocked_countries.is_empty() {
+        item.insert(FIELD_BLOCKED_COUNTRIES.to_string(), AttributeValue::from_ss(link.blocked_countries));
+    }
+    if let Some(deleted_at) = link.deleted_at {
+        item.insert(FIELD_DELETED_AT.to_string(), AttributeValue::from_n(deleted_at));
+    }
+    if let Some(redirect_url) = link.redirect_url {
+        item.insert(FIELD_REDIRECT_URL.to_string(), AttributeValue::from_s(redirect_url));
+    }
+    if let Some(downloaded_at) = link.downloaded_at {
+        item.insert(FIELD_DOWNLOADED_AT.to_string(), AttributeValue::from_n(downloaded_at));
+    }
+    if let Some(ip_address) = link.ip_address {
+        item.insert(FIELD_IP_ADDRESS.to_string(), AttributeValue::from_s(ip_address));
+    }
+    if let Some(revoked_at) = link.revoked_at {
+        item.insert(FIELD_REVOKED_AT.to_string(), AttributeValue::from_n(revoked_at));
+    }
+
+    item
+}
+
+fn link_projection_expression () -> String {
+    let mut fields = vec![TOKEN_SUBSTITUTE];
+    fields.extend_from_slice(LINK_FIELDS);
+    fields.join(", ")
 }
 
 impl TryFrom<Row> for OnetimeFile {
@@ -167,12 +340,16 @@ impl TryFrom<Row> for OnetimeFile {
         let contents = row.get_b(&FIELD_CONTENTS.to_string())?;
         let created_at = row.get_n(&FIELD_CREATED_AT.to_string())?;
         let updated_at = row.get_n(&FIELD_UPDATED_AT.to_string())?;
+        let expires_at = row.get_on(&FIELD_EXPIRES_AT.to_string())?;
+        let content_type = row.get_os(&FIELD_CONTENT_TYPE.to_string())?;
 
         Ok(Self {
             filename: filename,
             contents: contents,
             created_at: created_at,
             updated_at: updated_at,
+            expires_at: expires_at,
+            content_type: content_type,
         })
     }
 }
@@ -188,6 +365,19 @@ impl TryFrom<Row> for OnetimeLink {
         let expires_at = row.get_n(&FIELD_EXPIRES_AT.to_string())?;
         let downloaded_at = row.get_on(&FIELD_DOWNLOADED_AT.to_string())?;
         let ip_address = row.get_os(&FIELD_IP_ADDRESS.to_string())?;
+        // links created before attempts were tracked will not have this field
+        let attempts = row.get_on(&FIELD_ATTEMPTS.to_string())?.unwrap_or(0);
+        let last_attempt_at = row.get_on(&FIELD_LAST_ATTEMPT_AT.to_string())?;
+        let totp_secret = row.get_os(&FIELD_TOTP_SECRET.to_string())?;
+        let allowed_cidrs = row.get_ss(&FIELD_ALLOWED_CIDRS.to_string())?;
+        let allowed_countries = row.get_ss(&FIELD_ALLOWED_COUNTRIES.to_string())?;
+        let blocked_countries = row.get_ss(&FIELD_BLOCKED_COUNTRIES.to_string())?;
+        let deleted_at = row.get_on(&FIELD_DELETED_AT.to_string())?;
+        let redirect_url = row.get_os(&FIELD_REDIRECT_URL.to_string())?;
+        // links from before multi-download were single use
+        let downloads = row.get_on(&FIELD_DOWNLOADS.to_string())?.unwrap_or(0);
+        let max_downloads = row.get_on(&FIELD_MAX_DOWNLOADS.to_string())?.unwrap_or(1);
+        let revoked_at = row.get_on(&FIELD_REVOKED_AT.to_string())?;
 
         Ok(Self {
             token: token,
@@ -197,20 +387,232 @@ impl TryFrom<Row> for OnetimeLink {
             expires_at: expires_at,
             downloaded_at: downloaded_at,
             ip_address: ip_address,
+            attempts: attempts,
+            last_attempt_at: last_attempt_at,
+            totp_secret: totp_secret,
+            allowed_cidrs: allowed_cidrs,
+            allowed_countries: allowed_countries,
+            blocked_countries: blocked_countries,
+            deleted_at: deleted_at,
+            redirect_url: redirect_url,
+            downloads: downloads,
+            max_downloads: max_downloads,
+            revoked_at: revoked_at,
         })
     }
 }
 
 impl Storage {
-    pub fn from_env (time_provider: Box<dyn TimeProvider>) -> Self {
+    pub fn from_env (time_provider: Box<dyn TimeProvider>, lenient_lists: bool) -> Self {
+        // prepended to the table names whether they are the defaults or set explicitly, eg "staging." for "staging.Onetime.Files"
+        let prefix = OnetimeDownloaderConfig::env_var_string("DDB_TABLE_PREFIX", String::new());
         Self {
             time_provider: time_provider,
-            files_table: OnetimeDownloaderConfig::env_var_string("DDB_FILES_TABLE", String::from(DEFAULT_TABLE_FILES)),
-            links_table: OnetimeDownloaderConfig::env_var_string("DDB_LINKS_TABLE", String::from(DEFAULT_TABLE_LINKS)),
+            files_table: format!("{}{}", prefix, OnetimeDownloaderConfig::env_var_string("DDB_FILES_TABLE", String::from(DEFAULT_TABLE_FILES))),
+            links_table: format!("{}{}", prefix, OnetimeDownloaderConfig::env_var_string("DDB_LINKS_TABLE", String::from(DEFAULT_TABLE_LINKS))),
+            usage_table: format!("{}{}", prefix, OnetimeDownloaderConfig::env_var_string("DDB_USAGE_TABLE", String::from(DEFAULT_TABLE_USAGE))),
+            // optional GSI with Filename as the hash key, see README for the table definition
+            links_filename_index: match OnetimeDownloaderConfig::env_var_string("DDB_LINKS_FILENAME_INDEX", String::new()) {
+                index if index.is_empty() => None,
+                index => Some(index),
+            },
+            consistent_read: OnetimeDownloaderConfig::env_var_parse("DDB_CONSISTENT_READ", false),
+            lenient_lists: lenient_lists,
             // https://docs.rs/rusoto_dynamodb/0.45.0/rusoto_dynamodb/
             client: DynamoDbClient::new(Region::UsEast1),
         }
     }
+
+    // the default tables, with each request answered by the next mock in turn, for tests of what uses a storage
+    #[cfg(test)]
+    pub fn mocked (dispatchers: Vec<rusoto_mock::MockRequestDispatcher>) -> Self {
+        Self {
+            time_provider: Box::new(crate::time_provider::SystemTimeProvider {}),
+            files_table: DEFAULT_TABLE_FILES.to_string(),
+            links_table: DEFAULT_TABLE_LINKS.to_string(),
+            usage_table: DEFAULT_TABLE_USAGE.to_string(),
+            links_filename_index: None,
+            consistent_read: false,
+            lenient_lists: false,
+            client: DynamoDbClient::new_with(rusoto_mock::MultipleMockRequestDispatcher::new(dispatchers), rusoto_mock::MockCredentialsProvider, Region::UsEast1),
+        }
+    }
+
+    // the link as the consume with this attempt id left it, None when no such consume went through
+    //  always a consistent read, an eventually consistent one could miss the very write it is looking for
+    async fn consumed_by (&self, token: String, attempt_id: String) -> Result<Option<OnetimeLink>, MyError> {
+        let request = GetItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            consistent_read: Some(true),
+            ..Default::default()
+        };
+
+        match self.client.get_item(request).await {
+            Err(why) => Err(format!("Get consumed link failed: {}", why.to_string())),
+            Ok(output) => match output.item {
+                None => Ok(None),
+                Some(row) => match row.get_os(&FIELD_CONSUME_ATTEMPT_ID.to_string())? {
+                    Some(id) if id == attempt_id => OnetimeLink::try_from(row).map(Some),
+                    _ => Ok(None),
+                },
+            }
+        }
+    }
+
+    // https://docs.aws.amazon.com/amazondynamodb/latest/APIReference/API_BatchGetItem.html
+    async fn keys_exist (&self, table: String, field: &str, values: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        let mut exist: HashMap<String, bool> = values.into_iter().map(|value| (value, false)).collect();
+        // batch gets reject duplicate keys, the map already deduped them
+        let keys: Vec<Row> = exist.keys().map(|value| Row::new_key(field.to_string(), value.clone())).collect();
+
+        for chunk in keys.chunks(BATCH_GET_MAX_KEYS) {
+            let mut request_items = hashmap! {
+                table.clone() => KeysAndAttributes {
+                    keys: chunk.to_vec(),
+                    // only the key, anything more would make this as slow as getting each item
+                    projection_expression: Some(KEY_SUBSTITUTE.to_string()),
+                    consistent_read: Some(self.consistent_read),
+                    // the key may be a reserved word, eg token
+                    expression_attribute_names: Some(hashmap! {
+                        KEY_SUBSTITUTE.to_string() => field.to_string(),
+                    }),
+                    ..Default::default()
+                },
+            };
+            let mut attempts = 0;
+            loop {
+                let request = BatchGetItemInput {
+                    request_items: request_items,
+                    ..Default::default()
+                };
+                let output = self.client.batch_get_item(request).await
+                    .map_err(|why| format!("Batch get failed: {}", why.to_string()))?;
+                for rows in output.responses.into_iter().flat_map(|responses| responses.into_iter().map(|(_, rows)| rows)) {
+                    for row in rows {
+                        exist.insert(row.get_s(&field.to_string())?, true);
+                    }
+                }
+                match output.unprocessed_keys {
+                    Some(unprocessed) if !unprocessed.is_empty() => request_items = unprocessed,
+                    _ => break,
+                }
+                attempts += 1;
+                if attempts >= BATCH_RETRIES {
+                    return Err("Batch get failed: keys still unprocessed after retries".to_string());
+                }
+                tokio::time::delay_for(Duration::from_millis(50 << attempts)).await;
+            }
+        }
+        Ok(exist)
+    }
+
+    // a scan call reads at most 1MB, LastEvaluatedKey is where the next one starts, until there is none
+    //  https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/Scan.html#Scan.Pagination
+    async fn scan_all (&self, mut request: ScanInput) -> Result<Vec<Row>, MyError> {
+        let mut rows = Vec::new();
+        loop {
+            let output = self.client.scan(request.clone()).await
+                .map_err(|why| why.to_string())?;
+            rows.extend(output.items.unwrap_or_default());
+            match output.last_evaluated_key {
+                Some(key) if !key.is_empty() => request.exclusive_start_key = Some(key),
+                _ => return Ok(rows),
+            }
+        }
+    }
+
+    async fn batch_write (&self, table: String, items: Vec<Row>) -> Result<(), MyError> {
+        let requests = items.into_iter()
+            .map(|item| WriteRequest {
+                put_request: Some(PutRequest { item: item }),
+                delete_request: None,
+            })
+            .collect();
+        self.batch_write_requests(table, requests).await
+    }
+
+    // by key only, deleting a key that is not there is not an error
+    async fn batch_delete (&self, table: String, keys: Vec<Row>) -> Result<(), MyError> {
+        let requests = keys.into_iter()
+            .map(|key| WriteRequest {
+                put_request: None,
+                delete_request: Some(DeleteRequest { key: key }),
+            })
+            .collect();
+        self.batch_write_requests(table, requests).await
+    }
+
+    // https://docs.aws.amazon.com/amazondynamodb/latest/APIReference/API_BatchWriteItem.html
+    async fn batch_write_requests (&self, table: String, requests: Vec<WriteRequest>) -> Result<(), MyError> {
+        for chunk in requests.chunks(BATCH_WRITE_MAX_ITEMS) {
+            let mut request_items = hashmap! {
+                table.clone() => chunk.to_vec(),
+            };
+            // throttled writes come back as unprocessed, so resend those until none are left
+            let mut attempts = 0;
+            loop {
+                let request = BatchWriteItemInput {
+                    request_items: request_items,
+                    ..Default::default()
+                };
+                let output = self.client.batch_write_item(request).await
+                    .map_err(|why| format!("Batch write failed: {}", why.to_string()))?;
+                match output.unprocessed_items {
+                    Some(unprocessed) if !unprocessed.is_empty() => request_items = unprocessed,
+                    _ => break,
+                }
+                attempts += 1;
+                if attempts >= BATCH_RETRIES {
+                    return Err("Batch write failed: items still unprocessed after retries".to_string());
+                }
+                // aws recommends exponential backoff for unprocessed items
+                tokio::time::delay_for(Duration::from_millis(50 << attempts)).await;
+            }
+        }
+        Ok(())
+    }
+
+    // last writer wins, and updated_at only ever goes up, so two uploads racing can't leave it behind the contents
+    //  set from this write when that is newer, otherwise the stored one is bumped by 1, a second request only then
+    //  false when must_exist and there is no such file
+    async fn update_file_item (&self, filename: String, sets: Vec<String>, removes: Vec<&str>, values: HashMap<String, AttributeValue>, updated_at: i64, must_exist: bool) -> Result<bool, MyError> {
+        let exists = format!("attribute_exists({})", FIELD_FILENAME);
+        let removes = if removes.is_empty() { String::new() } else { format!(" REMOVE {}", removes.join(", ")) };
+
+        let mut newer_values = values.clone();
+        newer_values.insert(":updated_at".to_string(), AttributeValue::from_n(updated_at));
+        let newer = format!("attribute_not_exists({}) OR {} < :updated_at", FIELD_UPDATED_AT, FIELD_UPDATED_AT);
+        let request = UpdateItemInput {
+            key: Row::filename_key(filename.clone()),
+            table_name: self.files_table.clone(),
+            update_expression: Some(format!("SET {}, {} = :updated_at{}", sets.join(", "), FIELD_UPDATED_AT, removes)),
+            condition_expression: Some(if must_exist { format!("{} AND ({})", exists, newer) } else { newer }),
+            expression_attribute_values: Some(newer_values),
+            ..Default::default()
+        };
+        match self.client.update_item(request).await {
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => (),
+            Err(why) => return Err(why.to_string()),
+            Ok(_) => return Ok(true),
+        }
+
+        let mut bump_values = values;
+        bump_values.insert(":one".to_string(), AttributeValue::from_n(1));
+        let request = UpdateItemInput {
+            key: Row::filename_key(filename),
+            table_name: self.files_table.clone(),
+            update_expression: Some(format!("SET {}, {} = {} + :one{}", sets.join(", "), FIELD_UPDATED_AT, FIELD_UPDATED_AT, removes)),
+            condition_expression: if must_exist { Some(exists) } else { None },
+            expression_attribute_values: Some(bump_values),
+            ..Default::default()
+        };
+        match self.client.update_item(request).await {
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(why) => Err(why.to_string()),
+            Ok(_) => Ok(true),
+        }
+    }
 }
 
 // https://github.com/dtolnay/async-trait#non-threadsafe-futures
@@ -220,33 +622,112 @@ impl OnetimeStorage for Storage {
         "Dynamodb"
     }
 
+    // the filename index, when there is one, has to go by the same name on every links table
+    fn with_links_table (&self, table: &str) -> Result<Box<dyn OnetimeStorage>, MyError> {
+        let mut storage = self.clone();
+        storage.links_table = table.to_string();
+        Ok(Box::new(storage))
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        for table_name in [&self.files_table, &self.links_table].iter() {
+            let request = DescribeTableInput {
+                table_name: table_name.to_string(),
+            };
+
+            if let Err(why) = self.client.describe_table(request).await {
+                return Err(format!("Ping {} failed: {}", table_name, why.to_string()));
+            }
+        }
+        Ok(true)
+    }
+
     async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
-        let item = hashmap! {
-            FIELD_FILENAME.to_string() => AttributeValue::from_s(file.filename),
-            FIELD_CONTENTS.to_string() => AttributeValue::from_b(file.contents),
-            FIELD_CREATED_AT.to_string() => AttributeValue::from_n(file.created_at),
-            FIELD_UPDATED_AT.to_string() => AttributeValue::from_n(file.updated_at),
+        let mut expression_attribute_values = hashmap! {
+            ":contents".to_string() => AttributeValue::from_b(file.contents),
+            ":created_at".to_string() => AttributeValue::from_n(file.created_at),
         };
 
+        // upsert like postgres does: replacing a file keeps its original created_at
+        let mut sets = vec![
+            format!("{} = :contents", FIELD_CONTENTS),
+            format!("{} = if_not_exists({}, :created_at)", FIELD_CREATED_AT, FIELD_CREATED_AT),
+        ];
+        let mut removes = Vec::new();
+
+        // the new upload decides the expiry and type, including clearing old ones
+        match file.expires_at {
+            None => removes.push(FIELD_EXPIRES_AT),
+            Some(expires_at) => {
+                expression_attribute_values.insert(":expires_at".to_string(), AttributeValue::from_n(expires_at));
+                sets.push(format!("{} = :expires_at", FIELD_EXPIRES_AT));
+            }
+        }
+        match file.content_type {
+            None => removes.push(FIELD_CONTENT_TYPE),
+            Some(content_type) => {
+                expression_attribute_values.insert(":content_type".to_string(), AttributeValue::from_s(content_type));
+                sets.push(format!("{} = :content_type", FIELD_CONTENT_TYPE));
+            }
+        }
+
+        self.update_file_item(file.filename, sets, removes, expression_attribute_values, file.updated_at, false).await
+            .map_err(|why| format!("Add file failed: {}", why))
+    }
+
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        // the condition makes this atomic, no separate existence check needed
         let request = PutItemInput {
-            item: item,
+            item: file_item(file),
             table_name: self.files_table.clone(),
+            condition_expression: Some(format!("attribute_not_exists({})", FIELD_FILENAME)),
             ..Default::default()
         };
 
         match self.client.put_item(request).await {
-            Err(why) => Err(format!("Add file failed: {}", why.to_string())),
+            Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(why) => Err(format!("Create file failed: {}", why.to_string())),
             Ok(_) => Ok(true)
         }
     }
 
-    async fn list_files (&self) -> Result<Vec<OnetimeFile>, MyError>  {
-        let projection_expression = [
-            FIELD_FILENAME,
-            FIELD_CONTENTS,
-            FIELD_CREATED_AT,
-            FIELD_UPDATED_AT,
-        ].join(", ");
+    // same approach as import_links: batched puts, with the existing filenames looked up first without overwrite
+    //  unlike add_file, an overwritten file takes the given created_at too
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        let files = if overwrite {
+            files
+        } else {
+            let filenames = files.iter().map(|file| file.filename.clone()).collect();
+            let exist = self.keys_exist(self.files_table.clone(), FIELD_FILENAME, filenames).await
+                .map_err(|why| format!("Add files failed: {}", why))?;
+            files.into_iter().filter(|file| !exist.get(&file.filename).copied().unwrap_or(false)).collect()
+        };
+        let filenames = files.iter().map(|file| file.filename.clone()).collect();
+        if !files.is_empty() {
+            let items = files.into_iter().map(file_item).collect();
+            self.batch_write(self.files_table.clone(), items).await
+                .map_err(|why| format!("Add files failed: {}", why))?;
+        }
+        Ok(filenames)
+    }
+
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError> {
+        let mut expression_attribute_values = hashmap! {
+            ":contents".to_string() => AttributeValue::from_b(contents),
+        };
+        let mut sets = vec![format!("{} = :contents", FIELD_CONTENTS)];
+        if let Some(content_type) = content_type {
+            expression_attribute_values.insert(":content_type".to_string(), AttributeValue::from_s(content_type));
+            sets.push(format!("{} = :content_type", FIELD_CONTENT_TYPE));
+        }
+
+        // must exist, without that an update to an unknown filename would create a new file
+        self.update_file_item(filename, sets, Vec::new(), expression_attribute_values, updated_at, true).await
+            .map_err(|why| format!("Update file failed: {}", why))
+    }
+
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
+        let projection_expression = FILE_FIELDS.join(", ");
 
         // https://docs.rs/rusoto_dynamodb/0.45.0/rusoto_dynamodb/
         let request = ScanInput {
@@ -258,8 +739,14 @@ impl OnetimeStorage for Storage {
         match self.client.scan(request).await {
             Err(why) => Err(format!("List files failed: {}", why.to_string())),
             Ok(output) => match output.items {
-                None => Err("No files found".to_string()),
-                Some(rows) => try_from_vec(rows, "files"),
+                // an empty table is an empty list, not an error
+                None => Ok((Vec::new(), 0)),
+                Some(rows) => {
+                    // scans come back in hash order
+                    let (mut files, skipped) = list_from_vec(rows, "files", self.lenient_lists)?;
+                    sort_files(&mut files, sort);
+                    Ok((files, skipped))
+                }
             }
         }
     }
@@ -270,6 +757,7 @@ impl OnetimeStorage for Storage {
         let request = GetItemInput {
             key: Row::filename_key(filename),
             table_name: self.files_table.clone(),
+            consistent_read: Some(self.consistent_read),
             ..Default::default()
         };
 
@@ -277,30 +765,46 @@ impl OnetimeStorage for Storage {
             Err(why) => Err(format!("Get file failed: {}", why.to_string())),
             Ok(output) => match output.item {
                 None => Err("File not found".to_string()),
-                Some(row) => OnetimeFile::try_from(row),
+                Some(row) => match OnetimeFile::try_from(row)? {
+                    file if file.is_expired(self.time_provider.unix_ts_ms()) => Err("File expired".to_string()),
+                    file => Ok(file),
+                },
             }
         }
     }
 
-    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
-        let mut item = hashmap! {
-            FIELD_TOKEN.to_string() => AttributeValue::from_s(link.token),
-            FIELD_FILENAME.to_string() => AttributeValue::from_s(link.filename),
-            FIELD_CREATED_AT.to_string() => AttributeValue::from_n(link.created_at),
-            FIELD_EXPIRES_AT.to_string() => AttributeValue::from_n(link.expires_at),
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        let projection_expression = FILE_FIELDS.join(", ");
+
+        let request = ScanInput {
+            projection_expression: Some(projection_expression),
+            exclusive_start_key: after.map(Row::filename_key),
+            limit: Some(limit as i64),
+            table_name: self.files_table.clone(),
+            ..Default::default()
         };
-        if let Some(note) = link.note {
-            item.insert(FIELD_NOTE.to_string(), AttributeValue::from_s(note));
-        }
-        if let Some(downloaded_at) = link.downloaded_at {
-            item.insert(FIELD_DOWNLOADED_AT.to_string(), AttributeValue::from_n(downloaded_at));
-        }
-        if let Some(ip_address) = link.ip_address {
-            item.insert(FIELD_IP_ADDRESS.to_string(), AttributeValue::from_s(ip_address));
+
+        match self.client.scan(request).await {
+            Err(why) => Err(format!("List files page failed: {}", why.to_string())),
+            Ok(output) => {
+                let next = match output.last_evaluated_key {
+                    None => None,
+                    Some(key) => Some(key.get_s(&FIELD_FILENAME.to_string())?),
+                };
+                let files = try_from_vec(output.items.unwrap_or_default(), "files")?;
+                Ok((files, next))
+            }
         }
+    }
+
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        self.keys_exist(self.files_table.clone(), FIELD_FILENAME, filenames).await
+            .map_err(|why| format!("Files exist failed: {}", why))
+    }
 
+    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
         let request = PutItemInput {
-            item: item,
+            item: link_item(link),
             table_name: self.links_table.clone(),
             ..Default::default()
         };
@@ -311,26 +815,42 @@ impl OnetimeStorage for Storage {
         }
     }
 
-    async fn list_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
-        const TOKEN_SUBSTITUTE: &'static str = "#Token";
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        let items = links.into_iter().map(link_item).collect();
+        self.batch_write(self.links_table.clone(), items).await
+            .map_err(|why| format!("Add links failed: {}", why))?;
+        Ok(true)
+    }
+
+    // batch writes can't be conditional, so without overwrite the existing tokens are looked up first
+    //  a link created between that lookup and the write would still be replaced
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        let links = if overwrite {
+            links
+        } else {
+            let tokens = links.iter().map(|link| link.token.clone()).collect();
+            let exist = self.keys_exist(self.links_table.clone(), FIELD_TOKEN, tokens).await
+                .map_err(|why| format!("Import links failed: {}", why))?;
+            links.into_iter().filter(|link| !exist.get(&link.token).copied().unwrap_or(false)).collect()
+        };
+        let tokens = links.iter().map(|link| link.token.clone()).collect();
+        if !links.is_empty() {
+            self.add_links(links).await?;
+        }
+        Ok(tokens)
+    }
 
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
         let expression_attribute_names = hashmap! {
             TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
         };
 
-        let projection_expression = [
-            TOKEN_SUBSTITUTE,
-            FIELD_FILENAME,
-            FIELD_NOTE,
-            FIELD_CREATED_AT,
-            FIELD_EXPIRES_AT,
-            FIELD_DOWNLOADED_AT,
-            FIELD_IP_ADDRESS,
-        ].join(", ");
+        let projection_expression = link_projection_expression();
 
         // https://docs.rs/rusoto_dynamodb/0.45.0/rusoto_dynamodb/
         let request = ScanInput {
             projection_expression: Some(projection_expression),
+            filter_expression: Some(format!("attribute_not_exists({})", FIELD_DELETED_AT)),
             expression_attribute_names: Some(expression_attribute_names),
             table_name: self.links_table.clone(),
             ..Default::default()
@@ -339,18 +859,117 @@ impl OnetimeStorage for Storage {
         match self.client.scan(request).await {
             Err(why) => Err(format!("List links failed: {}", why.to_string())),
             Ok(output) => match output.items {
-                None => Err("No links found".to_string()),
-                Some(rows) => try_from_vec(rows, "links"),
+                // an empty table is an empty list, not an error
+                None => Ok((Vec::new(), 0)),
+                Some(rows) => {
+                    let (mut links, skipped) = list_from_vec(rows, "links", self.lenient_lists)?;
+                    sort_links(&mut links, sort);
+                    Ok((links, skipped))
+                }
             }
         }
     }
 
+    // scan pages are in hash order, the cursor is just the last evaluated token
+    //  tombstones are left in, as in postgres, so exports and migrations keep soft deleted links restorable
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let request = ScanInput {
+            projection_expression: Some(link_projection_expression()),
+            expression_attribute_names: Some(expression_attribute_names),
+            exclusive_start_key: after.map(Row::token_key),
+            limit: Some(limit as i64),
+            table_name: self.links_table.clone(),
+            ..Default::default()
+        };
+
+        match self.client.scan(request).await {
+            Err(why) => Err(format!("List links page failed: {}", why.to_string())),
+            Ok(output) => {
+                let next = match output.last_evaluated_key {
+                    None => None,
+                    Some(key) => Some(key.get_s(&FIELD_TOKEN.to_string())?),
+                };
+                let links = try_from_vec(output.items.unwrap_or_default(), "links")?;
+                Ok((links, next))
+            }
+        }
+    }
+
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":filename".to_string() => AttributeValue::from_s(filename),
+        };
+
+        let projection_expression = link_projection_expression();
+
+        let filename_expression = format!("{} = :filename", FIELD_FILENAME);
+        let not_deleted_expression = format!("attribute_not_exists({})", FIELD_DELETED_AT);
+
+        let items = match &self.links_filename_index {
+            // https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/GSI.html
+            Some(index_name) => {
+                let request = QueryInput {
+                    index_name: Some(index_name.clone()),
+                    key_condition_expression: Some(filename_expression),
+                    filter_expression: Some(not_deleted_expression),
+                    projection_expression: Some(projection_expression),
+                    expression_attribute_names: Some(expression_attribute_names),
+                    expression_attribute_values: Some(expression_attribute_values),
+                    table_name: self.links_table.clone(),
+                    ..Default::default()
+                };
+
+                match self.client.query(request).await {
+                    Err(why) => return Err(format!("List links for file query failed: {}", why.to_string())),
+                    Ok(output) => output.items,
+                }
+            }
+            // filtering happens after the scan reads every item, so this costs as much as list_links
+            None => {
+                let request = ScanInput {
+                    projection_expression: Some(projection_expression),
+                    filter_expression: Some(format!("{} AND {}", filename_expression, not_deleted_expression)),
+                    expression_attribute_names: Some(expression_attribute_names),
+                    expression_attribute_values: Some(expression_attribute_values),
+                    table_name: self.links_table.clone(),
+                    ..Default::default()
+                };
+
+                match self.client.scan(request).await {
+                    Err(why) => return Err(format!("List links for file scan failed: {}", why.to_string())),
+                    Ok(output) => output.items,
+                }
+            }
+        };
+
+        match items {
+            None => Ok(Vec::new()),
+            Some(rows) => try_from_vec(rows, "links"),
+        }
+    }
+
     async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        match self.get_any_link(token).await? {
+            link if link.deleted_at.is_some() => Err("Link not found".to_string()),
+            link => Ok(link),
+        }
+    }
+
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError> {
         // https://www.rusoto.org/futures.html has example uses
         // ... maybe use https://docs.rs/crate/serde_dynamodb/0.6.0 ?
         let request = GetItemInput {
             key: Row::token_key(token),
             table_name: self.links_table.clone(),
+            consistent_read: Some(self.consistent_read),
             ..Default::default()
         };
 
@@ -363,61 +982,585 @@ impl OnetimeStorage for Storage {
         }
     }
 
-    async fn mark_downloaded (&self, link: OnetimeLink, ip_address: String, downloaded_at: i64) -> Result<bool, MyError> {
-        let mut item = hashmap! {
-            FIELD_TOKEN.to_string() => AttributeValue::from_s(link.token),
-            FIELD_FILENAME.to_string() => AttributeValue::from_s(link.filename),
-            FIELD_CREATED_AT.to_string() => AttributeValue::from_n(link.created_at),
-            FIELD_EXPIRES_AT.to_string() => AttributeValue::from_n(link.expires_at),
-            FIELD_DOWNLOADED_AT.to_string() => AttributeValue::from_n(downloaded_at),
-            FIELD_IP_ADDRESS.to_string() => AttributeValue::from_s(ip_address),
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError> {
+        let request = GetItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            projection_expression: Some([FIELD_DOWNLOADED_AT, FIELD_EXPIRES_AT, FIELD_DELETED_AT, FIELD_REVOKED_AT].join(", ")),
+            consistent_read: Some(self.consistent_read),
+            ..Default::default()
         };
-        if let Some(note) = link.note {
-            item.insert(FIELD_NOTE.to_string(), AttributeValue::from_s(note));
+
+        match self.client.get_item(request).await {
+            Err(why) => Err(format!("Link status failed: {}", why.to_string())),
+            Ok(output) => match output.item {
+                None => Ok(LinkStatus::Missing),
+                Some(row) => Ok(link_status(
+                    row.get_on(&FIELD_DOWNLOADED_AT.to_string())?,
+                    row.get_n(&FIELD_EXPIRES_AT.to_string())?,
+                    row.get_on(&FIELD_DELETED_AT.to_string())?,
+                    row.get_on(&FIELD_REVOKED_AT.to_string())?,
+                    self.time_provider.unix_ts_ms(),
+                )),
+            }
         }
+    }
 
-        let request = PutItemInput {
-            item: item,
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":zero".to_string() => AttributeValue::from_n(0),
+            ":one".to_string() => AttributeValue::from_n(1),
+            ":ip_address".to_string() => AttributeValue::from_s(ip_address),
+            ":attempt_id".to_string() => AttributeValue::from_s(attempt_id.clone()),
+        };
+
+        // the condition is what makes this n-time: each concurrent update sees the last one's count
+        //  links from before multi-download have neither count, and were single use
+        //  and an attempt that already counted is never counted again, whatever the count is now
+        let request = UpdateItemInput {
+            key: Row::token_key(token.clone()),
             table_name: self.links_table.clone(),
-            return_values: Some("ALL_OLD".to_string()),
+            update_expression: Some(format!(
+                "SET {} = if_not_exists({}, :zero) + :one, {} = :ip_address, {} = :attempt_id",
+                FIELD_DOWNLOADS,
+                FIELD_DOWNLOADS,
+                FIELD_IP_ADDRESS,
+                FIELD_CONSUME_ATTEMPT_ID,
+            )),
+            condition_expression: Some(format!(
+                "attribute_exists({}) AND attribute_not_exists({}) AND attribute_not_exists({}) AND (attribute_not_exists({}) OR {} < {}) AND (attribute_not_exists({}) OR {} <> :attempt_id)",
+                TOKEN_SUBSTITUTE,
+                FIELD_DOWNLOADED_AT,
+                FIELD_DELETED_AT,
+                FIELD_DOWNLOADS,
+                FIELD_DOWNLOADS,
+                FIELD_MAX_DOWNLOADS,
+                FIELD_CONSUME_ATTEMPT_ID,
+                FIELD_CONSUME_ATTEMPT_ID,
+            )),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            return_values: Some("ALL_NEW".to_string()),
             ..Default::default()
         };
 
-        match self.client.put_item(request).await {
-            Err(why) => Err(format!("Mark downloaded put failed: {}", why.to_string())),
-            Ok(output) => match output.attributes {
-                None => Ok(false),
-                Some(row) => match OnetimeLink::try_from(row) {
-                    Err(why) => Err(format!("Mark downloaded build failed: {}", why.to_string())),
-                    Ok(link) => Ok(link.downloaded_at.is_some()),
+        let link = match self.client.update_item(request).await {
+            // only pay for the extra reads when the link could not be consumed
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => match self.consumed_by(token.clone(), attempt_id).await.map_err(OnetimeError::Failed)? {
+                // a retry of an attempt whose update went through, but whose answer never came back
+                //  it may not have got as far as the second write below, so that still has to happen
+                Some(link) => link,
+                None => return match self.get_link(token).await {
+                    Err(why) => Err(OnetimeError::NotFound(why)),
+                    Ok(link) => Err(OnetimeError::AlreadyConsumed(link)),
                 },
+            },
+            Err(why) => return Err(OnetimeError::Failed(format!("Consume link failed: {}", why.to_string()))),
+            Ok(output) => match output.attributes {
+                None => return Err(OnetimeError::Failed("Consume link returned nothing".to_string())),
+                Some(row) => OnetimeLink::try_from(row).map_err(OnetimeError::Failed)?,
+            }
+        };
+        if link.downloads < link.max_downloads || link.downloaded_at.is_some() {
+            return Ok(link);
+        }
+
+        // update expressions have no conditionals, so the last download marks it in a second write
+        //  the count already stops any more downloads, this is what makes it read as consumed
+        let request = UpdateItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            update_expression: Some(format!("SET {} = :downloaded_at", FIELD_DOWNLOADED_AT)),
+            expression_attribute_values: Some(hashmap! {
+                ":downloaded_at".to_string() => AttributeValue::from_n(downloaded_at),
+            }),
+            return_values: Some("ALL_NEW".to_string()),
+            ..Default::default()
+        };
+
+        match self.client.update_item(request).await {
+            Err(why) => Err(OnetimeError::Failed(format!("Consume link failed: {}", why.to_string()))),
+            Ok(output) => match output.attributes {
+                None => Err(OnetimeError::Failed("Consume link returned nothing".to_string())),
+                Some(row) => OnetimeLink::try_from(row).map_err(OnetimeError::Failed),
+            }
+        }
+    }
+
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":one".to_string() => AttributeValue::from_n(1),
+            ":attempted_at".to_string() => AttributeValue::from_n(attempted_at),
+        };
+
+        // ADD is atomic, so concurrent attempts do not lose counts
+        let request = UpdateItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            update_expression: Some(format!("ADD {} :one SET {} = :attempted_at", FIELD_ATTEMPTS, FIELD_LAST_ATTEMPT_AT)),
+            // without this, an update to an unknown token would create a new item
+            condition_expression: Some(format!("attribute_exists({})", TOKEN_SUBSTITUTE)),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            ..Default::default()
+        };
+
+        match self.client.update_item(request).await {
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(why) => Err(format!("Record attempt failed: {}", why.to_string())),
+            Ok(_) => Ok(true),
+        }
+    }
+
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":deleted_at".to_string() => AttributeValue::from_n(deleted_at),
+        };
+
+        let request = UpdateItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            update_expression: Some(format!("SET {} = :deleted_at", FIELD_DELETED_AT)),
+            // keeps the original deletion time, and does not create items for unknown tokens
+            condition_expression: Some(format!("attribute_exists({}) AND attribute_not_exists({})", TOKEN_SUBSTITUTE, FIELD_DELETED_AT)),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            ..Default::default()
+        };
+
+        match self.client.update_item(request).await {
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(why) => Err(format!("Soft delete link failed: {}", why.to_string())),
+            Ok(_) => Ok(true),
+        }
+    }
+
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":revoked_at".to_string() => AttributeValue::from_n(revoked_at),
+        };
+
+        let request = UpdateItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            // downloaded_at too, so every consume path already refuses it
+            update_expression: Some(format!("SET {} = :revoked_at, {} = :revoked_at", FIELD_REVOKED_AT, FIELD_DOWNLOADED_AT)),
+            condition_expression: Some(format!(
+                "attribute_exists({}) AND attribute_not_exists({}) AND attribute_not_exists({})",
+                TOKEN_SUBSTITUTE,
+                FIELD_DOWNLOADED_AT,
+                FIELD_DELETED_AT,
+            )),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            ..Default::default()
+        };
+
+        match self.client.update_item(request).await {
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(why) => Err(format!("Revoke link failed: {}", why.to_string())),
+            Ok(_) => Ok(true),
+        }
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let request = ScanInput {
+            projection_expression: Some(link_projection_expression()),
+            filter_expression: Some(format!("attribute_exists({})", FIELD_DELETED_AT)),
+            expression_attribute_names: Some(expression_attribute_names),
+            table_name: self.links_table.clone(),
+            ..Default::default()
+        };
+
+        let rows = self.scan_all(request).await
+            .map_err(|why| format!("List deleted links failed: {}", why))?;
+        try_from_vec(rows, "links")
+    }
+
+    // a filtered scan, the whole table is still read but only these come back
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+        let expression_attribute_values = hashmap! {
+            ":expired_before".to_string() => AttributeValue::from_n(expired_before),
+        };
+
+        let request = ScanInput {
+            projection_expression: Some(link_projection_expression()),
+            filter_expression: Some(format!(
+                "{} < :expired_before AND attribute_not_exists({}) AND attribute_not_exists({})",
+                FIELD_EXPIRES_AT,
+                FIELD_DOWNLOADED_AT,
+                FIELD_DELETED_AT,
+            )),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            table_name: self.links_table.clone(),
+            ..Default::default()
+        };
+
+        let rows = self.scan_all(request).await
+            .map_err(|why| format!("List expired unused links failed: {}", why))?;
+        try_from_vec(rows, "links")
+    }
+
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError> {
+        let expression_attribute_values = hashmap! {
+            ":deleted_after".to_string() => AttributeValue::from_n(deleted_after),
+        };
+
+        let request = UpdateItemInput {
+            key: Row::token_key(token),
+            table_name: self.links_table.clone(),
+            update_expression: Some(format!("REMOVE {}", FIELD_DELETED_AT)),
+            condition_expression: Some(format!("{} >= :deleted_after", FIELD_DELETED_AT)),
+            expression_attribute_values: Some(expression_attribute_values),
+            ..Default::default()
+        };
+
+        match self.client.update_item(request).await {
+            Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(why) => Err(format!("Restore link failed: {}", why.to_string())),
+            Ok(_) => Ok(true),
+        }
+    }
+
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError> {
+        let expression_attribute_names = hashmap! {
+            TOKEN_SUBSTITUTE.to_string() => FIELD_TOKEN.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":deleted_before".to_string() => AttributeValue::from_n(deleted_before),
+        };
+
+        let request = ScanInput {
+            projection_expression: Some(TOKEN_SUBSTITUTE.to_string()),
+            filter_expression: Some(format!("{} < :deleted_before", FIELD_DELETED_AT)),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            table_name: self.links_table.clone(),
+            ..Default::default()
+        };
+
+        let rows = self.scan_all(request).await
+            .map_err(|why| format!("Purge deleted links scan failed: {}", why))?;
+
+        let keys = rows.into_iter()
+            .map(|row| row.get_s(&FIELD_TOKEN.to_string()).map(Row::token_key))
+            .collect::<Result<Vec<Row>, MyError>>()?;
+        let purged = keys.len() as u64;
+        self.batch_delete(self.links_table.clone(), keys).await
+            .map_err(|why| format!("Purge deleted links failed: {}", why))?;
+        Ok(purged)
+    }
+
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError> {
+        let expression_attribute_values = hashmap! {
+            ":expired_before".to_string() => AttributeValue::from_n(expired_before),
+        };
+
+        let request = ScanInput {
+            projection_expression: Some(FIELD_FILENAME.to_string()),
+            filter_expression: Some(format!("{} < :expired_before", FIELD_EXPIRES_AT)),
+            expression_attribute_values: Some(expression_attribute_values),
+            table_name: self.files_table.clone(),
+            ..Default::default()
+        };
+
+        let rows = self.scan_all(request).await
+            .map_err(|why| format!("Purge expired files scan failed: {}", why))?;
+
+        let keys = rows.into_iter()
+            .map(|row| row.get_s(&FIELD_FILENAME.to_string()).map(Row::filename_key))
+            .collect::<Result<Vec<Row>, MyError>>()?;
+        let purged = keys.len() as u64;
+        self.batch_delete(self.files_table.clone(), keys).await
+            .map_err(|why| format!("Purge expired files failed: {}", why))?;
+        Ok(purged)
+    }
+
+    // one TransactWriteItems call, so it is limited to TRANSACT_MAX_ITEMS writes
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        if ops.len() > TRANSACT_MAX_ITEMS {
+            return Err(format!("Transaction failed: {} writes is more than {}", ops.len(), TRANSACT_MAX_ITEMS));
+        }
+        if ops.is_empty() {
+            return Ok(true);
+        }
+
+        let transact_items = ops.into_iter().map(|op| match op {
+            StorageOp::DeleteFile(filename) => TransactWriteItem {
+                delete: Some(Delete { key: Row::filename_key(filename), table_name: self.files_table.clone(), ..Default::default() }),
+                ..Default::default()
+            },
+            StorageOp::DeleteLink(token) => TransactWriteItem {
+                delete: Some(Delete { key: Row::token_key(token), table_name: self.links_table.clone(), ..Default::default() }),
+                ..Default::default()
+            },
+            // no condition on an earlier deletion, that would fail the whole transaction, if_not_exists keeps its time instead
+            StorageOp::SoftDeleteLink(token, deleted_at) => TransactWriteItem {
+                update: Some(Update {
+                    key: Row::token_key(token),
+                    table_name: self.links_table.clone(),
+                    update_expression: format!("SET {} = if_not_exists({}, :deleted_at)", FIELD_DELETED_AT, FIELD_DELETED_AT),
+                    expression_attribute_values: Some(hashmap! {
+                        ":deleted_at".to_string() => AttributeValue::from_n(deleted_at),
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        }).collect();
+
+        let request = TransactWriteItemsInput {
+            transact_items: transact_items,
+            ..Default::default()
+        };
+
+        match self.client.transact_write_items(request).await {
+            Err(why) => Err(format!("Transaction failed: {}", why.to_string())),
+            Ok(_) => Ok(true)
+        }
+    }
+
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError> {
+        // Count and Bytes are reserved words
+        let expression_attribute_names = hashmap! {
+            "#count".to_string() => FIELD_COUNT.to_string(),
+            "#bytes".to_string() => FIELD_BYTES.to_string(),
+        };
+
+        let expression_attribute_values = hashmap! {
+            ":count".to_string() => AttributeValue::from_n(count),
+            ":bytes".to_string() => AttributeValue::from_n(bytes),
+        };
+
+        // ADD is atomic and starts a missing item or attribute at zero
+        let request = UpdateItemInput {
+            key: Row::new_key(FIELD_USAGE_KEY.to_string(), format!("{}#{}", owner, window_start)),
+            table_name: self.usage_table.clone(),
+            update_expression: Some("ADD #count :count, #bytes :bytes".to_string()),
+            expression_attribute_names: Some(expression_attribute_names),
+            expression_attribute_values: Some(expression_attribute_values),
+            return_values: Some("UPDATED_NEW".to_string()),
+            ..Default::default()
+        };
+
+        match self.client.update_item(request).await {
+            Err(why) => Err(format!("Add usage failed: {}", why.to_string())),
+            Ok(output) => {
+                let row = output.attributes.ok_or("Add usage returned no totals".to_string())?;
+                Ok((row.get_n(&FIELD_COUNT.to_string())?, row.get_n(&FIELD_BYTES.to_string())?))
             }
         }
     }
 
     async fn delete_file(&self, filename: String) -> Result<bool, MyError> {
+        // the old item only comes back when there was one, which is how to tell a delete of nothing
+        let request = DeleteItemInput {
+            key: Row::filename_key(filename),
+            table_name: self.files_table.clone(),
+            return_values: Some("ALL_OLD".to_string()),
+            ..Default::default()
+        };
+
+        match self.client.delete_item(request).await {
+            Err(why) => Err(format!("Delete file failed: {}", why.to_string())),
+            Ok(output) => Ok(output.attributes.is_some()),
+        }
+    }
+
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError> {
         let request = DeleteItemInput {
             key: Row::filename_key(filename),
             table_name: self.files_table.clone(),
+            condition_expression: Some(format!("{} = :updated_at", FIELD_UPDATED_AT)),
+            expression_attribute_values: Some(hashmap! {
+                ":updated_at".to_string() => AttributeValue::from_n(updated_at),
+            }),
             ..Default::default()
         };
 
         match self.client.delete_item(request).await {
+            // a missing item fails the condition too
+            Err(RusotoError::Service(DeleteItemError::ConditionalCheckFailed(_))) => Ok(false),
             Err(why) => Err(format!("Delete file failed: {}", why.to_string())),
             Ok(_) => Ok(true),
         }
     }
 
     async fn delete_link(&self, token: String) -> Result<bool, MyError> {
+        // the old item only comes back when there was one, which is how to tell a delete of nothing
         let request = DeleteItemInput {
             key: Row::token_key(token),
             table_name: self.links_table.clone(),
+            return_values: Some("ALL_OLD".to_string()),
             ..Default::default()
         };
 
         match self.client.delete_item(request).await {
             Err(why) => Err(format!("Delete link failed: {}", why.to_string())),
-            Ok(_) => Ok(true),
+            Ok(output) => Ok(output.attributes.is_some()),
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+    use rusoto_mock::MockRequestDispatcher;
+
+    const TABLE: &'static str = DEFAULT_TABLE_FILES;
+
+    fn storage (dispatchers: Vec<MockRequestDispatcher>) -> Storage {
+        Storage::mocked(dispatchers)
+    }
+
+    // answers each batch write with the next body in turn, and records how many items each one carried
+    fn batch_storage (bodies: Vec<&'static str>, sizes: Arc<Mutex<Vec<usize>>>) -> Storage {
+        storage(bodies.into_iter().map(|body| {
+            let sizes = sizes.clone();
+            MockRequestDispatcher::with_status(200)
+                .with_body(body)
+                .with_request_checker(move |request: &SignedRequest| {
+                    let payload = match &request.payload {
+                        Some(SignedRequestPayload::Buffer(payload)) => payload.clone(),
+                        _ => panic!("batch write without a body"),
+                    };
+                    let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+                    sizes.lock().unwrap().push(json["RequestItems"][TABLE].as_array().unwrap().len());
+                })
+        }).collect())
+    }
+
+    fn keys (count: usize) -> Vec<Row> {
+        (0..count).map(|i| Row::filename_key(format!("file{}", i))).collect()
+    }
+
+    const UNPROCESSED: &'static str = r#"{"UnprocessedItems": {"Onetime.Files": [
+        {"DeleteRequest": {"Key": {"Filename": {"S": "file1"}}}},
+        {"DeleteRequest": {"Key": {"Filename": {"S": "file2"}}}}
+    ]}}"#;
+
+    #[actix_rt::test]
+    async fn batch_writes_chunked_by_25 () {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let storage = batch_storage(vec!["{}", "{}", "{}"], sizes.clone());
+        storage.batch_delete(TABLE.to_string(), keys(60)).await.unwrap();
+        assert_eq!(*sizes.lock().unwrap(), vec![25, 25, 10]);
+    }
+
+    #[actix_rt::test]
+    async fn batch_writes_resend_unprocessed () {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let storage = batch_storage(vec![UNPROCESSED, "{}", "{}"], sizes.clone());
+        storage.batch_delete(TABLE.to_string(), keys(30)).await.unwrap();
+        // only the two unprocessed ones again, before moving on to the next chunk
+        assert_eq!(*sizes.lock().unwrap(), vec![25, 2, 5]);
+    }
+
+    #[actix_rt::test]
+    async fn batch_writes_give_up_after_retries () {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let storage = batch_storage(vec![UNPROCESSED; BATCH_RETRIES], sizes.clone());
+        assert!(storage.batch_delete(TABLE.to_string(), keys(3)).await.is_err());
+        assert_eq!(*sizes.lock().unwrap(), vec![3, 2, 2, 2, 2]);
+    }
+
+    #[actix_rt::test]
+    async fn empty_tables_list_nothing () {
+        // a scan of an empty table has no Items at all
+        let files_storage = storage(vec![MockRequestDispatcher::with_status(200).with_body(r#"{"Count": 0, "ScannedCount": 0}"#)]);
+        let (files, skipped) = files_storage.list_files(ListSort::default()).await.unwrap();
+        assert!(files.is_empty());
+        assert_eq!(skipped, 0);
+
+        let links_storage = storage(vec![MockRequestDispatcher::with_status(200).with_body(r#"{"Count": 0, "ScannedCount": 0}"#)]);
+        let (links, _) = links_storage.list_links(ListSort::default()).await.unwrap();
+        assert!(links.is_empty());
+    }
+
+    const CONDITION_FAILED: &'static str = r#"{"__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException", "message": "The conditional request failed"}"#;
+
+    // answers each update with the next status and body in turn, and records the update and condition it was sent
+    fn update_storage (answers: Vec<(u16, &'static str)>, sent: Arc<Mutex<Vec<(String, Option<String>)>>>) -> Storage {
+        storage(answers.into_iter().map(|(status, body)| {
+            let sent = sent.clone();
+            MockRequestDispatcher::with_status(status)
+                .with_body(body)
+                .with_request_checker(move |request: &SignedRequest| {
+                    let payload = match &request.payload {
+                        Some(SignedRequestPayload::Buffer(payload)) => payload.clone(),
+                        _ => panic!("update without a body"),
+                    };
+                    let json: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+                    sent.lock().unwrap().push((
+                        json["UpdateExpression"].as_str().unwrap().to_string(),
+                        json["ConditionExpression"].as_str().map(|s| s.to_string()),
+                    ));
+                })
+        }).collect())
+    }
+
+    async fn update (storage: &Storage, must_exist: bool) -> Result<bool, MyError> {
+        let sets = vec![format!("{} = :contents", FIELD_CONTENTS)];
+        let values = hashmap! { ":contents".to_string() => AttributeValue::from_s("abc".to_string()) };
+        storage.update_file_item("a.txt".to_string(), sets, Vec::new(), values, 1600000000000, must_exist).await
+    }
+
+    #[actix_rt::test]
+    async fn newer_update_written_once () {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let storage = update_storage(vec![(200, "{}")], sent.clone());
+        assert_eq!(update(&storage, false).await, Ok(true));
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, format!("SET {} = :contents, {} = :updated_at", FIELD_CONTENTS, FIELD_UPDATED_AT));
+    }
+
+    #[actix_rt::test]
+    async fn older_update_still_wins_with_bump () {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let storage = update_storage(vec![(400, CONDITION_FAILED), (200, "{}")], sent.clone());
+        assert_eq!(update(&storage, false).await, Ok(true));
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        // last writer wins, but updated_at never goes backwards
+        assert_eq!(sent[1].0, format!("SET {} = :contents, {} = {} + :one", FIELD_CONTENTS, FIELD_UPDATED_AT, FIELD_UPDATED_AT));
+        assert_eq!(sent[1].1, None);
+    }
+
+    #[actix_rt::test]
+    async fn update_of_missing_file_is_false () {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let storage = update_storage(vec![(400, CONDITION_FAILED), (400, CONDITION_FAILED)], sent.clone());
+        assert_eq!(update(&storage, true).await, Ok(false));
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[1].1, Some(format!("attribute_exists({})", FIELD_FILENAME)));
+    }
+}
@@ -0,0 +1,277 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bytes::{Bytes};
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use rand::Rng;
+
+use crate::locks;
+use crate::models::{FileInfo, LinkStatus, ListSort, MyError, OnetimeDownloaderConfig, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp, PoolStatus};
+
+
+// what one call does before (maybe) reaching the real storage
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub enum Fault {
+    Pass,
+    Fail,
+    Delay(u64),
+}
+
+// wraps another storage and makes some of its calls slow or fail, to see how the service holds up
+//  unlike invalid, which fails everything, most calls here still go through
+#[derive(Clone)]
+pub struct Storage {
+    inner: Box<dyn OnetimeStorage>,
+    failure_rate: f64,
+    delay_ms: u64,
+    // used up in call order before failure_rate and delay_ms apply, shared by clones so a script runs once
+    script: Arc<Mutex<VecDeque<Fault>>>,
+}
+
+#[allow(dead_code)]
+impl Storage {
+    pub fn new (inner: Box<dyn OnetimeStorage>) -> Self {
+        Self {
+            inner: inner,
+            failure_rate: 0.0,
+            delay_ms: 0,
+            script: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    // 0.0 never fails, 1.0 always does
+    pub fn failure_rate (mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    // added to every call that is not scripted
+    pub fn delay_ms (mut self, delay_ms: u64) -> Self {
+        self.delay_ms = delay_ms;
+        self
+    }
+
+    pub fn script (self, script: Vec<Fault>) -> Self {
+        *locks::lock(&self.script) = script.into_iter().collect();
+        self
+    }
+
+    // the inner storage as is, unless FAULT_FAILURE_RATE or FAULT_DELAY_MS is set
+    pub fn from_env (inner: Box<dyn OnetimeStorage>) -> Box<dyn OnetimeStorage> {
+        let failure_rate = OnetimeDownloaderConfig::env_var_parse("FAULT_FAILURE_RATE", 0.0);
+        let delay_ms = OnetimeDownloaderConfig::env_var_parse("FAULT_DELAY_MS", 0);
+        if failure_rate <= 0.0 && delay_ms == 0 {
+            return inner;
+        }
+        println!("!!!!! INJECTING STORAGE FAULTS: failure rate {}, delay {}ms !!!!!", failure_rate, delay_ms);
+        Box::new(Self::new(inner).failure_rate(failure_rate).delay_ms(delay_ms))
+    }
+
+    async fn fault (&self, method: &str) -> Result<(), MyError> {
+        let scripted = locks::lock(&self.script).pop_front();
+        let fault = match scripted {
+            Some(fault) => fault,
+            None if self.failure_rate > 0.0 && rand::thread_rng().gen::<f64>() < self.failure_rate => Fault::Fail,
+            None if self.delay_ms > 0 => Fault::Delay(self.delay_ms),
+            None => Fault::Pass,
+        };
+        match fault {
+            Fault::Pass => Ok(()),
+            Fault::Fail => Err(format!("Injected failure in {}!", method)),
+            Fault::Delay(delay_ms) => {
+                // https://docs.rs/tokio/0.2.22/tokio/time/fn.delay_for.html
+                tokio::time::delay_for(Duration::from_millis(delay_ms)).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+// https://github.com/dtolnay/async-trait#non-threadsafe-futures
+#[async_trait(?Send)]
+impl OnetimeStorage for Storage {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn pool_status (&self) -> Option<PoolStatus> {
+        self.inner.pool_status()
+    }
+
+    fn unconfigured (&self) -> Option<MyError> {
+        self.inner.unconfigured()
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        self.fault("ping").await?;
+        self.inner.ping().await
+    }
+
+    async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.fault("add_file").await?;
+        self.inner.add_file(file).await
+    }
+
+    async fn add_file_stream (&self, filename: String, now: i64, expires_at: Option<i64>, content_type: Option<String>, stream: LocalBoxStream<'static, Result<Bytes, MyError>>) -> Result<bool, MyError> {
+        self.fault("add_file_stream").await?;
+        self.inner.add_file_stream(filename, now, expires_at, content_type, stream).await
+    }
+
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.fault("create_file").await?;
+        self.inner.create_file(file).await
+    }
+
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.fault("add_files").await?;
+        self.inner.add_files(files, overwrite).await
+    }
+
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError> {
+        self.fault("update_file").await?;
+        self.inner.update_file(filename, contents, content_type, updated_at).await
+    }
+
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
+        self.fault("list_files").await?;
+        self.inner.list_files(sort).await
+    }
+
+    async fn get_file (&self, filename: String) -> Result<OnetimeFile, MyError>  {
+        self.fault("get_file").await?;
+        self.inner.get_file(filename).await
+    }
+
+    async fn get_file_info (&self, filename: String) -> Result<FileInfo, MyError>  {
+        self.fault("get_file_info").await?;
+        self.inner.get_file_info(filename).await
+    }
+
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        self.fault("list_files_page").await?;
+        self.inner.list_files_page(after, limit).await
+    }
+
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        self.fault("files_exist").await?;
+        self.inner.files_exist(filenames).await
+    }
+
+    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
+        self.fault("add_link").await?;
+        self.inner.add_link(link).await
+    }
+
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        self.fault("add_links").await?;
+        self.inner.add_links(links).await
+    }
+
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.fault("import_links").await?;
+        self.inner.import_links(links, overwrite).await
+    }
+
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
+        self.fault("list_links").await?;
+        self.inner.list_links(sort).await
+    }
+
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        self.fault("list_links_page").await?;
+        self.inner.list_links_page(after, limit).await
+    }
+
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError> {
+        self.fault("list_links_for_file").await?;
+        self.inner.list_links_for_file(filename).await
+    }
+
+    async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.fault("get_link").await?;
+        self.inner.get_link(token).await
+    }
+
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.fault("get_any_link").await?;
+        self.inner.get_any_link(token).await
+    }
+
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError> {
+        self.fault("link_status").await?;
+        self.inner.link_status(token).await
+    }
+
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        self.fault("consume_link").await.map_err(OnetimeError::Failed)?;
+        self.inner.consume_link(token, ip_address, downloaded_at, attempt_id).await
+    }
+
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError> {
+        self.fault("record_attempt").await?;
+        self.inner.record_attempt(token, attempted_at).await
+    }
+
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError> {
+        self.fault("soft_delete_link").await?;
+        self.inner.soft_delete_link(token, deleted_at).await
+    }
+
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError> {
+        self.fault("revoke_link").await?;
+        self.inner.revoke_link(token, revoked_at).await
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        self.fault("list_deleted_links").await?;
+        self.inner.list_deleted_links().await
+    }
+
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        self.fault("list_expired_unused_links").await?;
+        self.inner.list_expired_unused_links(expired_before).await
+    }
+
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError> {
+        self.fault("restore_link").await?;
+        self.inner.restore_link(token, deleted_after).await
+    }
+
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError> {
+        self.fault("purge_deleted_links").await?;
+        self.inner.purge_deleted_links(deleted_before).await
+    }
+
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError> {
+        self.fault("purge_expired_files").await?;
+        self.inner.purge_expired_files(expired_before).await
+    }
+
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        self.fault("transact").await?;
+        self.inner.transact(ops).await
+    }
+
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError> {
+        self.fault("add_usage").await?;
+        self.inner.add_usage(owner, window_start, count, bytes).await
+    }
+
+    async fn delete_file (&self, filename: String) -> Result<bool, MyError> {
+        self.fault("delete_file").await?;
+        self.inner.delete_file(filename).await
+    }
+
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError> {
+        self.fault("delete_file_if_unchanged").await?;
+        self.inner.delete_file_if_unchanged(filename, updated_at).await
+    }
+
+    async fn delete_link (&self, token: String) -> Result<bool, MyError> {
+        self.fault("delete_link").await?;
+        self.inner.delete_link(token).await
+    }
+}
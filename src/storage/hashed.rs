@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use bytes::{Bytes};
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use sha2::{Digest, Sha256};
+
+use crate::models::{FileInfo, LinkStatus, ListSort, MyError, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp, PoolStatus};
+
+
+// marks a stored token as already hashed, so exports can be imported again as they are
+pub const HASHED_PREFIX: &'static str = "sha256:";
+
+// every token that reaches storage is hashed, lookups hash the token from the url the same way
+pub fn hash_token (token: &str) -> String {
+    format!("{}{:x}", HASHED_PREFIX, Sha256::digest(token.as_bytes()))
+}
+
+fn is_hashed (token: &str) -> bool {
+    token.starts_with(HASHED_PREFIX)
+}
+
+// links going in through import (exports of this same table) already have hashed tokens,
+//  and admin calls may be given the hash a list showed rather than the raw token, never downloads
+fn stored_token (token: &str) -> String {
+    if is_hashed(token) {
+        token.to_string()
+    } else {
+        hash_token(token)
+    }
+}
+
+fn hashed_link (mut link: OnetimeLink) -> OnetimeLink {
+    link.token = hash_token(link.token.as_str());
+    link
+}
+
+// links read by token go back out with the token they were asked for, handlers pass it on to the next call
+fn raw_link (mut link: OnetimeLink, token: &str) -> OnetimeLink {
+    link.token = token.to_string();
+    link
+}
+
+// wraps another storage so only sha-256 hashes of link tokens are ever at rest, the raw token is only in the url
+//  lists and exports show the hashes, which can't be used to download anything
+#[derive(Clone)]
+pub struct Storage {
+    pub inner: Box<dyn OnetimeStorage>,
+}
+
+// https://github.com/dtolnay/async-trait#non-threadsafe-futures
+#[async_trait(?Send)]
+impl OnetimeStorage for Storage {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn pool_status (&self) -> Option<PoolStatus> {
+        self.inner.pool_status()
+    }
+
+    fn unconfigured (&self) -> Option<MyError> {
+        self.inner.unconfigured()
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        self.inner.ping().await
+    }
+
+    async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.inner.add_file(file).await
+    }
+
+    async fn add_file_stream (&self, filename: String, now: i64, expires_at: Option<i64>, content_type: Option<String>, stream: LocalBoxStream<'static, Result<Bytes, MyError>>) -> Result<bool, MyError> {
+        self.inner.add_file_stream(filename, now, expires_at, content_type, stream).await
+    }
+
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.inner.create_file(file).await
+    }
+
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.inner.add_files(files, overwrite).await
+    }
+
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError> {
+        self.inner.update_file(filename, contents, content_type, updated_at).await
+    }
+
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
+        self.inner.list_files(sort).await
+    }
+
+    async fn get_file (&self, filename: String) -> Result<OnetimeFile, MyError>  {
+        self.inner.get_file(filename).await
+    }
+
+    async fn get_file_info (&self, filename: String) -> Result<FileInfo, MyError>  {
+        self.inner.get_file_info(filename).await
+    }
+
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        self.inner.list_files_page(after, limit).await
+    }
+
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        self.inner.files_exist(filenames).await
+    }
+
+    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
+        self.inner.add_link(hashed_link(link)).await
+    }
+
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        self.inner.add_links(links.into_iter().map(hashed_link).collect()).await
+    }
+
+    // the tokens written are reported as they were given, hashed or not
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        let mut given = HashMap::new();
+        let mut hashed = Vec::new();
+        for mut link in links.into_iter() {
+            let token = stored_token(link.token.as_str());
+            given.insert(token.clone(), link.token);
+            link.token = token;
+            hashed.push(link);
+        }
+        let written = self.inner.import_links(hashed, overwrite).await?;
+        Ok(written.into_iter().map(|token| given.remove(&token).unwrap_or(token)).collect())
+    }
+
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
+        self.inner.list_links(sort).await
+    }
+
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        self.inner.list_links_page(after, limit).await
+    }
+
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError> {
+        self.inner.list_links_for_file(filename).await
+    }
+
+    // downloads only ever hash, so a hash from a leaked table or export can't be used to download
+    async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        let link = self.inner.get_link(hash_token(token.as_str())).await?;
+        Ok(raw_link(link, token.as_str()))
+    }
+
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        let link = self.inner.get_any_link(stored_token(token.as_str())).await?;
+        Ok(raw_link(link, token.as_str()))
+    }
+
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError> {
+        self.inner.link_status(stored_token(token.as_str())).await
+    }
+
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        match self.inner.consume_link(hash_token(token.as_str()), ip_address, downloaded_at, attempt_id).await {
+            Ok(link) => Ok(raw_link(link, token.as_str())),
+            Err(OnetimeError::AlreadyConsumed(link)) => Err(OnetimeError::AlreadyConsumed(raw_link(link, token.as_str()))),
+            Err(why) => Err(why),
+        }
+    }
+
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError> {
+        self.inner.record_attempt(hash_token(token.as_str()), attempted_at).await
+    }
+
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError> {
+        self.inner.soft_delete_link(stored_token(token.as_str()), deleted_at).await
+    }
+
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError> {
+        self.inner.revoke_link(stored_token(token.as_str()), revoked_at).await
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        self.inner.list_deleted_links().await
+    }
+
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        self.inner.list_expired_unused_links(expired_before).await
+    }
+
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError> {
+        self.inner.restore_link(stored_token(token.as_str()), deleted_after).await
+    }
+
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError> {
+        self.inner.purge_deleted_links(deleted_before).await
+    }
+
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError> {
+        self.inner.purge_expired_files(expired_before).await
+    }
+
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        // the same as delete_link, tokens from a listing are already stored hashed
+        let ops = ops.into_iter().map(|op| match op {
+            StorageOp::DeleteLink(token) => StorageOp::DeleteLink(stored_token(token.as_str())),
+            StorageOp::SoftDeleteLink(token, deleted_at) => StorageOp::SoftDeleteLink(stored_token(token.as_str()), deleted_at),
+            op => op,
+        }).collect();
+        self.inner.transact(ops).await
+    }
+
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError> {
+        self.inner.add_usage(owner, window_start, count, bytes).await
+    }
+
+    async fn delete_file (&self, filename: String) -> Result<bool, MyError> {
+        self.inner.delete_file(filename).await
+    }
+
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError> {
+        self.inner.delete_file_if_unchanged(filename, updated_at).await
+    }
+
+    async fn delete_link (&self, token: String) -> Result<bool, MyError> {
+        self.inner.delete_link(stored_token(token.as_str())).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_token_is_prefixed_sha256 () {
+        assert_eq!(hash_token("abc"), "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert!(is_hashed(hash_token("abc").as_str()));
+        assert!(!is_hashed("abc"));
+    }
+
+    #[test]
+    fn stored_token_hashes_raw_tokens_once () {
+        let hashed = hash_token("0123456789abcdef");
+        assert_eq!(stored_token("0123456789abcdef"), hashed);
+        // what a list shows finds the same row as the raw token
+        assert_eq!(stored_token(hashed.as_str()), hashed);
+    }
+
+    #[test]
+    fn links_round_trip () {
+        let link: OnetimeLink = serde_json::from_str(r#"{"token":"0123456789abcdef","filename":"a.txt","created_at":0,"expires_at":0}"#).unwrap();
+        let stored = hashed_link(link);
+        assert_eq!(stored.token, hash_token("0123456789abcdef"));
+        let read = raw_link(stored, "0123456789abcdef");
+        assert_eq!(read.token, "0123456789abcdef");
+        assert_eq!(read.filename, "a.txt");
+    }
+}
@@ -1,7 +1,9 @@
 
+use std::collections::HashMap;
+use bytes::{Bytes};
 use async_trait::async_trait;
 
-use crate::models::{MyError, OnetimeFile, OnetimeLink, OnetimeStorage};
+use crate::models::{LinkStatus, ListSort, MyError, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp};
 
 
 #[derive(Clone)]
@@ -16,11 +18,31 @@ impl OnetimeStorage for Storage {
         "INVALID"
     }
 
+    fn unconfigured (&self) -> Option<MyError> {
+        Some(self.error.clone())
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
     async fn add_file (&self, _file: OnetimeFile) -> Result<bool, MyError> {
         Err(self.error.clone())
     }
 
-    async fn list_files (&self) -> Result<Vec<OnetimeFile>, MyError>  {
+    async fn create_file (&self, _file: OnetimeFile) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn add_files (&self, _files: Vec<OnetimeFile>, _overwrite: bool) -> Result<Vec<String>, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn update_file (&self, _filename: String, _contents: Bytes, _content_type: Option<String>, _updated_at: i64) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn list_files (&self, _sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
         Err(self.error.clone())
     }
 
@@ -28,11 +50,35 @@ impl OnetimeStorage for Storage {
         Err(self.error.clone())
     }
 
+    async fn list_files_page (&self, _after: Option<String>, _limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn files_exist (&self, _filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        Err(self.error.clone())
+    }
+
     async fn add_link (&self, _link: OnetimeLink) -> Result<bool, MyError> {
         Err(self.error.clone())
     }
 
-    async fn list_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+    async fn add_links (&self, _links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn import_links (&self, _links: Vec<OnetimeLink>, _overwrite: bool) -> Result<Vec<String>, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn list_links (&self, _sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn list_links_page (&self, _after: Option<String>, _limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn list_links_for_file (&self, _filename: String) -> Result<Vec<OnetimeLink>, MyError> {
         Err(self.error.clone())
     }
 
@@ -40,7 +86,55 @@ impl OnetimeStorage for Storage {
         Err(self.error.clone())
     }
 
-    async fn mark_downloaded (&self, _link: OnetimeLink, _ip_address: String, _downloaded_at: i64) -> Result<bool, MyError> {
+    async fn get_any_link (&self, _token: String) -> Result<OnetimeLink, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn link_status (&self, _token: String) -> Result<LinkStatus, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn consume_link (&self, _token: String, _ip_address: String, _downloaded_at: i64, _attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        Err(OnetimeError::Failed(self.error.clone()))
+    }
+
+    async fn record_attempt (&self, _token: String, _attempted_at: i64) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn soft_delete_link (&self, _token: String, _deleted_at: i64) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn revoke_link (&self, _token: String, _revoked_at: i64) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn list_expired_unused_links (&self, _expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn restore_link (&self, _token: String, _deleted_after: i64) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn purge_deleted_links (&self, _deleted_before: i64) -> Result<u64, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn purge_expired_files (&self, _expired_before: i64) -> Result<u64, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn transact (&self, _ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
+    async fn add_usage (&self, _owner: String, _window_start: i64, _count: i64, _bytes: i64) -> Result<(i64, i64), MyError> {
         Err(self.error.clone())
     }
 
@@ -48,6 +142,10 @@ impl OnetimeStorage for Storage {
         Err(self.error.clone())
     }
 
+    async fn delete_file_if_unchanged (&self, _filename: String, _updated_at: i64) -> Result<bool, MyError> {
+        Err(self.error.clone())
+    }
+
     async fn delete_link (&self, _token: String) -> Result<bool, MyError> {
         Err(self.error.clone())
     }
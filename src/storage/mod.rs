@@ -1,6 +1,13 @@
 
 mod util;
 
+#[cfg(feature = "dynamodb")]
 pub mod dynamodb;
 pub mod invalid;
+#[cfg(feature = "faults")]
+pub mod faulty;
+pub mod hashed;
+#[cfg(feature = "postgres")]
 pub mod postgres;
+pub mod timed;
+pub mod traced;
@@ -1,19 +1,22 @@
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use bytes::{Bytes};
 use async_trait::async_trait;
 
-use deadpool_postgres::{Client, Config, Pool};
-use tokio_postgres::{NoTls, row::Row};
+use futures::pin_mut;
+use deadpool_postgres::{Client, Config, ManagerConfig, Pool, RecyclingMethod};
+use tokio_postgres::{NoTls, binary_copy::BinaryCopyInWriter, row::Row, types::{ToSql, Type}};
 
 use crate::time_provider::TimeProvider;
-use crate::models::{MyError, OnetimeDownloaderConfig, OnetimeFile, OnetimeLink, OnetimeStorage};
-use super::util::{try_from_vec};
+use crate::models::{FileInfo, LinkStatus, ListSort, SortField, SortOrder, MyError, OnetimeDownloaderConfig, PoolStatus, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp};
+use super::util::{link_status, list_from_vec, try_from_vec};
 
 
 const DEFAULT_SCHEMA: &'static str = "onetime";
 const DEFAULT_TABLE_FILES: &'static str = "files";
 const DEFAULT_TABLE_LINKS: &'static str = "links";
+const DEFAULT_TABLE_USAGE: &'static str = "usage";
 
 const DEFAULT_HOST: &'static str = "postgres";
 const DEFAULT_PORT: &'static str = "5432";
@@ -21,16 +24,78 @@ const DEFAULT_USER: &'static str = "postgres";
 const DEFAULT_PASSWORD: &'static str = "";
 const DEFAULT_DBNAME: &'static str = "postgres";
 
+// how many connections to retire in one checkout, before using an old one anyway or giving up on dead ones
+const MAX_LIFETIME_RETRIES: usize = 3;
+
+// session-local, dropped again when the add_files transaction ends
+const TABLE_FILES_STAGING: &'static str = "files_staging";
+
+// every *_at column, and window_start, is a BIGINT of unix ms, none are seconds
 const FIELD_FILENAME: &'static str = "filename";
 const FIELD_CONTENTS: &'static str = "contents";
+// NULL for contents stored as they are, otherwise how they were compressed
+const FIELD_COMPRESSION: &'static str = "compression";
 const FIELD_CREATED_AT: &'static str = "created_at";
 const FIELD_UPDATED_AT: &'static str = "updated_at";
+const FIELD_CONTENT_TYPE: &'static str = "content_type";
+// not a column, OCTET_LENGTH(contents) for file info
+const FIELD_SIZE: &'static str = "size";
 
 const FIELD_TOKEN: &'static str = "token";
 const FIELD_NOTE: &'static str = "note";
 const FIELD_EXPIRES_AT: &'static str = "expires_at";
 const FIELD_DOWNLOADED_AT: &'static str = "downloaded_at";
 const FIELD_IP_ADDRESS: &'static str = "ip_address";
+const FIELD_ATTEMPTS: &'static str = "attempts";
+const FIELD_LAST_ATTEMPT_AT: &'static str = "last_attempt_at";
+const FIELD_TOTP_SECRET: &'static str = "totp_secret";
+const FIELD_ALLOWED_CIDRS: &'static str = "allowed_cidrs";
+const FIELD_ALLOWED_COUNTRIES: &'static str = "allowed_countries";
+const FIELD_BLOCKED_COUNTRIES: &'static str = "blocked_countries";
+const FIELD_DELETED_AT: &'static str = "deleted_at";
+const FIELD_REDIRECT_URL: &'static str = "redirect_url";
+const FIELD_DOWNLOADS: &'static str = "downloads";
+const FIELD_MAX_DOWNLOADS: &'static str = "max_downloads";
+const FIELD_REVOKED_AT: &'static str = "revoked_at";
+// only written and matched by consume_link, never read back into a link
+const FIELD_CONSUME_ATTEMPT_ID: &'static str = "consume_attempt_id";
+
+const FIELD_OWNER: &'static str = "owner";
+const FIELD_WINDOW_START: &'static str = "window_start";
+const FIELD_COUNT: &'static str = "count";
+const FIELD_BYTES: &'static str = "bytes";
+
+const FILE_FIELDS: &'static [&'static str] = &[
+    FIELD_FILENAME,
+    FIELD_CONTENTS,
+    FIELD_CREATED_AT,
+    FIELD_UPDATED_AT,
+    FIELD_EXPIRES_AT,
+    FIELD_CONTENT_TYPE,
+    FIELD_COMPRESSION,
+];
+
+// selected and inserted in this order, the add_link params must match
+const LINK_FIELDS: &'static [&'static str] = &[
+    FIELD_TOKEN,
+    FIELD_FILENAME,
+    FIELD_NOTE,
+    FIELD_CREATED_AT,
+    FIELD_EXPIRES_AT,
+    FIELD_DOWNLOADED_AT,
+    FIELD_IP_ADDRESS,
+    FIELD_ATTEMPTS,
+    FIELD_LAST_ATTEMPT_AT,
+    FIELD_TOTP_SECRET,
+    FIELD_ALLOWED_CIDRS,
+    FIELD_ALLOWED_COUNTRIES,
+    FIELD_BLOCKED_COUNTRIES,
+    FIELD_DELETED_AT,
+    FIELD_REDIRECT_URL,
+    FIELD_DOWNLOADS,
+    FIELD_MAX_DOWNLOADS,
+    FIELD_REVOKED_AT,
+];
 
 
 #[derive(Clone)]
@@ -39,7 +104,97 @@ pub struct Storage {
     schema: String,
     files_table: String,
     links_table: String,
+    usage_table: String,
+    // skip unreadable rows in whole lists rather than failing them
+    lenient_lists: bool,
+    // PG_COMPRESSION=zstd compresses contents before they are sent, 0 (the default) stores them as they are
+    compression_level: i32,
     pool: Pool,
+    max_lifetime_ms: i64,
+}
+
+// $1, $2, ... $count
+fn placeholders (count: usize) -> String {
+    (1..=count).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ")
+}
+
+// ($1, $2, ... $width), ($width+1, ...) for a multi-row insert
+fn row_placeholders (rows: usize, width: usize) -> String {
+    (0..rows)
+        .map(|row| format!("({})", (row * width + 1..=(row + 1) * width).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// in LINK_FIELDS order
+fn link_params (link: &OnetimeLink) -> [&(dyn ToSql + Sync); 18] {
+    [
+        &link.token,
+        &link.filename,
+        &link.note,
+        &link.created_at,
+        &link.expires_at,
+        &link.downloaded_at,
+        &link.ip_address,
+        &link.attempts,
+        &link.last_attempt_at,
+        &link.totp_secret,
+        &link.allowed_cidrs,
+        &link.allowed_countries,
+        &link.blocked_countries,
+        &link.deleted_at,
+        &link.redirect_url,
+        &link.downloads,
+        &link.max_downloads,
+        &link.revoked_at,
+    ]
+}
+
+const COMPRESSION_ZSTD: &'static str = "zstd";
+// the zstd default, a good tradeoff for documents
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+// https://docs.rs/zstd/0.5.3/zstd/stream/functions/fn.decode_all.html
+fn decompress (contents: Vec<u8>, compression: Option<String>) -> Result<Vec<u8>, MyError> {
+    match compression.as_deref() {
+        None => Ok(contents),
+        Some(COMPRESSION_ZSTD) => zstd::stream::decode_all(contents.as_slice()).map_err(|why| format!("Could not decompress contents! {}", why)),
+        Some(other) => Err(format!("Unknown contents compression '{}'", other)),
+    }
+}
+
+// identifiers can't be bind params, so anything format!-ed into sql must be a plain unquoted name
+// https://www.postgresql.org/docs/12/sql-syntax-lexical.html#SQL-SYNTAX-IDENTIFIERS
+fn identifier (name: String) -> Result<String, MyError> {
+    let valid = !name.is_empty() && name.len() <= 63 &&
+        !name.starts_with(|c: char| c.is_ascii_digit()) &&
+        name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(name)
+    } else {
+        Err(format!("Invalid sql identifier '{}'", name))
+    }
+}
+
+// sort/filter columns must be one of the known fields of the table being queried
+fn column (fields: &[&'static str], name: &str) -> Result<&'static str, MyError> {
+    match fields.iter().find(|field| **field == name) {
+        Some(field) => Ok(*field),
+        None => Err(format!("Unknown column '{}'", name)),
+    }
+}
+
+fn order_by (fields: &[&'static str], sort: ListSort) -> Result<String, MyError> {
+    let name = match sort.field {
+        SortField::CreatedAt => FIELD_CREATED_AT,
+        SortField::ExpiresAt => FIELD_EXPIRES_AT,
+        SortField::Filename => FIELD_FILENAME,
+    };
+    let direction = match sort.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    Ok(format!("ORDER BY {} {}", column(fields, name)?, direction))
 }
 
 impl TryFrom<Row> for OnetimeFile {
@@ -51,14 +206,20 @@ impl TryFrom<Row> for OnetimeFile {
         let filename = row.try_get(&FIELD_FILENAME).map_err(|why| format!("Could not get filename! {}", why))?;
         // https://docs.rs/tokio-postgres/0.5.0-alpha.1/tokio_postgres/types/trait.FromSql.html
         let contents: Vec<u8> = row.try_get(&FIELD_CONTENTS).map_err(|why| format!("Could not get contents! {}", why))?;
+        let compression: Option<String> = row.try_get(&FIELD_COMPRESSION).map_err(|why| format!("Could not get compression! {}", why))?;
+        let contents = decompress(contents, compression)?;
         let created_at = row.try_get(&FIELD_CREATED_AT).map_err(|why| format!("Could not get created_at! {}", why))?;
         let updated_at = row.try_get(&FIELD_UPDATED_AT).map_err(|why| format!("Could not get updated_at! {}", why))?;
+        let expires_at = row.try_get(&FIELD_EXPIRES_AT).map_err(|why| format!("Could not get expires_at! {}", why))?;
+        let content_type = row.try_get(&FIELD_CONTENT_TYPE).map_err(|why| format!("Could not get content_type! {}", why))?;
 
         Ok(Self {
             filename: filename,
             contents: Bytes::from(contents),
             created_at: created_at,
             updated_at: updated_at,
+            expires_at: expires_at,
+            content_type: content_type,
         })
     }
 }
@@ -74,6 +235,17 @@ impl TryFrom<Row> for OnetimeLink {
         let expires_at = row.try_get(&FIELD_EXPIRES_AT).map_err(|why| format!("Could not get {}! {}", FIELD_EXPIRES_AT, why))?;
         let downloaded_at = row.try_get(&FIELD_DOWNLOADED_AT).map_err(|why| format!("Could not get {}! {}", FIELD_DOWNLOADED_AT, why))?;
         let ip_address = row.try_get(&FIELD_IP_ADDRESS).map_err(|why| format!("Could not get {}! {}", FIELD_IP_ADDRESS, why))?;
+        let attempts = row.try_get(&FIELD_ATTEMPTS).map_err(|why| format!("Could not get {}! {}", FIELD_ATTEMPTS, why))?;
+        let last_attempt_at = row.try_get(&FIELD_LAST_ATTEMPT_AT).map_err(|why| format!("Could not get {}! {}", FIELD_LAST_ATTEMPT_AT, why))?;
+        let totp_secret = row.try_get(&FIELD_TOTP_SECRET).map_err(|why| format!("Could not get {}! {}", FIELD_TOTP_SECRET, why))?;
+        let allowed_cidrs = row.try_get(&FIELD_ALLOWED_CIDRS).map_err(|why| format!("Could not get {}! {}", FIELD_ALLOWED_CIDRS, why))?;
+        let allowed_countries = row.try_get(&FIELD_ALLOWED_COUNTRIES).map_err(|why| format!("Could not get {}! {}", FIELD_ALLOWED_COUNTRIES, why))?;
+        let blocked_countries = row.try_get(&FIELD_BLOCKED_COUNTRIES).map_err(|why| format!("Could not get {}! {}", FIELD_BLOCKED_COUNTRIES, why))?;
+        let deleted_at = row.try_get(&FIELD_DELETED_AT).map_err(|why| format!("Could not get {}! {}", FIELD_DELETED_AT, why))?;
+        let redirect_url = row.try_get(&FIELD_REDIRECT_URL).map_err(|why| format!("Could not get {}! {}", FIELD_REDIRECT_URL, why))?;
+        let downloads = row.try_get(&FIELD_DOWNLOADS).map_err(|why| format!("Could not get {}! {}", FIELD_DOWNLOADS, why))?;
+        let max_downloads = row.try_get(&FIELD_MAX_DOWNLOADS).map_err(|why| format!("Could not get {}! {}", FIELD_MAX_DOWNLOADS, why))?;
+        let revoked_at = row.try_get(&FIELD_REVOKED_AT).map_err(|why| format!("Could not get {}! {}", FIELD_REVOKED_AT, why))?;
 
         Ok(Self {
             token: token,
@@ -83,12 +255,23 @@ impl TryFrom<Row> for OnetimeLink {
             expires_at: expires_at,
             downloaded_at: downloaded_at,
             ip_address: ip_address,
+            attempts: attempts,
+            last_attempt_at: last_attempt_at,
+            totp_secret: totp_secret,
+            allowed_cidrs: allowed_cidrs,
+            allowed_countries: allowed_countries,
+            blocked_countries: blocked_countries,
+            deleted_at: deleted_at,
+            redirect_url: redirect_url,
+            downloads: downloads,
+            max_downloads: max_downloads,
+            revoked_at: revoked_at,
         })
     }
 }
 
 impl Storage {
-    pub fn from_env (time_provider: Box<dyn TimeProvider>) -> Result<Self, MyError> {
+    pub fn from_env (time_provider: Box<dyn TimeProvider>, lenient_lists: bool) -> Result<Self, MyError> {
         // https://crates.io/crates/deadpool-postgres
         let cfg = Config {
             host: Some(OnetimeDownloaderConfig::env_var_string("PG_HOST", String::from(DEFAULT_HOST))),
@@ -99,22 +282,106 @@ impl Storage {
             user: Some(OnetimeDownloaderConfig::env_var_string("PG_USER", String::from(DEFAULT_USER))),
             password: Some(OnetimeDownloaderConfig::env_var_string("PG_PASS", String::from(DEFAULT_PASSWORD))),
             dbname: Some(OnetimeDownloaderConfig::env_var_string("PG_DBNAME", String::from(DEFAULT_DBNAME))),
+            // verified runs a query on every idle connection before handing it out,
+            //  so one killed by a db restart or failover is replaced instead of failing the request
+            manager: Some(ManagerConfig {
+                recycling_method: if OnetimeDownloaderConfig::env_var_parse("PG_CHECK_ON_CHECKOUT", true) {
+                    RecyclingMethod::Verified
+                } else {
+                    RecyclingMethod::Fast
+                },
+            }),
             ..Default::default()
         };
 
         let storage = Self {
             time_provider: time_provider,
-            schema: OnetimeDownloaderConfig::env_var_string("PG_SCHEMA", String::from(DEFAULT_SCHEMA)),
-            files_table: OnetimeDownloaderConfig::env_var_string("PG_FILES_TABLE", String::from(DEFAULT_TABLE_FILES)),
-            links_table: OnetimeDownloaderConfig::env_var_string("PG_LINKS_TABLE", String::from(DEFAULT_TABLE_LINKS)),
+            // these get interpolated into every query, so refuse anything that isn't a bare identifier
+            // the prefix goes on the schema, default or explicit, eg "staging_" for "staging_onetime"
+            schema: identifier(format!(
+                "{}{}",
+                OnetimeDownloaderConfig::env_var_string("PG_SCHEMA_PREFIX", String::new()),
+                OnetimeDownloaderConfig::env_var_string("PG_SCHEMA", String::from(DEFAULT_SCHEMA)),
+            ))?,
+            files_table: identifier(OnetimeDownloaderConfig::env_var_string("PG_FILES_TABLE", String::from(DEFAULT_TABLE_FILES)))?,
+            links_table: identifier(OnetimeDownloaderConfig::env_var_string("PG_LINKS_TABLE", String::from(DEFAULT_TABLE_LINKS)))?,
+            usage_table: identifier(OnetimeDownloaderConfig::env_var_string("PG_USAGE_TABLE", String::from(DEFAULT_TABLE_USAGE)))?,
+            lenient_lists: lenient_lists,
+            compression_level: match OnetimeDownloaderConfig::env_var_string("PG_COMPRESSION", String::new()).as_str() {
+                "" | "none" => 0,
+                COMPRESSION_ZSTD => OnetimeDownloaderConfig::env_var_parse("PG_COMPRESSION_LEVEL", DEFAULT_COMPRESSION_LEVEL),
+                other => return Err(format!("Unknown PG_COMPRESSION '{}', use none or zstd", other)),
+            },
             pool: cfg.create_pool(NoTls).map_err(|why| format!("Failed creating pool: {}", why))?,
+            // 0 keeps connections for as long as they work
+            max_lifetime_ms: OnetimeDownloaderConfig::env_var_parse("PG_MAX_LIFETIME_MS", 0),
         };
 
         Ok(storage)
     }
 
+    // contents as they will be stored, with the marker for reading them back
+    //  left as they are when compression is off, or would not make them any smaller (already compressed formats)
+    // https://docs.rs/zstd/0.5.3/zstd/stream/functions/fn.encode_all.html
+    fn compress (&self, contents: &Bytes) -> Result<(Bytes, Option<String>), MyError> {
+        if self.compression_level == 0 || contents.is_empty() {
+            return Ok((contents.clone(), None));
+        }
+        let compressed = zstd::stream::encode_all(contents.as_ref(), self.compression_level)
+            .map_err(|why| format!("Could not compress contents! {}", why))?;
+        if compressed.len() >= contents.len() {
+            return Ok((contents.clone(), None));
+        }
+        Ok((Bytes::from(compressed), Some(COMPRESSION_ZSTD.to_string())))
+    }
+
+    // the link as the consume with this attempt id left it, None when no such consume went through
+    async fn consumed_by (&self, token: String, attempt_id: String) -> Result<Option<OnetimeLink>, MyError> {
+        match self.client().await?.query_opt(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} = $1 AND {} = $2",
+                LINK_FIELDS.join(", "),
+                self.schema,
+                self.links_table,
+                FIELD_TOKEN,
+                FIELD_CONSUME_ATTEMPT_ID,
+            ).as_str(),
+            &[
+                &token,
+                &attempt_id,
+            ],
+        ).await {
+            Err(why) => Err(format!("Get consumed link failed: {}", why.to_string())),
+            Ok(None) => Ok(None),
+            Ok(Some(row)) => OnetimeLink::try_from(row).map(Some),
+        }
+    }
+
     async fn client (&self) -> Result<Client, MyError> {
-        self.pool.get().await.map_err(|why| format!("Failed creating client: {}", why))
+        if self.max_lifetime_ms <= 0 {
+            return self.pool.get().await.map_err(|why| format!("Failed creating client: {}", why));
+        }
+
+        // deadpool has no max lifetime, so an old connection ends its own backend,
+        //  then the pool sees it closed when it comes back and drops it
+        let mut attempts = 0;
+        loop {
+            let client = self.pool.get().await.map_err(|why| format!("Failed creating client: {}", why))?;
+            attempts += 1;
+            // doubles as the health check, a dead connection is dropped here and another tried
+            let age_ms: i64 = match client.query_one(
+                "SELECT (EXTRACT(EPOCH FROM now() - backend_start) * 1000)::BIGINT FROM pg_stat_activity WHERE pid = pg_backend_pid()",
+                &[],
+            ).await {
+                Ok(row) => row.try_get(0).unwrap_or(0),
+                Err(why) if attempts >= MAX_LIFETIME_RETRIES => return Err(format!("Failed checking client: {}", why)),
+                Err(_) => continue,
+            };
+            if age_ms < self.max_lifetime_ms || attempts >= MAX_LIFETIME_RETRIES {
+                return Ok(client);
+            }
+            let _ = client.simple_query("SELECT pg_terminate_backend(pg_backend_pid())").await;
+        }
     }
 }
 
@@ -125,27 +392,61 @@ impl OnetimeStorage for Storage {
         "Postgres"
     }
 
+    fn pool_status (&self) -> Option<PoolStatus> {
+        let status = self.pool.status();
+        Some(PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+        })
+    }
+
+    fn with_links_table (&self, table: &str) -> Result<Box<dyn OnetimeStorage>, MyError> {
+        let mut storage = self.clone();
+        storage.links_table = identifier(table.to_string())?;
+        Ok(Box::new(storage))
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        match self.client().await?.query_one("SELECT 1", &[]).await {
+            Err(why) => Err(format!("Ping failed: {}", why.to_string())),
+            Ok(_) => Ok(true)
+        }
+    }
+
     async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        let (contents, compression) = self.compress(&file.contents)?;
+        // last writer wins, and updated_at only ever goes up, so two uploads racing can't leave it behind the contents
         match self.client().await?.execute(
             format!(
-                "INSERT INTO {}.{} ({}, {}, {}, {}) VALUES ($1, $2, $3, $4)
-                    ON CONFLICT ({}) DO UPDATE SET {}=$4, {}=$2",
+                "INSERT INTO {}.{} AS existing ({}, {}, {}, {}, {}, {}, {}) VALUES ($1, $2, $3, $4, $5, $6, $7)
+                    ON CONFLICT ({}) DO UPDATE SET {}=GREATEST(existing.{} + 1, $4), {}=$2, {}=$5, {}=$6, {}=$7",
                 self.schema,
                 self.files_table,
                 FIELD_FILENAME,
                 FIELD_CONTENTS,
                 FIELD_CREATED_AT,
                 FIELD_UPDATED_AT,
+                FIELD_EXPIRES_AT,
+                FIELD_CONTENT_TYPE,
+                FIELD_COMPRESSION,
 
                 FIELD_FILENAME,
                 FIELD_UPDATED_AT,
+                FIELD_UPDATED_AT,
                 FIELD_CONTENTS,
+                FIELD_EXPIRES_AT,
+                FIELD_CONTENT_TYPE,
+                FIELD_COMPRESSION,
             ).as_str(),
             &[
                 &file.filename,
-                &file.contents.as_ref(),
+                &contents.as_ref(),
                 &file.created_at,
                 &file.updated_at,
+                &file.expires_at,
+                &file.content_type,
+                &compression,
             ],
         ).await {
             Err(why) => Err(format!("Add file failed: {}", why.to_string())),
@@ -153,33 +454,197 @@ impl OnetimeStorage for Storage {
         }
     }
 
-    async fn list_files (&self) -> Result<Vec<OnetimeFile>, MyError>  {
-        match self.client().await?.query(
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        let (contents, compression) = self.compress(&file.contents)?;
+        // the primary key makes this atomic, no separate existence check needed
+        match self.client().await?.execute(
             format!(
-                "SELECT {}, {}, {}, {} FROM {}.{}",
+                "INSERT INTO {}.{} ({}, {}, {}, {}, {}, {}, {}) VALUES ($1, $2, $3, $4, $5, $6, $7)
+                    ON CONFLICT ({}) DO NOTHING",
+                self.schema,
+                self.files_table,
                 FIELD_FILENAME,
                 FIELD_CONTENTS,
                 FIELD_CREATED_AT,
                 FIELD_UPDATED_AT,
+                FIELD_EXPIRES_AT,
+                FIELD_CONTENT_TYPE,
+                FIELD_COMPRESSION,
+
+                FIELD_FILENAME,
+            ).as_str(),
+            &[
+                &file.filename,
+                &contents.as_ref(),
+                &file.created_at,
+                &file.updated_at,
+                &file.expires_at,
+                &file.content_type,
+                &compression,
+            ],
+        ).await {
+            Err(why) => Err(format!("Create file failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    // https://www.postgresql.org/docs/12/populate.html -- copy is far faster than inserts for many rows
+    //  but it can't skip or update conflicts, so rows are copied into a staging table and inserted from there
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        if files.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await.map_err(|why| format!("Add files failed: {}", why.to_string()))?;
+
+        transaction.batch_execute(
+            format!(
+                "CREATE TEMP TABLE {} (LIKE {}.{} INCLUDING DEFAULTS) ON COMMIT DROP",
+                TABLE_FILES_STAGING,
                 self.schema,
                 self.files_table,
             ).as_str(),
+        ).await.map_err(|why| format!("Add files staging failed: {}", why.to_string()))?;
+
+        // https://docs.rs/tokio-postgres/0.5.5/tokio_postgres/binary_copy/struct.BinaryCopyInWriter.html
+        let sink = transaction.copy_in(
+            format!(
+                "COPY {} ({}) FROM STDIN BINARY",
+                TABLE_FILES_STAGING,
+                FILE_FIELDS.join(", "),
+            ).as_str(),
+        ).await.map_err(|why| format!("Add files copy failed: {}", why.to_string()))?;
+        let writer = BinaryCopyInWriter::new(sink, &[Type::TEXT, Type::BYTEA, Type::INT8, Type::INT8, Type::INT8, Type::TEXT, Type::TEXT]);
+        pin_mut!(writer);
+        for file in files.iter() {
+            let (contents, compression) = self.compress(&file.contents)?;
+            writer.as_mut().write(&[
+                &file.filename,
+                &contents.as_ref(),
+                &file.created_at,
+                &file.updated_at,
+                &file.expires_at,
+                &file.content_type,
+                &compression,
+            ]).await.map_err(|why| format!("Add files copy failed: {}", why.to_string()))?;
+        }
+        writer.finish().await.map_err(|why| format!("Add files copy failed: {}", why.to_string()))?;
+
+        // an overwrite keeps the original created_at and only moves updated_at up, like add_file
+        let on_conflict = if overwrite {
+            format!(
+                "DO UPDATE SET {} = EXCLUDED.{}, {} = GREATEST(existing.{} + 1, EXCLUDED.{}), {} = EXCLUDED.{}, {} = EXCLUDED.{}, {} = EXCLUDED.{}",
+                FIELD_CONTENTS, FIELD_CONTENTS,
+                FIELD_UPDATED_AT, FIELD_UPDATED_AT, FIELD_UPDATED_AT,
+                FIELD_EXPIRES_AT, FIELD_EXPIRES_AT,
+                FIELD_CONTENT_TYPE, FIELD_CONTENT_TYPE,
+                FIELD_COMPRESSION, FIELD_COMPRESSION,
+            )
+        } else {
+            "DO NOTHING".to_string()
+        };
+        let rows = transaction.query(
+            format!(
+                "INSERT INTO {}.{} AS existing ({}) SELECT {} FROM {} ON CONFLICT ({}) {} RETURNING {}",
+                self.schema,
+                self.files_table,
+                FILE_FIELDS.join(", "),
+                FILE_FIELDS.join(", "),
+                TABLE_FILES_STAGING,
+                FIELD_FILENAME,
+                on_conflict,
+                FIELD_FILENAME,
+            ).as_str(),
+            &[
+            ],
+        ).await.map_err(|why| format!("Add files failed: {}", why.to_string()))?;
+
+        let filenames = rows.iter()
+            .map(|row| row.try_get(&FIELD_FILENAME).map_err(|why| format!("Could not get filename! {}", why)))
+            .collect::<Result<Vec<String>, MyError>>()?;
+        transaction.commit().await.map_err(|why| format!("Add files commit failed: {}", why.to_string()))?;
+        Ok(filenames)
+    }
+
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError> {
+        let (contents, compression) = self.compress(&contents)?;
+        match self.client().await?.execute(
+            format!(
+                "UPDATE {}.{} SET {} = $1, {} = GREATEST({} + 1, $2), {} = $3, {} = COALESCE($5, {}) WHERE {} = $4",
+                self.schema,
+                self.files_table,
+                FIELD_CONTENTS,
+                FIELD_UPDATED_AT,
+                FIELD_UPDATED_AT,
+                FIELD_COMPRESSION,
+                FIELD_CONTENT_TYPE,
+                FIELD_CONTENT_TYPE,
+                FIELD_FILENAME,
+            ).as_str(),
+            &[
+                &contents.as_ref(),
+                &updated_at,
+                &compression,
+                &filename,
+                &content_type,
+            ],
+        ).await {
+            Err(why) => Err(format!("Update file failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} {}",
+                FILE_FIELDS.join(", "),
+                self.schema,
+                self.files_table,
+                order_by(FILE_FIELDS, sort)?,
+            ).as_str(),
             &[
             ],
         ).await {
             Err(why) => Err(format!("List files failed: {}", why.to_string())),
-            Ok(rows) => try_from_vec(rows, "files"),
+            Ok(rows) => list_from_vec(rows, "files", self.lenient_lists),
         }
     }
 
     async fn get_file (&self, filename: String) -> Result<OnetimeFile, MyError>  {
         match self.client().await?.query_one(
             format!(
-                "SELECT {}, {}, {}, {} FROM {}.{} WHERE {} = $1",
+                "SELECT {} FROM {}.{} WHERE {} = $1",
+                FILE_FIELDS.join(", "),
+                self.schema,
+                self.files_table,
+                FIELD_FILENAME,
+            ).as_str(),
+            &[
+                &filename,
+            ],
+        ).await {
+            Err(why) => Err(format!("Get file failed: {}", why.to_string())),
+            Ok(row) => match OnetimeFile::try_from(row)? {
+                file if file.is_expired(self.time_provider.unix_ts_ms()) => Err("File expired".to_string()),
+                file => Ok(file),
+            },
+        }
+    }
+
+    // the contents never leave the db, except when compressed, since only the compressed size is known there
+    async fn get_file_info (&self, filename: String) -> Result<FileInfo, MyError> {
+        let row = self.client().await?.query_one(
+            format!(
+                "SELECT {}, OCTET_LENGTH({}) AS {}, {}, {}, {}, {}, {} FROM {}.{} WHERE {} = $1",
                 FIELD_FILENAME,
                 FIELD_CONTENTS,
+                FIELD_SIZE,
                 FIELD_CREATED_AT,
                 FIELD_UPDATED_AT,
+                FIELD_EXPIRES_AT,
+                FIELD_CONTENT_TYPE,
+                FIELD_COMPRESSION,
                 self.schema,
                 self.files_table,
                 FIELD_FILENAME,
@@ -187,59 +652,208 @@ impl OnetimeStorage for Storage {
             &[
                 &filename,
             ],
+        ).await.map_err(|why| format!("Get file info failed: {}", why.to_string()))?;
+
+        let compression: Option<String> = row.try_get(&FIELD_COMPRESSION).map_err(|why| format!("Could not get compression! {}", why))?;
+        if compression.is_some() {
+            return self.get_file(filename).await.map(|file| file.info());
+        }
+        let expires_at: Option<i64> = row.try_get(&FIELD_EXPIRES_AT).map_err(|why| format!("Could not get expires_at! {}", why))?;
+        if expires_at.map(|expires_at| expires_at < self.time_provider.unix_ts_ms()).unwrap_or(false) {
+            return Err("File expired".to_string());
+        }
+        let size: i32 = row.try_get(&FIELD_SIZE).map_err(|why| format!("Could not get size! {}", why))?;
+        Ok(FileInfo::new(
+            row.try_get(&FIELD_FILENAME).map_err(|why| format!("Could not get filename! {}", why))?,
+            row.try_get(&FIELD_CONTENT_TYPE).map_err(|why| format!("Could not get content_type! {}", why))?,
+            size as usize,
+            row.try_get(&FIELD_CREATED_AT).map_err(|why| format!("Could not get created_at! {}", why))?,
+            row.try_get(&FIELD_UPDATED_AT).map_err(|why| format!("Could not get updated_at! {}", why))?,
+        ))
+    }
+
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} > $1 ORDER BY {} LIMIT $2",
+                FILE_FIELDS.join(", "),
+                self.schema,
+                self.files_table,
+                FIELD_FILENAME,
+                FIELD_FILENAME,
+            ).as_str(),
+            &[
+                &after.unwrap_or_default(),
+                &(limit as i64),
+            ],
         ).await {
-            Err(why) => Err(format!("Get file failed: {}", why.to_string())),
-            Ok(row) => OnetimeFile::try_from(row),
+            Err(why) => Err(format!("List files page failed: {}", why.to_string())),
+            Ok(rows) => {
+                let files: Vec<OnetimeFile> = try_from_vec(rows, "files")?;
+                let next = if files.len() < limit { None } else { files.last().map(|file| file.filename.clone()) };
+                Ok((files, next))
+            }
         }
     }
 
-    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
-        match self.client().await?.execute(
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        let mut exist: HashMap<String, bool> = filenames.iter().map(|filename| (filename.clone(), false)).collect();
+        match self.client().await?.query(
             format!(
-                "INSERT INTO {}.{} ({}, {}, {}, {}, {}, {}, {}) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                "SELECT {} FROM {}.{} WHERE {} = ANY($1)",
+                FIELD_FILENAME,
                 self.schema,
-                self.links_table,
-                FIELD_TOKEN,
+                self.files_table,
                 FIELD_FILENAME,
-                FIELD_NOTE,
-                FIELD_CREATED_AT,
-                FIELD_EXPIRES_AT,
-                FIELD_DOWNLOADED_AT,
-                FIELD_IP_ADDRESS,
             ).as_str(),
             &[
-                &link.token,
-                &link.filename,
-                &link.note,
-                &link.created_at,
-                &link.expires_at,
-                &link.downloaded_at,
-                &link.ip_address,
+                &filenames,
             ],
+        ).await {
+            Err(why) => Err(format!("Files exist failed: {}", why.to_string())),
+            Ok(rows) => {
+                for row in rows {
+                    let filename: String = row.try_get(&FIELD_FILENAME).map_err(|why| format!("Could not get filename! {}", why))?;
+                    exist.insert(filename, true);
+                }
+                Ok(exist)
+            }
+        }
+    }
+
+    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "INSERT INTO {}.{} ({}) VALUES ({})",
+                self.schema,
+                self.links_table,
+                LINK_FIELDS.join(", "),
+                placeholders(LINK_FIELDS.len()),
+            ).as_str(),
+            &link_params(&link),
         ).await {
             Err(why) => Err(format!("Add link failed: {}", why.to_string())),
             Ok(_) => Ok(true)
         }
     }
 
-    async fn list_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+    // a single multi-row insert, so either all of them are added or none
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        if links.is_empty() {
+            return Ok(true);
+        }
+        let params: Vec<&(dyn ToSql + Sync)> = links.iter().flat_map(|link| link_params(link).to_vec()).collect();
+        match self.client().await?.execute(
+            format!(
+                "INSERT INTO {}.{} ({}) VALUES {}",
+                self.schema,
+                self.links_table,
+                LINK_FIELDS.join(", "),
+                row_placeholders(links.len(), LINK_FIELDS.len()),
+            ).as_str(),
+            &params,
+        ).await {
+            Err(why) => Err(format!("Add links failed: {}", why.to_string())),
+            Ok(_) => Ok(true)
+        }
+    }
+
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        if links.is_empty() {
+            return Ok(Vec::new());
+        }
+        // only the rows inserted or updated come back, so skipped tokens are the ones missing
+        let on_conflict = if overwrite {
+            format!(
+                "DO UPDATE SET {}",
+                LINK_FIELDS.iter()
+                    .filter(|field| **field != FIELD_TOKEN)
+                    .map(|field| format!("{} = EXCLUDED.{}", field, field))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )
+        } else {
+            "DO NOTHING".to_string()
+        };
+        let params: Vec<&(dyn ToSql + Sync)> = links.iter().flat_map(|link| link_params(link).to_vec()).collect();
         match self.client().await?.query(
             format!(
-                "SELECT {}, {}, {}, {}, {}, {}, {} FROM {}.{}",
+                "INSERT INTO {}.{} ({}) VALUES {} ON CONFLICT ({}) {} RETURNING {}",
+                self.schema,
+                self.links_table,
+                LINK_FIELDS.join(", "),
+                row_placeholders(links.len(), LINK_FIELDS.len()),
                 FIELD_TOKEN,
-                FIELD_FILENAME,
-                FIELD_NOTE,
-                FIELD_CREATED_AT,
-                FIELD_EXPIRES_AT,
-                FIELD_DOWNLOADED_AT,
-                FIELD_IP_ADDRESS,
+                on_conflict,
+                FIELD_TOKEN,
+            ).as_str(),
+            &params,
+        ).await {
+            Err(why) => Err(format!("Import links failed: {}", why.to_string())),
+            Ok(rows) => rows.iter()
+                .map(|row| row.try_get(&FIELD_TOKEN).map_err(|why| format!("Could not get token! {}", why)))
+                .collect(),
+        }
+    }
+
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} IS NULL {}",
+                LINK_FIELDS.join(", "),
                 self.schema,
                 self.links_table,
+                FIELD_DELETED_AT,
+                order_by(LINK_FIELDS, sort)?,
             ).as_str(),
             &[
             ],
         ).await {
             Err(why) => Err(format!("List links failed: {}", why.to_string())),
+            Ok(rows) => list_from_vec(rows, "links", self.lenient_lists),
+        }
+    }
+
+    // keyset pagination on the primary key, so later pages cost the same as the first
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} > $1 ORDER BY {} LIMIT $2",
+                LINK_FIELDS.join(", "),
+                self.schema,
+                self.links_table,
+                FIELD_TOKEN,
+                FIELD_TOKEN,
+            ).as_str(),
+            &[
+                &after.unwrap_or_default(),
+                &(limit as i64),
+            ],
+        ).await {
+            Err(why) => Err(format!("List links page failed: {}", why.to_string())),
+            Ok(rows) => {
+                let links: Vec<OnetimeLink> = try_from_vec(rows, "links")?;
+                let next = if links.len() < limit { None } else { links.last().map(|link| link.token.clone()) };
+                Ok((links, next))
+            }
+        }
+    }
+
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError> {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} = $1 AND {} IS NULL",
+                LINK_FIELDS.join(", "),
+                self.schema,
+                self.links_table,
+                FIELD_FILENAME,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &filename,
+            ],
+        ).await {
+            Err(why) => Err(format!("List links for file failed: {}", why.to_string())),
             Ok(rows) => try_from_vec(rows, "links"),
         }
     }
@@ -247,14 +861,27 @@ impl OnetimeStorage for Storage {
     async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError> {
         match self.client().await?.query_one(
             format!(
-                "SELECT {}, {}, {}, {}, {}, {}, {} FROM {}.{} WHERE {} = $1",
+                "SELECT {} FROM {}.{} WHERE {} = $1 AND {} IS NULL",
+                LINK_FIELDS.join(", "),
+                self.schema,
+                self.links_table,
                 FIELD_TOKEN,
-                FIELD_FILENAME,
-                FIELD_NOTE,
-                FIELD_CREATED_AT,
-                FIELD_EXPIRES_AT,
-                FIELD_DOWNLOADED_AT,
-                FIELD_IP_ADDRESS,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &token,
+            ],
+        ).await {
+            Err(why) => Err(format!("Get link failed: {}", why.to_string())),
+            Ok(row) => OnetimeLink::try_from(row),
+        }
+    }
+
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        match self.client().await?.query_one(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} = $1",
+                LINK_FIELDS.join(", "),
                 self.schema,
                 self.links_table,
                 FIELD_TOKEN,
@@ -268,25 +895,305 @@ impl OnetimeStorage for Storage {
         }
     }
 
-    async fn mark_downloaded (&self, link: OnetimeLink, ip_address: String, downloaded_at: i64) -> Result<bool, MyError> {
-        match self.client().await?.execute(
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError> {
+        match self.client().await?.query(
             format!(
-                "UPDATE {}.{} SET {} = $1, {} = $2 WHERE {} = $3 AND {} IS NULL",
+                "SELECT {}, {}, {}, {} FROM {}.{} WHERE {} = $1",
+                FIELD_DOWNLOADED_AT,
+                FIELD_EXPIRES_AT,
+                FIELD_DELETED_AT,
+                FIELD_REVOKED_AT,
                 self.schema,
                 self.links_table,
+                FIELD_TOKEN,
+            ).as_str(),
+            &[
+                &token,
+            ],
+        ).await {
+            Err(why) => Err(format!("Link status failed: {}", why.to_string())),
+            Ok(rows) => match rows.first() {
+                None => Ok(LinkStatus::Missing),
+                Some(row) => Ok(link_status(
+                    row.try_get(&FIELD_DOWNLOADED_AT).map_err(|why| format!("Could not get {}! {}", FIELD_DOWNLOADED_AT, why))?,
+                    row.try_get(&FIELD_EXPIRES_AT).map_err(|why| format!("Could not get {}! {}", FIELD_EXPIRES_AT, why))?,
+                    row.try_get(&FIELD_DELETED_AT).map_err(|why| format!("Could not get {}! {}", FIELD_DELETED_AT, why))?,
+                    row.try_get(&FIELD_REVOKED_AT).map_err(|why| format!("Could not get {}! {}", FIELD_REVOKED_AT, why))?,
+                    self.time_provider.unix_ts_ms(),
+                )),
+            },
+        }
+    }
+
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        let client = self.client().await.map_err(OnetimeError::Failed)?;
+
+        // the checks in the WHERE are what make this n-time: the row lock means each concurrent update sees the last one's count
+        //  the SET side reads the old row, so the download that reaches the cap is the one that sets downloaded_at
+        //  and an attempt that already counted is never counted again, whatever the count is now
+        let result = client.query_opt(
+            format!(
+                "UPDATE {}.{} SET {} = {} + 1, {} = CASE WHEN {} + 1 >= {} THEN $1 ELSE NULL END, {} = $2, {} = $4
+                    WHERE {} = $3 AND {} IS NULL AND {} IS NULL AND {} < {} AND {} IS DISTINCT FROM $4 RETURNING {}",
+                self.schema,
+                self.links_table,
+                FIELD_DOWNLOADS,
+                FIELD_DOWNLOADS,
                 FIELD_DOWNLOADED_AT,
+                FIELD_DOWNLOADS,
+                FIELD_MAX_DOWNLOADS,
                 FIELD_IP_ADDRESS,
+                FIELD_CONSUME_ATTEMPT_ID,
                 FIELD_TOKEN,
                 FIELD_DOWNLOADED_AT,
+                FIELD_DELETED_AT,
+                FIELD_DOWNLOADS,
+                FIELD_MAX_DOWNLOADS,
+                FIELD_CONSUME_ATTEMPT_ID,
+                LINK_FIELDS.join(", "),
             ).as_str(),
             &[
                 &downloaded_at,
                 &ip_address,
-                &link.token,
+                &token,
+                &attempt_id,
+            ],
+        ).await;
+
+        match result {
+            Err(why) => Err(OnetimeError::Failed(format!("Consume link failed: {}", why.to_string()))),
+            Ok(Some(row)) => OnetimeLink::try_from(row).map_err(OnetimeError::Failed),
+            // only pay for the extra reads when the link could not be consumed
+            Ok(None) => match self.consumed_by(token.clone(), attempt_id).await.map_err(OnetimeError::Failed)? {
+                // a retry of an attempt whose update went through, but whose answer never came back
+                Some(link) => Ok(link),
+                None => match self.get_link(token).await {
+                    Err(why) => Err(OnetimeError::NotFound(why)),
+                    Ok(link) => Err(OnetimeError::AlreadyConsumed(link)),
+                },
+            },
+        }
+    }
+
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "UPDATE {}.{} SET {} = {} + 1, {} = $1 WHERE {} = $2",
+                self.schema,
+                self.links_table,
+                FIELD_ATTEMPTS,
+                FIELD_ATTEMPTS,
+                FIELD_LAST_ATTEMPT_AT,
+                FIELD_TOKEN,
+            ).as_str(),
+            &[
+                &attempted_at,
+                &token,
+            ],
+        ).await {
+            Err(why) => Err(format!("Record attempt failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "UPDATE {}.{} SET {} = $1 WHERE {} = $2 AND {} IS NULL",
+                self.schema,
+                self.links_table,
+                FIELD_DELETED_AT,
+                FIELD_TOKEN,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &deleted_at,
+                &token,
             ],
         ).await {
-            Err(why) => Err(format!("Mark downloaded update failed: {}", why.to_string())),
-            Ok(update_count) => Ok(update_count == 0)
+            Err(why) => Err(format!("Soft delete link failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError> {
+        // downloaded_at too, so every consume path already refuses it
+        match self.client().await?.execute(
+            format!(
+                "UPDATE {}.{} SET {} = $1, {} = $1 WHERE {} = $2 AND {} IS NULL AND {} IS NULL",
+                self.schema,
+                self.links_table,
+                FIELD_REVOKED_AT,
+                FIELD_DOWNLOADED_AT,
+                FIELD_TOKEN,
+                FIELD_DOWNLOADED_AT,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &revoked_at,
+                &token,
+            ],
+        ).await {
+            Err(why) => Err(format!("Revoke link failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} IS NOT NULL",
+                LINK_FIELDS.join(", "),
+                self.schema,
+                self.links_table,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+            ],
+        ).await {
+            Err(why) => Err(format!("List deleted links failed: {}", why.to_string())),
+            Ok(rows) => try_from_vec(rows, "links"),
+        }
+    }
+
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        match self.client().await?.query(
+            format!(
+                "SELECT {} FROM {}.{} WHERE {} < $1 AND {} IS NULL AND {} IS NULL",
+                LINK_FIELDS.join(", "),
+                self.schema,
+                self.links_table,
+                FIELD_EXPIRES_AT,
+                FIELD_DOWNLOADED_AT,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &expired_before,
+            ],
+        ).await {
+            Err(why) => Err(format!("List expired unused links failed: {}", why.to_string())),
+            Ok(rows) => try_from_vec(rows, "links"),
+        }
+    }
+
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "UPDATE {}.{} SET {} = NULL WHERE {} = $1 AND {} >= $2",
+                self.schema,
+                self.links_table,
+                FIELD_DELETED_AT,
+                FIELD_TOKEN,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &token,
+                &deleted_after,
+            ],
+        ).await {
+            Err(why) => Err(format!("Restore link failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "DELETE FROM {}.{} WHERE {} < $1",
+                self.schema,
+                self.links_table,
+                FIELD_DELETED_AT,
+            ).as_str(),
+            &[
+                &deleted_before,
+            ],
+        ).await {
+            Err(why) => Err(format!("Purge deleted links failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count)
+        }
+    }
+
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "DELETE FROM {}.{} WHERE {} < $1",
+                self.schema,
+                self.files_table,
+                FIELD_EXPIRES_AT,
+            ).as_str(),
+            &[
+                &expired_before,
+            ],
+        ).await {
+            Err(why) => Err(format!("Purge expired files failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count)
+        }
+    }
+
+    // https://docs.rs/tokio-postgres/0.5.5/tokio_postgres/struct.Transaction.html -- rolled back when dropped without commit
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await.map_err(|why| format!("Transaction failed: {}", why.to_string()))?;
+
+        for op in ops {
+            let result = match &op {
+                StorageOp::DeleteFile(filename) => transaction.execute(
+                    format!("DELETE FROM {}.{} WHERE {} = $1", self.schema, self.files_table, FIELD_FILENAME).as_str(),
+                    &[filename],
+                ).await,
+                StorageOp::DeleteLink(token) => transaction.execute(
+                    format!("DELETE FROM {}.{} WHERE {} = $1", self.schema, self.links_table, FIELD_TOKEN).as_str(),
+                    &[token],
+                ).await,
+                StorageOp::SoftDeleteLink(token, deleted_at) => transaction.execute(
+                    format!("UPDATE {}.{} SET {} = $1 WHERE {} = $2 AND {} IS NULL", self.schema, self.links_table, FIELD_DELETED_AT, FIELD_TOKEN, FIELD_DELETED_AT).as_str(),
+                    &[deleted_at, token],
+                ).await,
+            };
+            result.map_err(|why| format!("Transaction {} failed: {}", op.name(), why.to_string()))?;
+        }
+
+        transaction.commit().await.map_err(|why| format!("Transaction commit failed: {}", why.to_string()))?;
+        Ok(true)
+    }
+
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError> {
+        // the upsert locks the row, so concurrent adds each see their own total
+        match self.client().await?.query_one(
+            format!(
+                "INSERT INTO {}.{} AS usage ({}, {}, {}, {}) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT ({}, {}) DO UPDATE SET {} = usage.{} + EXCLUDED.{}, {} = usage.{} + EXCLUDED.{}
+                    RETURNING {}, {}",
+                self.schema,
+                self.usage_table,
+                FIELD_OWNER,
+                FIELD_WINDOW_START,
+                FIELD_COUNT,
+                FIELD_BYTES,
+                FIELD_OWNER,
+                FIELD_WINDOW_START,
+                FIELD_COUNT,
+                FIELD_COUNT,
+                FIELD_COUNT,
+                FIELD_BYTES,
+                FIELD_BYTES,
+                FIELD_BYTES,
+                FIELD_COUNT,
+                FIELD_BYTES,
+            ).as_str(),
+            &[
+                &owner,
+                &window_start,
+                &count,
+                &bytes,
+            ],
+        ).await {
+            Err(why) => Err(format!("Add usage failed: {}", why.to_string())),
+            Ok(row) => {
+                let count: i64 = row.try_get(0).map_err(|why| format!("Add usage failed: {}", why))?;
+                let bytes: i64 = row.try_get(1).map_err(|why| format!("Add usage failed: {}", why))?;
+                Ok((count, bytes))
+            }
         }
     }
 
@@ -303,7 +1210,26 @@ impl OnetimeStorage for Storage {
             ],
         ).await {
             Err(why) => Err(format!("Delete file failed: {}", why.to_string())),
-            Ok(update_count) => Ok(update_count == 0)
+            Ok(update_count) => Ok(update_count > 0)
+        }
+    }
+
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError> {
+        match self.client().await?.execute(
+            format!(
+                "DELETE FROM {}.{} WHERE {} = $1 AND {} = $2",
+                self.schema,
+                self.files_table,
+                FIELD_FILENAME,
+                FIELD_UPDATED_AT,
+            ).as_str(),
+            &[
+                &filename,
+                &updated_at,
+            ],
+        ).await {
+            Err(why) => Err(format!("Delete file failed: {}", why.to_string())),
+            Ok(update_count) => Ok(update_count > 0)
         }
     }
 
@@ -320,7 +1246,7 @@ impl OnetimeStorage for Storage {
             ],
         ).await {
             Err(why) => Err(format!("Delete link failed: {}", why.to_string())),
-            Ok(update_count) => Ok(update_count == 0)
+            Ok(update_count) => Ok(update_count > 0)
         }
     }
 }
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use bytes::{Bytes};
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use serde::Serialize;
+
+use crate::locks;
+use crate::models::{FileInfo, LinkStatus, ListSort, MyError, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp, PoolStatus};
+
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StorageOpStats {
+    pub calls: u64,
+    // over SLOW_STORAGE_MS, each of these was logged
+    pub slow: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+// per storage method, shared by every worker like the upload metrics
+//  a mutex is fine here, it is held for a few adds next to a call that went over the network
+#[derive(Clone)]
+pub struct StorageTimings {
+    ops: Arc<Mutex<HashMap<&'static str, StorageOpStats>>>,
+}
+
+impl StorageTimings {
+    pub fn new () -> Self {
+        Self {
+            ops: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn record (&self, method: &'static str, elapsed_ms: u64, slow: bool) {
+        // a panic elsewhere mid-update leaves nothing worse than a miscount
+        let mut ops = locks::lock(&self.ops);
+        let stats = ops.entry(method).or_default();
+        stats.calls += 1;
+        stats.total_ms += elapsed_ms;
+        stats.max_ms = u64::max(stats.max_ms, elapsed_ms);
+        if slow {
+            stats.slow += 1;
+        }
+    }
+
+    // sorted by method, so the metrics read the same every time
+    pub fn snapshot (&self) -> BTreeMap<String, StorageOpStats> {
+        let ops = locks::lock(&self.ops);
+        ops.iter().map(|(method, stats)| (method.to_string(), stats.clone())).collect()
+    }
+}
+
+// wraps another storage to time every call, counting them per method and logging the slow ones
+//  so whether the backend is the bottleneck shows in the log and GET /api/metrics, without tracing set up
+#[derive(Clone)]
+pub struct Storage {
+    pub inner: Box<dyn OnetimeStorage>,
+    pub timings: StorageTimings,
+    // 0 never warns, the calls are still counted
+    pub slow_ms: u64,
+}
+
+impl Storage {
+    // failed calls count too, a timeout is often the slowest call of all
+    async fn time<T, F: Future<Output = T>> (&self, method: &'static str, call: F) -> T {
+        let started = Instant::now();
+        let result = call.await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        let slow = self.slow_ms > 0 && elapsed_ms > self.slow_ms;
+        if slow {
+            println!("Slow storage {} {}: {}ms > {}ms", self.inner.name(), method, elapsed_ms, self.slow_ms);
+        }
+        self.timings.record(method, elapsed_ms, slow);
+        result
+    }
+}
+
+// https://github.com/dtolnay/async-trait#non-threadsafe-futures
+#[async_trait(?Send)]
+impl OnetimeStorage for Storage {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn pool_status (&self) -> Option<PoolStatus> {
+        self.inner.pool_status()
+    }
+
+    fn unconfigured (&self) -> Option<MyError> {
+        self.inner.unconfigured()
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        self.time("ping", self.inner.ping()).await
+    }
+
+    async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.time("add_file", self.inner.add_file(file)).await
+    }
+
+    // this one includes reading the upload itself, so a big or slow upload is slow here without the backend being
+    async fn add_file_stream (&self, filename: String, now: i64, expires_at: Option<i64>, content_type: Option<String>, stream: LocalBoxStream<'static, Result<Bytes, MyError>>) -> Result<bool, MyError> {
+        self.time("add_file_stream", self.inner.add_file_stream(filename, now, expires_at, content_type, stream)).await
+    }
+
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.time("create_file", self.inner.create_file(file)).await
+    }
+
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.time("add_files", self.inner.add_files(files, overwrite)).await
+    }
+
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError> {
+        self.time("update_file", self.inner.update_file(filename, contents, content_type, updated_at)).await
+    }
+
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
+        self.time("list_files", self.inner.list_files(sort)).await
+    }
+
+    async fn get_file (&self, filename: String) -> Result<OnetimeFile, MyError>  {
+        self.time("get_file", self.inner.get_file(filename)).await
+    }
+
+    async fn get_file_info (&self, filename: String) -> Result<FileInfo, MyError>  {
+        self.time("get_file_info", self.inner.get_file_info(filename)).await
+    }
+
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        self.time("list_files_page", self.inner.list_files_page(after, limit)).await
+    }
+
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        self.time("files_exist", self.inner.files_exist(filenames)).await
+    }
+
+    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
+        self.time("add_link", self.inner.add_link(link)).await
+    }
+
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        self.time("add_links", self.inner.add_links(links)).await
+    }
+
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.time("import_links", self.inner.import_links(links, overwrite)).await
+    }
+
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
+        self.time("list_links", self.inner.list_links(sort)).await
+    }
+
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        self.time("list_links_page", self.inner.list_links_page(after, limit)).await
+    }
+
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError> {
+        self.time("list_links_for_file", self.inner.list_links_for_file(filename)).await
+    }
+
+    async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.time("get_link", self.inner.get_link(token)).await
+    }
+
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.time("get_any_link", self.inner.get_any_link(token)).await
+    }
+
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError> {
+        self.time("link_status", self.inner.link_status(token)).await
+    }
+
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        self.time("consume_link", self.inner.consume_link(token, ip_address, downloaded_at, attempt_id)).await
+    }
+
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError> {
+        self.time("record_attempt", self.inner.record_attempt(token, attempted_at)).await
+    }
+
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError> {
+        self.time("soft_delete_link", self.inner.soft_delete_link(token, deleted_at)).await
+    }
+
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError> {
+        self.time("revoke_link", self.inner.revoke_link(token, revoked_at)).await
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        self.time("list_deleted_links", self.inner.list_deleted_links()).await
+    }
+
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        self.time("list_expired_unused_links", self.inner.list_expired_unused_links(expired_before)).await
+    }
+
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError> {
+        self.time("restore_link", self.inner.restore_link(token, deleted_after)).await
+    }
+
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError> {
+        self.time("purge_deleted_links", self.inner.purge_deleted_links(deleted_before)).await
+    }
+
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError> {
+        self.time("purge_expired_files", self.inner.purge_expired_files(expired_before)).await
+    }
+
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        self.time("transact", self.inner.transact(ops)).await
+    }
+
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError> {
+        self.time("add_usage", self.inner.add_usage(owner, window_start, count, bytes)).await
+    }
+
+    async fn delete_file (&self, filename: String) -> Result<bool, MyError> {
+        self.time("delete_file", self.inner.delete_file(filename)).await
+    }
+
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError> {
+        self.time("delete_file_if_unchanged", self.inner.delete_file_if_unchanged(filename, updated_at)).await
+    }
+
+    async fn delete_link (&self, token: String) -> Result<bool, MyError> {
+        self.time("delete_link", self.inner.delete_link(token)).await
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use bytes::{Bytes};
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
+use tracing::{info_span, Instrument};
+
+use crate::models::{FileInfo, LinkStatus, ListSort, MyError, OnetimeError, OnetimeFile, OnetimeLink, OnetimeStorage, StorageOp, PoolStatus};
+
+
+// wraps another storage so time spent in the backend shows up as its own span
+#[derive(Clone)]
+pub struct Storage {
+    pub inner: Box<dyn OnetimeStorage>,
+}
+
+// https://github.com/dtolnay/async-trait#non-threadsafe-futures
+#[async_trait(?Send)]
+impl OnetimeStorage for Storage {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn pool_status (&self) -> Option<PoolStatus> {
+        self.inner.pool_status()
+    }
+
+    fn unconfigured (&self) -> Option<MyError> {
+        self.inner.unconfigured()
+    }
+
+    async fn ping (&self) -> Result<bool, MyError> {
+        self.inner.ping()
+            .instrument(info_span!("storage", method = "ping", backend = self.inner.name()))
+            .await
+    }
+
+    async fn add_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.inner.add_file(file)
+            .instrument(info_span!("storage", method = "add_file", backend = self.inner.name()))
+            .await
+    }
+
+    async fn add_file_stream (&self, filename: String, now: i64, expires_at: Option<i64>, content_type: Option<String>, stream: LocalBoxStream<'static, Result<Bytes, MyError>>) -> Result<bool, MyError> {
+        self.inner.add_file_stream(filename, now, expires_at, content_type, stream)
+            .instrument(info_span!("storage", method = "add_file_stream", backend = self.inner.name()))
+            .await
+    }
+
+    async fn create_file (&self, file: OnetimeFile) -> Result<bool, MyError> {
+        self.inner.create_file(file)
+            .instrument(info_span!("storage", method = "create_file", backend = self.inner.name()))
+            .await
+    }
+
+    async fn add_files (&self, files: Vec<OnetimeFile>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.inner.add_files(files, overwrite)
+            .instrument(info_span!("storage", method = "add_files", backend = self.inner.name()))
+            .await
+    }
+
+    async fn update_file (&self, filename: String, contents: Bytes, content_type: Option<String>, updated_at: i64) -> Result<bool, MyError> {
+        self.inner.update_file(filename, contents, content_type, updated_at)
+            .instrument(info_span!("storage", method = "update_file", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_files (&self, sort: ListSort) -> Result<(Vec<OnetimeFile>, usize), MyError>  {
+        self.inner.list_files(sort)
+            .instrument(info_span!("storage", method = "list_files", backend = self.inner.name()))
+            .await
+    }
+
+    async fn get_file (&self, filename: String) -> Result<OnetimeFile, MyError>  {
+        self.inner.get_file(filename)
+            .instrument(info_span!("storage", method = "get_file", backend = self.inner.name()))
+            .await
+    }
+
+    async fn get_file_info (&self, filename: String) -> Result<FileInfo, MyError>  {
+        self.inner.get_file_info(filename)
+            .instrument(info_span!("storage", method = "get_file_info", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_files_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeFile>, Option<String>), MyError> {
+        self.inner.list_files_page(after, limit)
+            .instrument(info_span!("storage", method = "list_files_page", backend = self.inner.name()))
+            .await
+    }
+
+    async fn files_exist (&self, filenames: Vec<String>) -> Result<HashMap<String, bool>, MyError> {
+        self.inner.files_exist(filenames)
+            .instrument(info_span!("storage", method = "files_exist", backend = self.inner.name()))
+            .await
+    }
+
+    async fn add_link (&self, link: OnetimeLink) -> Result<bool, MyError> {
+        self.inner.add_link(link)
+            .instrument(info_span!("storage", method = "add_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn add_links (&self, links: Vec<OnetimeLink>) -> Result<bool, MyError> {
+        self.inner.add_links(links)
+            .instrument(info_span!("storage", method = "add_links", backend = self.inner.name()))
+            .await
+    }
+
+    async fn import_links (&self, links: Vec<OnetimeLink>, overwrite: bool) -> Result<Vec<String>, MyError> {
+        self.inner.import_links(links, overwrite)
+            .instrument(info_span!("storage", method = "import_links", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_links (&self, sort: ListSort) -> Result<(Vec<OnetimeLink>, usize), MyError> {
+        self.inner.list_links(sort)
+            .instrument(info_span!("storage", method = "list_links", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_links_page (&self, after: Option<String>, limit: usize) -> Result<(Vec<OnetimeLink>, Option<String>), MyError> {
+        self.inner.list_links_page(after, limit)
+            .instrument(info_span!("storage", method = "list_links_page", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_links_for_file (&self, filename: String) -> Result<Vec<OnetimeLink>, MyError> {
+        self.inner.list_links_for_file(filename)
+            .instrument(info_span!("storage", method = "list_links_for_file", backend = self.inner.name()))
+            .await
+    }
+
+    async fn get_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.inner.get_link(token)
+            .instrument(info_span!("storage", method = "get_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn get_any_link (&self, token: String) -> Result<OnetimeLink, MyError> {
+        self.inner.get_any_link(token)
+            .instrument(info_span!("storage", method = "get_any_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn link_status (&self, token: String) -> Result<LinkStatus, MyError> {
+        self.inner.link_status(token)
+            .instrument(info_span!("storage", method = "link_status", backend = self.inner.name()))
+            .await
+    }
+
+    async fn consume_link (&self, token: String, ip_address: String, downloaded_at: i64, attempt_id: String) -> Result<OnetimeLink, OnetimeError> {
+        self.inner.consume_link(token, ip_address, downloaded_at, attempt_id)
+            .instrument(info_span!("storage", method = "consume_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn record_attempt (&self, token: String, attempted_at: i64) -> Result<bool, MyError> {
+        self.inner.record_attempt(token, attempted_at)
+            .instrument(info_span!("storage", method = "record_attempt", backend = self.inner.name()))
+            .await
+    }
+
+    async fn soft_delete_link (&self, token: String, deleted_at: i64) -> Result<bool, MyError> {
+        self.inner.soft_delete_link(token, deleted_at)
+            .instrument(info_span!("storage", method = "soft_delete_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn revoke_link (&self, token: String, revoked_at: i64) -> Result<bool, MyError> {
+        self.inner.revoke_link(token, revoked_at)
+            .instrument(info_span!("storage", method = "revoke_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_deleted_links (&self) -> Result<Vec<OnetimeLink>, MyError> {
+        self.inner.list_deleted_links()
+            .instrument(info_span!("storage", method = "list_deleted_links", backend = self.inner.name()))
+            .await
+    }
+
+    async fn list_expired_unused_links (&self, expired_before: i64) -> Result<Vec<OnetimeLink>, MyError> {
+        self.inner.list_expired_unused_links(expired_before)
+            .instrument(info_span!("storage", method = "list_expired_unused_links", backend = self.inner.name()))
+            .await
+    }
+
+    async fn restore_link (&self, token: String, deleted_after: i64) -> Result<bool, MyError> {
+        self.inner.restore_link(token, deleted_after)
+            .instrument(info_span!("storage", method = "restore_link", backend = self.inner.name()))
+            .await
+    }
+
+    async fn purge_deleted_links (&self, deleted_before: i64) -> Result<u64, MyError> {
+        self.inner.purge_deleted_links(deleted_before)
+            .instrument(info_span!("storage", method = "purge_deleted_links", backend = self.inner.name()))
+            .await
+    }
+
+    async fn purge_expired_files (&self, expired_before: i64) -> Result<u64, MyError> {
+        self.inner.purge_expired_files(expired_before)
+            .instrument(info_span!("storage", method = "purge_expired_files", backend = self.inner.name()))
+            .await
+    }
+
+    async fn transact (&self, ops: Vec<StorageOp>) -> Result<bool, MyError> {
+        self.inner.transact(ops)
+            .instrument(info_span!("storage", method = "transact", backend = self.inner.name()))
+            .await
+    }
+
+    async fn add_usage (&self, owner: String, window_start: i64, count: i64, bytes: i64) -> Result<(i64, i64), MyError> {
+        self.inner.add_usage(owner, window_start, count, bytes)
+            .instrument(info_span!("storage", method = "add_usage", backend = self.inner.name()))
+            .await
+    }
+
+    async fn delete_file (&self, filename: String) -> Result<bool, MyError> {
+        self.inner.delete_file(filename)
+            .instrument(info_span!("storage", method = "delete_file", backend = self.inner.name()))
+            .await
+    }
+
+    async fn delete_file_if_unchanged (&self, filename: String, updated_at: i64) -> Result<bool, MyError> {
+        self.inner.delete_file_if_unchanged(filename, updated_at)
+            .instrument(info_span!("storage", method = "delete_file_if_unchanged", backend = self.inner.name()))
+            .await
+    }
+
+    async fn delete_link (&self, token: String) -> Result<bool, MyError> {
+        self.inner.delete_link(token)
+            .instrument(info_span!("storage", method = "delete_link", backend = self.inner.name()))
+            .await
+    }
+}
@@ -2,7 +2,9 @@
 use std::convert::TryFrom;
 //use std::fmt::Display;
 
-use crate::models::MyError;
+use std::cmp::Ordering;
+
+use crate::models::{LinkStatus, ListSort, MyError, OnetimeFile, OnetimeLink, SortField, SortOrder};
 
 
 // https://users.rust-lang.org/t/impl-tryinto-as-an-argument-in-a-function-complains-about-the-error-conversion/34004
@@ -16,3 +18,110 @@ pub fn try_from_vec<T, U: TryFrom<T, Error=MyError>> (items: Vec<T>, name: &'sta
     }
     Ok(vec)
 }
+
+// every row that converts, with the errors for those that don't, so one bad row can't hide the rest
+pub fn try_from_vec_lenient<T, U: TryFrom<T, Error=MyError>> (items: Vec<T>, name: &'static str) -> (Vec<U>, Vec<MyError>) {
+    let mut vec = Vec::new();
+    let mut errors = Vec::new();
+    for item in items.into_iter() {
+        match U::try_from(item) {
+            Err(why) => errors.push(format!("Failed converting {}: {}", name, why)),
+            Ok(file) => vec.push(file),
+        }
+    }
+    (vec, errors)
+}
+
+// for whole lists: strict fails on the first bad row, lenient logs and counts them
+pub fn list_from_vec<T, U: TryFrom<T, Error=MyError>> (items: Vec<T>, name: &'static str, lenient: bool) -> Result<(Vec<U>, usize), MyError> {
+    if !lenient {
+        return try_from_vec(items, name).map(|vec| (vec, 0));
+    }
+    let (vec, errors) = try_from_vec_lenient(items, name);
+    for why in errors.iter() {
+        println!("Skipped from list: {}", why);
+    }
+    Ok((vec, errors.len()))
+}
+
+// the same decision for every backend, from just the fields link_status reads
+pub fn link_status (downloaded_at: Option<i64>, expires_at: i64, deleted_at: Option<i64>, revoked_at: Option<i64>, now: i64) -> LinkStatus {
+    if deleted_at.is_some() {
+        LinkStatus::Missing
+    } else if revoked_at.is_some() {
+        LinkStatus::Revoked
+    } else if downloaded_at.is_some() {
+        LinkStatus::Consumed
+    } else if expires_at < now {
+        LinkStatus::Expired
+    } else {
+        LinkStatus::Pending
+    }
+}
+
+fn ordered (ordering: Ordering, order: SortOrder) -> Ordering {
+    match order {
+        SortOrder::Asc => ordering,
+        SortOrder::Desc => ordering.reverse(),
+    }
+}
+
+// for storage that can't sort server side, eg dynamodb scans
+pub fn sort_files (files: &mut Vec<OnetimeFile>, sort: ListSort) {
+    match sort.field {
+        SortField::CreatedAt => files.sort_by(|a, b| ordered(a.created_at.cmp(&b.created_at), sort.order)),
+        // never expiring goes last, like nulls do in postgres
+        SortField::ExpiresAt => files.sort_by(|a, b| ordered(a.expires_at.unwrap_or(i64::MAX).cmp(&b.expires_at.unwrap_or(i64::MAX)), sort.order)),
+        SortField::Filename => files.sort_by(|a, b| ordered(a.filename.cmp(&b.filename), sort.order)),
+    }
+}
+
+pub fn sort_links (links: &mut Vec<OnetimeLink>, sort: ListSort) {
+    match sort.field {
+        SortField::CreatedAt => links.sort_by(|a, b| ordered(a.created_at.cmp(&b.created_at), sort.order)),
+        SortField::ExpiresAt => links.sort_by(|a, b| ordered(a.expires_at.cmp(&b.expires_at), sort.order)),
+        SortField::Filename => links.sort_by(|a, b| ordered(a.filename.cmp(&b.filename), sort.order)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Row(&'static str);
+    #[derive(Debug, PartialEq)]
+    struct Parsed(i64);
+
+    impl TryFrom<Row> for Parsed {
+        type Error = MyError;
+
+        fn try_from (row: Row) -> Result<Self, Self::Error> {
+            row.0.parse().map(Parsed).map_err(|why| format!("Not a number '{}'! {}", row.0, why))
+        }
+    }
+
+    fn rows () -> Vec<Row> {
+        vec![Row("1"), Row("oops"), Row("3")]
+    }
+
+    #[test]
+    fn strict_list_fails_on_a_malformed_row () {
+        let result: Result<(Vec<Parsed>, usize), MyError> = list_from_vec(rows(), "rows", false);
+        assert!(result.unwrap_err().starts_with("Failed converting rows: Not a number 'oops'!"));
+    }
+
+    #[test]
+    fn lenient_list_keeps_valid_rows_and_counts_the_malformed () {
+        let (parsed, skipped): (Vec<Parsed>, usize) = list_from_vec(rows(), "rows", true).unwrap();
+        assert_eq!(parsed, vec![Parsed(1), Parsed(3)]);
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn lenient_list_of_valid_rows_skips_nothing () {
+        let (parsed, skipped): (Vec<Parsed>, usize) = list_from_vec(vec![Row("1"), Row("2")], "rows", true).unwrap();
+        assert_eq!(parsed, vec![Parsed(1), Parsed(2)]);
+        assert_eq!(skipped, 0);
+    }
+}
@@ -0,0 +1,38 @@
+use opentelemetry_otlp::Uninstall;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+
+// https://docs.rs/tracing-opentelemetry/0.10.0/tracing_opentelemetry/
+// https://docs.rs/opentelemetry-otlp/0.4.0/opentelemetry_otlp/
+// keep the returned guard alive for as long as spans should be exported
+pub fn init (endpoint: &str) -> Option<Uninstall> {
+    if endpoint.is_empty() {
+        return None;
+    }
+
+    let (tracer, uninstall) = match opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .install() {
+        Err(why) => {
+            println!("Failed installing otlp pipeline for {}: {}", endpoint, why);
+            return None;
+        }
+        Ok(pipeline) => pipeline,
+    };
+
+    let result = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init();
+
+    match result {
+        Err(why) => {
+            println!("Failed initializing tracing subscriber: {}", why);
+            None
+        }
+        Ok(_) => {
+            println!("exporting traces to {}", endpoint);
+            Some(uninstall)
+        }
+    }
+}
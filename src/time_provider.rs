@@ -3,6 +3,15 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use dyn_clonable::clonable;
 
 
+// every timestamp stored or sent is unix ms, seconds are only for what needs them (totp steps, a DynamoDB TTL attribute)
+//  and are always converted with this, rounding down, so the two never disagree by more than that
+const MS_PER_S: i64 = 1000;
+
+pub fn ms_to_s (ms: i64) -> i64 {
+    // https://doc.rust-lang.org/std/primitive.i64.html#method.div_euclid -- down for times before 1970 too
+    ms.div_euclid(MS_PER_S)
+}
+
 // https://stackoverflow.com/questions/51822118/why-can-a-function-on-a-trait-object-not-be-called-when-bounded-with-self-size
 // https://stackoverflow.com/questions/42620022/why-does-a-generic-method-inside-a-trait-require-trait-object-to-be-sized
 // https://www.reddit.com/r/rust/comments/7q3bz8/trait_object_with_clone/
@@ -47,3 +56,26 @@ impl TimeProvider for FixedTimeProvider {
         self.fixed_unix_ts_ms
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ms_to_s_rounds_down () {
+        assert_eq!(ms_to_s(0), 0);
+        assert_eq!(ms_to_s(999), 0);
+        assert_eq!(ms_to_s(1000), 1);
+        assert_eq!(ms_to_s(1600000000999), 1600000000);
+    }
+
+    #[test]
+    fn ms_to_s_rounds_down_before_1970 () {
+        // plain division would round these towards zero instead
+        assert_eq!(ms_to_s(-1), -1);
+        assert_eq!(ms_to_s(-999), -1);
+        assert_eq!(ms_to_s(-1000), -1);
+        assert_eq!(ms_to_s(-1001), -2);
+    }
+}
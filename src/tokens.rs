@@ -0,0 +1,148 @@
+use std::str::FromStr;
+use rand::Rng;
+
+
+const BASE62_ALPHABET: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// ceil(128 / log2(62)) so every token has the same length
+const BASE62_LEN: usize = 22;
+// generated tokens are at most 36, signed ones carry a base32 filename so they can be much longer:
+//  for a filename at the default MAX_FILENAME_LEN of 255 that is 2 + 408 + 1 + 13 (expiry) + 1 + 52 (signature) = 477
+pub const MAX_LEN_TOKEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenFormat {
+    Hex,
+    Base62,
+    Uuid,
+}
+
+impl FromStr for TokenFormat {
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hex" => Ok(TokenFormat::Hex),
+            "base62" => Ok(TokenFormat::Base62),
+            "uuid" => Ok(TokenFormat::Uuid),
+            _ => Err(format!("Unknown token format '{}'", s)),
+        }
+    }
+}
+
+fn to_base62 (mut n: u128) -> String {
+    let mut chars = vec![BASE62_ALPHABET[0]; BASE62_LEN];
+    for i in (0..BASE62_LEN).rev() {
+        chars[i] = BASE62_ALPHABET[(n % 62) as usize];
+        n /= 62;
+    }
+    // alphabet is all ascii
+    String::from_utf8(chars).unwrap()
+}
+
+// anything a url token could be, checked before it is used as a key, so junk never costs a storage round trip
+//  hex, base62 and uuid are alphanumeric and '-', signed tokens add '.', and '_' lets in url-safe base64 tokens from imports
+pub fn validate_token (token: &str) -> Result<(), String> {
+    if token.is_empty() {
+        return Err("Empty token!".to_string());
+    }
+    if token.len() > MAX_LEN_TOKEN {
+        return Err(format!("Token too long! {} > {}", token.len(), MAX_LEN_TOKEN));
+    }
+    if !token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_') {
+        return Err("Invalid characters in token!".to_string());
+    }
+    Ok(())
+}
+
+// every format encodes the same 128 random bits, only the presentation differs
+//  uuid is the 8-4-4-4-12 layout only, version bits are not set so no entropy is lost to them
+// https://rust-lang-nursery.github.io/rust-cookbook/algorithms/randomness.html
+pub fn generate_token (format: TokenFormat) -> String {
+    let n: u128 = rand::thread_rng().gen();
+
+    match format {
+        TokenFormat::Hex => format!("{:032x}", n),
+        TokenFormat::Base62 => to_base62(n),
+        TokenFormat::Uuid => {
+            let hex = format!("{:032x}", n);
+            format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_tokens_validate () {
+        for format in &[TokenFormat::Hex, TokenFormat::Base62, TokenFormat::Uuid] {
+            let token = generate_token(*format);
+            assert_eq!(validate_token(token.as_str()), Ok(()), "{:?} {}", format, token);
+        }
+    }
+
+    #[test]
+    fn hex_tokens () {
+        let token = generate_token(TokenFormat::Hex);
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+    }
+
+    #[test]
+    fn base62_tokens () {
+        let token = generate_token(TokenFormat::Base62);
+        assert_eq!(token.len(), BASE62_LEN);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+        // the largest 128 bit value still fits, so no token is ever longer
+        assert_eq!(to_base62(u128::MAX).len(), BASE62_LEN);
+        assert_eq!(to_base62(0), "0".repeat(BASE62_LEN));
+    }
+
+    #[test]
+    fn uuid_tokens () {
+        let token = generate_token(TokenFormat::Uuid);
+        assert_eq!(token.len(), 36);
+        let groups: Vec<usize> = token.split('-').map(|group| group.len()).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert!(token.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn tokens_differ () {
+        assert_ne!(generate_token(TokenFormat::Hex), generate_token(TokenFormat::Hex));
+    }
+
+    #[test]
+    fn format_from_str () {
+        assert_eq!("HEX".parse::<TokenFormat>(), Ok(TokenFormat::Hex));
+        assert_eq!("base62".parse::<TokenFormat>(), Ok(TokenFormat::Base62));
+        assert_eq!("Uuid".parse::<TokenFormat>(), Ok(TokenFormat::Uuid));
+        assert!("base64".parse::<TokenFormat>().is_err());
+    }
+
+    #[test]
+    fn validate_accepts_signed_and_imported () {
+        assert_eq!(validate_token("s.MZUWYZJOOR4HI.1600000000000.ABCDEF"), Ok(()));
+        assert_eq!(validate_token("abc_DEF-123"), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_empty () {
+        assert!(validate_token("").is_err());
+    }
+
+    #[test]
+    fn validate_rejects_too_long () {
+        assert_eq!(validate_token("a".repeat(MAX_LEN_TOKEN).as_str()), Ok(()));
+        assert!(validate_token("a".repeat(MAX_LEN_TOKEN + 1).as_str()).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_bad_chars () {
+        for token in &["abc/def", "abc def", "abc%20", "../etc", "abc?x=1", "tökén"] {
+            assert!(validate_token(token).is_err(), "{}", token);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use rand::Rng;
+use base32::Alphabet;
+use totp_rs::{Algorithm, TOTP};
+
+use crate::time_provider::ms_to_s;
+
+
+// the usual authenticator app settings: https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+const SECRET_LEN: usize = 20;
+const DIGITS: usize = 6;
+const SKEW: u8 = 1;
+const STEP_SECS: u64 = 30;
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret () -> String {
+    let secret: [u8; SECRET_LEN] = rand::thread_rng().gen();
+    base32::encode(ALPHABET, &secret)
+}
+
+pub fn valid_secret (secret: &str) -> bool {
+    base32::decode(ALPHABET, secret).map(|secret| !secret.is_empty()).unwrap_or(false)
+}
+
+pub fn check_code (secret: &str, code: &str, now_ms: i64) -> bool {
+    match base32::decode(ALPHABET, secret) {
+        None => false,
+        Some(secret) => TOTP::new(Algorithm::SHA1, DIGITS, SKEW, STEP_SECS, secret)
+            .check(code, ms_to_s(now_ms) as u64),
+    }
+}
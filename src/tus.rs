@@ -0,0 +1,191 @@
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use bytes::Bytes;
+use serde::{Serialize, Deserialize};
+use actix_web::{error::BlockingError, web};
+
+use crate::models::MyError;
+use crate::tokens::{generate_token, TokenFormat};
+
+
+// https://tus.io/protocols/resumable-upload.html
+pub const TUS_VERSION: &'static str = "1.0.0";
+pub const TUS_EXTENSIONS: &'static str = "creation,termination";
+
+// what the upload will become once all of it has arrived, kept next to the data
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PartialUpload {
+    pub filename: String,
+    pub length: u64,
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub created_at: i64,
+}
+
+// partial uploads on local disk, so resuming has to reach the same instance (or a shared volume)
+//  <id>.json has the PartialUpload, <id>.bin the bytes so far, whose length is the offset
+#[derive(Clone)]
+pub struct UploadArea {
+    dir: PathBuf,
+}
+
+// ids are generated tokens, anything else could be a path
+fn valid_id (id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// std::fs blocks, so every disk call runs on actix's blocking thread pool instead of holding up the worker
+// https://docs.rs/actix-web/2.0.0/actix_web/web/fn.block.html
+async fn blocking<T, F> (f: F) -> Result<T, MyError>
+where
+    F: FnOnce() -> Result<T, MyError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|why| match why {
+        BlockingError::Error(why) => why,
+        BlockingError::Canceled => "Upload disk access was canceled!".to_string(),
+    })
+}
+
+// "key base64value,key base64value", a key may have no value
+pub fn parse_metadata (header: &str) -> Result<HashMap<String, String>, MyError> {
+    let mut metadata = HashMap::new();
+    for pair in header.split(',').map(|pair| pair.trim()).filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next().unwrap_or("").to_string();
+        let value = match parts.next() {
+            None => String::new(),
+            Some(value) => {
+                let value = base64::decode(value.trim()).map_err(|why| format!("Upload-Metadata {} is not base64! {}", key, why))?;
+                String::from_utf8(value).map_err(|_| format!("Upload-Metadata {} is not UTF-8!", key))?
+            }
+        };
+        metadata.insert(key, value);
+    }
+    Ok(metadata)
+}
+
+impl UploadArea {
+    pub fn new (dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    fn info_path (&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn data_path (&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", id))
+    }
+
+    pub async fn create (&self, upload: &PartialUpload) -> Result<String, MyError> {
+        let (area, upload) = (self.clone(), upload.clone());
+        blocking(move || area.create_blocking(&upload)).await
+    }
+
+    // the upload and its current offset, None for an unknown or finished id
+    pub async fn get (&self, id: &str) -> Result<Option<(PartialUpload, u64)>, MyError> {
+        let (area, id) = (self.clone(), id.to_string());
+        blocking(move || area.get_blocking(id.as_str())).await
+    }
+
+    // returns the new offset
+    pub async fn append (&self, id: &str, data: Bytes) -> Result<u64, MyError> {
+        let (area, id) = (self.clone(), id.to_string());
+        blocking(move || area.append_blocking(id.as_str(), &data)).await
+    }
+
+    pub async fn contents (&self, id: &str) -> Result<Vec<u8>, MyError> {
+        let (area, id) = (self.clone(), id.to_string());
+        blocking(move || area.contents_blocking(id.as_str())).await
+    }
+
+    // already gone is fine, there is nothing left to remove
+    pub async fn remove (&self, id: &str) -> Result<(), MyError> {
+        let (area, id) = (self.clone(), id.to_string());
+        blocking(move || area.remove_blocking(id.as_str())).await
+    }
+
+    // abandoned uploads would otherwise stay forever, best effort since a new upload should not fail over an old one
+    pub async fn prune (&self, created_before: i64) {
+        let area = self.clone();
+        let _ = blocking(move || {
+            area.prune_blocking(created_before);
+            Ok(())
+        }).await;
+    }
+
+    fn create_blocking (&self, upload: &PartialUpload) -> Result<String, MyError> {
+        fs::create_dir_all(&self.dir).map_err(|why| format!("Create upload dir failed! {}", why))?;
+        let id = generate_token(TokenFormat::Hex);
+        let info = serde_json::to_vec(upload).map_err(|why| format!("Create upload failed! {}", why))?;
+        fs::write(self.data_path(&id), b"").map_err(|why| format!("Create upload failed! {}", why))?;
+        fs::write(self.info_path(&id), info).map_err(|why| format!("Create upload failed! {}", why))?;
+        Ok(id)
+    }
+
+    fn get_blocking (&self, id: &str) -> Result<Option<(PartialUpload, u64)>, MyError> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        let info = match fs::read(self.info_path(id)) {
+            Err(why) if why.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(why) => return Err(format!("Read upload failed! {}", why)),
+            Ok(info) => info,
+        };
+        let upload: PartialUpload = serde_json::from_slice(&info).map_err(|why| format!("Read upload failed! {}", why))?;
+        let offset = fs::metadata(self.data_path(id)).map_err(|why| format!("Read upload failed! {}", why))?.len();
+        Ok(Some((upload, offset)))
+    }
+
+    fn append_blocking (&self, id: &str, data: &[u8]) -> Result<u64, MyError> {
+        let mut file = OpenOptions::new().append(true).open(self.data_path(id))
+            .map_err(|why| format!("Append upload failed! {}", why))?;
+        file.write_all(data).map_err(|why| format!("Append upload failed! {}", why))?;
+        Ok(file.metadata().map_err(|why| format!("Append upload failed! {}", why))?.len())
+    }
+
+    fn contents_blocking (&self, id: &str) -> Result<Vec<u8>, MyError> {
+        fs::read(self.data_path(id)).map_err(|why| format!("Read upload failed! {}", why))
+    }
+
+    fn remove_blocking (&self, id: &str) -> Result<(), MyError> {
+        if !valid_id(id) {
+            return Ok(());
+        }
+        for path in [self.data_path(id), self.info_path(id)].iter() {
+            match fs::remove_file(path) {
+                Err(why) if why.kind() != ErrorKind::NotFound => return Err(format!("Remove upload failed! {}", why)),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn prune_blocking (&self, created_before: i64) {
+        let entries = match fs::read_dir(&self.dir) {
+            Err(_) => return,
+            Ok(entries) => entries,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let id = match name.strip_suffix(".json") {
+                Some(id) => id.to_string(),
+                None => continue,
+            };
+            if let Ok(Some((upload, _))) = self.get_blocking(id.as_str()) {
+                if upload.created_at < created_before {
+                    if let Err(why) = self.remove_blocking(id.as_str()) {
+                        println!("Prune upload {} failed: {}", id, why);
+                    }
+                }
+            }
+        }
+    }
+}
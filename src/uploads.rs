@@ -0,0 +1,130 @@
+
+use std::fmt::Display;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use futures::stream::LocalBoxStream;
+
+
+// chunks are often only a few KB, so the shared counter is only touched once this much has built up
+const FLUSH_AT: u64 = 64 * 1024;
+
+// totals for the whole server, not per worker
+#[derive(Clone)]
+pub struct UploadMetrics {
+    bytes_total: Arc<AtomicU64>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+// one per upload request, logs its size and duration when dropped, however the upload ended
+pub struct UploadProgress {
+    metrics: UploadMetrics,
+    label: String,
+    started: Instant,
+    size: u64,
+    unflushed: u64,
+}
+
+impl UploadMetrics {
+    pub fn new () -> Self {
+        Self {
+            bytes_total: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn bytes_total (&self) -> u64 {
+        self.bytes_total.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight (&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn start (&self, label: String) -> UploadProgress {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        UploadProgress {
+            metrics: self.clone(),
+            label: label,
+            started: Instant::now(),
+            size: 0,
+            unflushed: 0,
+        }
+    }
+}
+
+impl UploadProgress {
+    pub fn add (&mut self, len: usize) {
+        self.size += len as u64;
+        self.unflushed += len as u64;
+        if self.unflushed >= FLUSH_AT {
+            self.flush();
+        }
+    }
+
+    fn flush (&mut self) {
+        self.metrics.bytes_total.fetch_add(self.unflushed, Ordering::Relaxed);
+        self.unflushed = 0;
+    }
+}
+
+impl Drop for UploadProgress {
+    fn drop (&mut self) {
+        self.flush();
+        self.metrics.in_flight.fetch_sub(1, Ordering::SeqCst);
+        let elapsed_ms = self.started.elapsed().as_millis();
+        // bytes per ms is KB per second, near enough for spotting a slow client
+        println!("upload {} {} bytes in {}ms ({} KB/s)", self.label, self.size, elapsed_ms, self.size as u128 / u128::max(1, elapsed_ms));
+    }
+}
+
+// how slow a client may send an upload before it is cut off, so a trickle can't hold a worker forever
+//  0 disables either check
+#[derive(Clone, Copy)]
+pub struct UploadPace {
+    // longest wait for the next chunk
+    pub idle_timeout_ms: u64,
+    // average over the whole upload so far, only checked once grace_ms have passed
+    pub min_bytes_per_s: u64,
+    pub grace_ms: u64,
+}
+
+pub enum UploadError {
+    Read(String),
+    TooSlow(String),
+}
+
+// the body's chunks as they come, ending with a TooSlow error once the client falls below the pace
+pub fn paced<S, E> (body: S, pace: UploadPace) -> LocalBoxStream<'static, Result<Bytes, UploadError>>
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: Display + 'static,
+{
+    // https://docs.rs/futures/0.3/futures/stream/fn.unfold.html -- the flag ends the stream after its error
+    stream::unfold((body.boxed_local(), Instant::now(), 0u64, false), move |(mut body, started, received, failed)| async move {
+        if failed {
+            return None;
+        }
+        let next = if pace.idle_timeout_ms == 0 {
+            Ok(body.next().await)
+        } else {
+            tokio::time::timeout(Duration::from_millis(pace.idle_timeout_ms), body.next()).await
+        };
+        match next {
+            Err(_) => Some((Err(UploadError::TooSlow(format!("No upload data for {}ms!", pace.idle_timeout_ms))), (body, started, received, true))),
+            Ok(None) => None,
+            Ok(Some(Err(why))) => Some((Err(UploadError::Read(format!("Reading upload failed! {}", why))), (body, started, received, true))),
+            Ok(Some(Ok(data))) => {
+                let received = received + data.len() as u64;
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+                if pace.min_bytes_per_s > 0 && elapsed_ms > pace.grace_ms && received * 1000 / elapsed_ms < pace.min_bytes_per_s {
+                    let why = format!("Upload too slow! {} bytes in {}ms", received, elapsed_ms);
+                    return Some((Err(UploadError::TooSlow(why)), (body, started, received, true)));
+                }
+                Some((Ok(data), (body, started, received, false)))
+            }
+        }
+    }).boxed_local()
+}